@@ -0,0 +1,102 @@
+use crate::image_processing::adjust::Adjustment;
+use crate::image_processing::selection::{restrict_to_selection, Selection};
+use crate::image_processing::ProcessingError;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, composite_for_selection, edit_image_file, edit_layer_pixels,
+            history_params, track_history};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Adjustment Route Handler Functions ***** ////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Apply a colour adjustment ////////////////////////////////////////////////////
+/// Runs one of the colour adjustments over an image or a single layer. The
+/// adjustment is picked by the path, the rest of the body is its parameters.
+/// An optional 'selection' limits the adjustment to the selected area.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'op' - A web::Path with the adjustment name: brightness_contrast, levels,
+///   curves or hue_saturation.
+/// * 'request' - A web::Json containing the target and the parameters.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new file hash of the image, or the ID of
+/// the edited layer.
+///
+/// # Example Request
+///
+/// POST /api/adjust/brightness_contrast
+/// Body: { "image_id": 1, "brightness": 10, "contrast": 25 }
+///
+/// POST /api/adjust/levels
+/// Body: { "image_id": 1, "input_black": 12, "input_white": 240, "gamma": 1.2 }
+///
+/// POST /api/adjust/curves
+/// Body: { "image_id": 1, "layer_id": 3, "rgb": [[0, 0], [64, 50], [190, 210], [255, 255]] }
+///
+/// POST /api/adjust/hue_saturation
+/// Body: { "image_id": 1, "hue": -15, "saturation": 20, "lightness": 0 }
+pub async fn adjust_handler(pool: web::Data<Pool>,
+                            user: AuthenticatedUser,
+                            op: web::Path<String>,
+                            request: web::Json<AdjustRequest>)
+                            -> HttpResponse
+{
+    let (op, request) = (op.into_inner(), request.into_inner());
+    if let Err(response) = authorize_image(&pool, &user, request.image_id).await {
+        return response;
+    }
+
+    let op_params = history_params(&request, None);
+    let selection = request.selection;
+    let adjustment = match Adjustment::from_op(&op, request.params) {
+        Ok(adjustment) => adjustment,
+        Err(ProcessingError::InvalidParameter(msg)) => return HttpResponse::BadRequest().json(msg),
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    track_history(&pool, request.image_id, &op, op_params, async {
+        match request.layer_id {
+            Some(layer_id) => {
+                let image_id = request.image_id;
+                let composite = composite_for_selection(&pool, image_id, selection.as_ref()).await;
+                let composite = match composite {
+                    Ok(composite) => composite,
+                    Err(response) => return response,
+                };
+                edit_layer_pixels(&pool, request.image_id, layer_id, move |pixels| {
+                    let mut adjusted = pixels.clone();
+                    adjustment.apply(&mut adjusted)?;
+                    restrict_to_selection(selection.as_ref(), &pixels, composite.as_ref(), adjusted)
+                }).await
+            }
+            None => {
+                edit_image_file(&pool, request.image_id, move |image| {
+                    let pixels = image.to_rgba8();
+                    let mut adjusted = pixels.clone();
+                    adjustment.apply(&mut adjusted)?;
+                    let adjusted =
+                        restrict_to_selection(selection.as_ref(), &pixels, None, adjusted)?;
+                    Ok(DynamicImage::ImageRgba8(adjusted))
+                }).await
+            }
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdjustRequest
+{
+    image_id: i32,
+    layer_id: Option<i32>,
+    selection: Option<Selection>,
+    #[serde(flatten)]
+    params: serde_json::Value,
+}
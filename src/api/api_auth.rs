@@ -0,0 +1,371 @@
+use crate::auth;
+use crate::config;
+use crate::db;
+use crate::db::users::Role;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{AUTHORIZATION, USER_AGENT, WWW_AUTHENTICATE};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use deadpool_postgres::Pool;
+use futures::future::LocalBoxFuture;
+use serde::Deserialize;
+use serde_json::json;
+use tokio_postgres::error::SqlState;
+
+use super::MyDbError;
+
+// Cookie the session token is kept in for browsers
+pub const SESSION_COOKIE: &str = "session_token";
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Auth Route Handler Functions ***** //////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Register Handler /////////////////////////////////////////////////////////////
+/// Create a user who logs in with a password. The password is hashed with
+/// Argon2 and only the hash is stored.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - The username, email and password of the new user. Passwords
+///   must be 8 to 1024 characters long.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new user's ID, or 409 if the username or
+/// email is already taken.
+///
+/// # Example Request
+///
+/// POST /auth/register
+/// Body: { "username": "ada", "email": "ada@example.com", "password": "correct horse" }
+pub async fn register_handler(pool: web::Data<Pool>,
+                              request: web::Json<RegisterRequest>)
+                              -> HttpResponse
+{
+    let RegisterRequest { username, email, password } = request.into_inner();
+    let username = username.trim().to_string();
+    let email = email.trim().to_string();
+    if username.is_empty() || username.chars().count() > 50 {
+        return HttpResponse::BadRequest().json("username must be 1 to 50 characters long.");
+    }
+    if !email.contains('@') {
+        return HttpResponse::BadRequest().json("email is not a valid email address.");
+    }
+    let password_length = password.chars().count();
+    if !(auth::MIN_PASSWORD_LENGTH..=auth::MAX_PASSWORD_LENGTH).contains(&password_length) {
+        return HttpResponse::BadRequest().json(format!("password must be {} to {} characters long.",
+                                                       auth::MIN_PASSWORD_LENGTH,
+                                                       auth::MAX_PASSWORD_LENGTH));
+    }
+
+    // Argon2 is slow on purpose, keep it off the async workers
+    let password_hash = match web::block(move || auth::hash_password(&password)).await {
+        Ok(Ok(password_hash)) => password_hash,
+        _ => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    match db::users::register_user(&pool, &username, &email, &password_hash).await {
+        Ok(user_id) => HttpResponse::Created().json(json!({
+            "status": "success",
+            "user_id": user_id,
+            "username": username,
+        })),
+        Err(MyDbError::PostgresError(e)) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            let taken = match e.as_db_error().and_then(|e| e.constraint()) {
+                Some("users_email_key") => "email",
+                _ => "username",
+            };
+            HttpResponse::Conflict().json(format!("That {} is already taken.", taken))
+        }
+        Err(e) => {
+            println!("Error registering user: {:?}", e);
+            HttpResponse::InternalServerError().json("Internal server error")
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RegisterRequest
+{
+    username: String,
+    email: String,
+    password: String,
+}
+
+/// Login Handler ////////////////////////////////////////////////////////////////
+/// Check a username and password and start a session. The session token is
+/// returned once, in the body and as an HttpOnly cookie; the server only keeps
+/// its hash. Send it back as 'Authorization: Bearer <token>' or with the cookie.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'req' - The request, for the client's address and user agent.
+/// * 'request' - The username and password.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the token and when the session expires unless it
+/// is used, or 401 with the same message whether the username or the password
+/// was wrong. Every request with the token pushes the expiry further out.
+///
+/// # Example Request
+///
+/// POST /auth/login
+/// Body: { "username": "ada", "password": "correct horse" }
+pub async fn login_handler(pool: web::Data<Pool>,
+                           req: HttpRequest,
+                           request: web::Json<LoginRequest>)
+                           -> HttpResponse
+{
+    let LoginRequest { username, password } = request.into_inner();
+    let password_hash = match db::users::get_user_by_username(&pool, username.trim()).await {
+        Ok(user) => user.password_hash.map(|password_hash| (user.id, password_hash)),
+        Err(MyDbError::NotFound) => None,
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    let verified = web::block(move || match password_hash {
+        Some((user_id, password_hash)) => {
+            auth::verify_password(&password, &password_hash).then_some(user_id)
+        }
+        None => {
+            auth::burn_password_check(&password);
+            None
+        }
+    }).await;
+    let user_id = match verified {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return HttpResponse::Unauthorized().json("Invalid username or password."),
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    let token = auth::new_session_token();
+    let session_data = json!({
+        "ip": req.connection_info().realip_remote_addr(),
+        "user_agent": req.headers().get(USER_AGENT).and_then(|value| value.to_str().ok()),
+    });
+    let token_hash = auth::hash_session_token(&token);
+    let session = match db::sessions::create_a_session(&pool, user_id, &token_hash, session_data)
+                            .await
+    {
+        Ok(session) => session,
+        Err(e) => {
+            println!("Error creating session: {:?}", e);
+            return HttpResponse::InternalServerError().json("Internal server error");
+        }
+    };
+
+    // The session can slide up to its maximum lifetime, the cookie has to last as long
+    let lifetime = config::session_max_lifetime().as_secs() as i64;
+    let cookie = Cookie::build(SESSION_COOKIE, token.clone())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(time::Duration::seconds(lifetime))
+        .finish();
+    HttpResponse::Ok().cookie(cookie).json(json!({
+        "status": "success",
+        "user_id": user_id,
+        "token": token,
+        "token_type": "Bearer",
+        "expires_at": session.expiration_time,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest
+{
+    username: String,
+    password: String,
+}
+
+/// Logout Handler ///////////////////////////////////////////////////////////////
+/// End the session the request's token belongs to and clear the cookie.
+///
+/// # Returns
+///
+/// Return an HttpResponse confirming the logout, or 401 without a live session.
+///
+/// # Example Request
+///
+/// POST /auth/logout
+/// Header: Authorization: Bearer <token>
+pub async fn logout_handler(pool: web::Data<Pool>, user: AuthenticatedUser) -> HttpResponse
+{
+    match db::sessions::end_session(&pool, user.session_id).await {
+        Ok(_) => HttpResponse::Ok().cookie(removal_cookie()).json(json!({
+            "status": "success",
+            "message": "Logged out.",
+        })),
+        Err(MyDbError::NotFound) => {
+            HttpResponse::Unauthorized().cookie(removal_cookie()).json("Not logged in.")
+        }
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+/// Logout Everywhere Handler ////////////////////////////////////////////////////
+/// End every session of the logged in user, this one included, e.g. after a
+/// device was lost.
+///
+/// # Returns
+///
+/// Return an HttpResponse with how many sessions were ended.
+///
+/// # Example Request
+///
+/// POST /auth/logout_all
+/// Header: Authorization: Bearer <token>
+pub async fn logout_all_handler(pool: web::Data<Pool>, user: AuthenticatedUser) -> HttpResponse
+{
+    match db::sessions::end_all_sessions_for_user(&pool, user.user_id).await {
+        Ok(ended) => HttpResponse::Ok().cookie(removal_cookie()).json(json!({
+            "status": "success",
+            "sessions_ended": ended,
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+/// Current User Handler /////////////////////////////////////////////////////////
+/// Tell a client who its session belongs to.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the user's ID, username and role and the
+/// session's ID, or 401 without a live session.
+///
+/// # Example Request
+///
+/// GET /auth/me
+/// Header: Authorization: Bearer <token>
+pub async fn current_user_handler(user: AuthenticatedUser) -> HttpResponse
+{
+    HttpResponse::Ok().json(json!({
+        "status": "success",
+        "user_id": user.user_id,
+        "username": user.username,
+        "role": user.role,
+        "session_id": user.session_id,
+    }))
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Request Authentication ********** ////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// The user whose session a request carries. Taking it as a handler argument
+/// makes the route require a live session: a request without a token, or with
+/// an unknown or expired one, is answered with 401 before the handler runs.
+/// Every authenticated request counts as activity on the session.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser
+{
+    pub user_id: i32,
+    pub username: String,
+    pub role: Role,
+    pub session_id: i32,
+}
+
+impl FromRequest for AuthenticatedUser
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<AuthenticatedUser, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future
+    {
+        let pool = req.app_data::<web::Data<Pool>>().cloned();
+        let token = session_token(req);
+        Box::pin(async move {
+            let pool = pool.ok_or_else(|| rejection(HttpResponse::InternalServerError()
+                                                        .json("Internal server error")))?;
+            let token = token.ok_or_else(|| unauthorized("Not logged in."))?;
+
+            let token_hash = auth::hash_session_token(&token);
+            let session = match db::sessions::touch_session(&pool, &token_hash).await {
+                Ok(session) => session,
+                Err(MyDbError::NotFound) => return Err(unauthorized("Invalid or expired session.")),
+                Err(e) => {
+                    println!("Error checking session: {:?}", e);
+                    return Err(rejection(HttpResponse::InternalServerError()
+                                             .json("Internal server error")));
+                }
+            };
+            match db::users::get_user_by_id(&pool, session.user_id).await {
+                Ok(user) => Ok(AuthenticatedUser { user_id: user.id,
+                                                   username: user.username,
+                                                   role: user.role,
+                                                   session_id: session.id }),
+                Err(MyDbError::NotFound) => Err(unauthorized("Invalid or expired session.")),
+                Err(_) => Err(rejection(HttpResponse::InternalServerError()
+                                            .json("Internal server error"))),
+            }
+        })
+    }
+}
+
+/// A logged in user with the admin role. Routes that take it answer 401 without
+/// a live session, like AuthenticatedUser, and 403 for users who aren't admins.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthenticatedUser);
+
+impl FromRequest for AdminUser
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<AdminUser, actix_web::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future
+    {
+        let user = AuthenticatedUser::from_request(req, payload);
+        Box::pin(async move {
+            let user = user.await?;
+            if user.role != Role::Admin {
+                return Err(rejection(HttpResponse::Forbidden().json("Admins only.")));
+            }
+            Ok(AdminUser(user))
+        })
+    }
+}
+
+// A 401 that tells clients to send a bearer token
+fn unauthorized(message: &str) -> actix_web::Error
+{
+    rejection(HttpResponse::Unauthorized().insert_header((WWW_AUTHENTICATE, "Bearer"))
+                                          .json(message))
+}
+
+// Turn a response into the error an extractor fails with
+fn rejection(response: HttpResponse) -> actix_web::Error
+{
+    let message = response.status().to_string();
+    InternalError::from_response(message, response).into()
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// A Set-Cookie that makes the browser drop its session cookie
+fn removal_cookie() -> Cookie<'static>
+{
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+    removal
+}
+
+// The session token a request carries: a bearer token, or else the cookie
+pub(crate) fn session_token(req: &HttpRequest) -> Option<String>
+{
+    let bearer = req.headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(|token| token.trim().to_string());
+    bearer.or_else(|| req.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string()))
+          .filter(|token| !token.is_empty())
+}
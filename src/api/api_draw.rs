@@ -0,0 +1,62 @@
+use crate::image_processing::draw::{self, Brush, StrokePoint};
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, edit_layer_pixels, history_params, track_history};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Draw Route Handler Functions ***** //////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Paint a brush stroke onto a layer ////////////////////////////////////////////
+/// Rasterizes a stroke of pressure-annotated points and stores the result in
+/// the layer's layer_data.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - A web::Json containing the layer, brush settings and points.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the image and layer ID that were painted on.
+///
+/// # Example Request
+///
+/// POST /api/draw/stroke
+/// Body: {
+///     "image_id": 1,
+///     "layer_id": 2,
+///     "brush": { "size": 24, "hardness": 0.8, "spacing": 0.2, "opacity": 1.0,
+///                "flow": 0.6, "color": [200, 30, 30, 255], "eraser": false },
+///     "points": [ { "x": 10, "y": 10, "pressure": 0.4 }, { "x": 80, "y": 45, "pressure": 0.9 } ]
+/// }
+pub async fn stroke_handler(pool: web::Data<Pool>,
+                            user: AuthenticatedUser,
+                            request: web::Json<StrokeRequest>)
+                            -> HttpResponse
+{
+    let request = request.into_inner();
+    let (image_id, op_params) = (request.image_id, history_params(&request, None));
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let (brush, points) = (request.brush, request.points);
+
+    let edit = edit_layer_pixels(&pool, image_id, request.layer_id, move |mut pixels| {
+        draw::draw_stroke(&mut pixels, &brush, &points)?;
+        Ok(pixels)
+    });
+    track_history(&pool, image_id, "stroke", op_params, edit).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StrokeRequest
+{
+    image_id: i32,
+    layer_id: i32,
+    brush: Brush,
+    points: Vec<StrokePoint>,
+}
@@ -0,0 +1,87 @@
+use crate::image_processing::filter::{self, Blur};
+use crate::image_processing::selection::{restrict_to_selection, Selection};
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, composite_for_selection, edit_image_file, edit_layer_pixels,
+            history_params, track_history};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Filter Route Handler Functions ***** ////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Blur an image or a single layer //////////////////////////////////////////////
+/// Applies a Gaussian, box or motion blur. Without a 'layer_id' the whole image
+/// is blurred and saved as a new revision, otherwise only that layer's pixels
+/// are replaced. An optional 'selection' limits the blur to the selected area.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - A web::Json containing the target and blur settings.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new file hash of the image, or the ID of
+/// the edited layer, and the ID of the new revision as 'revision_id'.
+///
+/// # Example Request
+///
+/// POST /api/filter/blur
+/// Body: { "image_id": 1, "kind": "gaussian", "sigma": 2.5 }
+/// Body: { "image_id": 1, "layer_id": 4, "kind": "box", "radius": 3 }
+/// Body: { "image_id": 1, "kind": "motion", "angle": 30, "length": 25 }
+/// Body: { "image_id": 1, "kind": "gaussian", "sigma": 4,
+///         "selection": { "parts": [ { "shape": "ellipse", "x": 0, "y": 0, "width": 300, "height": 200 } ],
+///                        "feather": 10 } }
+pub async fn blur_handler(pool: web::Data<Pool>,
+                          user: AuthenticatedUser,
+                          request: web::Json<BlurRequest>)
+                          -> HttpResponse
+{
+    let request = request.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, request.image_id).await {
+        return response;
+    }
+
+    let op_params = history_params(&request, None);
+    let (blur, selection) = (request.blur, request.selection);
+
+    track_history(&pool, request.image_id, "blur", op_params, async {
+        match request.layer_id {
+            Some(layer_id) => {
+                let image_id = request.image_id;
+                let composite = composite_for_selection(&pool, image_id, selection.as_ref()).await;
+                let composite = match composite {
+                    Ok(composite) => composite,
+                    Err(response) => return response,
+                };
+                edit_layer_pixels(&pool, request.image_id, layer_id, move |pixels| {
+                    let blurred = filter::blur(&pixels, blur)?;
+                    restrict_to_selection(selection.as_ref(), &pixels, composite.as_ref(), blurred)
+                }).await
+            }
+            None => {
+                edit_image_file(&pool, request.image_id, move |image| {
+                    let pixels = image.to_rgba8();
+                    let blurred = filter::blur(&pixels, blur)?;
+                    let blurred =
+                        restrict_to_selection(selection.as_ref(), &pixels, None, blurred)?;
+                    Ok(DynamicImage::ImageRgba8(blurred))
+                }).await
+            }
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlurRequest
+{
+    image_id: i32,
+    layer_id: Option<i32>,
+    #[serde(flatten)]
+    blur: Blur,
+    selection: Option<Selection>,
+}
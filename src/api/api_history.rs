@@ -0,0 +1,130 @@
+use crate::db;
+use crate::db::history::HistoryEntry;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use serde_json::json;
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, MyDbError};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** History Route Handler Functions ***** ///////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Get History Handler //////////////////////////////////////////////////////////
+/// List the recorded edits of an image, oldest first. Entries marked 'undone'
+/// are the ones redo would apply again.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the history entries and whether undo and redo
+/// are possible.
+///
+/// # Example Request
+///
+/// GET /image/1/history
+pub async fn get_history_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 image_id: web::Path<i32>)
+                                 -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    match db::history::get_history(&pool, image_id).await {
+        Ok(entries) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": image_id,
+            "can_undo": entries.iter().any(|entry| !entry.undone),
+            "can_redo": entries.iter().any(|entry| entry.undone),
+            "history": entries,
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+/// Undo Handler /////////////////////////////////////////////////////////////////
+/// Revert the newest edit of an image that hasn't been undone yet. The image
+/// file, layers and groups go back to how they were before that edit.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the history entry that was undone.
+///
+/// # Example Request
+///
+/// POST /image/1/undo
+pub async fn undo_handler(pool: web::Data<Pool>,
+                          user: AuthenticatedUser,
+                          image_id: web::Path<i32>)
+                          -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let _edit_lock = super::lock_image_edits(image_id).await;
+    let result = db::history::undo(&pool, image_id).await;
+    if result.is_ok() {
+        super::refresh_thumbnails(&pool, image_id);
+    }
+    history_step_response(image_id, result, "Nothing to undo.")
+}
+
+/// Redo Handler /////////////////////////////////////////////////////////////////
+/// Apply the oldest undone edit of an image again. Making a new edit after an
+/// undo clears what could be redone.
+///
+/// # Example Request
+///
+/// POST /image/1/redo
+pub async fn redo_handler(pool: web::Data<Pool>,
+                          user: AuthenticatedUser,
+                          image_id: web::Path<i32>)
+                          -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let _edit_lock = super::lock_image_edits(image_id).await;
+    let result = db::history::redo(&pool, image_id).await;
+    if result.is_ok() {
+        super::refresh_thumbnails(&pool, image_id);
+    }
+    history_step_response(image_id, result, "Nothing to redo.")
+}
+
+// Shared response once undo or redo has run /////////////////////////////////////
+fn history_step_response(image_id: i32,
+                         result: Result<HistoryEntry, MyDbError>,
+                         nothing_left: &str)
+                         -> HttpResponse
+{
+    match result {
+        Ok(entry) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": image_id,
+            "entry": entry,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json(nothing_left),
+        Err(e) => {
+            println!("Error walking history of image {}: {:?}", image_id, e);
+            HttpResponse::InternalServerError().json("Internal server error")
+        }
+    }
+}
@@ -1,20 +1,23 @@
 use crate::db;
-use actix_web::{ App,
-                 HttpResponse,
+use actix_web::{ HttpResponse,
                  HttpRequest,
-                 Error,
                  web,
-                 post,
-                 get,
                  http::header::CONTENT_LENGTH
                     }; 
 use actix_multipart::Multipart;
 use deadpool_postgres::Pool;
 use futures::{StreamExt, TryStreamExt};
-use std::io::Write;
-use serde::Serialize;
-use super::MyDbError;
-use rand::Rng;
+use std::io::Cursor;
+use image::{DynamicImage, ImageFormat};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, MyDbError};
+use crate::db::images::FileInfo;
+use crate::image_processing::{self, thumbnail};
+use crate::image_processing::export::{self, ExportFormat};
+use crate::image_processing::upload::{self, UploadError, MAX_UPLOAD_BYTES};
 use actix_web::http::header::ContentDisposition;
 
 //////////////////////////////////////////////////////////////////////////////////
@@ -24,95 +27,141 @@ use actix_web::http::header::ContentDisposition;
 //////////////////////////////////////////////////////////////////////////////////
 
 /// ADD IMAGE HANDLER  
-/// To allow users to upload an image. This function expect the username to ID
-/// the user adn the path of the image to be uploaded.
+/// To allow users to upload an image. The image belongs to the logged in user,
+/// taken from the request's session.
+///
+/// The file is checked before it is stored: the format is sniffed from its
+/// contents (the client's Content-Type is ignored), and the width, height and
+/// pixel count are read from the header and limited before anything is decoded.
+/// The real MIME type, dimensions and byte size are saved with the image.
 ///
 /// # Arguements
 ///
 /// * 'pool' - A reference to the database connection pool.
-/// * 'file_path' - The path to the image file.
-/// * 'file_type' - The type of the image file.
+/// * 'user' - The logged in user, who will own the image.
+/// * 'payload' - A multipart form with the image file.
 ///
 /// # Returns
 /// 
-/// Return an HttpResponse indicating the outcome of the operation.
+/// Return an HttpResponse with the new image ID and what the file turned out to
+/// be. A rejected file gets a JSON error with a machine readable 'error' code:
+/// 413 for 'file_too_large', 415 for 'unsupported_format', 400 for
+/// 'empty_file', 'missing_file' and 'incomplete_upload', and 422 for
+/// 'corrupt_image', 'dimensions_too_large' and 'too_many_pixels'. Only the
+/// first 'file' field is read, other fields are skipped.
 ///
 /// # Example Request
 /// 
-/// 
+/// POST /image/add_image
+/// Body: multipart/form-data with a 'file' field, e.g. curl -F "file=@photo.jpg"
 ///
 #[derive(Serialize)]
 struct ImageUploadResponse {
     message: String,
     image_id: i32,
     image_url: String,
+    file_type: String,
+    width: i32,
+    height: i32,
+    byte_size: usize,
 }
 
-#[post("/image/add_image")]
+// Room for the multipart boundaries and headers around the file itself
+const MULTIPART_OVERHEAD: usize = 64 * 1024;
+
 pub async fn add_image_handler(
     pool: web::Data<Pool>,
+    user: AuthenticatedUser,
     mut payload: Multipart,
     req: HttpRequest,
 ) -> HttpResponse {
 
-    let content_length: usize = match req.headers().get( CONTENT_LENGTH ){
-        Some( hv) => hv.to_str()unwrap_or("0" ).parse().unwrap(); 
-        None => 0, 
-    }; 
-
-    if content_length == 0 || content_length > max_file_size { return HttpResponse::BadRequest().into(); } 
-    
-    
+    // Chunked uploads have no Content-Length, they are capped as they stream in
+    let content_length: Option<usize> = req.headers()
+                                           .get( CONTENT_LENGTH )
+                                           .and_then( |hv| hv.to_str().ok() )
+                                           .and_then( |hv| hv.parse().ok() );
 
-    let mut rng = rand::thread_rng();
-    let user_id: i32 = rng.gen(); // FIXME: This should be the user's ID, extracted from the session
+    if content_length == Some( 0 ) {
+        return upload_error_response( UploadError::Empty );
+    }
+    if let Some( size ) = content_length {
+        if size > MAX_UPLOAD_BYTES + MULTIPART_OVERHEAD {
+            return upload_error_response( UploadError::TooLarge { size } );
+        }
+    }
 
     // Init variables to hold file details
-    let mut saved_file_path = String::new();
-    let mut file_type = String::new();
-
-    // Process the multipart payload
-    while let Ok( Some( mut field ) ) = payload.try_next().await{
-
-        if let Some( content_disposition ) = field.content_disposition() {
-            
-            let filename = content_disposition.get_filename().unwrap_or( "unnamed" ).to_string();
-            let filepath = format!( "./uploads/{}", sanitize_filename( &filename ));
-            saved_file_path = filepath.clone(); // Store file for DB entry
-            file_type = content_disposition.get_param( "Content-Type" ).map( |c| c.as_str()).unwrap_or( "unknown" ).to_string();
-
-            let mut file = match web::block( || std::fs::File::create( &filepath )).await {
-                Ok( file ) => file,
-                Err( e ) => return HttpResponse::InternalServerError().finish(),
-            };
+    let mut file_name = String::new();
+    let mut file_data: Option<Vec<u8>> = None;
+
+    // Process the multipart payload, the file is kept in memory and goes to the
+    // blob storage with the database entry
+    loop {
+        let mut field = match payload.try_next().await {
+            Ok( Some( field ) ) => field,
+            Ok( None ) => break,
+            Err( e ) => return upload_error_response( UploadError::Incomplete( e.to_string() ) ),
+        };
+        // Other fields are skipped, they can't replace the file
+        if field.name() != "file" || file_data.is_some() {
+            continue;
+        }
 
-            while let Some(chunk) = field.next().await {
-                let data = match chunk {
-                    Ok( data ) => data,
-                    Err(_) => return HttpResponse::InternalServerError().finish(),
-                };
+        let filename = field.content_disposition().get_filename().unwrap_or( "unnamed" );
+        file_name = sanitize_filename( filename ); // Store file name for DB entry
+        let file_data = file_data.insert( Vec::new() );
 
-                if let Err(_) = web::block( move || file.write_all( &data )).await {
-                    return HttpResponse::InternalServerError().finish();
+        while let Some(chunk) = field.next().await {
+            let data = match chunk {
+                Ok( data ) => data,
+                Err( e ) => {
+                    return upload_error_response( UploadError::Incomplete( e.to_string() ) )
                 }
+            };
+
+            // Content-Length can't be trusted, check what actually arrives
+            if file_data.len() + data.len() > MAX_UPLOAD_BYTES {
+                let size = file_data.len() + data.len();
+                return upload_error_response( UploadError::TooLarge { size } );
             }
-            // while let Some( chunk ) = field.next().await {
-            //     let data = chunk.map_err( |_| HttpResponse::InternalServerError())?;
-            //     web::block( move || file.write_all( &data ).map( |_| ())).await.map_err(|_| HttpResponse::InternalServerError())?;
-            // }
-        } else {
-            eprintln!( "No content disposition found in the field"); 
+            file_data.extend_from_slice( &data );
         }
-
     }
+
+    let file_data = match file_data {
+        Some( file_data ) => file_data,
+        None => return upload_error_response( UploadError::MissingFile ),
+    };
+
+    // Decoding is CPU heavy, keep it off the async workers
+    let result = web::block( move || {
+        upload::validate_upload( &file_data ).map( |upload| ( upload, file_data ) )
+    }).await;
+    let ( upload, file_data ) = match result {
+        Ok( Ok( validated ) ) => validated,
+        Ok( Err( e ) ) => return upload_error_response( e ),
+        Err(_) => return HttpResponse::InternalServerError().json( "Internal server error"),
+    };
+
+    let file_info = FileInfo { file_type: upload.mime_type().to_string(),
+                               width: upload.width as i32,
+                               height: upload.height as i32 };
+    let byte_size = file_data.len();
+
     // Save the image to the database
-    match db::images::add_image(&pool, &saved_file_path, user_id, &file_type ).await {
+    match db::images::add_image(&pool, &file_name, user.user_id, &file_info, file_data ).await {
 
         Ok( image_id ) => {
+            super::refresh_thumbnails( &pool, image_id );
             let response = ImageUploadResponse {
                 message: "Image has been uploaded successfully.".to_string(),
                 image_id,
-                image_url: format!( "http://yourserver.com/path/to/images/{}", saved_file_path ),
+                image_url: export_url( &req, image_id ),
+                file_type: file_info.file_type,
+                width: file_info.width,
+                height: file_info.height,
+                byte_size,
             };
             HttpResponse::Ok().json( response )
             },
@@ -123,6 +172,35 @@ pub async fn add_image_handler(
         }
 }
 
+// Where the uploaded image can be downloaded, as seen by the client. Honours the
+// Forwarded and X-Forwarded-* headers of a reverse proxy.
+fn export_url(req: &HttpRequest, image_id: i32) -> String {
+    let connection = req.connection_info();
+    format!( "{}://{}/image/{}/export", connection.scheme(), connection.host(), image_id )
+}
+
+// Tell the client why an upload was refused, with the limits it has to stay in
+fn upload_error_response(err: UploadError) -> HttpResponse {
+    let mut response = match err {
+        UploadError::Empty | UploadError::MissingFile | UploadError::Incomplete(_) => {
+            HttpResponse::BadRequest()
+        }
+        UploadError::TooLarge { .. } => HttpResponse::PayloadTooLarge(),
+        UploadError::UnsupportedFormat(_) => HttpResponse::UnsupportedMediaType(),
+        _ => HttpResponse::UnprocessableEntity(),
+    };
+    response.json( json!({
+        "status": "error",
+        "error": err.code(),
+        "message": err.to_string(),
+        "limits": {
+            "max_bytes": MAX_UPLOAD_BYTES,
+            "max_dimension": upload::MAX_UPLOAD_DIMENSION,
+            "max_pixels": upload::MAX_UPLOAD_PIXELS,
+        },
+    }))
+}
+
 // Sanitize filename
 fn sanitize_filename(filename: &str) -> String {
 
@@ -138,24 +216,15 @@ fn sanitize_filename(filename: &str) -> String {
 }
 
 
-// TODO: Get_image
-// QUEST: should this return a vector instead of HttpResonse?
-async fn get_single_image_handler(pool: web::Data<Pool>, image_id: i32) -> HttpResponse
-{
-    match db::images::get_single_image(&pool, image_id).await
-    {
-        Ok(image) => HttpResponse::Ok().json(image), // Return the image data
-        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Image not found."),
-        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
-    }
-}
-
 /// Get all iamges
-async fn get_all_images_handler(pool: web::Data<Pool>, user_id: i32) -> HttpResponse
+/// The images uploaded by the logged in user.
+///
+/// # Example Request
+///
+/// GET /image/all_images
+pub async fn get_all_images_handler(pool: web::Data<Pool>, user: AuthenticatedUser) -> HttpResponse
 {
-    // FIXME: Assuming user_id is extracted from authenticated session
-
-    match db::images::get_all_images(&pool, user_id).await
+    match db::images::get_all_images(&pool, user.user_id).await
     {
         Ok(images) => HttpResponse::Ok().json(images), // Return the actual images
         Err(MyDbError::NotFound) => HttpResponse::NotFound().json("No images found for this user."),
@@ -163,34 +232,253 @@ async fn get_all_images_handler(pool: web::Data<Pool>, user_id: i32) -> HttpResp
     }
 }
 
-/// Update image's file path
-// TODO: what other updates would take place? Adjust this fucntion to reflect that
-// e.g., any image change
-async fn update_image_handler(pool: web::Data<Pool>,
-                            //   _path: web::Path<String>,
-                              image_id: i32,
-                              new_image_path: String)
-                              -> HttpResponse
-{
-    match db::images::update_image(&pool, image_id, &new_image_path).await
-    {
-        Ok(_) => HttpResponse::Ok().json("Image path has been updated."),
-        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Image NOT found."),
-        Err(_) => HttpResponse::InternalServerError().json("Internal server error! Oh no."),
-    }
-}
-
 /// Delete image: Take an image within the database and delete it.
-async fn delete_image_handler(pool: web::Data<Pool>, image_id: web::Path<i32>) -> HttpResponse
+/// Only the user who uploaded it can delete it.
+///
+/// # Example Request
+///
+/// DELETE /image/1
+pub async fn delete_image_handler(pool: web::Data<Pool>,
+                                  user: AuthenticatedUser,
+                                  image_id: web::Path<i32>)
+                                  -> HttpResponse
 {
     let image_id = image_id.into_inner();
-    // Add authorization and validation logic stuff here
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
 
     match db::images::delete_image(&pool, image_id).await
     {
         Ok(_) => HttpResponse::Ok().json(format!("Image with ID {} was deleted succesfully!",
-                                                 image_id.to_string())),
+                                                 image_id)),
         Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Image NOT found!"),
         Err(_) => HttpResponse::InternalServerError().json("Internal Server Error!"),
     }
 }
+
+/// Get Composite Handler ////////////////////////////////////////////////////////
+/// Flatten an image and its visible layers into a single PNG.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the flattened image as 'image/png'.
+///
+/// # Example Request
+///
+/// GET /image/1/composite
+pub async fn get_composite_handler(pool: web::Data<Pool>,
+                                   user: AuthenticatedUser,
+                                   image_id: web::Path<i32>)
+                                   -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let composite = match super::load_composite(&pool, image_id).await {
+        Ok(composite) => composite,
+        Err(response) => return response,
+    };
+
+    let result = web::block(move || {
+        let mut bytes = Vec::new();
+        composite.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).map(|_| bytes)
+    }).await;
+
+    match result {
+        Ok(Ok(bytes)) => HttpResponse::Ok().content_type("image/png").body(bytes),
+        Ok(Err(e)) => {
+            println!("Error encoding composite for image {}: {:?}", image_id, e);
+            HttpResponse::InternalServerError().json("Internal server error")
+        }
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+/// Get Thumbnail Handler ////////////////////////////////////////////////////////
+/// Get a downscaled JPEG of an image with all of its layers. Renditions are
+/// rendered at 128, 512 and 2048 pixels on the long edge whenever the image is
+/// uploaded or edited; the smallest one that is at least 'size' is served, or
+/// the largest if 'size' is bigger than all of them. Images are never scaled up.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'query' - The wanted size of the long edge, 512 if left out.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the rendition as 'image/jpeg'. A rendition that
+/// hasn't been rendered yet is rendered before responding.
+///
+/// # Example Request
+///
+/// GET /image/1/thumbnail?size=128
+pub async fn get_thumbnail_handler(pool: web::Data<Pool>,
+                                   user: AuthenticatedUser,
+                                   image_id: web::Path<i32>,
+                                   query: web::Query<ThumbnailQuery>)
+                                   -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    if query.size == 0 {
+        return HttpResponse::BadRequest().json("size must be at least 1.");
+    }
+    let size = thumbnail::closest_size(query.size);
+
+    let stored = match db::thumbnails::get_thumbnail(&pool, image_id, size).await {
+        Ok(stored) => Some(stored),
+        Err(MyDbError::NotFound) => None,
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+    if let Some(stored) = stored {
+        match db::blobs::get_blob(&stored.blob_hash).await {
+            Ok(data) => return HttpResponse::Ok().content_type(stored.content_type).body(data),
+            // The blob went missing from storage, render it again below
+            Err(MyDbError::NotFound) => {}
+            Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }
+
+    // Images from before thumbnails existed, or a render that hasn't finished
+    let renditions = match super::render_thumbnails(&pool, image_id).await {
+        Ok(renditions) => renditions,
+        Err(response) => return response,
+    };
+    match renditions.into_iter().find(|rendition| rendition.size == size) {
+        Some(rendition) => {
+            HttpResponse::Ok().content_type(thumbnail::THUMBNAIL_CONTENT_TYPE).body(rendition.data)
+        }
+        None => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery
+{
+    #[serde(default = "default_thumbnail_size")]
+    size: u32,
+}
+
+fn default_thumbnail_size() -> u32
+{
+    512
+}
+
+/// Export Image Handler /////////////////////////////////////////////////////////
+/// Download an image as a file in the format the client asks for. By default
+/// the image is flattened with all of its visible layers, like the composite;
+/// with 'flatten=false' only the image file is exported, without the layers.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'query' - 'format' is png (the default), jpeg, webp, tiff or bmp.
+///   'quality' (1 to 100, default 90) applies to jpeg and webp, 100 makes a
+///   lossless webp. JPEG has no transparency, transparent pixels turn white.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the encoded file, its Content-Type and a
+/// Content-Disposition that names it after the uploaded file.
+///
+/// # Example Request
+///
+/// GET /image/1/export?format=jpeg&quality=80
+pub async fn export_image_handler(pool: web::Data<Pool>,
+                                  user: AuthenticatedUser,
+                                  image_id: web::Path<i32>,
+                                  query: web::Query<ExportQuery>)
+                                  -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let ExportQuery { format, quality, flatten } = query.into_inner();
+    let image = match db::images::get_single_image(&pool, image_id).await {
+        Ok(image) => image,
+        Err(MyDbError::NotFound) => return HttpResponse::NotFound().json("Image not found."),
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    let result = if flatten {
+        let composite = match super::load_composite(&pool, image_id).await {
+            Ok(composite) => DynamicImage::ImageRgba8(composite),
+            Err(response) => return response,
+        };
+        super::process_blocking(image_id, move || {
+            export::export_image(&composite, format, quality)
+        }).await
+    } else {
+        let file_data = match db::images::get_image_data(&image).await {
+            Ok(file_data) => file_data,
+            Err(MyDbError::NotFound) => {
+                return HttpResponse::NotFound().json("Image file not found.")
+            }
+            Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+        };
+        super::process_blocking(image_id, move || {
+            let original = image_processing::decode_image(&file_data)?;
+            export::export_image(&original, format, quality)
+        }).await
+    };
+
+    match result {
+        Ok(bytes) => {
+            let file_name = format!("{}.{}", export_file_stem(&image), format.extension());
+            HttpResponse::Ok().content_type(format.content_type())
+                              .insert_header(ContentDisposition::attachment(file_name))
+                              .body(bytes)
+        }
+        Err(response) => response,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery
+{
+    #[serde(default)]
+    format: ExportFormat,
+    quality: Option<u8>,
+    #[serde(default = "default_flatten")]
+    flatten: bool,
+}
+
+fn default_flatten() -> bool
+{
+    true
+}
+
+// The uploaded file's name without its extension, reduced to characters that
+// are safe in a header
+fn export_file_stem(image: &db::images::Image) -> String
+{
+    let stem: String = Path::new(&image.file_path).file_stem()
+                                                  .and_then(|stem| stem.to_str())
+                                                  .unwrap_or_default()
+                                                  .chars()
+                                                  .filter(|c| {
+                                                      c.is_ascii_alphanumeric()
+                                                      || matches!(c, '-' | '_' | ' ')
+                                                  })
+                                                  .collect();
+    match stem.trim() {
+        "" => format!("image-{}", image.id),
+        stem => stem.to_string(),
+    }
+}
@@ -1,36 +1,583 @@
 use crate::db;
-use crate::db::*; 
-use actix_web::{web, App, http, HttpResponse, HttpServer, Responder, test};
-use deadpool_postgres::{Config, Pool};
-use serde::Deserialize;
-use serde_json::json;
-use tokio_postgres::{Error, NoTls, Row};
-use super::MyDbError;
+use crate::db::layers::LayerType;
+use crate::image_processing::composite::{self, BlendMode};
+use crate::image_processing::{self, ProcessingError};
+use actix_web::{web, HttpResponse};
+// use actix_web::{web, App, http, HttpResponse, HttpServer, Responder, test};
+use deadpool_postgres::Pool;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+// use tokio_postgres::{Error, NoTls, Row};
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, history_params, load_image_file, load_layer, process_blocking,
+            track_history, MyDbError};
 
 //////////////////////////////////////////////////////////////////////////////////
 ////////////// *****  Layer Route Handler Functions ***** ////////////////////////
 //////////////////////////////////////////////////////////////////////////////////
 
-/// Add Layer: Add a layer to an existing image
-/// The default should be 1 layer minimum.
-async fn add_layer_handler(pool: web::Data<Pool>,
-                           image_id: i32,
-                           layer_name: &str,
-                           layer_type: &str,
-                           layer_data: &[u8],
-                           order: i32)
-                           -> HttpResponse
-{
-    match db::layers::add_layer(&pool, image_id, layer_name, layer_type, layer_data, order).await
-    {
-        Ok(_) => HttpResponse::Ok().json("Image Layer was added successfully!"),
-        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not added!"),
-        Err(_) => HttpResponse::InternalServerError().json("Internal Server Error!"),
+/// Add Layer: Add a layer to an existing image ///////////////////////////////////
+/// The new layer goes on top of the stack. Raster layers start out transparent
+/// and the size of the image; adjustment, fill, text and shape layers are
+/// described by 'layer_params' and are rendered when the image is composited.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'request' - A web::Json containing the name, type and parameters.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the ID of the new layer.
+///
+/// # Example Request
+///
+/// POST /image/1/layers
+/// Body: { "layer_name": "Paint", "layer_type": "raster" }
+/// Body: { "layer_name": "Warm up", "layer_type": "adjustment",
+///         "layer_params": { "op": "hue_saturation", "params": { "hue": 8, "saturation": 10 } } }
+/// Body: { "layer_name": "Backdrop", "layer_type": "fill", "layer_params": { "color": [20, 20, 40, 255] } }
+/// Body: { "layer_name": "Title", "layer_type": "text",
+///         "layer_params": { "text": "Hello", "font_family": "Open Sans", "font_size": 48,
+///                           "x": 20, "y": 20, "width": 400, "height": 80 } }
+/// Body: { "layer_name": "Badge", "layer_type": "shape",
+///         "layer_params": { "shape": "rectangle", "x": 20, "y": 20, "width": 200, "height": 60,
+///                           "corner_radius": 12, "fill": { "type": "solid", "color": [230, 40, 40, 255] },
+///                           "stroke": { "width": 3, "color": [0, 0, 0, 255], "dash": [8, 4] } } }
+pub async fn add_layer_handler(pool: web::Data<Pool>,
+                               user: AuthenticatedUser,
+                               image_id: web::Path<i32>,
+                               request: web::Json<AddLayerRequest>)
+                               -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let request = request.into_inner();
+
+    let file_data = match load_image_file(&pool, image_id).await {
+        Ok(file_data) => file_data,
+        Err(response) => return response,
+    };
+
+    let layer_data = match (request.layer_type, &request.layer_params) {
+        (LayerType::Raster, None) => {
+            let result = process_blocking(image_id, move || {
+                let image = image_processing::decode_image(&file_data)?;
+                let pixels = RgbaImage::new(image.width(), image.height());
+                Ok(image_processing::encode_layer_data(&pixels)?)
+            }).await;
+            match result {
+                Ok(layer_data) => layer_data,
+                Err(response) => return response,
+            }
+        }
+        (layer_type, layer_params) => {
+            let layer_params = layer_params.clone().unwrap_or(Value::Null);
+            match composite::validate_layer_params(layer_type, &layer_params) {
+                Ok(_) => Vec::new(),
+                Err(ProcessingError::InvalidParameter(msg)) => {
+                    return HttpResponse::BadRequest().json(msg)
+                }
+                Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+            }
+        }
+    };
+
+    track_history(&pool, image_id, "add_layer", history_params(&request, None), async {
+        match db::layers::add_layer(&pool,
+                                    image_id,
+                                    &request.layer_name,
+                                    request.layer_type,
+                                    &layer_data,
+                                    request.layer_params.as_ref(),
+                                    None).await
+        {
+            Ok(layer_id) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "layer_id": layer_id,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not added!"),
+            Err(_) => HttpResponse::InternalServerError().json("Internal Server Error!"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddLayerRequest
+{
+    layer_name: String,
+    #[serde(default)]
+    layer_type: LayerType,
+    layer_params: Option<Value>,
+}
+
+/// Update Layer Params Handler //////////////////////////////////////////////////
+/// Replace the settings of an adjustment, fill, text or shape layer. The layers
+/// below are untouched, the next composite simply renders the new settings.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'layer_params' - A web::Json with the new parameters for the layer's type.
+///
+/// # Returns
+///
+/// Return an HttpResponse indicating the outcome of the operation.
+///
+/// # Example Request
+///
+/// PUT /image/1/layers/6/params
+/// Body: { "op": "levels", "params": { "input_black": 20, "gamma": 1.1 } }
+pub async fn update_layer_params_handler(pool: web::Data<Pool>,
+                                         user: AuthenticatedUser,
+                                         path: web::Path<(i32,
+                                         i32)>,
+                                         layer_params: web::Json<Value>)
+                                         -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let layer_params = layer_params.into_inner();
+
+    let layer = match load_layer(&pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    match composite::validate_layer_params(layer.layer_type, &layer_params) {
+        Ok(_) => {}
+        Err(ProcessingError::InvalidParameter(msg)) => return HttpResponse::BadRequest().json(msg),
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
     }
+
+    let op_params = history_params(&layer_params, Some(layer_id));
+    track_history(&pool, image_id, "update_layer_params", op_params, async {
+        match db::layers::update_layer_params(&pool, layer_id, &layer_params).await {
+            Ok(_) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "layer_id": layer_id,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not found."),
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
 }
 
 // Get layer by layer_id
 
 // Update layer
 
-// Delete layer
\ No newline at end of file
+// Delete layer
+/// Update Blend Mode Handler ////////////////////////////////////////////////////
+/// Change how a layer mixes with the layers below it when the image is
+/// composited.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'request' - A web::Json containing the new blend mode.
+///
+/// # Returns
+///
+/// Return an HttpResponse indicating the outcome of the operation.
+///
+/// # Example Request
+///
+/// PUT /image/1/layers/4/blend_mode
+/// Body: { "blend_mode": "multiply" }
+pub async fn update_blend_mode_handler(pool: web::Data<Pool>,
+                                       user: AuthenticatedUser,
+                                       path: web::Path<(i32,
+                                       i32)>,
+                                       request: web::Json<BlendModeRequest>)
+                                       -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    if let Err(response) = load_layer(&pool, image_id, layer_id).await {
+        return response;
+    }
+
+    let op_params = history_params(&request.0, Some(layer_id));
+    track_history(&pool, image_id, "set_blend_mode", op_params, async {
+        let blend_mode = request.blend_mode;
+        match db::layers::update_layer_blend_mode(&pool, layer_id, blend_mode).await {
+            Ok(_) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "layer_id": layer_id,
+                "blend_mode": blend_mode,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not found."),
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BlendModeRequest
+{
+    blend_mode: BlendMode,
+}
+
+/// Update Clipping Handler //////////////////////////////////////////////////////
+/// Clip a layer to the alpha of the layer (or group) below it, or release it.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'request' - A web::Json containing the clipping flag.
+///
+/// # Returns
+///
+/// Return an HttpResponse indicating the outcome of the operation.
+///
+/// # Example Request
+///
+/// PUT /image/1/layers/4/clipping
+/// Body: { "is_clipped": true }
+pub async fn update_clipping_handler(pool: web::Data<Pool>,
+                                     user: AuthenticatedUser,
+                                     path: web::Path<(i32,
+                                     i32)>,
+                                     request: web::Json<ClippingRequest>)
+                                     -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    if let Err(response) = load_layer(&pool, image_id, layer_id).await {
+        return response;
+    }
+
+    let op_params = history_params(&request.0, Some(layer_id));
+    track_history(&pool, image_id, "set_clipping", op_params, async {
+        let is_clipped = request.is_clipped;
+        match db::layers::update_layer_clipping(&pool, layer_id, is_clipped).await {
+            Ok(_) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "layer_id": layer_id,
+                "is_clipped": is_clipped,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not found."),
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClippingRequest
+{
+    is_clipped: bool,
+}
+
+/// Merge Layers Handler /////////////////////////////////////////////////////////
+/// Composite two or more layers of an image into a single new layer. The
+/// source layers are removed and the merged layer takes the place of the
+/// topmost one.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'request' - A web::Json containing the layers to merge and an optional name.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the LayerGroup describing the merge.
+///
+/// # Example Request
+///
+/// POST /image/1/layers/merge
+/// Body: { "layer_ids": [3, 4, 7], "layer_name": "Background" }
+pub async fn merge_layers_handler(pool: web::Data<Pool>,
+                                  user: AuthenticatedUser,
+                                  image_id: web::Path<i32>,
+                                  request: web::Json<MergeLayersRequest>)
+                                  -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let request = request.into_inner();
+    if request.layer_ids.len() < 2 {
+        return HttpResponse::BadRequest().json("At least two layers are needed to merge.");
+    }
+    let op_params = history_params(&request, None);
+
+    track_history(&pool, image_id, "merge_layers", op_params, async {
+        let layer_name = request.layer_name.unwrap_or_else(|| "Merged Layer".to_string());
+        match db::layers::merge_layers(&pool, image_id, &request.layer_ids, &layer_name).await {
+            Ok(layer_group) => HttpResponse::Ok().json(layer_group),
+            Err(MyDbError::NotFound) => {
+                HttpResponse::NotFound().json("One or more layers not found on this image.")
+            }
+            Err(MyDbError::ImageError(e)) => {
+                println!("Error merging layers of image {}: {}", image_id, e);
+                HttpResponse::UnprocessableEntity().json("Layer data could not be processed.")
+            }
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MergeLayersRequest
+{
+    layer_ids: Vec<i32>,
+    layer_name: Option<String>,
+}
+
+/// Get Layers Handler ///////////////////////////////////////////////////////////
+/// Get the layer tree of an image, bottom first. Groups carry their children,
+/// pixel data is left out.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the layers and groups of the image.
+///
+/// # Example Request
+///
+/// GET /image/1/layers
+pub async fn get_layers_handler(pool: web::Data<Pool>,
+                                user: AuthenticatedUser,
+                                image_id: web::Path<i32>)
+                                -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+    match db::layers::get_layers_by_image_id(&pool, image_id).await {
+        Ok(layer_tree) => HttpResponse::Ok().json(layer_tree),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("No layers found for this image."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+/// Create Layer Group Handler ///////////////////////////////////////////////////
+/// Create a group (folder) on an image and move layers into it. Groups can be
+/// nested by giving a 'parent_id'.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'request' - A web::Json containing the group name, parent and layers.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the ID of the new group.
+///
+/// # Example Request
+///
+/// POST /image/1/groups
+/// Body: { "group_name": "Background", "layer_ids": [2, 3] }
+/// Body: { "group_name": "Shadows", "parent_id": 1, "layer_ids": [5] }
+pub async fn create_layer_group_handler(pool: web::Data<Pool>,
+                                        user: AuthenticatedUser,
+                                        image_id: web::Path<i32>,
+                                        request: web::Json<LayerGroupRequest>)
+                                        -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let request = request.into_inner();
+
+    track_history(&pool, image_id, "create_layer_group", history_params(&request, None), async {
+        match db::layers::create_layer_group(&pool,
+                                             image_id,
+                                             &request.group_name,
+                                             request.parent_id,
+                                             &request.layer_ids).await
+        {
+            Ok(group_id) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "group_id": group_id,
+            })),
+            Err(MyDbError::NotFound) => {
+                HttpResponse::NotFound().json("Parent group or layers not found on this image.")
+            }
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LayerGroupRequest
+{
+    group_name: String,
+    parent_id: Option<i32>,
+    #[serde(default)]
+    layer_ids: Vec<i32>,
+}
+
+/// Update Layer Group Handler ///////////////////////////////////////////////////
+/// Rename a group, change its visibility, opacity or blend mode, or move it into
+/// another group. Settings left out of the request keep their current value. A
+/// moved group goes on top of its new siblings.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the group ID.
+/// * 'request' - A web::Json containing the settings to change.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the group as it is after the update.
+///
+/// # Example Request
+///
+/// PUT /image/1/groups/2
+/// Body: { "group_name": "Sky", "opacity": 60, "blend_mode": "screen" }
+/// Body: { "visibility": false }
+/// Body: { "parent_id": 1 }
+/// Body: { "parent_id": null }
+pub async fn update_layer_group_handler(pool: web::Data<Pool>,
+                                        user: AuthenticatedUser,
+                                        path: web::Path<(i32,
+                                        i32)>,
+                                        request: web::Json<UpdateLayerGroupRequest>)
+                                        -> HttpResponse
+{
+    let (image_id, group_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let request = request.into_inner();
+    if request.group_name.is_none()
+       && request.visibility.is_none()
+       && request.opacity.is_none()
+       && request.blend_mode.is_none()
+       && request.parent_id.is_none()
+    {
+        return HttpResponse::BadRequest().json("Nothing to update.");
+    }
+    if request.group_name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+        return HttpResponse::BadRequest().json("Group name can't be empty.");
+    }
+    if request.opacity.is_some_and(|opacity| !(0.0..=100.0).contains(&opacity)) {
+        return HttpResponse::BadRequest().json("Opacity must be between 0 and 100.");
+    }
+
+    let mut op_params = history_params(&request, None);
+    op_params["group_id"] = json!(group_id);
+    track_history(&pool, image_id, "update_layer_group", op_params, async {
+        let changes = db::layers::LayerGroupChanges { group_name: request.group_name,
+                                                      visibility: request.visibility,
+                                                      opacity: request.opacity,
+                                                      blend_mode: request.blend_mode,
+                                                      parent_id: request.parent_id };
+        match db::layers::update_layer_group(&pool, image_id, group_id, &changes).await {
+            Ok(group) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "group": group,
+            })),
+            Err(MyDbError::NotFound) => {
+                HttpResponse::NotFound().json("Group or parent group not found on this image.")
+            }
+            Err(MyDbError::InvalidChange(msg)) => HttpResponse::BadRequest().json(msg),
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateLayerGroupRequest
+{
+    group_name: Option<String>,
+    visibility: Option<bool>,
+    opacity: Option<f32>,
+    blend_mode: Option<BlendMode>,
+    // Left out keeps the parent, null moves the group to the top level
+    #[serde(default, deserialize_with = "present", skip_serializing_if = "Option::is_none")]
+    parent_id: Option<Option<i32>>,
+}
+
+// Tell a field that was sent as null apart from one that was left out
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where D: serde::Deserializer<'de>,
+          T: Deserialize<'de>
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Delete Layer Group Handler ///////////////////////////////////////////////////
+/// Remove a group from an image. Its layers and groups move up to its parent,
+/// unless 'delete_contents' is set, then they are deleted along with it.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the group ID.
+/// * 'query' - A web::Query with the optional 'delete_contents' flag.
+///
+/// # Returns
+///
+/// Return an HttpResponse indicating the outcome of the operation.
+///
+/// # Example Request
+///
+/// DELETE /image/1/groups/2
+/// DELETE /image/1/groups/2?delete_contents=true
+pub async fn delete_layer_group_handler(pool: web::Data<Pool>,
+                                        user: AuthenticatedUser,
+                                        path: web::Path<(i32,
+                                        i32)>,
+                                        query: web::Query<DeleteLayerGroupQuery>)
+                                        -> HttpResponse
+{
+    let (image_id, group_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let delete_contents = query.delete_contents;
+    let op_params = json!({ "group_id": group_id, "delete_contents": delete_contents });
+    track_history(&pool, image_id, "delete_layer_group", op_params, async {
+        match db::layers::delete_layer_group(&pool, image_id, group_id, delete_contents).await {
+            Ok(_) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "group_id": group_id,
+                "delete_contents": delete_contents,
+            })),
+            Err(MyDbError::NotFound) => {
+                HttpResponse::NotFound().json("Group not found on this image.")
+            }
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteLayerGroupQuery
+{
+    #[serde(default)]
+    delete_contents: bool,
+}
@@ -0,0 +1,301 @@
+use crate::db;
+use crate::image_processing;
+use crate::image_processing::draw::{Brush, StrokePoint};
+use crate::image_processing::mask;
+use crate::image_processing::selection::Selection;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, composite_for_selection, history_params, load_layer,
+            process_blocking, track_history, MyDbError};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Layer Mask Route Handler Functions ***** ////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Create Mask Handler //////////////////////////////////////////////////////////
+/// Give a layer a new mask made from a selection. The selected area stays
+/// visible and the rest is hidden. Without a 'selection' the mask reveals the
+/// whole layer. Any existing mask is replaced.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'request' - A web::Json containing the optional selection.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the image and layer ID that got the mask.
+///
+/// # Example Request
+///
+/// POST /image/1/layers/4/mask
+/// Body: { "selection": { "parts": [ { "shape": "ellipse", "x": 40, "y": 40, "width": 200, "height": 120 } ],
+///                        "feather": 12 } }
+pub async fn create_mask_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 path: web::Path<(i32,
+                                 i32)>,
+                                 request: web::Json<CreateMaskRequest>)
+                                 -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let request = request.into_inner();
+    let op_params = history_params(&request, Some(layer_id));
+    let selection = request.selection;
+
+    let layer = match load_layer(&pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    let composite = match composite_for_selection(&pool, image_id, selection.as_ref()).await {
+        Ok(composite) => composite,
+        Err(response) => return response,
+    };
+
+    let layer_data = layer.layer_data;
+    let result = process_blocking(image_id, move || {
+        let pixels = image_processing::decode_layer_data(&layer_data)?;
+        let layer_mask = match selection {
+            Some(selection) => selection.to_mask(&pixels, composite.as_ref())?.into_gray(),
+            None => mask::reveal_all(pixels.width(), pixels.height()),
+        };
+        Ok(mask::encode_mask_data(&layer_mask)?)
+    }).await;
+    let mask_data = match result {
+        Ok(mask_data) => mask_data,
+        Err(response) => return response,
+    };
+
+    track_history(&pool, image_id, "create_mask", op_params, async {
+        // A fresh mask starts out enabled and not inverted
+        let saved = match db::layers::update_layer_mask(&pool, layer_id, Some(&mask_data)).await {
+            Ok(_) => db::layers::update_layer_mask_settings(&pool, layer_id, true, false).await,
+            Err(e) => Err(e),
+        };
+        mask_response(image_id, layer_id, saved)
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateMaskRequest
+{
+    selection: Option<Selection>,
+}
+
+/// Update Mask Handler //////////////////////////////////////////////////////////
+/// Enable, disable or invert a layer's mask. Settings that are left out keep
+/// their current value.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'request' - A web::Json containing the mask settings.
+///
+/// # Returns
+///
+/// Return an HttpResponse indicating the outcome of the operation.
+///
+/// # Example Request
+///
+/// PUT /image/1/layers/4/mask
+/// Body: { "enabled": false }
+/// Body: { "inverted": true }
+pub async fn update_mask_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 path: web::Path<(i32,
+                                 i32)>,
+                                 request: web::Json<UpdateMaskRequest>)
+                                 -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let layer = match load_layer(&pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    if layer.mask_data.is_none() {
+        return HttpResponse::BadRequest().json("Layer has no mask.");
+    }
+
+    let enabled = request.enabled.unwrap_or(layer.mask_enabled);
+    let inverted = request.inverted.unwrap_or(layer.mask_inverted);
+    let op_params = history_params(&request.0, Some(layer_id));
+    track_history(&pool, image_id, "update_mask", op_params, async {
+        let saved = db::layers::update_layer_mask_settings(&pool, layer_id, enabled, inverted).await;
+        mask_response(image_id, layer_id, saved)
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateMaskRequest
+{
+    enabled: Option<bool>,
+    inverted: Option<bool>,
+}
+
+/// Delete Mask Handler //////////////////////////////////////////////////////////
+/// Throw a layer's mask away, leaving the layer's pixels as they are.
+///
+/// # Example Request
+///
+/// DELETE /image/1/layers/4/mask
+pub async fn delete_mask_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 path: web::Path<(i32,
+                                 i32)>)
+                                 -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    if let Err(response) = load_layer(&pool, image_id, layer_id).await {
+        return response;
+    }
+
+    let op_params = history_params(&json!({}), Some(layer_id));
+    track_history(&pool, image_id, "delete_mask", op_params, async {
+        let saved = db::layers::update_layer_mask(&pool, layer_id, None).await;
+        mask_response(image_id, layer_id, saved)
+    }).await
+}
+
+/// Mask Stroke Handler //////////////////////////////////////////////////////////
+/// Paint a brush stroke onto a layer's mask. Painting white reveals the layer,
+/// painting black hides it and grays partly hide it.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'request' - A web::Json containing the brush settings and points.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the image and layer ID whose mask was painted.
+///
+/// # Example Request
+///
+/// POST /image/1/layers/4/mask/stroke
+/// Body: {
+///     "brush": { "size": 40, "hardness": 0.2, "color": [0, 0, 0, 255] },
+///     "points": [ { "x": 10, "y": 10 }, { "x": 120, "y": 60 } ]
+/// }
+pub async fn mask_stroke_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 path: web::Path<(i32,
+                                 i32)>,
+                                 request: web::Json<MaskStrokeRequest>)
+                                 -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let request = request.into_inner();
+    let op_params = history_params(&request, Some(layer_id));
+    let (brush, points) = (request.brush, request.points);
+
+    let layer = match load_layer(&pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    let mask_data = match layer.mask_data {
+        Some(mask_data) => mask_data,
+        None => return HttpResponse::BadRequest().json("Layer has no mask."),
+    };
+
+    let result = process_blocking(image_id, move || {
+        let mut layer_mask = mask::decode_mask_data(&mask_data)?;
+        mask::paint_mask(&mut layer_mask, &brush, &points)?;
+        Ok(mask::encode_mask_data(&layer_mask)?)
+    }).await;
+    let mask_data = match result {
+        Ok(mask_data) => mask_data,
+        Err(response) => return response,
+    };
+
+    track_history(&pool, image_id, "mask_stroke", op_params, async {
+        let saved = db::layers::update_layer_mask(&pool, layer_id, Some(&mask_data)).await;
+        mask_response(image_id, layer_id, saved)
+    }).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaskStrokeRequest
+{
+    brush: Brush,
+    points: Vec<StrokePoint>,
+}
+
+/// Apply Mask Handler ///////////////////////////////////////////////////////////
+/// Bake a layer's mask into its alpha channel and remove the mask. Hidden
+/// pixels become transparent for good.
+///
+/// # Example Request
+///
+/// POST /image/1/layers/4/mask/apply
+pub async fn apply_mask_handler(pool: web::Data<Pool>,
+                                user: AuthenticatedUser,
+                                path: web::Path<(i32,
+                                i32)>)
+                                -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let layer = match load_layer(&pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    let mask_data = match layer.mask_data {
+        Some(mask_data) => mask_data,
+        None => return HttpResponse::BadRequest().json("Layer has no mask."),
+    };
+
+    let (layer_data, inverted) = (layer.layer_data, layer.mask_inverted);
+    let result = process_blocking(image_id, move || {
+        let mut pixels = image_processing::decode_layer_data(&layer_data)?;
+        let layer_mask = mask::decode_mask_data(&mask_data)?;
+        mask::apply_mask(&mut pixels, &layer_mask, inverted);
+        Ok(image_processing::encode_layer_data(&pixels)?)
+    }).await;
+    let new_layer_data = match result {
+        Ok(new_layer_data) => new_layer_data,
+        Err(response) => return response,
+    };
+
+    let op_params = history_params(&json!({}), Some(layer_id));
+    track_history(&pool, image_id, "apply_mask", op_params, async {
+        let saved = db::layers::apply_layer_mask(&pool, layer_id, &new_layer_data).await;
+        mask_response(image_id, layer_id, saved)
+    }).await
+}
+
+// Shared response once a mask change has been written ///////////////////////////
+fn mask_response(image_id: i32, layer_id: i32, saved: Result<(), MyDbError>) -> HttpResponse
+{
+    match saved {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": image_id,
+            "layer_id": layer_id,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not found."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
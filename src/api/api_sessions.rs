@@ -1,97 +1,149 @@
 use crate::db;
-use crate::db::*; 
-use actix_web::{web, App, http, HttpResponse, HttpServer, Responder, test};
-use deadpool_postgres::{Config, Pool};
-use serde::Deserialize;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
 use serde_json::json;
-use tokio_postgres::{Error, NoTls, Row};
+
+use super::api_auth::AdminUser;
 use super::MyDbError;
 //////////////////////////////////////////////////////////////////////////////////
 //////////////////////////////////////////////////////////////////////////////////
 ////////////// *****  Session Route Handler Functions ***** //////////////////////
 //////////////////////////////////////////////////////////////////////////////////
 //////////////////////////////////////////////////////////////////////////////////
+// Sessions are started by POST /auth/login and ended by POST /auth/logout. These
+// routes are for administrative purposes, admins only.
 
-/// Create_session
-/// To start a new user session upon login
-pub async fn create_a_session_handler( pool: web::Data<Pool>, username: &str ) -> HttpResponse
+/// Get Active Sessions Handler //////////////////////////////////////////////////
+/// List every live session, most recently used first. Ended, expired and idle
+/// sessions are left out.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the sessions, each with the client address and
+/// user agent it was started from. Tokens are never included.
+///
+/// # Example Request
+///
+/// GET /admin/sessions
+pub async fn get_active_sessions_handler(pool: web::Data<Pool>,
+                                         _admin: AdminUser)
+                                         -> HttpResponse
 {
-    match db::sessions::create_a_session( &pool, username ).await {
-        Ok( session ) => HttpResponse::Ok().json( session ),
-        Err( e ) => {
-            println!( "Error creating session: {:?}", e );
-            HttpResponse::InternalServerError().json( "Internal server error" )
-        }
-
+    match db::sessions::get_active_sessions(&pool).await {
+        Ok(sessions) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "total_sessions": sessions.len(),
+            "sessions": sessions,
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
     }
 }
 
+/// Get User Sessions Handler ////////////////////////////////////////////////////
+/// List the live sessions of one user.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'user_id' - The ID of the user, taken from the path.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the user's sessions, or 404 if there is no such
+/// user.
+///
+/// # Example Request
+///
+/// GET /admin/users/2/sessions
+pub async fn get_user_sessions_handler(pool: web::Data<Pool>,
+                                       _admin: AdminUser,
+                                       user_id: web::Path<i32>)
+                                       -> HttpResponse
+{
+    let user_id = user_id.into_inner();
+    if let Err(response) = check_user_exists(&pool, user_id).await {
+        return response;
+    }
 
-// pub async fn create_a_session( pool: &Pool, user_id: i32, session_data: serde_json::Value ) -> Result< Session, MyDbError > {
-
-//     let client = pool.get().await.map_err(MyDbError::PoolError)?; // .map_err(MyDbError::PoolError)?; // .await?;
-//     let statement = client
-//         .prepare( "INSERT INTO sessions (user_id, creation_time, expiration_time, last_activity, session_data) VALUES ($1, $2, $3, $4, $5 ) RETURNING *" )
-//         .await.map_err(MyDbError::QueryError)?;
-
-//     let session_data_str = serde_json::to_string( &session_data ).map_err( MyDbError::SerializeError )?; 
-//     let expiration_time = calculate_expiration_time();
-
-//     // Execute prepared statment 
-//     match client.query_one( &statement, &[&user_id, &expiration_time, &session_data_str ] ).await {
-//         Ok( row ) => {
-//             // Session is a struct, that represents a single session
-//             let session = Session::from_row( &row )?;
-//             Ok( session )
-//         },
-//         Err( e ) => Err( MyDbError::QueryError( e )),
-//     }
-// }
-
-
-/// End_session
-/// To allow users to log out OR just end the session after a certain amount of
-/// time OR end session after a certain amount of inactivity.
-
-/// Get_active_sessions
-/// To retrieve active sessions, useful for administrative purposes.
+    match db::sessions::get_active_sessions_for_user(&pool, user_id).await {
+        Ok(sessions) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "user_id": user_id,
+            "total_sessions": sessions.len(),
+            "sessions": sessions,
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
 
+/// Revoke User Sessions Handler /////////////////////////////////////////////////
+/// End every session of one user, logging them out everywhere.
+///
+/// # Returns
+///
+/// Return an HttpResponse with how many sessions were ended, or 404 if there is
+/// no such user.
+///
+/// # Example Request
+///
+/// DELETE /admin/users/2/sessions
+pub async fn revoke_user_sessions_handler(pool: web::Data<Pool>,
+                                          _admin: AdminUser,
+                                          user_id: web::Path<i32>)
+                                          -> HttpResponse
+{
+    let user_id = user_id.into_inner();
+    if let Err(response) = check_user_exists(&pool, user_id).await {
+        return response;
+    }
 
+    match db::sessions::end_all_sessions_for_user(&pool, user_id).await {
+        Ok(ended) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "user_id": user_id,
+            "sessions_ended": ended,
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
 
+/// End Session Handler //////////////////////////////////////////////////////////
+/// End a single session by its ID.
+///
+/// # Returns
+///
+/// Return an HttpResponse confirming the session was ended, or 404 if there is
+/// no such session or it had already ended.
+///
+/// # Example Request
+///
+/// DELETE /admin/sessions/5
+pub async fn end_session_handler(pool: web::Data<Pool>,
+                                 _admin: AdminUser,
+                                 session_id: web::Path<i32>)
+                                 -> HttpResponse
+{
+    let session_id = session_id.into_inner();
+    match db::sessions::end_session(&pool, session_id).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "session_id": session_id,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Session not found."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
 
-// /// Route handler to add a new user //////////////////////////////////////////////
-// /// This allows new users to register.
-// /// 
-// /// # Arguements
-// /// * 'pool' - A reference to the database connection pool.
-// /// * 'new_user' - A web::Json tuple containing the new user data.
-// /// 
-// /// # Returns
-// /// 
-// /// Return an HttpResponse indicating the outcome of the operation.
-// /// 
-// /// # Example Request
-// /// 
-// /// POST /add_user
-// /// Body: { "username": "new_user", "email": "sample@email.com"}
-// /// 
-// /// # Example Response
-// /// 
-// /// "User added successfully with ID: 1"
-// pub async fn add_user_handler(pool: web::Data<Pool>, new_user: web::Json<NewUser>) -> HttpResponse
-// {
-//     match db::users::add_user(&pool, &new_user.username, &new_user.email).await
-//     {
-//         Ok( user_id) => HttpResponse::Ok().json( format!("User added successfully with ID: {}", user_id )),
-//         Err(MyDbError::PostgresError(e)) =>
-//         {
-//             HttpResponse::InternalServerError().json(format!("Postgres error: {}", e))
-//         }
-//         Err(MyDbError::PoolError(e)) =>
-//         {
-//             HttpResponse::InternalServerError().json(format!("Pool error: {}", e))
-//         }
-//         Err(_) => HttpResponse::InternalServerError().json("Unhandled error"),
-//         // Handle other errors
-//     }
-// }
\ No newline at end of file
+// 404 for users that don't exist, rather than an empty list
+async fn check_user_exists(pool: &Pool, user_id: i32) -> Result<(), HttpResponse>
+{
+    match db::users::get_user_by_id(pool, user_id).await {
+        Ok(_) => Ok(()),
+        Err(MyDbError::NotFound) => Err(HttpResponse::NotFound().json("User not found")),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::db;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, history_params, track_history, MyDbError};
+
+const MAX_SNAPSHOT_NAME_LENGTH: usize = 100;
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Snapshot Route Handler Functions ***** //////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Create Snapshot Handler //////////////////////////////////////////////////////
+/// Save the image's current layers and groups under a name, so the image can be
+/// taken back to this version later.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'request' - A web::Json containing the snapshot name and description.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new snapshot's metadata.
+///
+/// # Example Request
+///
+/// POST /image/1/snapshots
+/// Body: { "snapshot_name": "Sent to client", "description": "Second round of retouching" }
+pub async fn create_snapshot_handler(pool: web::Data<Pool>,
+                                     user: AuthenticatedUser,
+                                     image_id: web::Path<i32>,
+                                     request: web::Json<CreateSnapshotRequest>)
+                                     -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let snapshot_name = request.snapshot_name.trim();
+    if snapshot_name.is_empty() || snapshot_name.chars().count() > MAX_SNAPSHOT_NAME_LENGTH {
+        let msg = format!("snapshot_name must be between 1 and {} characters",
+                          MAX_SNAPSHOT_NAME_LENGTH);
+        return HttpResponse::BadRequest().json(msg);
+    }
+
+    let description = request.description.as_deref();
+    match db::snapshots::create_snapshot(&pool, image_id, snapshot_name, description).await {
+        Ok(snapshot) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "snapshot": snapshot,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Image not found."),
+        Err(e) => {
+            println!("Error creating snapshot of image {}: {:?}", image_id, e);
+            HttpResponse::InternalServerError().json("Internal server error")
+        }
+    }
+}
+#[derive(Debug, Deserialize)]
+pub struct CreateSnapshotRequest
+{
+    snapshot_name: String,
+    description: Option<String>,
+}
+
+/// Get Snapshots Handler ////////////////////////////////////////////////////////
+/// List the snapshots of an image, oldest first.
+///
+/// # Example Request
+///
+/// GET /image/1/snapshots
+pub async fn get_snapshots_handler(pool: web::Data<Pool>,
+                                   user: AuthenticatedUser,
+                                   image_id: web::Path<i32>)
+                                   -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    match db::snapshots::get_snapshots(&pool, image_id).await {
+        Ok(snapshots) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": image_id,
+            "snapshots": snapshots,
+        })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+/// Diff Snapshot Handler ////////////////////////////////////////////////////////
+/// Compare a snapshot with another snapshot, or with the image as it is now
+/// when 'against' is left out. Lists the layers and groups that were added,
+/// removed or changed, and which of their settings changed.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the snapshot ID.
+/// * 'query' - A web::Query with the optional snapshot ID to compare against.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the differences.
+///
+/// # Example Request
+///
+/// GET /image/1/snapshots/3/diff
+/// GET /image/1/snapshots/3/diff?against=5
+pub async fn diff_snapshot_handler(pool: web::Data<Pool>,
+                                   user: AuthenticatedUser,
+                                   path: web::Path<(i32,
+                                   i32)>,
+                                   query: web::Query<DiffSnapshotQuery>)
+                                   -> HttpResponse
+{
+    let (image_id, snapshot_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    match db::snapshots::diff_snapshots(&pool, image_id, snapshot_id, query.against).await {
+        Ok(diff) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": image_id,
+            "diff": diff,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Snapshot not found."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+#[derive(Debug, Deserialize)]
+pub struct DiffSnapshotQuery
+{
+    against: Option<i32>,
+}
+
+/// Restore Snapshot Handler /////////////////////////////////////////////////////
+/// Take the image back to a snapshot. Layers and groups made since are removed
+/// and the rest get their saved pixels and settings back, all at once. The
+/// restore is an edit like any other, so it can be undone.
+///
+/// # Example Request
+///
+/// POST /image/1/snapshots/3/restore
+pub async fn restore_snapshot_handler(pool: web::Data<Pool>,
+                                      user: AuthenticatedUser,
+                                      path: web::Path<(i32,
+                                      i32)>)
+                                      -> HttpResponse
+{
+    let (image_id, snapshot_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let op_params = history_params(&RestoreParams { snapshot_id }, None);
+
+    track_history(&pool, image_id, "restore_snapshot", op_params, async {
+        match db::snapshots::restore_snapshot(&pool, image_id, snapshot_id).await {
+            Ok(snapshot) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "snapshot": snapshot,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Snapshot not found."),
+            Err(e) => {
+                println!("Error restoring snapshot {} of image {}: {:?}", snapshot_id, image_id, e);
+                HttpResponse::InternalServerError().json("Internal server error")
+            }
+        }
+    }).await
+}
+#[derive(Debug, Serialize)]
+struct RestoreParams
+{
+    snapshot_id: i32,
+}
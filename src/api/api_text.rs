@@ -0,0 +1,185 @@
+use crate::db;
+use crate::db::layers::LayerType;
+use crate::image_processing::text::{self, TextParams};
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, history_params, load_layer, process_blocking, track_history,
+            MyDbError};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Text Layer Route Handler Functions ***** ////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Create Text Layer Handler ////////////////////////////////////////////////////
+/// Add a text layer on top of the stack. The text is kept as settings and is
+/// typeset every time the image is composited, so it stays editable. Without a
+/// 'layer_name' the layer is named after the start of its text.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'image_id' - The ID of the image, taken from the path.
+/// * 'request' - A web::Json containing the text, font and text box.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the ID of the new layer.
+///
+/// # Example Request
+///
+/// POST /image/1/text
+/// Body: { "text": "Summer Sale\nUp to 50% off", "font_family": "Open Sans", "font_size": 64,
+///         "color": [255, 255, 255, 255], "align": "center", "line_spacing": 1.2,
+///         "x": 40, "y": 40, "width": 1000, "height": 300 }
+pub async fn create_text_layer_handler(pool: web::Data<Pool>,
+                                       user: AuthenticatedUser,
+                                       image_id: web::Path<i32>,
+                                       request: web::Json<TextLayerRequest>)
+                                       -> HttpResponse
+{
+    let image_id = image_id.into_inner();
+    let request = request.into_inner();
+
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let params = request.params;
+    let layer_name = request.layer_name.unwrap_or_else(|| default_layer_name(&params.text));
+    let layer_params = match validate_text(image_id, params).await {
+        Ok(layer_params) => layer_params,
+        Err(response) => return response,
+    };
+
+    let op_params = history_params(&layer_params, None);
+    track_history(&pool, image_id, "create_text_layer", op_params, async {
+        match db::layers::add_layer(&pool,
+                                    image_id,
+                                    &layer_name,
+                                    LayerType::Text,
+                                    &[],
+                                    Some(&layer_params),
+                                    None).await
+        {
+            Ok(layer_id) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "layer_id": layer_id,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not added!"),
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+#[derive(Debug, Deserialize)]
+pub struct TextLayerRequest
+{
+    layer_name: Option<String>,
+    #[serde(flatten)]
+    params: TextParams,
+}
+
+/// Update Text Layer Handler ////////////////////////////////////////////////////
+/// Change some of a text layer's settings. Only the fields in the body are
+/// replaced, the rest keep their current value.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'path' - A web::Path tuple containing the image ID and the layer ID.
+/// * 'changes' - A web::Json object with the settings to change.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the layer's new text settings.
+///
+/// # Example Request
+///
+/// PUT /image/1/layers/7/text
+/// Body: { "text": "Autumn Sale", "color": [240, 120, 20, 255] }
+pub async fn update_text_layer_handler(pool: web::Data<Pool>,
+                                       user: AuthenticatedUser,
+                                       path: web::Path<(i32,
+                                       i32)>,
+                                       changes: web::Json<Value>)
+                                       -> HttpResponse
+{
+    let (image_id, layer_id) = path.into_inner();
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let changes = match changes.into_inner() {
+        Value::Object(changes) => changes,
+        _ => return HttpResponse::BadRequest().json("Expected an object of text settings."),
+    };
+
+    let layer = match load_layer(&pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    if layer.layer_type != LayerType::Text {
+        return HttpResponse::BadRequest().json("Layer is not a text layer.");
+    }
+
+    let mut layer_params = match layer.layer_params {
+        Some(Value::Object(layer_params)) => layer_params,
+        _ => serde_json::Map::new(),
+    };
+    layer_params.extend(changes);
+    let params: TextParams = match serde_json::from_value(Value::Object(layer_params)) {
+        Ok(params) => params,
+        Err(e) => return HttpResponse::BadRequest().json(format!("bad text settings: {}", e)),
+    };
+    let layer_params = match validate_text(image_id, params).await {
+        Ok(layer_params) => layer_params,
+        Err(response) => return response,
+    };
+
+    let op_params = history_params(&layer_params, Some(layer_id));
+    track_history(&pool, image_id, "update_text_layer", op_params, async {
+        match db::layers::update_layer_params(&pool, layer_id, &layer_params).await {
+            Ok(_) => HttpResponse::Ok().json(json!({
+                "status": "success",
+                "image_id": image_id,
+                "layer_id": layer_id,
+                "text": layer_params,
+            })),
+            Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not found."),
+            Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+        }
+    }).await
+}
+
+/// List Fonts Handler ///////////////////////////////////////////////////////////
+/// The font families that text layers can use, one per font file in FONT_DIR.
+///
+/// # Example Request
+///
+/// GET /api/fonts
+pub async fn list_fonts_handler() -> HttpResponse
+{
+    match web::block(text::list_fonts).await {
+        Ok(fonts) => HttpResponse::Ok().json(json!({ "fonts": fonts })),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+// Check text settings off the async workers, the font may have to be read ///////
+async fn validate_text(image_id: i32, params: TextParams) -> Result<Value, HttpResponse>
+{
+    let params = process_blocking(image_id, move || params.validate().map(|_| params)).await?;
+    serde_json::to_value(params)
+        .map_err(|_| HttpResponse::InternalServerError().json("Internal server error"))
+}
+
+// Name a new text layer after its first line, like most editors do
+fn default_layer_name(text: &str) -> String
+{
+    let first_line = text.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or("Text");
+    first_line.chars().take(32).collect()
+}
@@ -0,0 +1,232 @@
+use crate::db;
+use crate::image_processing::transform::{self, Anchor, CanvasMode, ResampleFilter, ResizeMode};
+use crate::image_processing::ProcessingError;
+use actix_web::{web, HttpResponse};
+use deadpool_postgres::Pool;
+use image::{DynamicImage, Rgba};
+use serde::{Deserialize, Serialize};
+
+use super::api_auth::AuthenticatedUser;
+use super::{authorize_image, edit_image_file, history_params, track_history};
+
+//////////////////////////////////////////////////////////////////////////////////
+////////////// ***** Transform Route Handler Functions ***** /////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Rotate an image //////////////////////////////////////////////////////////////
+/// Rotates the stored image by any angle and saves the result as a new revision
+/// of the image.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - A web::Json containing the image ID and rotation settings.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new file hash of the image, or a 400 if the
+/// image has layers.
+///
+/// # Example Request
+///
+/// POST /api/transform/rotate
+/// Body: { "image_id": 1, "angle": 12.5, "filter": "bicubic", "canvas": "expand" }
+///
+/// 'filter' is one of nearest, bilinear (default), bicubic or lanczos.
+/// 'canvas' is either expand (default) or crop. 'background' is an optional
+/// [r, g, b, a] fill for the uncovered corners, transparent by default.
+pub async fn rotate_image_handler(pool: web::Data<Pool>,
+                                  user: AuthenticatedUser,
+                                  request: web::Json<RotateRequest>)
+                                  -> HttpResponse
+{
+    let request = request.into_inner();
+    if !request.angle.is_finite() {
+        return HttpResponse::BadRequest().json("Angle must be a finite number of degrees.");
+    }
+    let (image_id, op_params) = (request.image_id, history_params(&request, None));
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let edit = transform_image_file(&pool, image_id, move |image| {
+        let rotated = transform::rotate(&image.to_rgba8(),
+                                        request.angle,
+                                        request.filter,
+                                        request.canvas,
+                                        Rgba(request.background))?;
+        Ok(DynamicImage::ImageRgba8(rotated))
+    });
+    track_history(&pool, image_id, "rotate", op_params, edit).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RotateRequest
+{
+    image_id: i32,
+    angle: f32,
+    #[serde(default)]
+    filter: ResampleFilter,
+    #[serde(default)]
+    canvas: CanvasMode,
+    #[serde(default)]
+    background: [u8; 4],
+}
+
+/// Resize an image /////////////////////////////////////////////////////////////
+/// Scales the stored image and saves the result as a new revision.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - A web::Json containing the image ID, resize mode and filter.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new file hash of the image, or a 400 if the
+/// image has layers.
+///
+/// # Example Request
+///
+/// POST /api/transform/resize
+/// Body: { "image_id": 1, "mode": "fit", "width": 1920, "height": 1080, "filter": "lanczos" }
+///
+/// 'mode' is one of exact, fit, fill (scale to cover and crop the overflow)
+/// or percentage, which takes { "percent": 50 } instead of a width and height.
+pub async fn resize_image_handler(pool: web::Data<Pool>,
+                                  user: AuthenticatedUser,
+                                  request: web::Json<ResizeRequest>)
+                                  -> HttpResponse
+{
+    let request = request.into_inner();
+    let (image_id, op_params) = (request.image_id, history_params(&request, None));
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let edit = transform_image_file(&pool, image_id, move |image| {
+        let resized = transform::resize(&image.to_rgba8(), request.mode, request.filter)?;
+        Ok(DynamicImage::ImageRgba8(resized))
+    });
+    track_history(&pool, image_id, "resize", op_params, edit).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResizeRequest
+{
+    image_id: i32,
+    #[serde(flatten)]
+    mode: ResizeMode,
+    #[serde(default)]
+    filter: ResampleFilter,
+}
+
+/// Crop an image ///////////////////////////////////////////////////////////////
+/// Cuts the stored image down to a rectangle and saves it as a new revision.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - A web::Json containing the image ID and crop rectangle.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new file hash of the image, or a 400 if
+/// the rectangle doesn't fit inside the image or the image has layers.
+///
+/// # Example Request
+///
+/// POST /api/transform/crop
+/// Body: { "image_id": 1, "x": 10, "y": 20, "width": 300, "height": 200 }
+pub async fn crop_image_handler(pool: web::Data<Pool>,
+                                user: AuthenticatedUser,
+                                request: web::Json<CropRequest>)
+                                -> HttpResponse
+{
+    let request = request.into_inner();
+    let (image_id, op_params) = (request.image_id, history_params(&request, None));
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let edit = transform_image_file(&pool, image_id, move |image| {
+        let cropped = transform::crop(&image.to_rgba8(),
+                                      request.x,
+                                      request.y,
+                                      request.width,
+                                      request.height)?;
+        Ok(DynamicImage::ImageRgba8(cropped))
+    });
+    track_history(&pool, image_id, "crop", op_params, edit).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CropRequest
+{
+    image_id: i32,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Change canvas size //////////////////////////////////////////////////////////
+/// Grows or shrinks the canvas around the stored image without scaling it.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'request' - A web::Json containing the image ID, new size, anchor and fill.
+///
+/// # Returns
+///
+/// Return an HttpResponse with the new file hash of the image, or a 400 if the
+/// image has layers.
+///
+/// # Example Request
+///
+/// POST /api/transform/canvas
+/// Body: { "image_id": 1, "width": 2000, "height": 2000, "anchor": "top_left", "fill": [255, 255, 255, 255] }
+///
+/// 'anchor' defaults to center and 'fill' to transparent.
+pub async fn canvas_size_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 request: web::Json<CanvasRequest>)
+                                 -> HttpResponse
+{
+    let request = request.into_inner();
+    let (image_id, op_params) = (request.image_id, history_params(&request, None));
+    if let Err(response) = authorize_image(&pool, &user, image_id).await {
+        return response;
+    }
+
+    let edit = transform_image_file(&pool, image_id, move |image| {
+        let canvas = transform::canvas_size(&image.to_rgba8(),
+                                            request.width,
+                                            request.height,
+                                            request.anchor,
+                                            Rgba(request.fill))?;
+        Ok(DynamicImage::ImageRgba8(canvas))
+    });
+    track_history(&pool, image_id, "canvas_size", op_params, edit).await
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CanvasRequest
+{
+    image_id: i32,
+    width: u32,
+    height: u32,
+    #[serde(default)]
+    anchor: Anchor,
+    #[serde(default)]
+    fill: [u8; 4],
+}
+
+// Transforms change the image file only, the layers would keep their old size
+// and position and no longer line up with it. Images with layers are refused.
+async fn transform_image_file<F>(pool: &Pool, image_id: i32, edit: F) -> HttpResponse
+    where F: FnOnce(DynamicImage) -> Result<DynamicImage, ProcessingError> + Send + 'static
+{
+    match db::layers::count_layers(pool, image_id).await {
+        Ok(0) => edit_image_file(pool, image_id, edit).await,
+        Ok(_) => HttpResponse::BadRequest().json("Images with layers can't be transformed."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
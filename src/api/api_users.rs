@@ -7,13 +7,16 @@ use serde::Deserialize;
 use serde_json::json;
 // use tokio_postgres::{Error, NoTls, Row};
 
+use super::api_auth::{AdminUser, AuthenticatedUser};
 use super::MyDbError;
+use crate::db::users::Role;
 //////////////////////////////////////////////////////////////////////////////////
 ////////////// ***** User Route Handler Functions ***** //////////////////////////
 //////////////////////////////////////////////////////////////////////////////////
 
 /// Route handler to add a new user //////////////////////////////////////////////
-/// This allows new users to register.
+/// Admins only. The user is added without a password, so new users register
+/// through /auth/register instead.
 /// 
 /// # Arguements
 /// * 'pool' - A reference to the database connection pool.
@@ -31,7 +34,10 @@ use super::MyDbError;
 /// # Example Response
 /// 
 /// "User added successfully with ID: 1"
-pub async fn add_user_handler(pool: web::Data<Pool>, new_user: web::Json<NewUser>) -> HttpResponse
+pub async fn add_user_handler(pool: web::Data<Pool>,
+                              _admin: AdminUser,
+                              new_user: web::Json<NewUser>)
+                              -> HttpResponse
 {
     match db::users::add_user(&pool, &new_user.username, &new_user.email).await
     {
@@ -57,11 +63,13 @@ pub struct NewUser
 
 /// Get a User by providing a username ///////////////////////////////////////////
 /// This is a GET request to get a user by a username. 
-/// This allows users to get their user profile
+/// This allows users to get their user profile. Only the user themselves or an
+/// admin can look it up.
 ///
 /// # Arguements
 ///
 /// * 'pool' - A reference to the database connection pool.
+/// * 'user' - The logged in user.
 /// * 'path' - A web::Path tuple.
 ///
 /// # Returns
@@ -72,9 +80,15 @@ pub struct NewUser
 ///
 /// GET /users/{username}
 //////////////////////////////////////////////////////////////////////////////////
-pub async fn get_user_handler(pool: web::Data<Pool>, path: web::Path<(String,)>) -> HttpResponse
+pub async fn get_user_handler(pool: web::Data<Pool>,
+                              user: AuthenticatedUser,
+                              path: web::Path<(String,)>)
+                              -> HttpResponse
 {
     let username = &path.into_inner().0;
+    if !can_manage_user(&user, username) {
+        return HttpResponse::Forbidden().json("You can only look up your own account.");
+    }
     match db::users::get_user_by_username(&pool, username).await
     {
         // Ok(user) => HttpResponse::Ok().json(user),
@@ -86,6 +100,7 @@ pub async fn get_user_handler(pool: web::Data<Pool>, path: web::Path<(String,)>)
 
 /// Get all users //////////////////////////////////////////////////////////////
 /// This is for administrative purposes. It retrieves all users in the database.
+/// Admins only.
 /// 
 /// # Arguements
 /// 
@@ -99,7 +114,7 @@ pub async fn get_user_handler(pool: web::Data<Pool>, path: web::Path<(String,)>)
 /// 
 /// GET /users
 //////////////////////////////////////////////////////////////////////////////////
-pub async fn get_all_users_handler(pool: web::Data<Pool>) -> HttpResponse
+pub async fn get_all_users_handler(pool: web::Data<Pool>, _admin: AdminUser) -> HttpResponse
 {
     match db::users::get_all_users(&pool).await
     {
@@ -116,10 +131,12 @@ pub async fn get_all_users_handler(pool: web::Data<Pool>) -> HttpResponse
 }
 
 /// Get user by email
+/// Only the user themselves or an admin can look it up.
 /// 
 /// # Arguements
 /// 
 /// * 'pool' - A reference to the database connection pool.
+/// * 'user' - The logged in user.
 /// * 'email' - A web::Path tuple containing the email.
 /// 
 /// # Returns
@@ -129,21 +146,32 @@ pub async fn get_all_users_handler(pool: web::Data<Pool>) -> HttpResponse
 /// # Example Request
 /// 
 /// GET /get_user_by_email/{email}/
-pub async fn get_user_by_email_handler(pool: web::Data<Pool>, email: web::Path<(String,)>) -> HttpResponse
+pub async fn get_user_by_email_handler(pool: web::Data<Pool>,
+                                       user: AuthenticatedUser,
+                                       email: web::Path<(String,)>)
+                                       -> HttpResponse
 {
     let email = &email.into_inner().0;
+    let is_admin = user.role == Role::Admin;
     match db::users::get_user_by_email(&pool, email).await
     {
         // Ok(user) => HttpResponse::Ok().json(user),
-        // Ok(user) => HttpResponse::Ok().json(user),
-        Ok(user) => HttpResponse::Ok().body(format!("This is the requested user: {}", user)),
+        Ok(found) if is_admin || found.id == user.user_id => {
+            HttpResponse::Ok().body(format!("This is the requested user: {}", found))
+        }
+        // Don't tell other users whether the email is taken.
+        Ok(_) => HttpResponse::Forbidden().json("You can only look up your own account."),
+        Err(MyDbError::NotFound) if !is_admin => {
+            HttpResponse::Forbidden().json("You can only look up your own account.")
+        }
         Err(MyDbError::NotFound) => HttpResponse::NotFound().json("User not found"),
         Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
     }
 }
 
 /// Get user by user ID
-pub async fn get_user_by_user_id_handler( pool: web::Data<Pool>, user_id: web::Path< i32 > ) -> HttpResponse
+/// Admins only.
+pub async fn get_user_by_user_id_handler( pool: web::Data<Pool>, _admin: AdminUser, user_id: web::Path< i32 > ) -> HttpResponse
 {
     let user_id = user_id.into_inner();
 
@@ -158,11 +186,13 @@ pub async fn get_user_by_user_id_handler( pool: web::Data<Pool>, user_id: web::P
 }
 
 /// Delete user by username //////////////////////////////////////
-/// This is for user account deletion.
+/// This is for user account deletion. Users can delete their own account, admins
+/// can delete any.
 /// 
 /// # Arguements
 /// 
 ///     * 'pool' - A reference to the database connection pool.
+///    * 'user' - The logged in user.
 ///    * 'path' - A web::Path tuple containing the username.
 /// 
 /// # Returns
@@ -173,9 +203,15 @@ pub async fn get_user_by_user_id_handler( pool: web::Data<Pool>, user_id: web::P
 /// 
 /// DELETE /delete_user/{username}
 /// curl -X DELETE http://localhost:8080/delete_user/{username}
-pub async fn delete_user_handler(pool: web::Data<Pool>, path: web::Path<(String,)>) -> HttpResponse
+pub async fn delete_user_handler(pool: web::Data<Pool>,
+                                 user: AuthenticatedUser,
+                                 path: web::Path<(String,)>)
+                                 -> HttpResponse
 {
     let username = &path.into_inner().0;
+    if !can_manage_user(&user, username) {
+        return HttpResponse::Forbidden().json("You can only delete your own account.");
+    }
 
     match db::users::delete_user(&pool, username).await
     {
@@ -187,11 +223,12 @@ pub async fn delete_user_handler(pool: web::Data<Pool>, path: web::Path<(String,
 
 
 /// Update user email ///////////////////////////////////////////////////////////
-/// To allow users to update thier email.
+/// To allow users to update thier email. Admins can update anyone's.
 ///
 /// # Arguements
 ///
 /// * 'pool' - A reference to the database connection pool.
+/// * 'user' - The logged in user.
 /// * 'path' - A web::Path tuple containing the username.
 /// * 'new_email' - The new email address to update
 ///
@@ -205,11 +242,15 @@ pub async fn delete_user_handler(pool: web::Data<Pool>, path: web::Path<(String,
 /// Body: { "new_email": "new_email@example.com" }
 ///
 pub async fn update_user_email_handler(pool: web::Data<Pool>,
+                                   user: AuthenticatedUser,
                                    path: web::Path<String>,
                                    new_email: String)
                                    -> HttpResponse
 {
     let username = path.into_inner();
+    if !can_manage_user(&user, &username) {
+        return HttpResponse::Forbidden().json("You can only change your own email.");
+    }
 
     match db::users::update_user_email(&pool, &username, &new_email).await
     {
@@ -220,11 +261,64 @@ pub async fn update_user_email_handler(pool: web::Data<Pool>,
 }
 
 /// Delete all users ////////////////////////////////////////////////////////////
-pub async fn delete_all_users_handler( pool: web::Data<Pool> ) -> HttpResponse
+/// Admins only.
+pub async fn delete_all_users_handler( pool: web::Data<Pool>, _admin: AdminUser ) -> HttpResponse
 {
     match db::users::delete_all_users( &pool ).await
     {
         Ok(_) => HttpResponse::Ok().json( "All users deleted successfully" ),
         Err(_) => HttpResponse::InternalServerError().json( "Internal server error" ),
     }
-}
\ No newline at end of file
+}
+
+/// Update User Role Handler /////////////////////////////////////////////////////
+/// Make a user an admin, or an admin a regular user again. Admins only, and an
+/// admin can't change their own role so there is always one left.
+///
+/// # Arguements
+///
+/// * 'pool' - A reference to the database connection pool.
+/// * 'user_id' - The ID of the user, taken from the path.
+/// * 'request' - A web::Json with the new role, "user" or "admin".
+///
+/// # Returns
+///
+/// Return an HttpResponse with the user's new role, or 404 if there is no such
+/// user.
+///
+/// # Example Request
+///
+/// PUT /admin/users/2/role
+/// Body: { "role": "admin" }
+pub async fn update_user_role_handler(pool: web::Data<Pool>,
+                                      admin: AdminUser,
+                                      user_id: web::Path<i32>,
+                                      request: web::Json<UserRoleRequest>)
+                                      -> HttpResponse
+{
+    let user_id = user_id.into_inner();
+    if user_id == admin.0.user_id {
+        return HttpResponse::BadRequest().json("You can't change your own role.");
+    }
+
+    match db::users::set_user_role(&pool, user_id, request.role).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "user_id": user_id,
+            "role": request.role,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("User not found"),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+#[derive(Debug, Deserialize)]
+pub struct UserRoleRequest
+{
+    role: Role,
+}
+
+/// Users manage their own account, admins manage everyone's.
+fn can_manage_user(user: &AuthenticatedUser, username: &str) -> bool
+{
+    user.role == Role::Admin || user.username == username
+}
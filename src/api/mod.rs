@@ -1,17 +1,41 @@
 pub mod api_users;
+pub mod api_auth;
 pub mod api_images;
-// pub mod api_sessions;
-// pub mod api_layers;
+pub mod api_transform;
+pub mod api_filter;
+pub mod api_adjust;
+pub mod api_draw;
+pub mod api_masks;
+pub mod api_text;
+pub mod api_history;
+pub mod api_snapshots;
+pub mod api_sessions;
+pub mod api_layers;
 
 // use crate::db;
 use crate::db::*;
+use crate::db::images::FileInfo;
 
+use crate::image_processing::composite;
+use api_auth::AuthenticatedUser;
+use crate::image_processing::selection::Selection;
+use crate::image_processing::thumbnail::{self, Rendition};
+use crate::image_processing::{self, ProcessingError};
+use actix_web::body::{self, BoxBody};
+use actix_web::http::header;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use chrono::Utc;
 // use actix_web::{web, App, http, HttpResponse, HttpServer, Responder, test};
 // use deadpool_postgres::{Config, Pool};
 use deadpool_postgres::Pool;
+use image::{DynamicImage, ImageFormat, RgbaImage};
 // use serde::Deserialize;
-// use serde_json::json;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError, Weak};
+use tokio::sync::OwnedMutexGuard;
 // use tokio_postgres::{Error, NoTls, Row};
 
 // use crate::db::users::*;
@@ -19,8 +43,6 @@ use deadpool_postgres::Pool;
 
 // TODO: include all the following endpoints
 // web api endpoints
-// POST /api/transform/rotate for a rotate tool.
-// POST /api/filter/blur for applying a blur filter.
 // use crate::db::{ add_user, create_pool };
 
 //////////////////////////////////////////////////////////////////////////////////
@@ -31,15 +53,61 @@ pub async fn start_server(pool: Pool) -> Result<(), MyError>
     HttpServer::new(move || {
         App::new().app_data(web::Data::new(pool.clone()))
                   .route("/", web::get().to(index))
-                  .route("/user/add_user", web::post().to(api_users::add_user_handler))
-                  .route( "/user/get_user_by_id/{id}", web::get().to( api_users::get_user_by_user_id_handler )) // TODO: remove this in PROD
+                  .route("/auth/register", web::post().to(api_auth::register_handler))
+                  .route("/auth/login", web::post().to(api_auth::login_handler))
+                  .route("/auth/logout", web::post().to(api_auth::logout_handler))
+                  .route("/auth/logout_all", web::post().to(api_auth::logout_all_handler))
+                  .route("/auth/me", web::get().to(api_auth::current_user_handler))
+                  .route("/admin/sessions", web::get().to(api_sessions::get_active_sessions_handler))
+                  .route("/admin/sessions/{session_id}", web::delete().to(api_sessions::end_session_handler))
+                  .route("/admin/users/{user_id}/sessions", web::get().to(api_sessions::get_user_sessions_handler))
+                  .route("/admin/users/{user_id}/sessions", web::delete().to(api_sessions::revoke_user_sessions_handler))
+                  .route("/user/add_user", web::post().to(api_users::add_user_handler)) // admins only
+                  .route("/admin/users/{user_id}/role", web::put().to(api_users::update_user_role_handler))
+                  .route( "/user/get_user_by_id/{id}", web::get().to( api_users::get_user_by_user_id_handler )) // admins only
                   .route("/user/get_user_by_username/{username}", web::get().to(api_users::get_user_handler))
                   .route( "/user/get_user_by_email/{email}", web::get().to(api_users::get_user_by_email_handler))
-                  .route("/user/all_users", web::get().to(api_users::get_all_users_handler)) // TODO: remove this in PROD
+                  .route("/user/all_users", web::get().to(api_users::get_all_users_handler)) // admins only
                   .route("/user/{username}/update_email", web::put().to( api_users::update_user_email_handler ))
                   .route( "/user/delete_user/{username}", web::delete().to( api_users::delete_user_handler )) 
-                  .route("/user/delete_all_users", web::delete().to(api_users::delete_all_users_handler)) // TODO: remove this in PROD
+                  .route("/user/delete_all_users", web::delete().to(api_users::delete_all_users_handler)) // admins only
                   .route( "/image/add_image", web::post().to(api_images::add_image_handler))
+                  .route("/image/all_images", web::get().to(api_images::get_all_images_handler))
+                  .route("/image/{id}", web::delete().to(api_images::delete_image_handler))
+                  .route("/image/{id}/composite", web::get().to(api_images::get_composite_handler))
+                  .route("/image/{id}/thumbnail", web::get().to(api_images::get_thumbnail_handler))
+                  .route("/image/{id}/export", web::get().to(api_images::export_image_handler))
+                  .route("/image/{id}/history", web::get().to(api_history::get_history_handler))
+                  .route("/image/{id}/undo", web::post().to(api_history::undo_handler))
+                  .route("/image/{id}/redo", web::post().to(api_history::redo_handler))
+                  .route("/image/{id}/snapshots", web::get().to(api_snapshots::get_snapshots_handler))
+                  .route("/image/{id}/snapshots", web::post().to(api_snapshots::create_snapshot_handler))
+                  .route("/image/{id}/snapshots/{snapshot_id}/diff", web::get().to(api_snapshots::diff_snapshot_handler))
+                  .route("/image/{id}/snapshots/{snapshot_id}/restore", web::post().to(api_snapshots::restore_snapshot_handler))
+                  .route("/image/{id}/layers", web::get().to(api_layers::get_layers_handler))
+                  .route("/image/{id}/layers", web::post().to(api_layers::add_layer_handler))
+                  .route("/image/{id}/groups", web::post().to(api_layers::create_layer_group_handler))
+                  .route("/image/{id}/groups/{group_id}", web::put().to(api_layers::update_layer_group_handler))
+                  .route("/image/{id}/groups/{group_id}", web::delete().to(api_layers::delete_layer_group_handler))
+                  .route("/image/{id}/layers/merge", web::post().to(api_layers::merge_layers_handler))
+                  .route("/image/{id}/layers/{layer_id}/blend_mode", web::put().to(api_layers::update_blend_mode_handler))
+                  .route("/image/{id}/layers/{layer_id}/params", web::put().to(api_layers::update_layer_params_handler))
+                  .route("/image/{id}/layers/{layer_id}/clipping", web::put().to(api_layers::update_clipping_handler))
+                  .route("/image/{id}/text", web::post().to(api_text::create_text_layer_handler))
+                  .route("/image/{id}/layers/{layer_id}/text", web::put().to(api_text::update_text_layer_handler))
+                  .route("/image/{id}/layers/{layer_id}/mask", web::post().to(api_masks::create_mask_handler))
+                  .route("/image/{id}/layers/{layer_id}/mask", web::put().to(api_masks::update_mask_handler))
+                  .route("/image/{id}/layers/{layer_id}/mask", web::delete().to(api_masks::delete_mask_handler))
+                  .route("/image/{id}/layers/{layer_id}/mask/stroke", web::post().to(api_masks::mask_stroke_handler))
+                  .route("/image/{id}/layers/{layer_id}/mask/apply", web::post().to(api_masks::apply_mask_handler))
+                  .route("/api/transform/rotate", web::post().to(api_transform::rotate_image_handler))
+                  .route("/api/transform/resize", web::post().to(api_transform::resize_image_handler))
+                  .route("/api/transform/crop", web::post().to(api_transform::crop_image_handler))
+                  .route("/api/transform/canvas", web::post().to(api_transform::canvas_size_handler))
+                  .route("/api/filter/blur", web::post().to(api_filter::blur_handler))
+                  .route("/api/adjust/{op}", web::post().to(api_adjust::adjust_handler))
+                  .route("/api/draw/stroke", web::post().to(api_draw::stroke_handler))
+                  .route("/api/fonts", web::get().to(api_text::list_fonts_handler))
                 
                 // Other routes
     // TODO: Does this number/address need to change in PROD?
@@ -65,6 +133,346 @@ async fn index() -> impl Responder
 //     );
 // }
 
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Shared Edit Helpers ********** ///////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// Load a stored image, apply an edit to it and store the result as its file /////
+// Used by every route that changes the pixels of a whole image.
+pub(crate) async fn edit_image_file<F>(pool: &Pool, image_id: i32, edit: F) -> HttpResponse
+    where F: FnOnce(DynamicImage) -> Result<DynamicImage, ProcessingError> + Send + 'static
+{
+    let file_data = match load_image_file(pool, image_id).await {
+        Ok(file_data) => file_data,
+        Err(response) => return response,
+    };
+
+    // Decoding and resampling are CPU heavy, keep them off the async workers
+    let result = web::block(move || {
+        let edited = edit(image_processing::decode_image(&file_data)?)?;
+        let file_info = FileInfo { file_type: ImageFormat::Png.to_mime_type().to_string(),
+                                   width: edited.width() as i32,
+                                   height: edited.height() as i32 };
+        Ok::<_, ProcessingError>((file_info, image_processing::encode_png(&edited)?))
+    }).await;
+
+    let (file_info, edited_data) = match result {
+        Ok(Ok(edited)) => edited,
+        Ok(Err(e)) => return processing_error_response(image_id, e),
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    match images::update_image_file(pool, image_id, &file_info, edited_data).await {
+        Ok(file_hash) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": image_id,
+            "file_hash": file_hash,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Image not found."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+// Load a layer's pixels, apply an edit to them and store the result /////////////
+pub(crate) async fn edit_layer_pixels<F>(pool: &Pool,
+                                         image_id: i32,
+                                         layer_id: i32,
+                                         edit: F)
+                                         -> HttpResponse
+    where F: FnOnce(RgbaImage) -> Result<RgbaImage, ProcessingError> + Send + 'static
+{
+    let layer = match load_layer(pool, image_id, layer_id).await {
+        Ok(layer) => layer,
+        Err(response) => return response,
+    };
+    if let Err(response) = require_raster(layer.layer_type) {
+        return response;
+    }
+
+    let layer_data = layer.layer_data;
+    let result = process_blocking(image_id, move || {
+        let edited = edit(image_processing::decode_layer_data(&layer_data)?)?;
+        Ok(image_processing::encode_layer_data(&edited)?)
+    }).await;
+
+    let new_layer_data = match result {
+        Ok(data) => data,
+        Err(response) => return response,
+    };
+
+    match layers::update_layer_data(pool, layer_id, &new_layer_data).await {
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "status": "success",
+            "image_id": layer.image_id,
+            "layer_id": layer_id,
+        })),
+        Err(MyDbError::NotFound) => HttpResponse::NotFound().json("Layer not found."),
+        Err(_) => HttpResponse::InternalServerError().json("Internal server error"),
+    }
+}
+
+// Only raster layers have pixels of their own to edit //////////////////////////
+// The others are drawn from their parameters, editing their pixels would leave
+// the two out of step.
+pub(crate) fn require_raster(layer_type: layers::LayerType) -> Result<(), HttpResponse>
+{
+    match layer_type {
+        layers::LayerType::Raster => Ok(()),
+        other => Err(HttpResponse::BadRequest().json(format!(
+            "Only raster layers can be edited this way, this is a {} layer.",
+            other.as_str()
+        ))),
+    }
+}
+
+// Make sure an image belongs to the user working on it //////////////////////////
+// 404 when there is no such image, 403 when it belongs to someone else. Images
+// without an owner are left to admins. Layers, masks, history and snapshots
+// belong to their image, checking the image covers them too.
+pub(crate) async fn authorize_image(pool: &Pool,
+                                    user: &AuthenticatedUser,
+                                    image_id: i32)
+                                    -> Result<(), HttpResponse>
+{
+    match images::get_image_owner(pool, image_id).await {
+        Ok(Some(owner_id)) if owner_id == user.user_id => Ok(()),
+        Ok(None) if user.role == users::Role::Admin => Ok(()),
+        Ok(_) => Err(HttpResponse::Forbidden().json("You don't have access to this image.")),
+        Err(MyDbError::NotFound) => Err(HttpResponse::NotFound().json("Image not found.")),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
+
+// Load a layer, making sure it belongs to the image in the request /////////////
+pub(crate) async fn load_layer(pool: &Pool,
+                               image_id: i32,
+                               layer_id: i32)
+                               -> Result<layers::Layer, HttpResponse>
+{
+    match layers::get_layer_by_layer_id(pool, layer_id).await {
+        Ok(layer) if layer.image_id == image_id => Ok(layer),
+        Ok(_) => Err(HttpResponse::NotFound().json("Layer not found on this image.")),
+        Err(MyDbError::NotFound) => Err(HttpResponse::NotFound().json("Layer not found.")),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
+
+// Run pixel work off the async workers and turn failures into a response ///////
+pub(crate) async fn process_blocking<T, F>(image_id: i32, work: F) -> Result<T, HttpResponse>
+    where F: FnOnce() -> Result<T, ProcessingError> + Send + 'static,
+          T: Send + 'static
+{
+    match web::block(work).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(processing_error_response(image_id, e)),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
+
+// Load the flattened image when a selection samples from it ///////////////////
+// Only layer edits need this, whole image edits sample the image file itself.
+pub(crate) async fn composite_for_selection(pool: &Pool,
+                                            image_id: i32,
+                                            selection: Option<&Selection>)
+                                            -> Result<Option<RgbaImage>, HttpResponse>
+{
+    if !selection.is_some_and(|selection| selection.needs_composite()) {
+        return Ok(None);
+    }
+    load_composite(pool, image_id).await.map(Some)
+}
+
+// Read the file of a stored image ///////////////////////////////////////////////
+pub(crate) async fn load_image_file(pool: &Pool, image_id: i32) -> Result<Vec<u8>, HttpResponse>
+{
+    let image = match images::get_single_image(pool, image_id).await {
+        Ok(image) => image,
+        Err(MyDbError::NotFound) => return Err(HttpResponse::NotFound().json("Image not found.")),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Internal server error")),
+    };
+
+    match images::get_image_data(&image).await {
+        Ok(file_data) => Ok(file_data),
+        Err(MyDbError::NotFound) => Err(HttpResponse::NotFound().json("Image file not found.")),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
+
+// Flatten an image: the stored image file with all of its layers on top ////////
+pub(crate) async fn load_composite(pool: &Pool, image_id: i32) -> Result<RgbaImage, HttpResponse>
+{
+    let file_data = load_image_file(pool, image_id).await?;
+
+    // An image without layers is just its file
+    let image_layers = match layers::get_layers_by_image_id(pool, image_id).await {
+        Ok(image_layers) => image_layers,
+        Err(MyDbError::NotFound) => Vec::new(),
+        Err(_) => return Err(HttpResponse::InternalServerError().json("Internal server error")),
+    };
+
+    let result = web::block(move || {
+        let mut canvas = image_processing::decode_image(&file_data)?.to_rgba8();
+        composite::composite_layers(&mut canvas, &image_layers)?;
+        Ok::<_, ProcessingError>(canvas)
+    }).await;
+
+    match result {
+        Ok(Ok(composite)) => Ok(composite),
+        Ok(Err(e)) => Err(processing_error_response(image_id, e)),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
+
+// Render an image's thumbnails again in the background /////////////////////////
+// Called once an upload or an edit has gone through, so the response doesn't
+// wait for it. Failures are only logged, the thumbnail route renders a missing
+// rendition itself.
+pub(crate) fn refresh_thumbnails(pool: &Pool, image_id: i32)
+{
+    let pool = pool.clone();
+    actix_web::rt::spawn(async move {
+        if let Err(response) = render_thumbnails(&pool, image_id).await {
+            println!("Error rendering thumbnails for image {}: {}", image_id, response.status());
+        }
+    });
+}
+
+// Flatten an image, render every thumbnail size and store them //////////////////
+pub(crate) async fn render_thumbnails(pool: &Pool,
+                                      image_id: i32)
+                                      -> Result<Vec<Rendition>, HttpResponse>
+{
+    // Taken before the image is read, see thumbnails::save_thumbnails
+    let rendered_at = Utc::now().naive_utc();
+    let composite = load_composite(pool, image_id).await?;
+    let renditions = process_blocking(image_id, move || {
+        Ok(thumbnail::render_thumbnails(&composite)?)
+    }).await?;
+
+    match thumbnails::save_thumbnails(pool, image_id, rendered_at, &renditions).await {
+        Ok(_) => Ok(renditions),
+        Err(MyDbError::NotFound) => Err(HttpResponse::NotFound().json("Image not found.")),
+        Err(_) => Err(HttpResponse::InternalServerError().json("Internal server error")),
+    }
+}
+
+// One lock per image with an edit in progress, see lock_image_edits
+static IMAGE_EDIT_LOCKS: LazyLock<Mutex<HashMap<i32, Weak<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+// Wait until no other edit, undo or redo of the image is running ///////////////
+// The database row lock can't be held across an edit, since the edit's own
+// transactions update the images row. Edits of one image are queued here
+// instead, for as long as the returned guard lives.
+pub(crate) async fn lock_image_edits(image_id: i32) -> OwnedMutexGuard<()>
+{
+    let lock = {
+        let mut locks = IMAGE_EDIT_LOCKS.lock().unwrap_or_else(PoisonError::into_inner);
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        match locks.get(&image_id).and_then(Weak::upgrade) {
+            Some(lock) => lock,
+            None => {
+                let lock = Arc::new(tokio::sync::Mutex::new(()));
+                locks.insert(image_id, Arc::downgrade(&lock));
+                lock
+            }
+        }
+    };
+    lock.lock_owned().await
+}
+
+// Run an edit and record what it changed in the image's history ////////////////
+// The image is captured before and after the edit and only the rows that
+// differ are stored, so every kind of edit can be undone the same way. The
+// image's other edits wait until this one is recorded, so their changes never
+// end up in its entry. A failed edit records nothing, and failing to record
+// never fails the edit. The ID of the recorded entry is added to the edit's
+// JSON response as 'revision_id', so the frontend knows what to refresh to.
+pub(crate) async fn track_history<Fut>(pool: &Pool,
+                                       image_id: i32,
+                                       op_name: &str,
+                                       op_params: Value,
+                                       edit: Fut)
+                                       -> HttpResponse
+    where Fut: Future<Output = HttpResponse>
+{
+    let _edit_lock = lock_image_edits(image_id).await;
+    let before = match history::capture_state(pool, image_id).await {
+        Ok(before) => before,
+        // Let the edit report the missing image itself
+        Err(MyDbError::NotFound) => return edit.await,
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+
+    let response = edit.await;
+    if !response.status().is_success() {
+        return response;
+    }
+
+    let recorded = match history::capture_state(pool, image_id).await {
+        Ok(after) => {
+            history::record_history(pool, image_id, op_name, &op_params, &before, &after).await
+        }
+        Err(e) => Err(e),
+    };
+    match recorded {
+        // Nothing that shows changed, the thumbnails are still right
+        Ok(None) => {}
+        Ok(Some(revision_id)) => {
+            refresh_thumbnails(pool, image_id);
+            return with_revision_id(response, revision_id).await;
+        }
+        Err(e) => {
+            println!("Error recording history for image {}: {:?}", image_id, e);
+            refresh_thumbnails(pool, image_id);
+        }
+    }
+    response
+}
+
+// Add the history entry an edit made to its response, when that is a JSON object
+async fn with_revision_id(response: HttpResponse, revision_id: i32) -> HttpResponse
+{
+    let (mut response, body) = response.into_parts();
+    let body = match body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => return HttpResponse::InternalServerError().json("Internal server error"),
+    };
+    let body = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(mut fields)) => {
+            fields.insert("revision_id".to_string(), json!(revision_id));
+            Value::Object(fields).to_string().into()
+        }
+        _ => body,
+    };
+    response.headers_mut().remove(header::CONTENT_LENGTH);
+    response.set_body(BoxBody::new(body))
+}
+
+// The parameters an edit is recorded with: its request, plus the layer from the
+// path when there is one
+pub(crate) fn history_params<T: Serialize>(request: &T, layer_id: Option<i32>) -> Value
+{
+    let mut params = serde_json::to_value(request).unwrap_or(Value::Null);
+    if let (Value::Object(params), Some(layer_id)) = (&mut params, layer_id) {
+        params.insert("layer_id".to_string(), json!(layer_id));
+    }
+    params
+}
+
+// Bad parameters are the client's fault, anything else means the pixels couldn't
+// be read or written
+fn processing_error_response(image_id: i32, err: ProcessingError) -> HttpResponse
+{
+    match err {
+        ProcessingError::InvalidParameter(msg) => HttpResponse::BadRequest().json(msg),
+        e => {
+            println!("Error editing image {}: {:?}", image_id, e);
+            HttpResponse::UnprocessableEntity().json("Image data could not be processed.")
+        }
+    }
+}
+
 // Error Enum for server function ////////////////////////////////////////////////
 #[derive(Debug)]
 pub enum MyError
@@ -73,6 +481,17 @@ pub enum MyError
     Postgres(postgres::Error),
     // Other error types as needed HERE
 }
+impl std::fmt::Display for MyError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self {
+            MyError::Io(e) => write!(f, "IO error: {}", e),
+            MyError::Postgres(e) => write!(f, "Postgres error: {}", e),
+        }
+    }
+}
+impl std::error::Error for MyError {}
 impl From<std::io::Error> for MyError
 {
     fn from(err: std::io::Error) -> MyError
@@ -87,3 +506,28 @@ impl From<postgres::Error> for MyError
         MyError::Postgres(err)
     }
 }
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use actix_web::http::StatusCode;
+    use layers::LayerType;
+
+    #[test]
+    fn only_raster_layers_have_pixels_to_edit()
+    {
+        assert!(require_raster(LayerType::Raster).is_ok());
+        let others = [LayerType::Adjustment, LayerType::Fill, LayerType::Text, LayerType::Shape];
+        for layer_type in others {
+            match require_raster(layer_type) {
+                Err(response) => assert_eq!(response.status(), StatusCode::BAD_REQUEST),
+                Ok(_) => panic!("{:?} layers were accepted", layer_type),
+            }
+        }
+    }
+}
@@ -0,0 +1,71 @@
+// password hashing and session tokens
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+// Shortest and longest password accepted. The upper bound keeps a single request
+// from making Argon2 hash megabytes.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+pub const MAX_PASSWORD_LENGTH: usize = 1024;
+
+// Random bytes in a session token, sent to the client as hex
+const SESSION_TOKEN_BYTES: usize = 32;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Password Hashing ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// hash_password: Argon2id hash of a password in PHC string format ////////////////
+// The string carries the salt and parameters, so it's all that needs storing.
+// Hashing is slow on purpose, call this off the async workers.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error>
+{
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+// verify_password: check a password against a stored hash ///////////////////////
+// A hash that can't be parsed never matches.
+pub fn verify_password(password: &str, password_hash: &str) -> bool
+{
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// burn_password_check: spend as long as verify_password does, without a user //
+// Used when a login names a user that doesn't exist, so the response time
+// doesn't tell which usernames are taken.
+pub fn burn_password_check(password: &str)
+{
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let dummy_hash = DUMMY_HASH.get_or_init(|| {
+                                   hash_password("not a real password").unwrap_or_default()
+                               });
+    verify_password(password, dummy_hash);
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Session Tokens ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// new_session_token: a random, opaque token for a new session /////////////////////
+// Only the client ever sees the token, the sessions table keeps its hash.
+pub fn new_session_token() -> String
+{
+    let mut bytes = [0u8; SESSION_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// hash_session_token: what the sessions table stores for a token ////////////////
+// Tokens are long and random, a fast hash is enough to make a leaked table
+// useless for logging in.
+pub fn hash_session_token(token: &str) -> String
+{
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
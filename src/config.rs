@@ -1 +1,101 @@
-// configuration related code
\ No newline at end of file
+// configuration related code
+use std::path::PathBuf;
+use std::time::Duration;
+
+// font_dir: where text layers look for .ttf and .otf files //////////////////////
+// Set FONT_DIR in the .env file, defaults to ./fonts
+pub fn font_dir() -> PathBuf
+{
+    std::env::var("FONT_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("./fonts"))
+}
+
+/// Where image files, layer pixels and masks are kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend
+{
+    Local,
+    S3,
+}
+
+// storage_backend: STORAGE_BACKEND is "local" (the default) or "s3" /////////////
+pub fn storage_backend() -> StorageBackend
+{
+    match std::env::var("STORAGE_BACKEND") {
+        Ok(backend) if backend.eq_ignore_ascii_case("s3") => StorageBackend::S3,
+        _ => StorageBackend::Local,
+    }
+}
+
+// storage_dir: root of the local blob store /////////////////////////////////////
+// Set STORAGE_DIR in the .env file, defaults to ./uploads
+pub fn storage_dir() -> PathBuf
+{
+    std::env::var("STORAGE_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from("./uploads"))
+}
+
+/// Connection settings for an S3 compatible store, e.g. AWS or a local MinIO.
+#[derive(Debug, Clone)]
+pub struct S3Config
+{
+    /// Base URL of the service, e.g. http://localhost:9000
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// s3_config: read S3_ENDPOINT, S3_BUCKET, S3_ACCESS_KEY and S3_SECRET_KEY ////////
+// S3_REGION is optional and defaults to us-east-1.
+pub fn s3_config() -> Result<S3Config, String>
+{
+    let var = |name: &str| std::env::var(name).map_err(|_| format!("{} is not set", name));
+    Ok(S3Config { endpoint: var("S3_ENDPOINT")?.trim_end_matches('/').to_string(),
+                  bucket: var("S3_BUCKET")?,
+                  region: var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+                  access_key: var("S3_ACCESS_KEY")?,
+                  secret_key: var("S3_SECRET_KEY")? })
+}
+
+// session_lifetime: how far each request pushes a session's expiry ///////////////
+// Set SESSION_LIFETIME_HOURS in the .env file, defaults to 24
+pub fn session_lifetime() -> Duration
+{
+    Duration::from_secs(env_number("SESSION_LIFETIME_HOURS", 24) * 3600)
+}
+
+// session_max_lifetime: no session outlives its login by more than this ///////////
+// Set SESSION_MAX_LIFETIME_DAYS in the .env file, defaults to 30
+pub fn session_max_lifetime() -> Duration
+{
+    Duration::from_secs(env_number("SESSION_MAX_LIFETIME_DAYS", 30) * 86_400)
+}
+
+// session_idle_timeout: sessions unused for this long are ended //////////////////
+// Set SESSION_IDLE_TIMEOUT_MINUTES in the .env file, defaults to 120. 0 turns the
+// idle timeout off.
+pub fn session_idle_timeout() -> Option<Duration>
+{
+    match env_number("SESSION_IDLE_TIMEOUT_MINUTES", 120) {
+        0 => None,
+        minutes => Some(Duration::from_secs(minutes * 60)),
+    }
+}
+
+// admin_usernames: users made admins when the server starts ///////////////////////
+// Set ADMIN_USERNAMES in the .env file as a comma separated list. Admins can then
+// change other users' roles through the API.
+pub fn admin_usernames() -> Vec<String>
+{
+    std::env::var("ADMIN_USERNAMES").unwrap_or_default()
+                                    .split(',')
+                                    .map(|name| name.trim().to_string())
+                                    .filter(|name| !name.is_empty())
+                                    .collect()
+}
+
+// A whole number from the environment, or the default if unset or unparsable
+fn env_number(name: &str, default: u64) -> u64
+{
+    std::env::var(name).ok().and_then(|value| value.trim().parse().ok()).unwrap_or(default)
+}
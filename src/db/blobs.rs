@@ -0,0 +1,181 @@
+#![allow(dead_code)]
+use super::MyDbError;
+use crate::storage::{self, StorageError};
+use deadpool_postgres::{GenericClient, Pool};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+// Blobs nobody has used for this long are removed by purge_unused_blobs
+const PURGE_GRACE_MINUTES: i32 = 30;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Blob Management Functions ********** /////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+// Image files, layer pixels and masks live in the storage backend under their
+// SHA-256 hash. Every reference to a blob (a layer, an image, a history entry, a
+// snapshot or a thumbnail) holds one count in blobs.ref_count. The count changes in the same
+// transaction as the reference. Blobs whose count is back at zero are kept for a
+// grace period, so an edit's history can still take a reference to the pixels
+// the edit replaced, and are then removed by purge_unused_blobs.
+
+// put_blob: store content and take a reference to it, returns the hash //////////
+// The row is counted before the bytes are written, so a purge running at the
+// same time either waits for this transaction or is already done with the blob.
+// The bytes are written straight away, if the transaction then rolls back they
+// are left without a row until sweep_orphaned_blobs removes them.
+pub async fn put_blob<C: GenericClient>(client: &C, data: Vec<u8>) -> Result<String, MyDbError> {
+    let hash = storage::content_hash(&data);
+    let statement = client
+        .prepare("INSERT INTO blobs (hash, byte_size, ref_count) VALUES ($1, $2, 1) ON CONFLICT (hash) DO UPDATE SET ref_count = blobs.ref_count + 1, last_used = NOW()")
+        .await?;
+    client.execute(&statement, &[&hash, &(data.len() as i64)]).await?;
+
+    tokio::task::spawn_blocking(move || storage::backend().put(&data))
+        .await
+        .map_err(|e| MyDbError::StorageError(e.to_string()))??;
+    Ok(hash)
+}
+
+// retain_blob: take another reference to a blob that is already stored //////////
+// Returns NotFound if there is no such blob.
+pub async fn retain_blob<C: GenericClient>(client: &C, hash: &str) -> Result<(), MyDbError> {
+    let statement = client
+        .prepare("UPDATE blobs SET ref_count = ref_count + 1, last_used = NOW() WHERE hash = $1")
+        .await?;
+    match client.execute(&statement, &[&hash]).await? {
+        0 => Err(MyDbError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+// release_blob: drop a reference to a blob //////////////////////////////////////
+pub async fn release_blob<C: GenericClient>(client: &C, hash: &str) -> Result<(), MyDbError> {
+    let statement = client
+        .prepare("UPDATE blobs SET ref_count = GREATEST(ref_count - 1, 0), last_used = NOW() WHERE hash = $1")
+        .await?;
+    client.execute(&statement, &[&hash]).await?;
+    Ok(())
+}
+
+// get_blob: read a blob's content from storage //////////////////////////////////
+pub async fn get_blob(hash: &str) -> Result<Vec<u8>, MyDbError> {
+    let hash = hash.to_string();
+    let data = tokio::task::spawn_blocking(move || storage::backend().get(&hash))
+        .await
+        .map_err(|e| MyDbError::StorageError(e.to_string()))?;
+    match data {
+        Ok(data) => Ok(data),
+        Err(StorageError::NotFound(_)) => Err(MyDbError::NotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// purge_unused_blobs: remove blobs that have been unused for the grace period ///
+// Returns how many were removed. A blob that fails to go is logged and left for
+// the next run.
+pub async fn purge_unused_blobs(pool: &Pool) -> Result<usize, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("SELECT hash FROM blobs WHERE ref_count = 0 AND last_used < NOW() - make_interval(mins => $1)")
+        .await?;
+    let hashes: Vec<String> = client
+        .query(&statement, &[&PURGE_GRACE_MINUTES])
+        .await?
+        .iter()
+        .map(|row| row.get("hash"))
+        .collect();
+    drop(client);
+
+    let mut purged = 0;
+    for hash in &hashes {
+        match purge_blob(pool, hash).await {
+            Ok(true) => purged += 1,
+            Ok(false) => {}
+            Err(e) => println!("Error purging blob {}: {:?}", hash, e),
+        }
+    }
+    Ok(purged)
+}
+
+// sweep_orphaned_blobs: remove stored blobs that have no blobs row ////////////
+// They come from transactions that rolled back after put_blob wrote the bytes.
+// Blobs stored within the grace period are left alone, their transaction may
+// still be running. Returns how many were removed.
+pub async fn sweep_orphaned_blobs(pool: &Pool) -> Result<usize, MyDbError> {
+    let before = SystemTime::now() - Duration::from_secs(PURGE_GRACE_MINUTES as u64 * 60);
+    let stored = tokio::task::spawn_blocking(move || storage::backend().list(before))
+        .await
+        .map_err(|e| MyDbError::StorageError(e.to_string()))??;
+
+    let client = pool.get().await?;
+    let statement = client.prepare("SELECT hash FROM blobs WHERE hash = ANY($1)").await?;
+    let mut orphans = Vec::new();
+    for hashes in stored.chunks(1000) {
+        let known: HashSet<String> = client
+            .query(&statement, &[&hashes])
+            .await?
+            .iter()
+            .map(|row| row.get("hash"))
+            .collect();
+        orphans.extend(hashes.iter().filter(|hash| !known.contains(*hash)).cloned());
+    }
+    drop(client);
+
+    let mut swept = 0;
+    for hash in &orphans {
+        match sweep_blob(pool, hash).await {
+            Ok(true) => swept += 1,
+            Ok(false) => {}
+            Err(e) => println!("Error sweeping blob {}: {:?}", hash, e),
+        }
+    }
+    Ok(swept)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// The row stays locked until the bytes are gone, so put_blob can't count the
+// blob again in between. A blob that was used again since it was picked is left
+// alone.
+async fn purge_blob(pool: &Pool, hash: &str) -> Result<bool, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare("DELETE FROM blobs WHERE hash = $1 AND ref_count = 0 AND last_used < NOW() - make_interval(mins => $2)")
+        .await?;
+    if transaction.execute(&statement, &[&hash, &PURGE_GRACE_MINUTES]).await? == 0 {
+        return Ok(false);
+    }
+
+    let owned_hash = hash.to_string();
+    tokio::task::spawn_blocking(move || storage::backend().delete(&owned_hash))
+        .await
+        .map_err(|e| MyDbError::StorageError(e.to_string()))??;
+    transaction.commit().await?;
+    Ok(true)
+}
+
+// A placeholder row holds the hash while the bytes are deleted. A put_blob of
+// the same content waits for it and then writes the bytes again. A blob that
+// got a row in the meantime is left alone.
+async fn sweep_blob(pool: &Pool, hash: &str) -> Result<bool, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare("INSERT INTO blobs (hash, byte_size, ref_count) VALUES ($1, 0, 0) ON CONFLICT (hash) DO NOTHING")
+        .await?;
+    if transaction.execute(&statement, &[&hash]).await? == 0 {
+        return Ok(false);
+    }
+
+    let owned_hash = hash.to_string();
+    tokio::task::spawn_blocking(move || storage::backend().delete(&owned_hash))
+        .await
+        .map_err(|e| MyDbError::StorageError(e.to_string()))??;
+    let statement = transaction.prepare("DELETE FROM blobs WHERE hash = $1").await?;
+    transaction.execute(&statement, &[&hash]).await?;
+    transaction.commit().await?;
+    Ok(true)
+}
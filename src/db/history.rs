@@ -0,0 +1,677 @@
+#![allow(dead_code)]
+use super::blobs;
+use super::images::FileInfo;
+use super::MyDbError;
+use crate::storage;
+use chrono::NaiveDateTime;
+use deadpool_postgres::{Pool, Transaction};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use tokio_postgres::Row;
+
+// Oldest edits are dropped once an image has this many
+const MAX_HISTORY_ENTRIES: i64 = 200;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** History Management Functions ********** //////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// capture_state: everything an edit can change on an image //////////////////////
+// The image's file plus every layer and group row, with the layer pixels and
+// masks replaced by their blob hashes. Returns NotFound if the image doesn't exist.
+pub async fn capture_state(pool: &Pool, image_id: i32) -> Result<ImageState, MyDbError> {
+    let client = pool.get().await?;
+
+    let image_statement = client
+        .prepare("SELECT file_path, file_hash, file_type, width, height FROM images WHERE id = $1")
+        .await?;
+    let mut state = match client.query_opt(&image_statement, &[&image_id]).await? {
+        Some(row) => ImageState {
+            file_path: row.get("file_path"),
+            file_hash: row.get("file_hash"),
+            file_info: FileInfo::from_row(&row),
+            ..ImageState::default()
+        },
+        None => return Err(MyDbError::NotFound),
+    };
+
+    let layer_statement = client.prepare("SELECT * FROM layers WHERE image_id = $1").await?;
+    for row in client.query(&layer_statement, &[&image_id]).await? {
+        let layer_hash = row_blob(&row, "layer_hash", "layer_data", &mut state.blobs);
+        let mask_hash = row_blob(&row, "mask_hash", "mask_data", &mut state.blobs);
+        state.layers.insert(row.get("id"), LayerRecord::from_row(&row, layer_hash, mask_hash));
+    }
+
+    let group_statement = client.prepare("SELECT * FROM layer_groups WHERE image_id = $1").await?;
+    for row in client.query(&group_statement, &[&image_id]).await? {
+        state.groups.insert(row.get("id"), GroupRecord::from_row(&row));
+    }
+    Ok(state)
+}
+
+// record_history: store an edit as the newest history entry /////////////////////
+// Only the rows that differ between the two states are kept. Edits that were
+// undone are dropped, since a new edit starts a new branch. Returns the new
+// entry's ID, or None if nothing changed.
+pub async fn record_history(
+    pool: &Pool,
+    image_id: i32,
+    op_name: &str,
+    op_params: &Value,
+    before: &ImageState,
+    after: &ImageState,
+) -> Result<Option<i32>, MyDbError> {
+    let (before_change, after_change) = diff_states(before, after);
+    if before_change.is_empty() {
+        return Ok(None);
+    }
+    let layer_ids: Vec<i32> = before_change.layers.keys().copied().collect();
+
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    lock_image(&transaction, image_id).await?;
+
+    // Keep the pixels both states point at
+    let legacy_blobs = before.blobs.iter().chain(&after.blobs).collect();
+    store_blobs(&transaction, before_change.blob_hashes().chain(after_change.blob_hashes()), &legacy_blobs).await?;
+
+    let redo_statement = transaction
+        .prepare("DELETE FROM image_history WHERE image_id = $1 AND undone RETURNING before_state, after_state")
+        .await?;
+    let dropped = transaction.query(&redo_statement, &[&image_id]).await?;
+    release_entries(&transaction, &dropped).await?;
+
+    let insert_statement = transaction
+        .prepare("INSERT INTO image_history (image_id, op_name, op_params, layer_ids, before_state, after_state) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id")
+        .await?;
+    let history_id: i32 = transaction
+        .query_one(
+            &insert_statement,
+            &[
+                &image_id,
+                &op_name,
+                &op_params,
+                &layer_ids,
+                &serde_json::to_value(&before_change)?,
+                &serde_json::to_value(&after_change)?,
+            ],
+        )
+        .await?
+        .get(0);
+
+    let prune_statement = transaction
+        .prepare("DELETE FROM image_history WHERE image_id = $1 AND id NOT IN (SELECT id FROM image_history WHERE image_id = $1 ORDER BY id DESC LIMIT $2) RETURNING before_state, after_state")
+        .await?;
+    let pruned = transaction.query(&prune_statement, &[&image_id, &MAX_HISTORY_ENTRIES]).await?;
+    release_entries(&transaction, &pruned).await?;
+
+    transaction.commit().await?;
+    Ok(Some(history_id))
+}
+
+// undo: put the image back the way it was before its newest edit ////////////////
+// Returns the entry that was undone, or NotFound if there is nothing to undo.
+pub async fn undo(pool: &Pool, image_id: i32) -> Result<HistoryEntry, MyDbError> {
+    step_history(pool, image_id, HistoryStep::Undo).await
+}
+
+// redo: re-apply the oldest edit that was undone ////////////////////////////////
+// Returns the entry that was redone, or NotFound if there is nothing to redo.
+pub async fn redo(pool: &Pool, image_id: i32) -> Result<HistoryEntry, MyDbError> {
+    step_history(pool, image_id, HistoryStep::Redo).await
+}
+
+// get_history: every recorded edit of an image, oldest first ////////////////////
+pub async fn get_history(pool: &Pool, image_id: i32) -> Result<Vec<HistoryEntry>, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("SELECT * FROM image_history WHERE image_id = $1 ORDER BY id")
+        .await?;
+    let rows = client.query(&statement, &[&image_id]).await?;
+    Ok(rows.iter().map(HistoryEntry::from_row).collect())
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+enum HistoryStep {
+    Undo,
+    Redo,
+}
+
+// Move one step through the history and restore that side of the entry.
+// Everything happens in one transaction, so a failed restore changes nothing.
+async fn step_history(pool: &Pool, image_id: i32, step: HistoryStep) -> Result<HistoryEntry, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    lock_image(&transaction, image_id).await?;
+
+    let query = match step {
+        HistoryStep::Undo => "SELECT * FROM image_history WHERE image_id = $1 AND NOT undone ORDER BY id DESC LIMIT 1",
+        HistoryStep::Redo => "SELECT * FROM image_history WHERE image_id = $1 AND undone ORDER BY id LIMIT 1",
+    };
+    let statement = transaction.prepare(query).await?;
+    let row = match transaction.query_opt(&statement, &[&image_id]).await? {
+        Some(row) => row,
+        None => return Err(MyDbError::NotFound),
+    };
+
+    let (state, undone) = match step {
+        HistoryStep::Undo => (row.get::<_, Value>("before_state"), true),
+        HistoryStep::Redo => (row.get::<_, Value>("after_state"), false),
+    };
+    restore_change(&transaction, image_id, &serde_json::from_value(state)?).await?;
+
+    let history_id: i32 = row.get("id");
+    let update_statement = transaction
+        .prepare("UPDATE image_history SET undone = $1 WHERE id = $2")
+        .await?;
+    transaction.execute(&update_statement, &[&undone, &history_id]).await?;
+    transaction.commit().await?;
+
+    let mut entry = HistoryEntry::from_row(&row);
+    entry.undone = undone;
+    Ok(entry)
+}
+
+// Hold the image row so edits, undo and redo of one image don't interleave
+pub(super) async fn lock_image(transaction: &Transaction<'_>, image_id: i32) -> Result<(), MyDbError> {
+    let statement = transaction.prepare("SELECT id FROM images WHERE id = $1 FOR UPDATE").await?;
+    match transaction.query_opt(&statement, &[&image_id]).await? {
+        Some(_) => Ok(()),
+        None => Err(MyDbError::NotFound),
+    }
+}
+
+// Take one blob reference for every hash. Pixels captured from rows that
+// predate the blob storage are stored first, legacy_blobs has their bytes.
+pub(super) async fn store_blobs<'a, I>(
+    transaction: &Transaction<'_>,
+    hashes: I,
+    legacy_blobs: &HashMap<&String, &Vec<u8>>,
+) -> Result<(), MyDbError>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    for hash in hashes {
+        match legacy_blobs.get(hash) {
+            Some(data) => {
+                blobs::put_blob(transaction, data.to_vec()).await?;
+            }
+            None => blobs::retain_blob(transaction, hash).await?,
+        }
+    }
+    Ok(())
+}
+
+// Release the blobs held by deleted image_history rows
+pub(super) async fn release_entries(transaction: &Transaction<'_>, rows: &[Row]) -> Result<(), MyDbError> {
+    for row in rows {
+        for column in ["before_state", "after_state"] {
+            let change: StateChange = serde_json::from_value(row.get(column))?;
+            for hash in change.blob_hashes() {
+                blobs::release_blob(transaction, hash).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Make an image's layers and groups exactly those of a captured state. Rows the
+// state doesn't have are deleted, the rest point back at the state's blobs.
+pub(super) async fn restore_state(transaction: &Transaction<'_>, image_id: i32, state: &ImageState) -> Result<(), MyDbError> {
+    let mut change = StateChange {
+        file_path: Some(state.file_path.clone()),
+        file_hash: state.file_hash.clone(),
+        file_info: state.file_info.clone(),
+        layers: state.layers.iter().map(|(id, layer)| (*id, Some(layer.clone()))).collect(),
+        groups: state.groups.iter().map(|(id, group)| (*id, Some(group.clone()))).collect(),
+    };
+
+    let layer_statement = transaction.prepare("SELECT id FROM layers WHERE image_id = $1").await?;
+    for row in transaction.query(&layer_statement, &[&image_id]).await? {
+        change.layers.entry(row.get("id")).or_insert(None);
+    }
+    let group_statement = transaction.prepare("SELECT id FROM layer_groups WHERE image_id = $1").await?;
+    for row in transaction.query(&group_statement, &[&image_id]).await? {
+        change.groups.entry(row.get("id")).or_insert(None);
+    }
+    restore_change(transaction, image_id, &change).await
+}
+
+// Write one side of a history entry back into the images, layers and groups.
+// Rows recorded as null didn't exist on that side and are deleted. The rows take
+// a reference to the blobs they are given and release the ones they had.
+async fn restore_change(transaction: &Transaction<'_>, image_id: i32, change: &StateChange) -> Result<(), MyDbError> {
+    if let Some(file_path) = &change.file_path {
+        let current_statement = transaction.prepare("SELECT file_hash FROM images WHERE id = $1").await?;
+        let old_hash: Option<String> = transaction.query_one(&current_statement, &[&image_id]).await?.get("file_hash");
+        // Entries from before file_info was recorded leave the dimensions unknown
+        let file_info = change.file_info.as_ref();
+        let statement = transaction
+            .prepare("UPDATE images SET file_path = $1, file_hash = $2::VARCHAR, byte_size = (SELECT byte_size FROM blobs WHERE hash = $2::VARCHAR), file_type = COALESCE($3, file_type), width = $4, height = $5, updated_at = NOW() WHERE id = $6")
+            .await?;
+        transaction
+            .execute(
+                &statement,
+                &[file_path, &change.file_hash, &file_info.map(|info| &info.file_type),
+                  &file_info.map(|info| info.width), &file_info.map(|info| info.height), &image_id],
+            )
+            .await?;
+        swap_blobs(transaction, old_hash, change.file_hash.as_ref()).await?;
+    }
+
+    // Groups first, parents before their children, so the layers have somewhere to go
+    let group_statement = transaction
+        .prepare("INSERT INTO layer_groups (id, image_id, parent_id, group_name, creation_date, visibility, opacity, blend_mode, layer_order) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) ON CONFLICT (id) DO UPDATE SET parent_id = EXCLUDED.parent_id, group_name = EXCLUDED.group_name, visibility = EXCLUDED.visibility, opacity = EXCLUDED.opacity, blend_mode = EXCLUDED.blend_mode, layer_order = EXCLUDED.layer_order")
+        .await?;
+    let mut pending: Vec<(&i32, &GroupRecord)> = change.groups
+        .iter()
+        .filter_map(|(id, group)| group.as_ref().map(|group| (id, group)))
+        .collect();
+    while !pending.is_empty() {
+        let waiting: Vec<i32> = pending.iter().map(|(id, _)| **id).collect();
+        let (ready, rest): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|(_, group)| !group.parent_id.is_some_and(|parent| waiting.contains(&parent)));
+        // A cycle can't be restored in any order
+        if ready.is_empty() {
+            return Err(MyDbError::JsonError("history groups form a cycle".to_string()));
+        }
+        for (id, group) in ready {
+            transaction
+                .execute(
+                    &group_statement,
+                    &[id, &image_id, &group.parent_id, &group.group_name, &group.creation_date,
+                      &group.visibility, &group.opacity, &group.blend_mode, &group.layer_order],
+                )
+                .await?;
+        }
+        pending = rest;
+    }
+
+    let current_statement = transaction
+        .prepare("SELECT layer_hash, mask_hash FROM layers WHERE id = $1 AND image_id = $2")
+        .await?;
+    let layer_statement = transaction
+        .prepare("INSERT INTO layers (id, image_id, layer_name, creation_date, last_modified, user_id, layer_type, visibility, opacity, layer_data, layer_hash, layer_order, blend_mode, group_id, mask_data, mask_hash, mask_enabled, mask_inverted, is_clipped, layer_params) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, NULL, $10, $11, $12, $13, NULL, $14, $15, $16, $17, $18) ON CONFLICT (id) DO UPDATE SET layer_name = EXCLUDED.layer_name, last_modified = EXCLUDED.last_modified, layer_type = EXCLUDED.layer_type, visibility = EXCLUDED.visibility, opacity = EXCLUDED.opacity, layer_data = NULL, layer_hash = EXCLUDED.layer_hash, layer_order = EXCLUDED.layer_order, blend_mode = EXCLUDED.blend_mode, group_id = EXCLUDED.group_id, mask_data = NULL, mask_hash = EXCLUDED.mask_hash, mask_enabled = EXCLUDED.mask_enabled, mask_inverted = EXCLUDED.mask_inverted, is_clipped = EXCLUDED.is_clipped, layer_params = EXCLUDED.layer_params")
+        .await?;
+    let delete_layer_statement = transaction
+        .prepare("DELETE FROM layers WHERE id = $1 AND image_id = $2")
+        .await?;
+    for (id, layer) in &change.layers {
+        let current = transaction.query_opt(&current_statement, &[id, &image_id]).await?;
+        let current_hash = |column: &str| current.as_ref().and_then(|row| row.get::<_, Option<String>>(column));
+        let (old_layer_hash, old_mask_hash) = (current_hash("layer_hash"), current_hash("mask_hash"));
+
+        let layer = match layer {
+            Some(layer) => layer,
+            None => {
+                transaction.execute(&delete_layer_statement, &[id, &image_id]).await?;
+                swap_blobs(transaction, old_layer_hash, None).await?;
+                swap_blobs(transaction, old_mask_hash, None).await?;
+                continue;
+            }
+        };
+
+        transaction
+            .execute(
+                &layer_statement,
+                &[id, &image_id, &layer.layer_name, &layer.creation_date, &layer.last_modified,
+                  &layer.user_id, &layer.layer_type, &layer.visibility, &layer.opacity, &layer.layer_data,
+                  &layer.layer_order, &layer.blend_mode, &layer.group_id, &layer.mask_data,
+                  &layer.mask_enabled, &layer.mask_inverted, &layer.is_clipped, &layer.layer_params],
+            )
+            .await?;
+        swap_blobs(transaction, old_layer_hash, layer.layer_data.as_ref()).await?;
+        swap_blobs(transaction, old_mask_hash, layer.mask_data.as_ref()).await?;
+    }
+
+    let delete_group_statement = transaction
+        .prepare("DELETE FROM layer_groups WHERE id = $1 AND image_id = $2")
+        .await?;
+    for (id, _) in change.groups.iter().filter(|(_, group)| group.is_none()) {
+        transaction.execute(&delete_group_statement, &[id, &image_id]).await?;
+    }
+    Ok(())
+}
+
+// Move a reference from the blob a row had to the one it has now
+async fn swap_blobs(transaction: &Transaction<'_>, old: Option<String>, new: Option<&String>) -> Result<(), MyDbError> {
+    if let Some(new) = new {
+        blobs::retain_blob(transaction, new).await?;
+    }
+    if let Some(old) = old {
+        blobs::release_blob(transaction, &old).await?;
+    }
+    Ok(())
+}
+
+// Keep only what differs between two states, once as it was and once as it is
+fn diff_states(before: &ImageState, after: &ImageState) -> (StateChange, StateChange) {
+    let mut before_change = StateChange::default();
+    let mut after_change = StateChange::default();
+
+    if before.file_path != after.file_path || before.file_hash != after.file_hash {
+        before_change.file_path = Some(before.file_path.clone());
+        before_change.file_hash = before.file_hash.clone();
+        before_change.file_info = before.file_info.clone();
+        after_change.file_path = Some(after.file_path.clone());
+        after_change.file_hash = after.file_hash.clone();
+        after_change.file_info = after.file_info.clone();
+    }
+    for id in before.layers.keys().chain(after.layers.keys()) {
+        let (old, new) = (before.layers.get(id), after.layers.get(id));
+        if old != new {
+            before_change.layers.insert(*id, old.cloned());
+            after_change.layers.insert(*id, new.cloned());
+        }
+    }
+    for id in before.groups.keys().chain(after.groups.keys()) {
+        let (old, new) = (before.groups.get(id), after.groups.get(id));
+        if old != new {
+            before_change.groups.insert(*id, old.cloned());
+            after_change.groups.insert(*id, new.cloned());
+        }
+    }
+    (before_change, after_change)
+}
+
+// The blob hash of a layers row's pixels or mask. Rows written before the blob
+// storage still hold the bytes themselves, those are hashed and kept in blobs so
+// they can be stored when a history entry or snapshot needs them.
+fn row_blob(row: &Row, hash_column: &str, data_column: &str, blobs: &mut HashMap<String, Vec<u8>>) -> Option<String> {
+    if let Some(hash) = row.get::<_, Option<String>>(hash_column) {
+        return Some(hash);
+    }
+    let data: Vec<u8> = row.get::<_, Option<Vec<u8>>>(data_column).filter(|data| !data.is_empty())?;
+    let hash = storage::content_hash(&data);
+    blobs.insert(hash.clone(), data);
+    Some(hash)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** History Representation ********** ////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// A recorded edit, without the stored states.
+#[derive(Debug, Serialize)]
+pub struct HistoryEntry {
+    pub id: i32,
+    pub image_id: i32,
+    pub op_name: String,
+    pub op_params: Option<Value>,
+    pub layer_ids: Vec<i32>,
+    pub undone: bool,
+    pub created_at: NaiveDateTime,
+}
+
+impl HistoryEntry {
+    // Create a new history entry instance from a database row
+    pub fn from_row(row: &Row) -> HistoryEntry {
+        HistoryEntry {
+            id: row.get("id"),
+            image_id: row.get("image_id"),
+            op_name: row.get("op_name"),
+            op_params: row.get("op_params"),
+            layer_ids: row.get("layer_ids"),
+            undone: row.get("undone"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// An image's file, layers and groups at one moment, see capture_state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageState {
+    pub file_path: String,
+    #[serde(default)]
+    pub file_hash: Option<String>,
+    #[serde(default)]
+    pub file_info: Option<FileInfo>,
+    pub layers: BTreeMap<i32, LayerRecord>,
+    pub groups: BTreeMap<i32, GroupRecord>,
+    // Pixels of legacy rows that aren't in the blob storage yet, by hash. Only
+    // filled in by capture_state.
+    #[serde(skip)]
+    pub(super) blobs: HashMap<String, Vec<u8>>,
+}
+
+impl ImageState {
+    // Every blob the state points at, once per reference
+    pub(super) fn blob_hashes(&self) -> impl Iterator<Item = &String> {
+        self.file_hash.iter().chain(self.layers.values().flat_map(LayerRecord::blob_hashes))
+    }
+}
+
+/// The rows an edit touched on one side of it. A None row didn't exist.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateChange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_info: Option<FileInfo>,
+    #[serde(default)]
+    layers: BTreeMap<i32, Option<LayerRecord>>,
+    #[serde(default)]
+    groups: BTreeMap<i32, Option<GroupRecord>>,
+}
+
+impl StateChange {
+    fn is_empty(&self) -> bool {
+        self.file_path.is_none() && self.layers.is_empty() && self.groups.is_empty()
+    }
+
+    // Every blob the change points at, once per reference
+    fn blob_hashes(&self) -> impl Iterator<Item = &String> {
+        self.file_hash.iter().chain(self.layers.values().flatten().flat_map(LayerRecord::blob_hashes))
+    }
+}
+
+/// A layers row with its pixels and mask as blob hashes. Layers without pixels
+/// have no layer_data.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerRecord {
+    pub layer_name: String,
+    pub creation_date: NaiveDateTime,
+    pub last_modified: NaiveDateTime,
+    pub user_id: Option<i32>,
+    pub layer_type: String,
+    pub visibility: bool,
+    pub opacity: f32,
+    pub layer_data: Option<String>,
+    pub layer_order: i32,
+    pub blend_mode: String,
+    pub group_id: Option<i32>,
+    pub mask_data: Option<String>,
+    pub mask_enabled: bool,
+    pub mask_inverted: bool,
+    pub is_clipped: bool,
+    pub layer_params: Option<Value>,
+}
+
+impl LayerRecord {
+    // Create a new record from a layers row and the hashes of its blobs
+    pub fn from_row(row: &Row, layer_data: Option<String>, mask_data: Option<String>) -> LayerRecord {
+        LayerRecord {
+            layer_name: row.get("layer_name"),
+            creation_date: row.get("creation_date"),
+            last_modified: row.get("last_modified"),
+            user_id: row.get("user_id"),
+            layer_type: row.get("layer_type"),
+            visibility: row.get("visibility"),
+            opacity: row.get("opacity"),
+            layer_data,
+            layer_order: row.get("layer_order"),
+            blend_mode: row.get("blend_mode"),
+            group_id: row.get("group_id"),
+            mask_data,
+            mask_enabled: row.get("mask_enabled"),
+            mask_inverted: row.get("mask_inverted"),
+            is_clipped: row.get("is_clipped"),
+            layer_params: row.get("layer_params"),
+        }
+    }
+
+    fn blob_hashes(&self) -> impl Iterator<Item = &String> {
+        self.layer_data.iter().chain(self.mask_data.as_ref())
+    }
+}
+
+/// A layer_groups row.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GroupRecord {
+    pub parent_id: Option<i32>,
+    pub group_name: String,
+    pub creation_date: NaiveDateTime,
+    pub visibility: bool,
+    pub opacity: f32,
+    pub blend_mode: String,
+    pub layer_order: i32,
+}
+
+impl GroupRecord {
+    // Create a new record from a layer_groups row
+    pub fn from_row(row: &Row) -> GroupRecord {
+        GroupRecord {
+            parent_id: row.get("parent_id"),
+            group_name: row.get("group_name"),
+            creation_date: row.get("creation_date"),
+            visibility: row.get("visibility"),
+            opacity: row.get("opacity"),
+            blend_mode: row.get("blend_mode"),
+            layer_order: row.get("layer_order"),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &str, layer_data: Option<&str>) -> LayerRecord {
+        let date = NaiveDateTime::default();
+        LayerRecord {
+            layer_name: name.to_string(),
+            creation_date: date,
+            last_modified: date,
+            user_id: Some(1),
+            layer_type: "raster".to_string(),
+            visibility: true,
+            opacity: 1.0,
+            layer_data: layer_data.map(str::to_string),
+            layer_order: 0,
+            blend_mode: "normal".to_string(),
+            group_id: None,
+            mask_data: None,
+            mask_enabled: true,
+            mask_inverted: false,
+            is_clipped: false,
+            layer_params: None,
+        }
+    }
+
+    fn group(name: &str) -> GroupRecord {
+        GroupRecord {
+            parent_id: None,
+            group_name: name.to_string(),
+            creation_date: NaiveDateTime::default(),
+            visibility: true,
+            opacity: 1.0,
+            blend_mode: "normal".to_string(),
+            layer_order: 0,
+        }
+    }
+
+    fn state(layers: Vec<(i32, LayerRecord)>, groups: Vec<(i32, GroupRecord)>) -> ImageState {
+        ImageState {
+            file_path: "cat.png".to_string(),
+            file_hash: Some("file".to_string()),
+            layers: layers.into_iter().collect(),
+            groups: groups.into_iter().collect(),
+            ..ImageState::default()
+        }
+    }
+
+    #[test]
+    fn identical_states_have_no_diff() {
+        let before = state(vec![(1, layer("a", Some("x")))], vec![(1, group("g"))]);
+        let after = state(vec![(1, layer("a", Some("x")))], vec![(1, group("g"))]);
+        let (old, new) = diff_states(&before, &after);
+        assert!(old.is_empty());
+        assert!(new.is_empty());
+    }
+
+    #[test]
+    fn diff_keeps_only_changed_rows() {
+        let before = state(vec![(1, layer("a", Some("x"))), (2, layer("b", Some("y")))], vec![]);
+        let after = state(vec![(1, layer("a", Some("x"))), (2, layer("b", Some("z")))], vec![]);
+        let (old, new) = diff_states(&before, &after);
+        assert_eq!(old.layers.keys().collect::<Vec<_>>(), vec![&2]);
+        assert_eq!(old.layers[&2].as_ref().unwrap().layer_data.as_deref(), Some("y"));
+        assert_eq!(new.layers[&2].as_ref().unwrap().layer_data.as_deref(), Some("z"));
+        // The file didn't change
+        assert!(old.file_path.is_none() && new.file_path.is_none());
+    }
+
+    #[test]
+    fn added_and_deleted_rows_are_none_on_the_other_side() {
+        let before = state(vec![(1, layer("a", None))], vec![(4, group("old"))]);
+        let after = state(vec![(2, layer("b", None))], vec![(5, group("new"))]);
+        let (old, new) = diff_states(&before, &after);
+        assert!(old.layers[&1].is_some() && new.layers[&1].is_none());
+        assert!(old.layers[&2].is_none() && new.layers[&2].is_some());
+        assert!(old.groups[&4].is_some() && new.groups[&4].is_none());
+        assert!(old.groups[&5].is_none() && new.groups[&5].is_some());
+    }
+
+    #[test]
+    fn file_changes_are_recorded_on_both_sides() {
+        let before = state(vec![], vec![]);
+        let mut after = state(vec![], vec![]);
+        after.file_hash = Some("resized".to_string());
+        let (old, new) = diff_states(&before, &after);
+        assert_eq!(old.file_path.as_deref(), Some("cat.png"));
+        assert_eq!(old.file_hash.as_deref(), Some("file"));
+        assert_eq!(new.file_hash.as_deref(), Some("resized"));
+    }
+
+    #[test]
+    fn blob_hashes_count_every_reference() {
+        let mut shared = layer("a", Some("x"));
+        shared.mask_data = Some("m".to_string());
+        let before = state(vec![(1, shared), (2, layer("b", Some("x")))], vec![]);
+        let mut hashes: Vec<&String> = before.blob_hashes().collect();
+        hashes.sort();
+        assert_eq!(hashes, vec!["file", "m", "x", "x"]);
+
+        let (old, _) = diff_states(&before, &state(vec![], vec![]));
+        assert_eq!(old.blob_hashes().count(), 3);
+    }
+
+    #[test]
+    fn state_changes_survive_the_json_round_trip() {
+        let before = state(vec![(1, layer("a", Some("x")))], vec![]);
+        let after = state(vec![(1, layer("renamed", Some("x")))], vec![(3, group("g"))]);
+        let (old, new) = diff_states(&before, &after);
+
+        for change in [old, new] {
+            let value = serde_json::to_value(&change).unwrap();
+            let parsed: StateChange = serde_json::from_value(value).unwrap();
+            assert_eq!(parsed.layers, change.layers);
+            assert_eq!(parsed.groups, change.groups);
+            assert_eq!(parsed.file_path, change.file_path);
+        }
+        // Entries written before groups were recorded still load
+        let parsed: StateChange = serde_json::from_value(serde_json::json!({ "layers": {} })).unwrap();
+        assert!(parsed.is_empty());
+    }
+}
@@ -1,7 +1,12 @@
 #![allow(dead_code)]
+use super::blobs;
+use super::history::{self, ImageState};
 use super::MyDbError;
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
+use chrono::NaiveDateTime;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Row;
 // use chrono::{DateTime, Duration, Utc};
 // use deadpool_postgres::{Config, Pool};
 // use postgres::types::ToSql;
@@ -15,21 +20,27 @@ use serde::{Deserialize, Serialize};
 //////////////////////////////////////////////////////////////////////////////////
 
 // add_image: add new image to database //////////////////////////////////////////
-pub async fn add_image(pool: &Pool, file_path: &str, user_id: i32, file_type: &str ) -> Result<i32, MyDbError> {
-    let client = pool.get().await?;
-    let statement = client
+// The file goes to the blob storage, file_path keeps the uploaded file's name.
+pub async fn add_image(pool: &Pool, file_path: &str, user_id: i32, file_info: &FileInfo, data: Vec<u8> ) -> Result<i32, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let byte_size = data.len() as i64;
+    let file_hash = blobs::put_blob(&transaction, data).await?;
+    let statement = transaction
         .prepare(
-            "INSERT INTO images (user_id, file_type, file_path, created_at, updated_at) VALUES ( $1, $2, $3, NOW(), NOW() ) RETURNING id"
+            "INSERT INTO images (user_id, file_type, file_path, file_hash, width, height, byte_size, created_at, updated_at) VALUES ( $1, $2, $3, $4, $5, $6, $7, NOW(), NOW() ) RETURNING id"
         )
         .await
         .map_err( MyDbError::PostgresError )?;
 
-    match client.query_one(&statement, &[&user_id, &file_type, &file_path]).await {
+    let params: [&(dyn ToSql + Sync); 7] = [&user_id, &file_info.file_type, &file_path, &file_hash, &file_info.width, &file_info.height, &byte_size];
+    match transaction.query_one(&statement, &params).await {
 
         Ok(row) => {
             let image_id: i32 = row.get(0);
             println!("Image ID: {}", image_id); 
             assert!( image_id > 0); 
+            transaction.commit().await?;
             Ok(image_id)
         },
         Err(e) => {
@@ -48,14 +59,7 @@ pub async fn get_all_images(pool: &Pool, user_id: i32) -> Result<Vec< Image >, M
 
     let mut images = Vec::new();
     for row in rows {
-        let image = Image {
-            id: row.get("id"),
-            session_id: row.get("session_id"),
-            file_path: row.get("file_path"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
-        };
-        images.push(image); 
+        images.push(Image::from_row(&row)); 
     }
 
     if images.is_empty() {
@@ -69,26 +73,31 @@ pub async fn get_all_images(pool: &Pool, user_id: i32) -> Result<Vec< Image >, M
 pub async fn get_single_image(pool: &Pool, image_id: i32) -> Result<Image, MyDbError> {
 
     let client = pool.get().await?;
-    let statement = client.prepare("SELECT * FROM images WHERE image_id = $1").await?;
+    let statement = client.prepare("SELECT * FROM images WHERE id = $1").await?;
     let rows = client.query(&statement, &[&image_id ]).await?;
     if let Some(row) = rows.into_iter().next() {
-        Ok(Image {
-            id: row.get("id"),
-            session_id: row.get("session_id"),
-            file_path: row.get("file_path"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
-        })
+        Ok(Image::from_row(&row))
     } else {
         Err(MyDbError::NotFound)
     }
 }
 
+// get_image_owner: the ID of the user an image belongs to ////////////////////////
+// None when the image has no owner, user_id is nullable.
+pub async fn get_image_owner(pool: &Pool, image_id: i32) -> Result<Option<i32>, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client.prepare("SELECT user_id FROM images WHERE id = $1").await?;
+    match client.query_opt(&statement, &[&image_id]).await? {
+        Some(row) => Ok(row.get("user_id")),
+        None => Err(MyDbError::NotFound),
+    }
+}
+
 // udpate_image: update image data/details ///////////////////////////////////////
 pub async fn update_image(pool: &Pool, id: i32, new_file_path: &str) -> Result<(), MyDbError> {
     let client = pool.get().await?;
     let statement = client
-        .prepare("UPDATE images set file_path = $1 WHERE id = $2")
+        .prepare("UPDATE images set file_path = $1, updated_at = NOW() WHERE id = $2")
         .await?;
     let result = client.execute(&statement, &[&new_file_path, &id]).await?;
 
@@ -100,17 +109,97 @@ pub async fn update_image(pool: &Pool, id: i32, new_file_path: &str) -> Result<(
     }
 }
 
+// update_image_file: replace the file of an image, returns the new file hash ////
+pub async fn update_image_file(pool: &Pool, id: i32, file_info: &FileInfo, data: Vec<u8>) -> Result<String, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare("SELECT file_hash FROM images WHERE id = $1 FOR UPDATE")
+        .await?;
+    let old_hash: Option<String> = match transaction.query_opt(&statement, &[&id]).await? {
+        Some(row) => row.get("file_hash"),
+        None => return Err(MyDbError::NotFound),
+    };
+
+    let byte_size = data.len() as i64;
+    let file_hash = blobs::put_blob(&transaction, data).await?;
+    let statement = transaction
+        .prepare("UPDATE images set file_hash = $1, file_type = $2, width = $3, height = $4, byte_size = $5, updated_at = NOW() WHERE id = $6")
+        .await?;
+    transaction
+        .execute(&statement, &[&file_hash, &file_info.file_type, &file_info.width, &file_info.height, &byte_size, &id])
+        .await?;
+    if let Some(old_hash) = old_hash {
+        blobs::release_blob(&transaction, &old_hash).await?;
+    }
+    transaction.commit().await?;
+    Ok(file_hash)
+}
+
+// get_image_data: the content of an image's file ////////////////////////////////
+// Images from before the blob storage are read from their file_path on disk.
+pub async fn get_image_data(image: &Image) -> Result<Vec<u8>, MyDbError> {
+    match &image.file_hash {
+        Some(file_hash) => blobs::get_blob(file_hash).await,
+        None => match tokio::fs::read(&image.file_path).await {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(MyDbError::NotFound),
+            Err(e) => Err(MyDbError::StorageError(e.to_string())),
+        },
+    }
+}
+
 // delete_image: delete image from database //////////////////////////////////////
+// Its layers, groups, history, snapshots and thumbnails go with it, and every
+// blob they used is released.
 pub async fn delete_image(pool: &Pool, id: i32) -> Result<(), MyDbError> {
-    let client = pool.get().await?;
-    let statement = client.prepare("DELETE FROM images WHERE id = $1").await?;
-    let result = client.execute(&statement, &[&id]).await?;
-    if result == 0 {
-        // No rows were deleted, i.e., the image was not found
-        Err(MyDbError::NotFound)
-    } else {
-        Ok(())
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    // Fails with NotFound if the image doesn't exist
+    history::lock_image(&transaction, id).await?;
+
+    let mut released: Vec<String> = Vec::new();
+    let statement = transaction
+        .prepare("DELETE FROM layers WHERE image_id = $1 RETURNING layer_hash, mask_hash")
+        .await?;
+    for row in transaction.query(&statement, &[&id]).await? {
+        released.extend(row.get::<_, Option<String>>("layer_hash"));
+        released.extend(row.get::<_, Option<String>>("mask_hash"));
     }
+    let statement = transaction
+        .prepare("SELECT state FROM image_snapshots WHERE image_id = $1")
+        .await?;
+    for row in transaction.query(&statement, &[&id]).await? {
+        let state: ImageState = serde_json::from_value(row.get("state"))?;
+        released.extend(state.blob_hashes().cloned());
+    }
+    let statement = transaction
+        .prepare("SELECT before_state, after_state FROM image_history WHERE image_id = $1")
+        .await?;
+    let entries = transaction.query(&statement, &[&id]).await?;
+    history::release_entries(&transaction, &entries).await?;
+
+    let statement = transaction
+        .prepare("DELETE FROM image_thumbnails WHERE image_id = $1 RETURNING blob_hash")
+        .await?;
+    for row in transaction.query(&statement, &[&id]).await? {
+        released.push(row.get("blob_hash"));
+    }
+
+    let statement = transaction.prepare("DELETE FROM layer_groups WHERE image_id = $1").await?;
+    transaction.execute(&statement, &[&id]).await?;
+    // History and snapshots are removed by ON DELETE CASCADE
+    let statement = transaction
+        .prepare("DELETE FROM images WHERE id = $1 RETURNING file_hash")
+        .await?;
+    let row = transaction.query_one(&statement, &[&id]).await?;
+    released.extend(row.get::<_, Option<String>>("file_hash"));
+
+    for hash in &released {
+        blobs::release_blob(&transaction, hash).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
 }
 
 //////////////////////////////////////////////////////////////////////////////////
@@ -119,9 +208,51 @@ pub async fn delete_image(pool: &Pool, id: i32) -> Result<(), MyDbError> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Image {
     pub id: i32,
-    pub session_id: i32,
+    pub user_id: Option<i32>,
+    pub file_type: String,
     pub file_path: String,
-    pub created_at: String,
-    pub updated_at: String,
+    pub file_hash: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub byte_size: Option<i64>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
     // Add other fields TODO:
-}
\ No newline at end of file
+}
+
+impl Image {
+    // Create a new image instance from a database row
+    pub fn from_row(row: &Row) -> Image {
+        Image {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            file_type: row.get("file_type"),
+            file_path: row.get("file_path"),
+            file_hash: row.get("file_hash"),
+            width: row.get("width"),
+            height: row.get("height"),
+            byte_size: row.get("byte_size"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+/// What an image file is, as found by decoding it rather than from the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub file_type: String, // MIME type, e.g. image/png
+    pub width: i32,
+    pub height: i32,
+}
+
+impl FileInfo {
+    // Read the file columns of an images row, None for rows without dimensions
+    pub fn from_row(row: &Row) -> Option<FileInfo> {
+        Some(FileInfo {
+            file_type: row.get("file_type"),
+            width: row.get::<_, Option<i32>>("width")?,
+            height: row.get::<_, Option<i32>>("height")?,
+        })
+    }
+}
@@ -1,8 +1,14 @@
 #![allow(dead_code)]
+use super::blobs;
 use super::MyDbError;
-use deadpool_postgres::Pool;
+use crate::image_processing::composite::{self, BlendMode};
+use crate::image_processing::{self, ProcessingError};
+use chrono::NaiveDateTime;
+use deadpool_postgres::{Pool, Transaction};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::str::FromStr;
 use tokio_postgres::Row;
 // use serde_json::json;
 // use tokio_postgres::{Error, NoTls, Row};
@@ -15,27 +21,40 @@ use tokio_postgres::Row;
 //////////// ********** Layer Management Functions ********** ////////////////////
 //////////////////////////////////////////////////////////////////////////////////
 
-// add_layer: add new layer to an image //////////////////////////////////////////
+// Next free layer_order on an image, i.e. the top of the stack, for image $1
+const NEXT_LAYER_ORDER: &str = "SELECT COALESCE(MAX(layer_order), -1) + 1 FROM (SELECT layer_order FROM layers WHERE image_id = $1 UNION ALL SELECT layer_order FROM layer_groups WHERE image_id = $1) AS orders";
+
+// add_layer: add new layer to an image, returns the new layer ID ////////////////
+// Raster layers bring their pixels in layer_data, the other types are described
+// by layer_params. Without a layer_order the layer goes on top of the stack.
 pub async fn add_layer(
     pool: &Pool,
     image_id: i32,
     layer_name: &str,
-    layer_type: &str,
+    layer_type: LayerType,
     layer_data: &[u8],
-    layer_order: i32,
-) -> Result<(), MyDbError> {
-    let client = pool.get().await?;
-    let statement = client
-        .prepare( "INSERT INTO layers (image_id, layer_name, layer_type, layer_data, layer_order ) VALUES ($1, $2, $3, $4, $5)")
+    layer_params: Option<&Value>,
+    layer_order: Option<i32>,
+) -> Result<i32, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let layer_hash = put_layer_blob(&transaction, layer_data).await?;
+
+    let statement = transaction
+        .prepare(&format!(
+            "INSERT INTO layers (image_id, layer_name, layer_type, layer_hash, layer_params, layer_order ) VALUES ($1, $2, $3, $4, $5, COALESCE($6, ({}))) RETURNING id",
+            NEXT_LAYER_ORDER
+        ))
         .await?;
 
-    client
-        .execute(
+    let row = transaction
+        .query_one(
             &statement,
-            &[&image_id, &layer_name, &layer_type, &layer_data, &layer_order],
+            &[&image_id, &layer_name, &layer_type.as_str(), &layer_hash, &layer_params, &layer_order],
         )
         .await?;
-    Ok(())
+    transaction.commit().await?;
+    Ok(row.get(0))
 }
 
 // get a single layer by layer id ////////////////////////////////////////////////
@@ -44,26 +63,54 @@ pub async fn get_layer_by_layer_id(pool: &Pool, id: i32) -> Result<Layer, MyDbEr
     let statement = client.prepare("SELECT * FROM layers WHERE id = $1").await?;
     let rows = client.query(&statement, &[&id]).await?;
     if let Some(row) = rows.into_iter().next() {
-        Ok(Layer {
-            id: row.get("id"),
-            image_id: row.get("image_id"),
-            layer_name: row.get("layer_name"),
-            creation_date: row.get("creation_date"),
-            last_modified: row.get("last_modified"),
-            user_id: row.get("user_id"),
-            layer_type: row.get("layer_type"),
-            visibility: row.get("visibility"),
-            opacity: row.get("opacity"),
-            layer_data: row.get("layer_data"),
-            layer_order: row.get("layer_order"),
-        })
+        let mut layer = Layer::from_row(&row);
+        load_layer_blobs(std::slice::from_mut(&mut layer)).await?;
+        Ok(layer)
     } else {
         Err(MyDbError::NotFound)
     }
 }
 
 // get_layers_by_image_id: Retrieve ALL layers for a specific image //////////////
-pub async fn get_layers_by_image_id(pool: &Pool, image_id: i32) -> Result<Vec<Layer>, MyDbError> {
+// Returns the layer tree: layers and groups at the top level, bottom first, with
+// each group holding its own children in the same order.
+pub async fn get_layers_by_image_id(
+    pool: &Pool,
+    image_id: i32,
+) -> Result<Vec<LayerNode>, MyDbError> {
+    let layers = get_layer_list_by_image_id(pool, image_id).await;
+    let layers = match layers {
+        Ok(layers) => layers,
+        Err(MyDbError::NotFound) => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("SELECT * FROM layer_groups WHERE image_id = $1 ORDER BY layer_order")
+        .await?;
+    let rows = client.query(&statement, &[&image_id]).await?;
+    let groups: Vec<LayerFolder> = rows.iter().map(LayerFolder::from_row).collect();
+
+    if layers.is_empty() && groups.is_empty() {
+        Err(MyDbError::NotFound)
+    } else {
+        Ok(build_layer_tree(None, &groups, layers))
+    }
+}
+
+// count_layers: how many layers an image has //////////////////////////////////
+pub async fn count_layers(pool: &Pool, image_id: i32) -> Result<i64, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client.prepare("SELECT COUNT(*) FROM layers WHERE image_id = $1").await?;
+    Ok(client.query_one(&statement, &[&image_id]).await?.get(0))
+}
+
+// get_layer_list_by_image_id: all layers of an image as a flat list /////////////
+pub async fn get_layer_list_by_image_id(
+    pool: &Pool,
+    image_id: i32,
+) -> Result<Vec<Layer>, MyDbError> {
     let client = pool.get().await?;
     let statement = client
         .prepare("SELECT * FROM layers WHERE image_id = $1 ORDER BY \"layer_order\"")
@@ -73,27 +120,64 @@ pub async fn get_layers_by_image_id(pool: &Pool, image_id: i32) -> Result<Vec<La
     // Sort the layers based on the order field
     let mut layers = Vec::new();
     for row in rows {
-        layers.push(Layer {
-            id: row.get("id"),
-            image_id: row.get("image_id"),
-            layer_name: row.get("layer_name"),
-            creation_date: row.get("creation_date"),
-            last_modified: row.get("last_modified"),
-            user_id: row.get("user_id"),
-            layer_type: row.get("layer_type"),
-            visibility: row.get("visibility"),
-            opacity: row.get("opacity"),
-            layer_data: row.get("layer_data"),
-            layer_order: row.get("layer_order"),
-        });
+        layers.push(Layer::from_row(&row));
     }
     if layers.is_empty() {
         Err(MyDbError::NotFound)
     } else {
+        load_layer_blobs(&mut layers).await?;
         Ok(layers)
     }
 }
 
+// Collect the children of a group (or the top level) and recurse into groups ///
+// Only groups reachable from the top level end up in the tree.
+fn build_layer_tree(
+    parent_id: Option<i32>,
+    groups: &[LayerFolder],
+    layers: Vec<Layer>,
+) -> Vec<LayerNode> {
+    let (children, mut rest): (Vec<Layer>, Vec<Layer>) =
+        layers.into_iter().partition(|layer| layer.group_id == parent_id);
+    let mut nodes: Vec<LayerNode> = children.into_iter().map(LayerNode::Layer).collect();
+
+    for group in groups.iter().filter(|group| group.parent_id == parent_id) {
+        let (inside, outside): (Vec<Layer>, Vec<Layer>) = rest
+            .into_iter()
+            .partition(|layer| layer_in_group(layer.group_id, group.id, groups));
+        rest = outside;
+        nodes.push(LayerNode::Group {
+            children: build_layer_tree(Some(group.id), groups, inside),
+            group: group.clone(),
+        });
+    }
+
+    nodes.sort_by_key(|node| match node {
+        LayerNode::Layer(layer) => layer.layer_order,
+        LayerNode::Group { group, .. } => group.layer_order,
+    });
+    nodes
+}
+
+// Is a layer somewhere below the given group, directly or in a nested group? ///
+fn layer_in_group(layer_group_id: Option<i32>, group_id: i32, groups: &[LayerFolder]) -> bool {
+    let mut current = layer_group_id;
+    // Bounded by the number of groups in case the parents ever form a loop
+    for _ in 0..=groups.len() {
+        match current {
+            Some(id) if id == group_id => return true,
+            Some(id) => {
+                current = groups
+                    .iter()
+                    .find(|group| group.id == id)
+                    .and_then(|group| group.parent_id)
+            }
+            None => return false,
+        }
+    }
+    false
+}
+
 // update_layer_order: update layer order ////////////////////////////////////////
 pub async fn update_layer_order(
     pool: &Pool,
@@ -101,7 +185,7 @@ pub async fn update_layer_order(
     layer_id: i32,
     new_order: i32,
 ) -> Result<(), MyDbError> {
-    let layers = get_layers_by_image_id(pool, image_id).await?;
+    let layers = get_layer_list_by_image_id(pool, image_id).await?;
     let mut layer_map = HashMap::new();
 
     // Create a map from layer id to layer data
@@ -180,20 +264,18 @@ pub async fn update_layer(
     id: i32,
     new_layer_name: &str,
     new_layer_type: &str,
-    new_layer_data: &[u8],
-    new_layer_order: i32)
+    new_layer_data: &[u8])
 -> Result<(), MyDbError> {
+    set_layer_blobs(pool, id, Some(new_layer_data), None).await?;
+
     let client = pool.get().await?;
     let statement = client
         .prepare(
-            "UPDATE layers set layer_name = $1, layer_type = $2, layer_data = $3 WHERE id = $4",
+            "UPDATE layers set layer_name = $1, layer_type = $2 WHERE id = $3",
         )
         .await?;
     let result = client
-        .execute(
-            &statement,
-            &[&id, &new_layer_name, &new_layer_type, &new_layer_data, &new_layer_data],
-        )
+        .execute(&statement, &[&new_layer_name, &new_layer_type, &id])
         .await?;
 
     if result == 0 {
@@ -204,19 +286,99 @@ pub async fn update_layer(
     }
 }
 
-// delete_layer: delete layer from database/image ////////////////////////////////
-pub async fn delete_layer(pool: &Pool, id: i32) -> Result<(), MyDbError> {
+// update_layer_data: replace the pixels of a layer //////////////////////////////
+pub async fn update_layer_data(pool: &Pool, id: i32, new_layer_data: &[u8]) -> Result<(), MyDbError> {
+    set_layer_blobs(pool, id, Some(new_layer_data), None).await
+}
+
+// update_layer_params: replace the settings of an adjustment, fill or text layer
+pub async fn update_layer_params(pool: &Pool, id: i32, new_layer_params: &Value) -> Result<(), MyDbError> {
     let client = pool.get().await?;
-    let statement = client.prepare("DELETE FROM layers WHERE id = $1").await?;
-    let result = client.execute(&statement, &[&id]).await?;
+    let statement = client
+        .prepare("UPDATE layers SET layer_params = $1, last_modified = NOW() WHERE id = $2")
+        .await?;
+    let result = client.execute(&statement, &[&new_layer_params, &id]).await?;
+
     if result == 0 {
-        // No rows were deleted, i.e., the layer was not found
+        // No rows were updated, i.e., the layer was not found
         Err(MyDbError::NotFound)
     } else {
         Ok(())
     }
 }
 
+// update_layer_mask: replace or remove (None) the mask of a layer ///////////////
+pub async fn update_layer_mask(
+    pool: &Pool,
+    id: i32,
+    new_mask_data: Option<&[u8]>,
+) -> Result<(), MyDbError> {
+    set_layer_blobs(pool, id, None, Some(new_mask_data)).await
+}
+
+// update_layer_mask_settings: enable/disable and invert a layer mask ////////////
+pub async fn update_layer_mask_settings(
+    pool: &Pool,
+    id: i32,
+    mask_enabled: bool,
+    mask_inverted: bool,
+) -> Result<(), MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("UPDATE layers SET mask_enabled = $1, mask_inverted = $2, last_modified = NOW() WHERE id = $3")
+        .await?;
+    let result = client.execute(&statement, &[&mask_enabled, &mask_inverted, &id]).await?;
+
+    if result == 0 {
+        // No rows were updated, i.e., the layer was not found
+        Err(MyDbError::NotFound)
+    } else {
+        Ok(())
+    }
+}
+
+// apply_layer_mask: store the masked pixels and drop the mask in one go /////////
+pub async fn apply_layer_mask(pool: &Pool, id: i32, new_layer_data: &[u8]) -> Result<(), MyDbError> {
+    set_layer_blobs(pool, id, Some(new_layer_data), Some(None)).await
+}
+
+// update_layer_clipping: clip a layer to the layer below it, or release it //////
+pub async fn update_layer_clipping(pool: &Pool, id: i32, is_clipped: bool) -> Result<(), MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("UPDATE layers SET is_clipped = $1, last_modified = NOW() WHERE id = $2")
+        .await?;
+    let result = client.execute(&statement, &[&is_clipped, &id]).await?;
+
+    if result == 0 {
+        // No rows were updated, i.e., the layer was not found
+        Err(MyDbError::NotFound)
+    } else {
+        Ok(())
+    }
+}
+
+// delete_layer: delete layer from database/image ////////////////////////////////
+pub async fn delete_layer(pool: &Pool, id: i32) -> Result<(), MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare("DELETE FROM layers WHERE id = $1 RETURNING layer_hash, mask_hash")
+        .await?;
+    let row = match transaction.query_opt(&statement, &[&id]).await? {
+        Some(row) => row,
+        // No rows were deleted, i.e., the layer was not found
+        None => return Err(MyDbError::NotFound),
+    };
+
+    let released = row_blob_hashes(&row);
+    for hash in &released {
+        blobs::release_blob(&transaction, hash).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
 // update_toggle_layer_visibility: toggle layer visibility ///////////////////////
 pub async fn update_toggle_layer_visibility(
     pool: &Pool,
@@ -236,30 +398,42 @@ pub async fn update_toggle_layer_visibility(
     }
 }
 
+// update_layer_blend_mode: change how a layer mixes with the layers below ///////
+pub async fn update_layer_blend_mode(
+    pool: &Pool,
+    layer_id: i32,
+    blend_mode: BlendMode,
+) -> Result<(), MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("UPDATE layers SET blend_mode = $1, last_modified = NOW() WHERE id = $2")
+        .await?;
+    let result = client.execute(&statement, &[&blend_mode.as_str(), &layer_id]).await?;
+    if result == 0 {
+        // No rows were updated, i.e., the layer was not found
+        Err(MyDbError::NotFound)
+    } else {
+        Ok(())
+    }
+}
+
 // duplicate_layer: duplicate a layer, returns new layer ID //////////////////////
 pub async fn duplicate_layer(pool: &Pool, layer_id: i32) -> Result<i32, MyDbError> {
-    let client = pool.get().await?;
-    let statement = client.prepare("SELECT * FROM layers WHERE id = $1").await?;
-    let rows = client.query(&statement, &[&layer_id]).await?;
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction.prepare("SELECT * FROM layers WHERE id = $1").await?;
+    let rows = transaction.query(&statement, &[&layer_id]).await?;
 
     if let Some(row) = rows.into_iter().next() {
-        let layer = Layer {
-            id: row.get("id"),
-            image_id: row.get("image_id"),
-            layer_name: row.get("layer_name"),
-            creation_date: row.get("creation_date"),
-            last_modified: row.get("last_modified"),
-            user_id: row.get("user_id"),
-            layer_type: row.get("layer_type"),
-            visibility: row.get("visibility"),
-            opacity: row.get("opacity"),
-            layer_data: row.get("layer_data"),
-            layer_order: row.get("layer_order"),
-        };
+        let layer = Layer::from_row(&row);
+        // The copy shares the original's blobs
+        for hash in row_blob_hashes(&row) {
+            blobs::retain_blob(&transaction, &hash).await?;
+        }
 
-        let statement = client.prepare( "INSERT INTO layers (image_id, layer_name, creation_date, last_modified, user_id, layer_type, visibility, opacity, layer_data, layer_order) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)").await?;
-        let result = client
-            .execute(
+        let statement = transaction.prepare( "INSERT INTO layers (image_id, layer_name, creation_date, last_modified, user_id, layer_type, visibility, opacity, layer_data, layer_hash, layer_order, blend_mode, group_id, mask_data, mask_hash, mask_enabled, mask_inverted, is_clipped, layer_params) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19) RETURNING id").await?;
+        let new_layer_id: i32 = transaction
+            .query_one(
                 &statement,
                 &[
                     &layer.image_id,
@@ -267,81 +441,371 @@ pub async fn duplicate_layer(pool: &Pool, layer_id: i32) -> Result<i32, MyDbErro
                     &layer.creation_date,
                     &layer.last_modified,
                     &layer.user_id,
-                    &layer.layer_type,
+                    &layer.layer_type.as_str(),
                     &layer.visibility,
                     &layer.opacity,
-                    &layer.layer_data,
+                    &row.get::<_, Option<Vec<u8>>>("layer_data"),
+                    &layer.layer_hash,
                     &layer.layer_order,
+                    &layer.blend_mode.as_str(),
+                    &layer.group_id,
+                    &row.get::<_, Option<Vec<u8>>>("mask_data"),
+                    &layer.mask_hash,
+                    &layer.mask_enabled,
+                    &layer.mask_inverted,
+                    &layer.is_clipped,
+                    &layer.layer_params,
                 ],
             )
-            .await?;
+            .await?
+            .get(0);
 
-        if result == 0 {
-            // No rows were inserted, i.e., the layer was not duplicated
-            Err(MyDbError::NotFound)
-        } else {
-            Ok(layer.id)
-        }
+        transaction.commit().await?;
+        Ok(new_layer_id)
     } else {
         Err(MyDbError::NotFound)
     }
 }
 
-// TODO:
-// Returns new merged layer ID
-// pub async fn merge_layers(pool: &Pool, layer_ids: Vec<i32>) -> Result<LayerGroup, MyDbError> {
-//     let client = pool.get().await?;
-
-//     // Fetch all layers in one query
-//     let fetch_statement = client
-//         .prepare("SELECT * FROM layers WHERE id = ANY($1)")
-//         .await?;
-//     let rows = client.query(&fetch_statement, &[&layer_ids]).await?;
-
-//     // Process rows into layers and merge their data
-//     let mut merged_layer_data: Vec<u8> = Vec::new();
-//     for row in rows {
-//         let layer_data: Vec<u8> = row.get("layer_data");
-//         // Actual merging logic here (currently just appending)
-//         merged_layer_data.extend(layer_data);
-//     }
-
-//     // Insert the merged layer into the database
-//     let insert_statement = client
-//         .prepare("INSERT INTO layers (image_id, layer_name, layer_type, visibility, opacity, layer_data) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id")
-//         .await?;
-
-//     // Assuming you've determined the appropriate values for these parameters
-//     let image_id = /* determine image_id */;
-//     let layer_name = "Merged Layer";
-//     let layer_type = /* determine layer_type */;
-//     let visibility = true;
-//     let opacity = 1.0;
-
-//     let merged_layer_id: i32 = client
-//         .query_one(&insert_statement, &[&image_id, &layer_name, &layer_type, &visibility, &opacity, &merged_layer_data])
-//         .await?
-//         .get(0);
-
-//     // Create and return a new LayerGroup
-//     let layer_group = LayerGroup {
-//         group_id: merged_layer_id, // Assuming group_id is the merged layer's ID
-//         group_name: String::from("Merged Group"),
-//         layer_ids: layer_ids,
-//         total_layers: layer_ids.len() as i32,
-//         creation_date: Utc::now().to_rfc3339(), // Using chrono for current timestamp
-//     };
-
-//     Ok(layer_group)
-// }
+// merge_layers: composite layers into a new one and remove the originals /////////
+// Everything happens in one transaction, so a failed merge leaves the layers as
+// they were. The merged layer takes the place (and group) of the topmost source
+// layer.
+pub async fn merge_layers(
+    pool: &Pool,
+    image_id: i32,
+    layer_ids: &[i32],
+    layer_name: &str,
+) -> Result<LayerGroup, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+
+    // Lock the source layers so nothing edits them while they are merged
+    let fetch_statement = transaction
+        .prepare("SELECT * FROM layers WHERE image_id = $1 AND id = ANY($2) ORDER BY layer_order FOR UPDATE")
+        .await?;
+    let rows = transaction.query(&fetch_statement, &[&image_id, &layer_ids]).await?;
+    let mut layers: Vec<Layer> = rows.iter().map(Layer::from_row).collect();
+    let released: Vec<String> = rows.iter().flat_map(row_blob_hashes).collect();
+
+    let mut requested_ids = layer_ids.to_vec();
+    requested_ids.sort_unstable();
+    requested_ids.dedup();
+    if layers.len() != requested_ids.len() {
+        // Some of the layers don't exist or belong to another image
+        return Err(MyDbError::NotFound);
+    }
+    load_layer_blobs(&mut layers).await?;
+
+    let layer_order = layers.iter().map(|layer| layer.layer_order).max().unwrap_or(0);
+    let user_id = layers.iter().rev().find_map(|layer| layer.user_id);
+    let group_id = layers.last().and_then(|layer| layer.group_id);
+
+    // Decoding and compositing are CPU heavy, keep them off the async workers
+    let merged_layer_data = tokio::task::spawn_blocking(move || {
+        let merged = composite::flatten_layers(layers)?;
+        Ok::<_, ProcessingError>(image_processing::encode_layer_data(&merged)?)
+    })
+    .await
+    .map_err(|e| MyDbError::ImageError(e.to_string()))?
+    .map_err(|e| MyDbError::ImageError(e.to_string()))?;
+
+    let merged_layer_hash = blobs::put_blob(&transaction, merged_layer_data).await?;
+    let insert_statement = transaction
+        .prepare("INSERT INTO layers (image_id, layer_name, user_id, layer_type, layer_hash, layer_order, group_id) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING id, creation_date")
+        .await?;
+    let merged_row = transaction
+        .query_one(
+            &insert_statement,
+            &[
+                &image_id,
+                &layer_name,
+                &user_id,
+                &LayerType::Raster.as_str(),
+                &merged_layer_hash,
+                &layer_order,
+                &group_id,
+            ],
+        )
+        .await?;
+
+    let delete_statement = transaction
+        .prepare("DELETE FROM layers WHERE id = ANY($1)")
+        .await?;
+    transaction.execute(&delete_statement, &[&requested_ids]).await?;
+    for hash in &released {
+        blobs::release_blob(&transaction, hash).await?;
+    }
+    transaction.commit().await?;
+
+    let creation_date: NaiveDateTime = merged_row.get("creation_date");
+    Ok(LayerGroup {
+        group_id: merged_row.get("id"),
+        group_name: layer_name.to_string(),
+        total_layers: requested_ids.len() as i32,
+        layer_ids: requested_ids,
+        creation_date: creation_date.and_utc().to_rfc3339(),
+    })
+}
+
+// Store a layer's pixels as a blob, layers without pixels get no blob
+async fn put_layer_blob(transaction: &Transaction<'_>, layer_data: &[u8]) -> Result<Option<String>, MyDbError> {
+    if layer_data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(blobs::put_blob(transaction, layer_data.to_vec()).await?))
+}
+
+// Point a layer at new pixels and/or a new mask (Some(None) removes the mask).
+// The blobs it pointed at before are released in the same transaction.
+async fn set_layer_blobs(
+    pool: &Pool,
+    id: i32,
+    layer_data: Option<&[u8]>,
+    mask_data: Option<Option<&[u8]>>,
+) -> Result<(), MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare("SELECT layer_hash, mask_hash FROM layers WHERE id = $1 FOR UPDATE")
+        .await?;
+    let row = match transaction.query_opt(&statement, &[&id]).await? {
+        Some(row) => row,
+        None => return Err(MyDbError::NotFound),
+    };
+
+    let mut released = Vec::new();
+    if let Some(layer_data) = layer_data {
+        let layer_hash = put_layer_blob(&transaction, layer_data).await?;
+        let statement = transaction
+            .prepare("UPDATE layers SET layer_hash = $1, layer_data = NULL, last_modified = NOW() WHERE id = $2")
+            .await?;
+        transaction.execute(&statement, &[&layer_hash, &id]).await?;
+        released.extend(row.get::<_, Option<String>>("layer_hash"));
+    }
+    if let Some(mask_data) = mask_data {
+        let mask_hash = match mask_data {
+            Some(mask_data) => Some(blobs::put_blob(&transaction, mask_data.to_vec()).await?),
+            None => None,
+        };
+        let statement = transaction
+            .prepare("UPDATE layers SET mask_hash = $1, mask_data = NULL, last_modified = NOW() WHERE id = $2")
+            .await?;
+        transaction.execute(&statement, &[&mask_hash, &id]).await?;
+        released.extend(row.get::<_, Option<String>>("mask_hash"));
+    }
+
+    for hash in &released {
+        blobs::release_blob(&transaction, hash).await?;
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+// Read the pixels and masks of layers that keep them as blobs
+pub(super) async fn load_layer_blobs(layers: &mut [Layer]) -> Result<(), MyDbError> {
+    let loads = layers.iter_mut().map(|layer| async move {
+        if let Some(hash) = &layer.layer_hash {
+            layer.layer_data = blobs::get_blob(hash).await?;
+        }
+        if let Some(hash) = &layer.mask_hash {
+            layer.mask_data = Some(blobs::get_blob(hash).await?);
+        }
+        Ok::<_, MyDbError>(())
+    });
+    futures::future::try_join_all(loads).await?;
+    Ok(())
+}
+
+// The blobs a layers row points at
+fn row_blob_hashes(row: &Row) -> Vec<String> {
+    let layer_hash: Option<String> = row.get("layer_hash");
+    let mask_hash: Option<String> = row.get("mask_hash");
+    layer_hash.into_iter().chain(mask_hash).collect()
+}
 
 // TODO: pub async fn search_layers(pool: &Pool, search_query: &str) -> Result<Vec<Layer>, MyDbError>;
-// TODO: pub async fn create_layer_group(pool: &Pool, group_name: &str, layer_ids: Vec<i32>) -> Result<i32, MyDbError>; // Returns group ID
+
+// create_layer_group: add a group (folder) and move layers into it //////////////
+// Returns the new group ID. The group sits where the topmost of its layers was,
+// or on top of the stack when it starts out empty.
+pub async fn create_layer_group(
+    pool: &Pool,
+    image_id: i32,
+    group_name: &str,
+    parent_id: Option<i32>,
+    layer_ids: &[i32],
+) -> Result<i32, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+
+    if let Some(parent_id) = parent_id {
+        let parent_statement = transaction
+            .prepare("SELECT id FROM layer_groups WHERE id = $1 AND image_id = $2")
+            .await?;
+        if transaction.query_opt(&parent_statement, &[&parent_id, &image_id]).await?.is_none() {
+            return Err(MyDbError::NotFound);
+        }
+    }
+
+    let mut requested_ids = layer_ids.to_vec();
+    requested_ids.sort_unstable();
+    requested_ids.dedup();
+
+    let layer_order: i32 = if requested_ids.is_empty() {
+        let order_statement = transaction.prepare(NEXT_LAYER_ORDER).await?;
+        transaction.query_one(&order_statement, &[&image_id]).await?.get(0)
+    } else {
+        let fetch_statement = transaction
+            .prepare("SELECT layer_order FROM layers WHERE image_id = $1 AND id = ANY($2) FOR UPDATE")
+            .await?;
+        let rows = transaction.query(&fetch_statement, &[&image_id, &requested_ids]).await?;
+        if rows.len() != requested_ids.len() {
+            // Some of the layers don't exist or belong to another image
+            return Err(MyDbError::NotFound);
+        }
+        rows.iter().map(|row| row.get::<_, i32>(0)).max().unwrap_or(0)
+    };
+
+    let insert_statement = transaction
+        .prepare("INSERT INTO layer_groups (image_id, parent_id, group_name, layer_order) VALUES ($1, $2, $3, $4) RETURNING id")
+        .await?;
+    let group_id: i32 = transaction
+        .query_one(&insert_statement, &[&image_id, &parent_id, &group_name, &layer_order])
+        .await?
+        .get(0);
+
+    let move_statement = transaction
+        .prepare("UPDATE layers SET group_id = $1, last_modified = NOW() WHERE id = ANY($2)")
+        .await?;
+    transaction.execute(&move_statement, &[&group_id, &requested_ids]).await?;
+    transaction.commit().await?;
+
+    Ok(group_id)
+}
+
+// update_layer_group: rename, restyle or move a group ///////////////////////////
+// Only the settings in changes are touched. A group moved to another parent goes
+// on top of its new siblings, it can't be moved into itself or a group inside it.
+// Returns the group as it is now.
+pub async fn update_layer_group(
+    pool: &Pool,
+    image_id: i32,
+    group_id: i32,
+    changes: &LayerGroupChanges,
+) -> Result<LayerFolder, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+
+    let fetch_statement = transaction
+        .prepare("SELECT parent_id FROM layer_groups WHERE id = $1 AND image_id = $2 FOR UPDATE")
+        .await?;
+    let current_parent: Option<i32> = match transaction.query_opt(&fetch_statement, &[&group_id, &image_id]).await? {
+        Some(row) => row.get("parent_id"),
+        None => return Err(MyDbError::NotFound),
+    };
+
+    let new_parent = changes.parent_id.filter(|parent_id| *parent_id != current_parent);
+    let mut layer_order: Option<i32> = None;
+    if let Some(parent_id) = new_parent {
+        // Walk up from the new parent, the group must not be one of its ancestors
+        let parent_statement = transaction
+            .prepare("SELECT parent_id FROM layer_groups WHERE id = $1 AND image_id = $2")
+            .await?;
+        let mut current = parent_id;
+        let mut visited = Vec::new();
+        while let Some(id) = current {
+            if id == group_id {
+                return Err(MyDbError::InvalidChange("a group can't be moved into itself or a group inside it".to_string()));
+            }
+            if visited.contains(&id) {
+                break;
+            }
+            visited.push(id);
+            current = match transaction.query_opt(&parent_statement, &[&id, &image_id]).await? {
+                Some(row) => row.get("parent_id"),
+                None => return Err(MyDbError::NotFound),
+            };
+        }
+        let order_statement = transaction.prepare(NEXT_LAYER_ORDER).await?;
+        layer_order = Some(transaction.query_one(&order_statement, &[&image_id]).await?.get(0));
+    }
+
+    let update_statement = transaction
+        .prepare("UPDATE layer_groups SET group_name = COALESCE($1, group_name), visibility = COALESCE($2, visibility), opacity = COALESCE($3, opacity), blend_mode = COALESCE($4, blend_mode), parent_id = CASE WHEN $5 THEN $6::INTEGER ELSE parent_id END, layer_order = COALESCE($7, layer_order) WHERE id = $8 RETURNING *")
+        .await?;
+    let row = transaction
+        .query_one(
+            &update_statement,
+            &[
+                &changes.group_name,
+                &changes.visibility,
+                &changes.opacity,
+                &changes.blend_mode.map(BlendMode::as_str),
+                &new_parent.is_some(),
+                &new_parent.flatten(),
+                &layer_order,
+                &group_id,
+            ],
+        )
+        .await?;
+    transaction.commit().await?;
+    Ok(LayerFolder::from_row(&row))
+}
+
+// delete_layer_group: remove a group from an image //////////////////////////////
+// Its layers and groups move up to its parent and keep their order, unless
+// delete_contents is set, then they are deleted with it and their blobs released.
+pub async fn delete_layer_group(
+    pool: &Pool,
+    image_id: i32,
+    group_id: i32,
+    delete_contents: bool,
+) -> Result<(), MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+
+    let fetch_statement = transaction
+        .prepare("SELECT parent_id FROM layer_groups WHERE id = $1 AND image_id = $2 FOR UPDATE")
+        .await?;
+    let parent_id: Option<i32> = match transaction.query_opt(&fetch_statement, &[&group_id, &image_id]).await? {
+        Some(row) => row.get("parent_id"),
+        None => return Err(MyDbError::NotFound),
+    };
+
+    if delete_contents {
+        // Nested groups go with it through ON DELETE CASCADE, their layers don't
+        let delete_statement = transaction
+            .prepare("WITH RECURSIVE tree AS (SELECT id FROM layer_groups WHERE id = $1 UNION SELECT layer_groups.id FROM layer_groups JOIN tree ON layer_groups.parent_id = tree.id) DELETE FROM layers WHERE group_id IN (SELECT id FROM tree) RETURNING layer_hash, mask_hash")
+            .await?;
+        for row in transaction.query(&delete_statement, &[&group_id]).await? {
+            for hash in row_blob_hashes(&row) {
+                blobs::release_blob(&transaction, &hash).await?;
+            }
+        }
+    } else {
+        let layer_statement = transaction
+            .prepare("UPDATE layers SET group_id = $1, last_modified = NOW() WHERE group_id = $2")
+            .await?;
+        transaction.execute(&layer_statement, &[&parent_id, &group_id]).await?;
+        let group_statement = transaction
+            .prepare("UPDATE layer_groups SET parent_id = $1 WHERE parent_id = $2")
+            .await?;
+        transaction.execute(&group_statement, &[&parent_id, &group_id]).await?;
+    }
+
+    let delete_statement = transaction.prepare("DELETE FROM layer_groups WHERE id = $1").await?;
+    transaction.execute(&delete_statement, &[&group_id]).await?;
+    transaction.commit().await?;
+    Ok(())
+}
 
 //////////////////////////////////////////////////////////////////////////////////
-//////////// ********** LayerStatistics Representation ********** ////////////////
+//////////// ********** LayerGroup Representation ********** /////////////////////
 //////////////////////////////////////////////////////////////////////////////////
-/// Merge a group of layers into a single layer
+/// Merge a group of layers into a single layer. The group ID is the ID of the
+/// merged layer and the layer IDs are the layers that went into it, which no
+/// longer exist.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LayerGroup {
     pub group_id: i32,
@@ -370,25 +834,81 @@ pub struct LayerStatistics {
 
 //////////////////////////////////////////////////////////////////////////////////
 //////////// ********** Layer Representation ********** //////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+/// What a layer holds. Raster layers keep their pixels in layer_data, the other
+/// types are rendered from layer_params whenever the image is composited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerType {
+    #[default]
+    Raster,
+    Adjustment,
+    Fill,
+    Text,
+    Shape,
+}
+
+impl LayerType {
+    // The name stored in the layers.layer_type column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LayerType::Raster => "raster",
+            LayerType::Adjustment => "adjustment",
+            LayerType::Fill => "fill",
+            LayerType::Text => "text",
+            LayerType::Shape => "shape",
+        }
+    }
+}
+
+impl FromStr for LayerType {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<LayerType, String> {
+        match name {
+            "raster" => Ok(LayerType::Raster),
+            "adjustment" => Ok(LayerType::Adjustment),
+            "fill" => Ok(LayerType::Fill),
+            "text" => Ok(LayerType::Text),
+            "shape" => Ok(LayerType::Shape),
+            _ => Err(format!("unknown layer type '{}'", name)),
+        }
+    }
+}
+
 //////////////////////////////////////////////////////////////////////////////////
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Layer {
     pub id: i32,
     pub image_id: i32,
     pub layer_name: String,
-    pub creation_date: String,
-    pub last_modified: String,
-    pub user_id: i32,
-    pub layer_type: String,
+    pub creation_date: NaiveDateTime,
+    pub last_modified: NaiveDateTime,
+    pub user_id: Option<i32>,
+    pub layer_type: LayerType,
     pub visibility: bool,
-    pub opacity: f32,
-    pub layer_data: Vec<u8>, // Raw data for the layer
+    pub opacity: f32,        // Percentage, 0 to 100
+    #[serde(skip_serializing)]
+    pub layer_data: Vec<u8>, // Encoded PNG pixels for the layer
+    #[serde(skip_serializing)]
+    pub layer_hash: Option<String>, // Blob the pixels were loaded from
     pub layer_order: i32,          // Maintain layer order!
+    pub blend_mode: BlendMode,
+    pub group_id: Option<i32>,     // None for layers at the top level
+    #[serde(skip_serializing)]
+    pub mask_data: Option<Vec<u8>>, // Encoded grayscale PNG, None without a mask
+    #[serde(skip_serializing)]
+    pub mask_hash: Option<String>, // Blob the mask was loaded from
+    pub mask_enabled: bool,
+    pub mask_inverted: bool,
+    pub is_clipped: bool,          // Clip to the alpha of the layer below
+    pub layer_params: Option<Value>, // Settings for adjustment, fill and text layers
                              // Add other fields TODO:
 }
 
 impl Layer {
-    // Create a new layer instance from a database row
+    // Create a new layer instance from a database row. Pixels and masks kept as
+    // blobs are left empty here, see load_layer_blobs.
     pub fn from_row(row: &Row) -> Layer {
         Layer {
             id: row.get("id"),
@@ -397,11 +917,80 @@ impl Layer {
             creation_date: row.get("creation_date"),
             last_modified: row.get("last_modified"),
             user_id: row.get("user_id"),
-            layer_type: row.get("layer_type"),
+            // Old free-form types (and anything unknown) are treated as raster
+            layer_type: row.get::<_, &str>("layer_type").parse().unwrap_or_default(),
             visibility: row.get("visibility"),
             opacity: row.get("opacity"),
-            layer_data: row.get("layer_data"),
+            layer_data: row.get::<_, Option<Vec<u8>>>("layer_data").unwrap_or_default(),
+            layer_hash: row.get("layer_hash"),
             layer_order: row.get("layer_order"),
+            // An unknown name in the column falls back to normal
+            blend_mode: row.get::<_, &str>("blend_mode").parse().unwrap_or_default(),
+            group_id: row.get("group_id"),
+            mask_data: row.get("mask_data"),
+            mask_hash: row.get("mask_hash"),
+            mask_enabled: row.get("mask_enabled"),
+            mask_inverted: row.get("mask_inverted"),
+            is_clipped: row.get("is_clipped"),
+            layer_params: row.get("layer_params"),
         }
     }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Layer Tree Representation ********** /////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+/// A row of the layer_groups table, shown to users as a folder of layers.
+/// Groups and layers with the same parent share one layer_order sequence.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerFolder {
+    pub id: i32,
+    pub image_id: i32,
+    pub parent_id: Option<i32>, // None for groups at the top level
+    pub group_name: String,
+    pub creation_date: NaiveDateTime,
+    pub visibility: bool,
+    pub opacity: f32, // Percentage, 0 to 100
+    pub blend_mode: BlendMode,
+    pub layer_order: i32,
+}
+
+impl LayerFolder {
+    // Create a new group instance from a database row
+    pub fn from_row(row: &Row) -> LayerFolder {
+        LayerFolder {
+            id: row.get("id"),
+            image_id: row.get("image_id"),
+            parent_id: row.get("parent_id"),
+            group_name: row.get("group_name"),
+            creation_date: row.get("creation_date"),
+            visibility: row.get("visibility"),
+            opacity: row.get("opacity"),
+            blend_mode: row.get::<_, &str>("blend_mode").parse().unwrap_or_default(),
+            layer_order: row.get("layer_order"),
+        }
+    }
+}
+
+/// The settings of a group to change, see update_layer_group. A parent_id of
+/// Some(None) moves the group to the top level.
+#[derive(Debug, Default)]
+pub struct LayerGroupChanges {
+    pub group_name: Option<String>,
+    pub visibility: Option<bool>,
+    pub opacity: Option<f32>,
+    pub blend_mode: Option<BlendMode>,
+    pub parent_id: Option<Option<i32>>,
+}
+
+/// A node of an image's layer tree: a layer or a group with its children.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LayerNode {
+    Layer(Layer),
+    Group {
+        #[serde(flatten)]
+        group: LayerFolder,
+        children: Vec<LayerNode>,
+    },
 }
\ No newline at end of file
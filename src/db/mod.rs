@@ -1,8 +1,12 @@
 #![allow(dead_code)]
 pub mod users;
+pub mod blobs;
 pub mod images;
 pub mod sessions;
 pub mod layers;
+pub mod history;
+pub mod snapshots;
+pub mod thumbnails;
 // ... other module declarations ...
 
 
@@ -16,6 +20,7 @@ use deadpool_postgres::{Config, Pool};
 use tokio_postgres::{Error, NoTls };
 // use tokio_postgres::Row;
 use std::fmt;
+use crate::storage::StorageError;
 
 //////////////////////////////////////////////////////////////////////////////////
 //////////// ********** DB Connection Management ********** //////////////////////
@@ -39,6 +44,8 @@ pub fn create_pool() -> Pool {
 pub async fn setup_database(client: &mut deadpool_postgres::Client) -> Result<(), Error> {
 
     // Create User Table ////////////////////////////////////////////////////////
+    // password_hash is an Argon2 PHC string, users added without a password
+    // can't log in. role is 'user' or 'admin'.
     client
         .batch_execute(
             "
@@ -46,13 +53,18 @@ pub async fn setup_database(client: &mut deadpool_postgres::Client) -> Result<()
             id              SERIAL PRIMARY KEY,
             username        VARCHAR UNIQUE NOT NULL,
             email           VARCHAR UNIQUE NOT NULL
-        )
+        );
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash VARCHAR;
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS role VARCHAR( 20 ) NOT NULL DEFAULT 'user';
     ",
         )
         .await?;
     println!("Users table created successfully.");
 
     // Create Session Table //////////////////////////////////////////////////////
+    // token_hash is the SHA-256 of the token the client holds, the token itself
+    // is never stored. end_time is set when a session is ended or revoked. A
+    // user's sessions go when the user does.
     client
         .batch_execute(
             "
@@ -63,14 +75,42 @@ pub async fn setup_database(client: &mut deadpool_postgres::Client) -> Result<()
             expiration_time TIMESTAMP NOT NULL,
             last_activity   TIMESTAMP NOT NULL,
             session_data    JSONB
-        )
+        );
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS token_hash VARCHAR( 64 ) UNIQUE;
+        ALTER TABLE sessions ADD COLUMN IF NOT EXISTS end_time TIMESTAMP;
+        CREATE INDEX IF NOT EXISTS sessions_user_id ON sessions ( user_id );
+        ALTER TABLE sessions DROP CONSTRAINT IF EXISTS sessions_user_id_fkey;
+        ALTER TABLE sessions ADD CONSTRAINT sessions_user_id_fkey FOREIGN KEY ( user_id ) REFERENCES users ( id ) ON DELETE CASCADE;
     ",
         )
         .await?;
     println!("Sessions table created successfully.");
 
+    // Create Blobs Table ///////////////////////////////////////////////////////
+    // One row per blob in the storage backend, keyed by its SHA-256 hash.
+    // ref_count is how many images, layers, history entries, snapshots and
+    // thumbnails use it.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS blobs (
+            hash            VARCHAR( 64 ) PRIMARY KEY,
+            byte_size       BIGINT NOT NULL,
+            ref_count       INTEGER NOT NULL DEFAULT 0,
+            created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_used       TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )
+    ",
+        )
+        .await?;
+    println!("blobs table created successfully.");
+
     // Create Image Table ///////////////////////////////////////////////////////
-    // This table does NOT use session_id as a foreign key
+    // This table does NOT use session_id as a foreign key. file_hash is the
+    // image file in the blob storage; rows from before it existed only have a
+    // file_path on disk. file_type, width, height and byte_size describe the
+    // file as found by decoding it, older rows have the client's Content-Type
+    // and no dimensions.
     client
         .batch_execute(
             "
@@ -79,9 +119,17 @@ pub async fn setup_database(client: &mut deadpool_postgres::Client) -> Result<()
             user_id         INTEGER REFERENCES users(id),
             file_type       VARCHAR NOT NULL,
             file_path       VARCHAR NOT NULL,
+            file_hash       VARCHAR( 64 ) REFERENCES blobs,
+            width           INTEGER,
+            height          INTEGER,
+            byte_size       BIGINT,
             created_at      TIMESTAMP NOT NULL,
             updated_at      TIMESTAMP NOT NULL
-        )
+        );
+        ALTER TABLE images ADD COLUMN IF NOT EXISTS file_hash VARCHAR( 64 ) REFERENCES blobs;
+        ALTER TABLE images ADD COLUMN IF NOT EXISTS width INTEGER;
+        ALTER TABLE images ADD COLUMN IF NOT EXISTS height INTEGER;
+        ALTER TABLE images ADD COLUMN IF NOT EXISTS byte_size BIGINT;
     ",
         )
         .await?;
@@ -102,55 +150,143 @@ pub async fn setup_database(client: &mut deadpool_postgres::Client) -> Result<()
     //     .await?;
     // println!("images table created successfully.");
 
+    // Create Layer Groups Table ////////////////////////////////////////////////
+    // Groups nest through parent_id and share layer_order with their sibling layers
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS layer_groups (
+            id              SERIAL PRIMARY KEY,
+            image_id        INTEGER NOT NULL REFERENCES images,
+            parent_id       INTEGER REFERENCES layer_groups ON DELETE CASCADE,
+            group_name      VARCHAR( 255 ) NOT NULL,
+            creation_date   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            visibility      BOOLEAN NOT NULL DEFAULT TRUE,
+            opacity         REAL NOT NULL DEFAULT 100,
+            blend_mode      VARCHAR( 20 ) NOT NULL DEFAULT 'normal',
+            layer_order     INTEGER NOT NULL
+        )
+    ",
+        )
+        .await?;
+    println!("layer_groups table created successfully.");
+
     // Create Layers Table //////////////////////////////////////////////////////
-    // client
-    //     .batch_execute(
-    //         "
-    //     CREATE TABLE IF NOT EXISTS layers (
-    //         id              SERIAL PRIMARY KEY,
-    //         image_id        INTEGER REFERENCES images,
-    //         layer_name      VARCHAR( 255 ),
-    //         creation_date   TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-    //         last_modified   TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
-    //         user_id         INTEGER REFERENCES users,
-    //         layer_type      VARCHAR( 50 ),
-    //         visibility      BOOLEAN DEFAULT TRUE,
-    //         opacity         FLOAT DEFAULT 100,
-    //         layer_data      BYTEA,
-    //         layer_order     INTEGER
-    //     );  
-    // ",
-    //     )
-    //     .await?;
-    // println!("layers table created successfully.");
+    // layer_order 0 is the bottom of the stack, opacity is a percentage.
+    // layer_hash and mask_hash point at the layer's PNG pixels and optional
+    // grayscale mask in the blob storage; layer_data and mask_data only hold them
+    // for rows written before that. is_clipped clips a layer to the alpha of the
+    // layer below it. layer_type is raster, adjustment, fill, text or shape;
+    // everything but raster is described by layer_params instead of pixels.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS layers (
+            id              SERIAL PRIMARY KEY,
+            image_id        INTEGER REFERENCES images,
+            layer_name      VARCHAR( 255 ) NOT NULL,
+            creation_date   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_modified   TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            user_id         INTEGER REFERENCES users,
+            layer_type      VARCHAR( 50 ) NOT NULL DEFAULT 'raster',
+            visibility      BOOLEAN NOT NULL DEFAULT TRUE,
+            opacity         REAL NOT NULL DEFAULT 100,
+            layer_data      BYTEA,
+            layer_hash      VARCHAR( 64 ) REFERENCES blobs,
+            layer_order     INTEGER NOT NULL,
+            blend_mode      VARCHAR( 20 ) NOT NULL DEFAULT 'normal',
+            group_id        INTEGER REFERENCES layer_groups ON DELETE SET NULL,
+            mask_data       BYTEA,
+            mask_hash       VARCHAR( 64 ) REFERENCES blobs,
+            mask_enabled    BOOLEAN NOT NULL DEFAULT TRUE,
+            mask_inverted   BOOLEAN NOT NULL DEFAULT FALSE,
+            is_clipped      BOOLEAN NOT NULL DEFAULT FALSE,
+            layer_params    JSONB
+        );  
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS blend_mode VARCHAR( 20 ) NOT NULL DEFAULT 'normal';
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS group_id INTEGER REFERENCES layer_groups ON DELETE SET NULL;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS mask_data BYTEA;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS mask_enabled BOOLEAN NOT NULL DEFAULT TRUE;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS mask_inverted BOOLEAN NOT NULL DEFAULT FALSE;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS is_clipped BOOLEAN NOT NULL DEFAULT FALSE;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS layer_params JSONB;
+        ALTER TABLE layers ALTER COLUMN layer_data DROP NOT NULL;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS layer_hash VARCHAR( 64 ) REFERENCES blobs;
+        ALTER TABLE layers ADD COLUMN IF NOT EXISTS mask_hash VARCHAR( 64 ) REFERENCES blobs;
+    ",
+        )
+        .await?;
+    println!("layers table created successfully.");
+
+    // Create Image History Table ///////////////////////////////////////////////
+    // One row per edit. before_state and after_state hold the rows of the layers
+    // and groups the edit touched (null when a row didn't exist) with their
+    // pixels as blob hashes. Undone rows are what redo walks forward through.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS image_history (
+            id              SERIAL PRIMARY KEY,
+            image_id        INTEGER NOT NULL REFERENCES images ON DELETE CASCADE,
+            op_name         VARCHAR( 50 ) NOT NULL,
+            op_params       JSONB,
+            layer_ids       INTEGER[] NOT NULL DEFAULT '{}',
+            before_state    JSONB NOT NULL,
+            after_state     JSONB NOT NULL,
+            undone          BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS image_history_image_id ON image_history ( image_id, id );
+    ",
+        )
+        .await?;
+    println!("image_history table created successfully.");
+
+    // Create Image Snapshots Table /////////////////////////////////////////////
+    // Named copies of an image's whole layer stack. state holds every layer and
+    // group row with the pixels as blob hashes, like image_history does.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS image_snapshots (
+            id              SERIAL PRIMARY KEY,
+            image_id        INTEGER NOT NULL REFERENCES images ON DELETE CASCADE,
+            snapshot_name   VARCHAR( 100 ) NOT NULL,
+            description     TEXT,
+            layer_count     INTEGER NOT NULL,
+            group_count     INTEGER NOT NULL,
+            state           JSONB NOT NULL,
+            created_at      TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE INDEX IF NOT EXISTS image_snapshots_image_id ON image_snapshots ( image_id, id );
+    ",
+        )
+        .await?;
+    println!("image_snapshots table created successfully.");
+
+    // Create Image Thumbnails Table ////////////////////////////////////////////
+    // The downscaled renditions of each image, one row per size. rendered_at is
+    // when the render started, a row is only replaced by a later render.
+    client
+        .batch_execute(
+            "
+        CREATE TABLE IF NOT EXISTS image_thumbnails (
+            image_id        INTEGER NOT NULL REFERENCES images ON DELETE CASCADE,
+            size            INTEGER NOT NULL,
+            blob_hash       VARCHAR( 64 ) NOT NULL REFERENCES blobs,
+            width           INTEGER NOT NULL,
+            height          INTEGER NOT NULL,
+            content_type    VARCHAR( 50 ) NOT NULL,
+            rendered_at     TIMESTAMP NOT NULL,
+            PRIMARY KEY ( image_id, size )
+        );
+    ",
+        )
+        .await?;
+    println!("image_thumbnails table created successfully.");
 
     Ok(())
 }
-// TODO: move to sessions.rs
-// // end_session: for an individual  ///////////////////////////////////////////////
-// pub async fn end_session(pool: &Pool, user_id: i32) -> Result<(), MyDbError> {
-//     // Fetch a database connection from the pool
-//     let client = pool.get().await?;
-
-//     // Prep the SQL query to update the session
-//     let statement = client
-//         .prepare("UPDATE session SET end_time = NOW() WHERE id = $1")
-//         .await?;
-
-//     let session_id = get_session_id_for_user(&pool, user_id).await?;
-
-//     // Execute the query
-//     let result = client.execute(&statement, &[&session_id]).await?;
-
-//     // Check if any rows were affected
-//     if result == 0 {
-//         // No rows were updated, session not found or already ended
-//         Err(MyDbError::NotFound)
-//     } else {
-//         // Session succesfully ended
-//         Ok(())
-//     }
-// }
 //////////////////////////////////////////////////////////////////////////////////
 //////////// ********** Error Handling ********** ////////////////////////////////
 //////////////////////////////////////////////////////////////////////////////////
@@ -163,6 +299,15 @@ pub enum MyDbError {
     SerializeError( serde_json::error::Error ),
     NotFound,
     JsonError( String ),
+    ImageError( String ), // Layer pixels couldn't be decoded or composited
+    StorageError( String ), // A blob couldn't be read from or written to storage
+    InvalidChange( String ), // The change would leave the rows inconsistent, e.g. a group inside itself
+}
+
+impl From<StorageError> for MyDbError {
+    fn from(err: StorageError) -> MyDbError {
+        MyDbError::StorageError( err.to_string() )
+    }
 }
 
 impl From<serde_json::Error> for MyDbError {
@@ -1,32 +1,43 @@
 #![allow(dead_code)]
 use super::MyDbError;
-use std::time::SystemTime;
-use chrono::{DateTime, Utc};
+use chrono::NaiveDateTime;
 use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
 use tokio_postgres::Row;
 use crate::db::users::User;
 use crate::db::users::get_user_by_id; 
+use crate::config;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Session Lifecycle ********** /////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+// A session is live until one of these happens:
+// - it is ended (logout or revocation), which sets end_time
+// - expiration_time passes. Every request pushes it to config::session_lifetime()
+//   from now (sliding expiration), but never past config::session_max_lifetime()
+//   from the login.
+// - nothing used it for config::session_idle_timeout(), going by last_activity
+// Sessions that aren't live any more are deleted by purge_dead_sessions.
 
 /// Create a single session for a user ////////////////////////////////////////////
 /// 
-/// This treats all the users the same, just creates a session for a user
+/// This treats all the users the same, just creates a session for a user.
+/// token_hash is auth::hash_session_token of the token handed to the client.
 /// 
-pub async fn create_a_session( pool: &Pool, user_id: i32, session_data: serde_json::Value ) -> Result< Session, MyDbError > 
+pub async fn create_a_session( pool: &Pool, user_id: i32, token_hash: &str, session_data: serde_json::Value ) -> Result< Session, MyDbError > 
 {
     // Check if user_id exists in the users table
     match get_user_by_id( pool, user_id ).await {
         Ok( _user ) => {
-            let client = pool.get().await.map_err(MyDbError::PoolError)?; // .map_err(MyDbError::PoolError)?; // .await?;
+            let client = pool.get().await.map_err(MyDbError::PoolError)?;
             let statement = client
-                .prepare( "INSERT INTO sessions (user_id, creation_time, expiration_time, last_activity, session_data) VALUES ($1, $2, $3, $4, $5 ) RETURNING *" )
+                .prepare( "INSERT INTO sessions (user_id, token_hash, creation_time, expiration_time, last_activity, session_data) VALUES ($1, $2, NOW(), NOW() + make_interval(secs => $3), NOW(), $4 ) RETURNING *" )
                 .await.map_err(MyDbError::QueryError)?;
 
-            let session_data_str = serde_json::to_string( &session_data ).map_err( MyDbError::SerializeError )?; 
-            let expiration_time = calculate_expiration_time();
+            let lifetime = config::session_lifetime().min( config::session_max_lifetime() ).as_secs_f64();
 
             // Execute prepared statment - insert a new session
-            match client.query_one( &statement, &[&user_id, &expiration_time, &session_data_str ] ).await {
+            match client.query_one( &statement, &[&user_id, &token_hash, &lifetime, &session_data ] ).await {
                 Ok( row ) => {
                     // Session is a struct, that represents a single session
                     let session = Session::from_row( &row )?;
@@ -41,6 +52,61 @@ pub async fn create_a_session( pool: &Pool, user_id: i32, session_data: serde_js
 
 }
 
+/// Find the live session a token belongs to and mark it as used /////////////////
+/// Bumps last_activity and slides expiration_time forward. Returns NotFound for
+/// unknown tokens and for sessions that have ended, expired or gone idle, so a
+/// dead token is treated like any other bad token.
+pub async fn touch_session( pool: &Pool, token_hash: &str ) -> Result< Session, MyDbError >
+{
+    let client = pool.get().await?;
+    let statement = client
+        .prepare( &format!( "UPDATE sessions SET last_activity = NOW(), expiration_time = GREATEST(expiration_time, LEAST(NOW() + make_interval(secs => $2), creation_time + make_interval(secs => $3))) WHERE token_hash = $1 AND {} RETURNING *", live_session_condition( 4 ) ) )
+        .await?;
+    let lifetime = config::session_lifetime().as_secs_f64();
+    let max_lifetime = config::session_max_lifetime().as_secs_f64();
+    let idle_timeout = idle_timeout_secs();
+    match client.query_opt( &statement, &[ &token_hash, &lifetime, &max_lifetime, &idle_timeout ] ).await? {
+        Some( row ) => Session::from_row( &row ),
+        None => Err( MyDbError::NotFound ),
+    }
+}
+
+// end_session: for an individual session, e.g. on logout ////////////////////////
+// Returns NotFound if there is no such session or it has already ended.
+pub async fn end_session( pool: &Pool, session_id: i32 ) -> Result< (), MyDbError >
+{
+    let client = pool.get().await?;
+    let statement = client
+        .prepare( "UPDATE sessions SET end_time = NOW() WHERE id = $1 AND end_time IS NULL" )
+        .await?;
+    match client.execute( &statement, &[ &session_id ] ).await? {
+        0 => Err( MyDbError::NotFound ),
+        _ => Ok( () ),
+    }
+}
+
+// end_all_sessions_for_user: revoke every session of a user /////////////////////
+// Returns how many sessions were still open.
+pub async fn end_all_sessions_for_user( pool: &Pool, user_id: i32 ) -> Result< u64, MyDbError >
+{
+    let client = pool.get().await?;
+    let statement = client
+        .prepare( "UPDATE sessions SET end_time = NOW() WHERE user_id = $1 AND end_time IS NULL" )
+        .await?;
+    Ok( client.execute( &statement, &[ &user_id ] ).await? )
+}
+
+// purge_dead_sessions: delete sessions that can't be used any more ///////////////
+// Returns how many were deleted.
+pub async fn purge_dead_sessions( pool: &Pool ) -> Result< u64, MyDbError >
+{
+    let client = pool.get().await?;
+    let statement = client
+        .prepare( &format!( "DELETE FROM sessions WHERE NOT ({})", live_session_condition( 1 ) ) )
+        .await?;
+    Ok( client.execute( &statement, &[ &idle_timeout_secs() ] ).await? )
+}
+
 /// Get user info from a session_id, returns a user struct ////////////////////////
 pub async fn get_user_from_session_id( pool: &Pool, session_id: i32 ) -> Result< User, MyDbError > 
 {
@@ -65,14 +131,15 @@ pub async fn get_user_from_session_id( pool: &Pool, session_id: i32 ) -> Result<
 }
 
 // get_active_sessions: for all current users ////////////////////////////////////
+// The live sessions, most recently used first.
 pub async fn get_active_sessions(pool: &Pool) -> Result< Vec<Session>, MyDbError > 
 {
 
     let client = pool.get().await?;
     let statement = client
-        .prepare("SELECT * FROM sessions WHERE end_time IS NULL")
+        .prepare(&format!("SELECT * FROM sessions WHERE {} ORDER BY last_activity DESC", live_session_condition(1)))
         .await?;
-    let rows = client.query(&statement, &[]).await?;
+    let rows = client.query(&statement, &[&idle_timeout_secs()]).await?;
     let mut sessions = Vec::new();
 
     for row in rows {
@@ -80,11 +147,18 @@ pub async fn get_active_sessions(pool: &Pool) -> Result< Vec<Session>, MyDbError
         sessions.push(Session::from_row(&row)?);
     }
 
-    if sessions.is_empty() {
-        Err(MyDbError::NotFound)
-    } else {
-        Ok(sessions)
-    }
+    Ok(sessions)
+}
+
+// get_active_sessions_for_user: the live sessions of a SINGLE user ///////////////
+pub async fn get_active_sessions_for_user(pool: &Pool, user_id: i32) -> Result< Vec<Session>, MyDbError >
+{
+    let client = pool.get().await?;
+    let statement = client
+        .prepare(&format!("SELECT * FROM sessions WHERE user_id = $1 AND {} ORDER BY last_activity DESC", live_session_condition(2)))
+        .await?;
+    let rows = client.query(&statement, &[&user_id, &idle_timeout_secs()]).await?;
+    rows.iter().map(Session::from_row).collect()
 }
 
 // get_session_ID for a SINGLE user //////////////////////////////////////////////
@@ -105,9 +179,15 @@ pub async fn get_session_id_for_user(pool: &Pool, user_id: i32) -> Result<i32, M
 //////////////////////////////////////////////////////////////////////////////////
 //////////// ********** Helper Functions ********** //////////////////////////////
 //////////////////////////////////////////////////////////////////////////////////
-fn calculate_expiration_time() -> SystemTime{ 
-    // TODO: update the time for expiration
-    SystemTime::now() + std::time::Duration::new( 86_400, 0 ) // 86_400 seconds in a day
+
+// SQL that holds for live sessions. idle_param is the number of the parameter
+// with the idle timeout in seconds, NULL when there is none.
+fn live_session_condition( idle_param: usize ) -> String {
+    format!( "end_time IS NULL AND expiration_time > NOW() AND (${0}::FLOAT8 IS NULL OR last_activity > NOW() - make_interval(secs => ${0}::FLOAT8))", idle_param )
+}
+
+fn idle_timeout_secs() -> Option<f64> {
+    config::session_idle_timeout().map( |timeout| timeout.as_secs_f64() )
 }
 
 //////////////////////////////////////////////////////////////////////////////////
@@ -117,9 +197,12 @@ fn calculate_expiration_time() -> SystemTime{
 pub struct Session {
     pub id: i32,
     pub user_id: i32,
-    pub creation_time: DateTime<Utc>,
-    pub expiration_time: DateTime<Utc>,
-    pub last_activity: DateTime<Utc>,
+    #[serde(skip)]
+    pub token_hash: Option<String>, // Never sent to clients
+    pub creation_time: NaiveDateTime,
+    pub expiration_time: NaiveDateTime,
+    pub last_activity: NaiveDateTime,
+    pub end_time: Option<NaiveDateTime>, // Set once the session is ended
     pub session_data: serde_json::Value, // What should you store in a session?
     // TODO: track how many images were uploaded in the session
 }
@@ -127,17 +210,17 @@ pub struct Session {
 impl Session {
     // Function to create a session instance from a database row
     pub fn from_row(row: &Row) -> Result<Session, MyDbError> {
-        let session_data_str: String = row.get("session_data");
-        let session_data = serde_json::from_str(&session_data_str)
-            .map_err(|e| MyDbError::JsonError(e.to_string()))?;
+        let session_data: Option<serde_json::Value> = row.get("session_data");
 
         Ok(Session {
             id: row.get("id"),
             user_id: row.get("user_id"),
+            token_hash: row.get("token_hash"),
             creation_time: row.get("creation_time"),
             expiration_time: row.get("expiration_time"),
             last_activity: row.get("last_activity"),
-            session_data,
+            end_time: row.get("end_time"),
+            session_data: session_data.unwrap_or_default(),
         })
     }
 }
@@ -0,0 +1,222 @@
+#![allow(dead_code)]
+use super::history::{self, GroupRecord, ImageState, LayerRecord};
+use super::MyDbError;
+use chrono::NaiveDateTime;
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use tokio_postgres::Row;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Snapshot Management Functions ********** /////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// create_snapshot: save the whole layer stack of an image under a name //////////
+// The snapshot keeps every layer and group row. It holds a reference to the
+// blobs of the image file, pixels and masks, so layers that didn't change between
+// snapshots (or since an edit in the history) are only stored once.
+pub async fn create_snapshot(
+    pool: &Pool,
+    image_id: i32,
+    snapshot_name: &str,
+    description: Option<&str>,
+) -> Result<Snapshot, MyDbError> {
+    let state = history::capture_state(pool, image_id).await?;
+
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    history::lock_image(&transaction, image_id).await?;
+    let legacy_blobs = state.blobs.iter().collect();
+    history::store_blobs(&transaction, state.blob_hashes(), &legacy_blobs).await?;
+
+    let statement = transaction
+        .prepare("INSERT INTO image_snapshots (image_id, snapshot_name, description, layer_count, group_count, state) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *")
+        .await?;
+    let row = transaction
+        .query_one(
+            &statement,
+            &[
+                &image_id,
+                &snapshot_name,
+                &description,
+                &(state.layers.len() as i32),
+                &(state.groups.len() as i32),
+                &serde_json::to_value(&state)?,
+            ],
+        )
+        .await?;
+    transaction.commit().await?;
+    Ok(Snapshot::from_row(&row))
+}
+
+// get_snapshots: every snapshot of an image, oldest first ///////////////////////
+pub async fn get_snapshots(pool: &Pool, image_id: i32) -> Result<Vec<Snapshot>, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("SELECT * FROM image_snapshots WHERE image_id = $1 ORDER BY id")
+        .await?;
+    let rows = client.query(&statement, &[&image_id]).await?;
+    Ok(rows.iter().map(Snapshot::from_row).collect())
+}
+
+// restore_snapshot: put an image's layer stack back to a snapshot ///////////////
+// Layers and groups made since the snapshot are deleted and the rest are
+// rewritten, all in one transaction. Returns NotFound if the image has no such
+// snapshot.
+pub async fn restore_snapshot(pool: &Pool, image_id: i32, snapshot_id: i32) -> Result<Snapshot, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    history::lock_image(&transaction, image_id).await?;
+
+    let statement = transaction
+        .prepare("SELECT * FROM image_snapshots WHERE id = $1 AND image_id = $2")
+        .await?;
+    let row = match transaction.query_opt(&statement, &[&snapshot_id, &image_id]).await? {
+        Some(row) => row,
+        None => return Err(MyDbError::NotFound),
+    };
+    let state: ImageState = serde_json::from_value(row.get("state"))?;
+    history::restore_state(&transaction, image_id, &state).await?;
+
+    transaction.commit().await?;
+    Ok(Snapshot::from_row(&row))
+}
+
+// diff_snapshots: what changed going from one snapshot to another ///////////////
+// Without `to` the snapshot is compared with the image as it is now. Only the
+// row settings are compared; pixels show up as a change of 'layer_data'.
+pub async fn diff_snapshots(
+    pool: &Pool,
+    image_id: i32,
+    from: i32,
+    to: Option<i32>,
+) -> Result<SnapshotDiff, MyDbError> {
+    let before = get_snapshot_state(pool, image_id, from).await?;
+    let after = match to {
+        Some(to) => get_snapshot_state(pool, image_id, to).await?,
+        None => history::capture_state(pool, image_id).await?,
+    };
+
+    Ok(SnapshotDiff {
+        from,
+        to,
+        file_changed: before.file_path != after.file_path || before.file_hash != after.file_hash,
+        layers: diff_rows(&before.layers, &after.layers, |layer: &LayerRecord| layer.layer_name.clone()),
+        groups: diff_rows(&before.groups, &after.groups, |group: &GroupRecord| group.group_name.clone()),
+    })
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// The stored state of one of an image's snapshots
+async fn get_snapshot_state(pool: &Pool, image_id: i32, snapshot_id: i32) -> Result<ImageState, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("SELECT state FROM image_snapshots WHERE id = $1 AND image_id = $2")
+        .await?;
+    match client.query_opt(&statement, &[&snapshot_id, &image_id]).await? {
+        Some(row) => Ok(serde_json::from_value(row.get("state"))?),
+        None => Err(MyDbError::NotFound),
+    }
+}
+
+// Sort rows into added, removed and changed, naming the fields that changed
+fn diff_rows<T, F>(before: &BTreeMap<i32, T>, after: &BTreeMap<i32, T>, name: F) -> RowDiff
+where
+    T: Serialize + PartialEq,
+    F: Fn(&T) -> String,
+{
+    let mut diff = RowDiff::default();
+    for (id, old) in before {
+        match after.get(id) {
+            None => diff.removed.push(RowSummary { id: *id, name: name(old) }),
+            Some(new) if new != old => diff.changed.push(RowChange {
+                id: *id,
+                name: name(new),
+                fields: changed_fields(old, new),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (id, new) in after {
+        if !before.contains_key(id) {
+            diff.added.push(RowSummary { id: *id, name: name(new) });
+        }
+    }
+    diff
+}
+
+fn changed_fields<T: Serialize>(old: &T, new: &T) -> Vec<String> {
+    match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(Value::Object(old)), Ok(Value::Object(new))) => old
+            .iter()
+            .filter(|(field, value)| new.get(field.as_str()) != Some(value))
+            .map(|(field, _)| field.clone())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Snapshot Representation ********** ///////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// A snapshot's metadata, without the stored layer stack.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub id: i32,
+    pub image_id: i32,
+    pub snapshot_name: String,
+    pub description: Option<String>,
+    pub layer_count: i32,
+    pub group_count: i32,
+    pub created_at: NaiveDateTime,
+}
+
+impl Snapshot {
+    // Create a new snapshot instance from a database row
+    pub fn from_row(row: &Row) -> Snapshot {
+        Snapshot {
+            id: row.get("id"),
+            image_id: row.get("image_id"),
+            snapshot_name: row.get("snapshot_name"),
+            description: row.get("description"),
+            layer_count: row.get("layer_count"),
+            group_count: row.get("group_count"),
+            created_at: row.get("created_at"),
+        }
+    }
+}
+
+/// The differences between two snapshots, or a snapshot and the image now.
+#[derive(Debug, Serialize)]
+pub struct SnapshotDiff {
+    pub from: i32,
+    pub to: Option<i32>,
+    pub file_changed: bool,
+    pub layers: RowDiff,
+    pub groups: RowDiff,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RowDiff {
+    pub added: Vec<RowSummary>,
+    pub removed: Vec<RowSummary>,
+    pub changed: Vec<RowChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowSummary {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RowChange {
+    pub id: i32,
+    pub name: String,
+    pub fields: Vec<String>,
+}
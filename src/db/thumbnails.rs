@@ -0,0 +1,107 @@
+#![allow(dead_code)]
+use super::blobs;
+use super::MyDbError;
+use crate::image_processing::thumbnail::{Rendition, THUMBNAIL_CONTENT_TYPE};
+use chrono::NaiveDateTime;
+use deadpool_postgres::Pool;
+use serde::Serialize;
+use tokio_postgres::Row;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Thumbnail Management Functions ********** ////////////////
+//////////////////////////////////////////////////////////////////////////////////
+// Every image has one rendition per size in image_processing::thumbnail, kept in
+// the blob storage next to the image file. They are rendered again after each
+// edit, so they always show the image with all of its layers.
+
+// save_thumbnails: store freshly rendered renditions of an image ////////////////
+// rendered_at is when the rendering started, before the image was read. A
+// rendition only replaces one that was started earlier, so a slow render of an
+// old state can't overwrite a newer one. Returns how many were stored.
+pub async fn save_thumbnails(
+    pool: &Pool,
+    image_id: i32,
+    rendered_at: NaiveDateTime,
+    renditions: &[Rendition],
+) -> Result<usize, MyDbError> {
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    // Keeps the image from being deleted until the renditions are in
+    let statement = transaction
+        .prepare("SELECT id FROM images WHERE id = $1 FOR KEY SHARE")
+        .await?;
+    if transaction.query_opt(&statement, &[&image_id]).await?.is_none() {
+        return Err(MyDbError::NotFound);
+    }
+
+    let mut saved = 0;
+    for rendition in renditions {
+        let size = rendition.size as i32;
+        let statement = transaction
+            .prepare("SELECT blob_hash, rendered_at FROM image_thumbnails WHERE image_id = $1 AND size = $2 FOR UPDATE")
+            .await?;
+        let existing = transaction.query_opt(&statement, &[&image_id, &size]).await?;
+        if let Some(row) = &existing {
+            if row.get::<_, NaiveDateTime>("rendered_at") >= rendered_at {
+                continue;
+            }
+        }
+
+        let (width, height) = (rendition.width as i32, rendition.height as i32);
+        let blob_hash = blobs::put_blob(&transaction, rendition.data.clone()).await?;
+        let statement = transaction
+            .prepare("INSERT INTO image_thumbnails (image_id, size, blob_hash, width, height, content_type, rendered_at) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (image_id, size) DO UPDATE SET blob_hash = EXCLUDED.blob_hash, width = EXCLUDED.width, height = EXCLUDED.height, content_type = EXCLUDED.content_type, rendered_at = EXCLUDED.rendered_at")
+            .await?;
+        transaction
+            .execute(&statement, &[&image_id, &size, &blob_hash, &width, &height, &THUMBNAIL_CONTENT_TYPE, &rendered_at])
+            .await?;
+        if let Some(row) = existing {
+            blobs::release_blob(&transaction, row.get("blob_hash")).await?;
+        }
+        saved += 1;
+    }
+    transaction.commit().await?;
+    Ok(saved)
+}
+
+// get_thumbnail: the stored rendition of an image for one size //////////////////
+// Returns NotFound if it hasn't been rendered (yet).
+pub async fn get_thumbnail(pool: &Pool, image_id: i32, size: u32) -> Result<Thumbnail, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("SELECT * FROM image_thumbnails WHERE image_id = $1 AND size = $2")
+        .await?;
+    match client.query_opt(&statement, &[&image_id, &(size as i32)]).await? {
+        Some(row) => Ok(Thumbnail::from_row(&row)),
+        None => Err(MyDbError::NotFound),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Thumbnail Representation ********** //////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug, Serialize)]
+pub struct Thumbnail {
+    pub image_id: i32,
+    pub size: i32,
+    pub blob_hash: String,
+    pub width: i32,
+    pub height: i32,
+    pub content_type: String,
+    pub rendered_at: NaiveDateTime,
+}
+
+impl Thumbnail {
+    // Create a new thumbnail instance from a database row
+    pub fn from_row(row: &Row) -> Thumbnail {
+        Thumbnail {
+            image_id: row.get("image_id"),
+            size: row.get("size"),
+            blob_hash: row.get("blob_hash"),
+            width: row.get("width"),
+            height: row.get("height"),
+            content_type: row.get("content_type"),
+            rendered_at: row.get("rendered_at"),
+        }
+    }
+}
@@ -3,6 +3,7 @@ use deadpool_postgres::Pool;
 use serde::{Deserialize, Serialize};
 use tokio_postgres::Row;
 use std::fmt;
+use std::str::FromStr;
 
 //////////////////////////////////////////////////////////////////////////////////
 // ************* User Insertion Functions ************** /////////////////////////
@@ -19,6 +20,22 @@ pub async fn add_user(pool: &Pool, username: &str, email: &str) -> Result<i32, M
     Ok(user_id)
 }
 
+// Add a user who logs in with a password ////////////////////////////////////////
+// password_hash comes from auth::hash_password, never the password itself.
+pub async fn register_user(
+    pool: &Pool,
+    username: &str,
+    email: &str,
+    password_hash: &str,
+) -> Result<i32, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("INSERT INTO users (username, email, password_hash) VALUES ($1, $2, $3) RETURNING id")
+        .await?;
+    let row = client.query_one(&statement, &[&username, &email, &password_hash]).await?;
+    Ok(row.get("id"))
+}
+
 //////////////////////////////////////////////////////////////////////////////////
 //////////// ********** User Retrieval Functions ********** //////////////////////
 //////////////////////////////////////////////////////////////////////////////////
@@ -116,6 +133,32 @@ pub async fn update_user_email(
     }
 }
 
+// Change what a user is allowed to do ///////////////////////////////////////////
+pub async fn set_user_role(pool: &Pool, user_id: i32, role: Role) -> Result<(), MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("UPDATE users SET role = $1 WHERE id = $2")
+        .await?;
+    let result = client.execute(&statement, &[&role.as_str(), &user_id]).await?;
+
+    if result == 0 {
+        Err(MyDbError::NotFound)
+    } else {
+        Ok(())
+    }
+}
+
+// Make the named users admins, returning how many of them exist /////////////////
+// Names without a user are skipped, they can be registered later.
+pub async fn grant_admin(pool: &Pool, usernames: &[String]) -> Result<u64, MyDbError> {
+    let client = pool.get().await?;
+    let statement = client
+        .prepare("UPDATE users SET role = $1 WHERE username = ANY($2)")
+        .await?;
+    let granted = client.execute(&statement, &[&Role::Admin.as_str(), &usernames]).await?;
+    Ok(granted)
+}
+
 // TODO: Update user profile, profile details, names, contact info, etc. /////////
 
 //////////////////////////////////////////////////////////////////////////////////
@@ -162,6 +205,9 @@ pub struct User {
     pub id: i32,
     pub username: String,
     pub email: String,
+    #[serde(skip)]
+    pub password_hash: Option<String>, // Never sent to clients
+    pub role: Role,
     // Add other fields TODO:
 }
 
@@ -172,6 +218,8 @@ impl User {
             id: row.get("id"),
             username: row.get("username"),
             email: row.get("email"),
+            password_hash: row.get("password_hash"),
+            role: row.get::<_, &str>("role").parse().unwrap_or_default(),
             // TODO: add other fields
         }
     }
@@ -183,4 +231,36 @@ impl fmt::Display for User {
     fn fmt( &self, f: &mut fmt::Formatter< '_ > ) -> fmt::Result {
         write!( f, "ID: {}, Username: {}, Email: {}", self.id, self.username, self.email )
     }
-}
\ No newline at end of file
+}
+
+/// What a user is allowed to do. Every user works on their own images, admins
+/// can also manage users and sessions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
+impl Role {
+    // The name stored in the users.role column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Role, String> {
+        match name {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role '{}'", name)),
+        }
+    }
+}
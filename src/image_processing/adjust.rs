@@ -0,0 +1,495 @@
+// colour adjustments like brightness/contrast, levels, curves, etc.
+use super::ProcessingError;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Adjustment Parameters ********** /////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Shift brightness and stretch contrast, both from -100 to 100.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BrightnessContrast
+{
+    #[serde(default)]
+    pub brightness: f32,
+    #[serde(default)]
+    pub contrast: f32,
+}
+
+/// Remap the input range [input_black, input_white] onto
+/// [output_black, output_white], bending the midtones with `gamma`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Levels
+{
+    #[serde(default)]
+    pub input_black: u8,
+    #[serde(default = "white")]
+    pub input_white: u8,
+    #[serde(default = "one")]
+    pub gamma: f32,
+    #[serde(default)]
+    pub output_black: u8,
+    #[serde(default = "white")]
+    pub output_white: u8,
+}
+
+impl Default for Levels
+{
+    fn default() -> Self
+    {
+        Levels { input_black: 0, input_white: 255, gamma: 1.0, output_black: 0, output_white: 255 }
+    }
+}
+
+/// Tone curves given as [input, output] control points. The `rgb` curve is
+/// applied on top of the per-channel ones; an empty curve leaves the channel
+/// alone.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Curves
+{
+    #[serde(default)]
+    pub rgb: Vec<[u8; 2]>,
+    #[serde(default)]
+    pub red: Vec<[u8; 2]>,
+    #[serde(default)]
+    pub green: Vec<[u8; 2]>,
+    #[serde(default)]
+    pub blue: Vec<[u8; 2]>,
+}
+
+/// HSL shifts: `hue` in degrees (-180 to 180), `saturation` and `lightness`
+/// from -100 to 100.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HueSaturation
+{
+    #[serde(default)]
+    pub hue: f32,
+    #[serde(default)]
+    pub saturation: f32,
+    #[serde(default)]
+    pub lightness: f32,
+}
+
+fn white() -> u8
+{
+    255
+}
+
+fn one() -> f32
+{
+    1.0
+}
+
+/// Any of the colour adjustments together with its parameters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", content = "params", rename_all = "snake_case")]
+pub enum Adjustment
+{
+    BrightnessContrast(BrightnessContrast),
+    Levels(Levels),
+    Curves(Curves),
+    HueSaturation(HueSaturation),
+}
+
+impl Adjustment
+{
+    // from_op: build an adjustment from an op name and its JSON parameters //////
+    pub fn from_op(op: &str, params: serde_json::Value) -> Result<Adjustment, ProcessingError>
+    {
+        let parsed = match op {
+            "brightness_contrast" => serde_json::from_value(params).map(Adjustment::BrightnessContrast),
+            "levels" => serde_json::from_value(params).map(Adjustment::Levels),
+            "curves" => serde_json::from_value(params).map(Adjustment::Curves),
+            "hue_saturation" => serde_json::from_value(params).map(Adjustment::HueSaturation),
+            _ => return Err(invalid(format!("unknown adjustment '{}'", op))),
+        };
+        parsed.map_err(|e| invalid(format!("bad parameters for {}: {}", op, e)))
+    }
+
+    // apply: run the adjustment over every pixel, alpha is left untouched ///////
+    pub fn apply(&self, image: &mut RgbaImage) -> Result<(), ProcessingError>
+    {
+        match self {
+            Adjustment::BrightnessContrast(params) => brightness_contrast(image, params),
+            Adjustment::Levels(params) => levels(image, params),
+            Adjustment::Curves(params) => curves(image, params),
+            Adjustment::HueSaturation(params) => hue_saturation(image, params),
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Adjustments ********** ///////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// brightness_contrast: shift, then stretch around mid-grey //////////////////////
+pub fn brightness_contrast(image: &mut RgbaImage,
+                           params: &BrightnessContrast)
+                           -> Result<(), ProcessingError>
+{
+    check_range("brightness", params.brightness, -100.0, 100.0)?;
+    check_range("contrast", params.contrast, -100.0, 100.0)?;
+
+    let shift = params.brightness * 1.28;
+    // Scaled so -100 flattens to grey and +100 is close to a hard threshold
+    let c = params.contrast * 2.55;
+    let factor = (259.0 * (c + 255.0)) / (255.0 * (259.0 - c));
+
+    let lut = build_lut(|v| factor * (v + shift - 128.0) + 128.0);
+    apply_luts(image, &lut, &lut, &lut);
+    Ok(())
+}
+
+// levels: input/output range remap with a gamma curve ///////////////////////////
+pub fn levels(image: &mut RgbaImage, params: &Levels) -> Result<(), ProcessingError>
+{
+    if params.input_white <= params.input_black {
+        return Err(invalid("input_white must be greater than input_black".to_string()));
+    }
+    check_range("gamma", params.gamma, 0.01, 10.0)?;
+
+    let in_black = params.input_black as f32;
+    let in_range = params.input_white as f32 - in_black;
+    let out_black = params.output_black as f32;
+    let out_range = params.output_white as f32 - out_black;
+
+    let lut = build_lut(|v| {
+        let t = ((v - in_black) / in_range).clamp(0.0, 1.0).powf(1.0 / params.gamma);
+        out_black + t * out_range
+    });
+    apply_luts(image, &lut, &lut, &lut);
+    Ok(())
+}
+
+// curves: monotone cubic through the control points of each channel ////////////
+pub fn curves(image: &mut RgbaImage, params: &Curves) -> Result<(), ProcessingError>
+{
+    let rgb = curve_lut("rgb", &params.rgb)?;
+    let red = curve_lut("red", &params.red)?;
+    let green = curve_lut("green", &params.green)?;
+    let blue = curve_lut("blue", &params.blue)?;
+
+    // Per-channel curve first, then the composite curve on top
+    let combine = |channel: &[u8; 256]| -> [u8; 256] {
+        let mut lut = [0u8; 256];
+        for (out, &v) in lut.iter_mut().zip(channel.iter()) {
+            *out = rgb[v as usize];
+        }
+        lut
+    };
+    apply_luts(image, &combine(&red), &combine(&green), &combine(&blue));
+    Ok(())
+}
+
+// hue_saturation: shift in HSL space ////////////////////////////////////////////
+pub fn hue_saturation(image: &mut RgbaImage, params: &HueSaturation) -> Result<(), ProcessingError>
+{
+    check_range("hue", params.hue, -180.0, 180.0)?;
+    check_range("saturation", params.saturation, -100.0, 100.0)?;
+    check_range("lightness", params.lightness, -100.0, 100.0)?;
+
+    let sat = params.saturation / 100.0;
+    let light = params.lightness / 100.0;
+
+    for pixel in image.pixels_mut() {
+        let (h, s, l) = rgb_to_hsl(pixel[0], pixel[1], pixel[2]);
+        let h = (h + params.hue).rem_euclid(360.0);
+        let s = if sat >= 0.0 { s + (1.0 - s) * sat } else { s * (1.0 + sat) };
+        let l = if light >= 0.0 { l + (1.0 - l) * light } else { l * (1.0 + light) };
+        let (r, g, b) = hsl_to_rgb(h, s, l);
+        pixel[0] = r;
+        pixel[1] = g;
+        pixel[2] = b;
+    }
+    Ok(())
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+fn invalid(msg: String) -> ProcessingError
+{
+    ProcessingError::InvalidParameter(msg)
+}
+
+fn check_range(name: &str, value: f32, min: f32, max: f32) -> Result<(), ProcessingError>
+{
+    if !(min..=max).contains(&value) {
+        return Err(invalid(format!("{} must be between {} and {}", name, min, max)));
+    }
+    Ok(())
+}
+
+// Tabulate a tone function over all 256 input values ///////////////////////////
+fn build_lut<F: Fn(f32) -> f32>(f: F) -> [u8; 256]
+{
+    let mut lut = [0u8; 256];
+    for (i, out) in lut.iter_mut().enumerate() {
+        *out = f(i as f32).round().clamp(0.0, 255.0) as u8;
+    }
+    lut
+}
+
+fn apply_luts(image: &mut RgbaImage, red: &[u8; 256], green: &[u8; 256], blue: &[u8; 256])
+{
+    for pixel in image.pixels_mut() {
+        pixel[0] = red[pixel[0] as usize];
+        pixel[1] = green[pixel[1] as usize];
+        pixel[2] = blue[pixel[2] as usize];
+    }
+}
+
+// Build a lookup table from curve control points ////////////////////////////////
+// Uses Fritsch-Carlson monotone cubic interpolation so the curve never
+// overshoots between points. Outside the first/last point the curve is flat.
+fn curve_lut(name: &str, points: &[[u8; 2]]) -> Result<[u8; 256], ProcessingError>
+{
+    if points.is_empty() {
+        return Ok(build_lut(|v| v));
+    }
+
+    let mut points = points.to_vec();
+    points.sort_by_key(|p| p[0]);
+    points.dedup_by_key(|p| p[0]);
+    if points.len() < 2 {
+        return Err(invalid(format!("the {} curve needs at least two distinct points", name)));
+    }
+
+    let xs: Vec<f32> = points.iter().map(|p| p[0] as f32).collect();
+    let ys: Vec<f32> = points.iter().map(|p| p[1] as f32).collect();
+    let n = xs.len();
+
+    let slopes: Vec<f32> = (0..n - 1).map(|k| (ys[k + 1] - ys[k]) / (xs[k + 1] - xs[k])).collect();
+    let mut tangents = vec![0.0f32; n];
+    tangents[0] = slopes[0];
+    tangents[n - 1] = slopes[n - 2];
+    for k in 1..n - 1 {
+        if slopes[k - 1] * slopes[k] > 0.0 {
+            tangents[k] = (slopes[k - 1] + slopes[k]) / 2.0;
+        }
+    }
+    for k in 0..n - 1 {
+        if slopes[k] == 0.0 {
+            tangents[k] = 0.0;
+            tangents[k + 1] = 0.0;
+            continue;
+        }
+        let alpha = tangents[k] / slopes[k];
+        let beta = tangents[k + 1] / slopes[k];
+        let norm = alpha * alpha + beta * beta;
+        if norm > 9.0 {
+            let tau = 3.0 / norm.sqrt();
+            tangents[k] = tau * alpha * slopes[k];
+            tangents[k + 1] = tau * beta * slopes[k];
+        }
+    }
+
+    Ok(build_lut(|v| {
+        if v <= xs[0] {
+            return ys[0];
+        }
+        if v >= xs[n - 1] {
+            return ys[n - 1];
+        }
+        let k = xs.iter().rposition(|&x| x <= v).unwrap_or(0).min(n - 2);
+        let h = xs[k + 1] - xs[k];
+        let t = (v - xs[k]) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        (2.0 * t3 - 3.0 * t2 + 1.0) * ys[k]
+        + (t3 - 2.0 * t2 + t) * h * tangents[k]
+        + (-2.0 * t3 + 3.0 * t2) * ys[k + 1]
+        + (t3 - t2) * h * tangents[k + 1]
+    }))
+}
+
+// Hue in degrees, saturation and lightness in [0, 1] ///////////////////////////
+pub(crate) fn rgb_to_hsl(r: u8, g: u8, b: u8) -> (f32, f32, f32)
+{
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let l = (max + min) / 2.0;
+    let d = max - min;
+    if d == 0.0 {
+        return (0.0, 0.0, l);
+    }
+
+    let s = d / (1.0 - (2.0 * l - 1.0).abs());
+    let h = if max == r {
+        60.0 * ((g - b) / d).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / d + 2.0)
+    } else {
+        60.0 * ((r - g) / d + 4.0)
+    };
+    (h, s.clamp(0.0, 1.0), l)
+}
+
+pub(crate) fn hsl_to_rgb(h: f32, s: f32, l: f32) -> (u8, u8, u8)
+{
+    let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    let hp = h.rem_euclid(360.0) / 60.0;
+    let x = c * (1.0 - (hp.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match hp as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = l - c / 2.0;
+    let to_u8 = |v: f32| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    (to_u8(r), to_u8(g), to_u8(b))
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use image::Rgba;
+
+    fn pixel(rgba: [u8; 4]) -> RgbaImage
+    {
+        RgbaImage::from_pixel(1, 1, Rgba(rgba))
+    }
+
+    #[test]
+    fn neutral_settings_change_nothing()
+    {
+        let original = RgbaImage::from_fn(16, 16, |x, y| {
+            Rgba([x as u8 * 16, y as u8 * 16, 77, 200])
+        });
+        let adjustments = [Adjustment::BrightnessContrast(BrightnessContrast::default()),
+                           Adjustment::Levels(Levels::default()),
+                           Adjustment::Curves(Curves::default()),
+                           Adjustment::HueSaturation(HueSaturation::default())];
+        for adjustment in adjustments {
+            let mut image = original.clone();
+            adjustment.apply(&mut image).unwrap();
+            assert_eq!(image, original, "{:?}", adjustment);
+        }
+    }
+
+    #[test]
+    fn brightness_contrast_extremes()
+    {
+        let mut image = pixel([10, 128, 250, 99]);
+        let params = BrightnessContrast { brightness: 0.0, contrast: -100.0 };
+        brightness_contrast(&mut image, &params).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([128, 128, 128, 99]));
+
+        let mut image = pixel([100, 0, 255, 255]);
+        let params = BrightnessContrast { brightness: 100.0, contrast: 0.0 };
+        brightness_contrast(&mut image, &params).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([228, 128, 255, 255]));
+
+        let params = BrightnessContrast { brightness: 101.0, contrast: 0.0 };
+        assert!(brightness_contrast(&mut image, &params).is_err());
+    }
+
+    #[test]
+    fn levels_remaps_the_input_range()
+    {
+        let mut image = RgbaImage::from_fn(3, 1, |x, _| {
+            let v = [50, 150, 250][x as usize];
+            Rgba([v, v, v, 255])
+        });
+        let params = Levels { input_black: 50, input_white: 250, ..Levels::default() };
+        levels(&mut image, &params).unwrap();
+        let values: Vec<u8> = image.pixels().map(|p| p[0]).collect();
+        assert_eq!(values, [0, 128, 255]);
+
+        let params = Levels { input_black: 200, input_white: 100, ..Levels::default() };
+        assert!(levels(&mut image, &params).is_err());
+    }
+
+    #[test]
+    fn levels_gamma_brightens_midtones()
+    {
+        let mut image = pixel([64, 128, 192, 255]);
+        levels(&mut image, &Levels { gamma: 2.0, ..Levels::default() }).unwrap();
+        let p = image.get_pixel(0, 0);
+        // (v / 255) ^ (1 / 2) * 255
+        assert_eq!([p[0], p[1], p[2]], [128, 181, 221]);
+    }
+
+    #[test]
+    fn curve_passes_through_its_points()
+    {
+        let points = [[0, 0], [64, 40], [190, 220], [255, 255]];
+        let lut = curve_lut("rgb", &points).unwrap();
+        for [x, y] in points {
+            assert_eq!(lut[x as usize], y);
+        }
+    }
+
+    #[test]
+    fn curve_is_monotone_and_flat_outside_its_points()
+    {
+        // Points that would make a natural cubic spline overshoot
+        let lut = curve_lut("rgb", &[[30, 20], [40, 200], [220, 210], [230, 240]]).unwrap();
+        assert!(lut.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(lut[..=30].iter().all(|&v| v == 20));
+        assert!(lut[230..].iter().all(|&v| v == 240));
+    }
+
+    #[test]
+    fn curve_needs_two_distinct_points()
+    {
+        assert!(curve_lut("red", &[[10, 10], [10, 50]]).is_err());
+        assert_eq!(curve_lut("red", &[]).unwrap()[77], 77);
+    }
+
+    #[test]
+    fn curves_apply_the_channel_curve_before_the_rgb_curve()
+    {
+        let mut image = pixel([100, 100, 100, 255]);
+        let params = Curves { red: vec![[0, 255], [255, 0]],
+                              rgb: vec![[0, 0], [255, 128]],
+                              ..Curves::default() };
+        curves(&mut image, &params).unwrap();
+        // red: 100 -> 155 -> 78, green and blue: 100 -> 50
+        assert_eq!(image.get_pixel(0, 0), &Rgba([78, 50, 50, 255]));
+    }
+
+    #[test]
+    fn hsl_round_trips()
+    {
+        for rgb in [(255, 0, 0), (12, 200, 99), (128, 128, 128), (0, 0, 0), (250, 240, 10)] {
+            let (h, s, l) = rgb_to_hsl(rgb.0, rgb.1, rgb.2);
+            assert_eq!(hsl_to_rgb(h, s, l), rgb);
+        }
+        assert_eq!(rgb_to_hsl(0, 0, 255), (240.0, 1.0, 0.5));
+    }
+
+    #[test]
+    fn hue_shift_rotates_primaries()
+    {
+        let mut image = pixel([255, 0, 0, 255]);
+        let params = HueSaturation { hue: 120.0, ..HueSaturation::default() };
+        hue_saturation(&mut image, &params).unwrap();
+        assert_eq!(image.get_pixel(0, 0), &Rgba([0, 255, 0, 255]));
+
+        let mut image = pixel([200, 40, 90, 255]);
+        let params = HueSaturation { saturation: -100.0, ..HueSaturation::default() };
+        hue_saturation(&mut image, &params).unwrap();
+        let p = image.get_pixel(0, 0);
+        assert!(p[0] == p[1] && p[1] == p[2]);
+    }
+
+    #[test]
+    fn from_op_rejects_unknown_adjustments()
+    {
+        assert!(Adjustment::from_op("posterize", serde_json::json!({})).is_err());
+        assert!(Adjustment::from_op("levels", serde_json::json!({ "gamma": "high" })).is_err());
+        assert!(matches!(Adjustment::from_op("levels", serde_json::json!({ "gamma": 1.5 })),
+                         Ok(Adjustment::Levels(Levels { gamma, .. })) if gamma == 1.5));
+    }
+}
@@ -0,0 +1,729 @@
+// layer compositing, flattens a stack of layers into one image
+use super::adjust::Adjustment;
+use super::draw::{self, ShapeParams};
+use super::text::{self, TextParams};
+use super::transform;
+use super::{decode_layer_data, mask, ProcessingError};
+use crate::db::layers::{Layer, LayerFolder, LayerNode, LayerType};
+use image::{io::Reader as ImageReader, GrayImage, ImageError, Luma, Rgba, RgbaImage};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Cursor;
+use std::str::FromStr;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Blend Modes ********** ///////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// How a layer's colours are mixed with the layers below it. The formulas are
+/// the ones from the W3C Compositing and Blending spec, which match Photoshop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode
+{
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    SoftLight,
+    HardLight,
+    Darken,
+    Lighten,
+    ColorDodge,
+    ColorBurn,
+    Difference,
+    Exclusion,
+    Hue,
+    Saturation,
+    Color,
+    Luminosity,
+}
+
+impl BlendMode
+{
+    pub const ALL: [BlendMode; 16] = [BlendMode::Normal,
+                                      BlendMode::Multiply,
+                                      BlendMode::Screen,
+                                      BlendMode::Overlay,
+                                      BlendMode::SoftLight,
+                                      BlendMode::HardLight,
+                                      BlendMode::Darken,
+                                      BlendMode::Lighten,
+                                      BlendMode::ColorDodge,
+                                      BlendMode::ColorBurn,
+                                      BlendMode::Difference,
+                                      BlendMode::Exclusion,
+                                      BlendMode::Hue,
+                                      BlendMode::Saturation,
+                                      BlendMode::Color,
+                                      BlendMode::Luminosity];
+
+    // The name stored in the layers.blend_mode column
+    pub fn as_str(self) -> &'static str
+    {
+        match self {
+            BlendMode::Normal => "normal",
+            BlendMode::Multiply => "multiply",
+            BlendMode::Screen => "screen",
+            BlendMode::Overlay => "overlay",
+            BlendMode::SoftLight => "soft_light",
+            BlendMode::HardLight => "hard_light",
+            BlendMode::Darken => "darken",
+            BlendMode::Lighten => "lighten",
+            BlendMode::ColorDodge => "color_dodge",
+            BlendMode::ColorBurn => "color_burn",
+            BlendMode::Difference => "difference",
+            BlendMode::Exclusion => "exclusion",
+            BlendMode::Hue => "hue",
+            BlendMode::Saturation => "saturation",
+            BlendMode::Color => "color",
+            BlendMode::Luminosity => "luminosity",
+        }
+    }
+
+    // Mix a backdrop colour with a source colour, both straight RGB in 0..1
+    fn blend(self, backdrop: [f32; 3], source: [f32; 3]) -> [f32; 3]
+    {
+        let separable = |f: fn(f32, f32) -> f32| {
+            [f(backdrop[0], source[0]), f(backdrop[1], source[1]), f(backdrop[2], source[2])]
+        };
+
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => separable(multiply),
+            BlendMode::Screen => separable(screen),
+            BlendMode::Overlay => separable(|b, s| hard_light(s, b)),
+            BlendMode::SoftLight => separable(soft_light),
+            BlendMode::HardLight => separable(hard_light),
+            BlendMode::Darken => separable(f32::min),
+            BlendMode::Lighten => separable(f32::max),
+            BlendMode::ColorDodge => separable(color_dodge),
+            BlendMode::ColorBurn => separable(color_burn),
+            BlendMode::Difference => separable(|b, s| (b - s).abs()),
+            BlendMode::Exclusion => separable(|b, s| b + s - 2.0 * b * s),
+            BlendMode::Hue => set_lum(set_sat(source, sat(backdrop)), lum(backdrop)),
+            BlendMode::Saturation => set_lum(set_sat(backdrop, sat(source)), lum(backdrop)),
+            BlendMode::Color => set_lum(source, lum(backdrop)),
+            BlendMode::Luminosity => set_lum(backdrop, lum(source)),
+        }
+    }
+}
+
+impl FromStr for BlendMode
+{
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<BlendMode, String>
+    {
+        BlendMode::ALL.into_iter()
+                      .find(|mode| mode.as_str() == name)
+                      .ok_or_else(|| format!("unknown blend mode '{}'", name))
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Layer Parameters ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Settings of a fill layer, a solid colour over the whole canvas.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FillParams
+{
+    /// RGBA fill colour.
+    pub color: [u8; 4],
+}
+
+// validate_layer_params: check the layer_params of a layer before storing them //
+// Adjustment layers hold an Adjustment ({ "op": ..., "params": ... }), fill
+// layers hold FillParams, text layers hold TextParams and shape layers hold
+// ShapeParams. Raster layers have no parameters.
+pub fn validate_layer_params(layer_type: LayerType, params: &Value) -> Result<(), ProcessingError>
+{
+    match layer_type {
+        LayerType::Adjustment => {
+            // The adjustments check their ranges when they run, so try it on one pixel
+            let adjustment: Adjustment = parse_params(Some(params))?;
+            adjustment.apply(&mut RgbaImage::new(1, 1))
+        }
+        LayerType::Fill => parse_params::<FillParams>(Some(params)).map(|_| ()),
+        LayerType::Raster => {
+            Err(ProcessingError::InvalidParameter("raster layers have no parameters".to_string()))
+        }
+        LayerType::Text => parse_params::<TextParams>(Some(params))?.validate(),
+        LayerType::Shape => parse_params::<ShapeParams>(Some(params))?.validate(),
+    }
+}
+
+// Read the stored parameters of a layer into their typed form
+fn parse_params<T: DeserializeOwned>(params: Option<&Value>) -> Result<T, ProcessingError>
+{
+    let params = params.ok_or_else(|| {
+        ProcessingError::InvalidParameter("layer parameters are missing".to_string())
+    })?;
+    serde_json::from_value(params.clone())
+        .map_err(|e| ProcessingError::InvalidParameter(format!("bad layer parameters: {}", e)))
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Layer Compositing ********** /////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// composite_layers: draw a layer tree onto a canvas /////////////////////////////
+// The nodes are expected bottom first, the way get_layers_by_image_id returns
+// them. Hidden layers are skipped, a layer's opacity (a percentage) scales
+// its alpha and its blend mode decides how it mixes with what is below. Every layer
+// is anchored at the top left corner of the canvas and anything that hangs over
+// the edge is clipped. A group is flattened on its own first and then drawn
+// like a single layer with the group's visibility, opacity and blend mode.
+//
+// Layer masks hide parts of their layer, and a clipped layer only shows where
+// the nearest unclipped layer or group below it has pixels. Fill, text and shape
+// layers are rendered from their parameters and adjustment layers re-run their
+// adjustment over everything below them, so editing the parameters never loses
+// pixels.
+pub fn composite_layers(canvas: &mut RgbaImage, nodes: &[LayerNode]) -> Result<(), ProcessingError>
+{
+    // Alpha of the layer that the clipped layers above it are clipped to
+    let mut clip_base: Option<GrayImage> = None;
+
+    for (index, node) in nodes.iter().enumerate() {
+        let clipped = is_clipped(node) && clip_base.is_some();
+        let clip = if clipped { clip_base.as_ref() } else { None };
+        // Only hold on to a layer's alpha when the next layer is clipped to it
+        let keep_alpha = !clipped && nodes.get(index + 1).is_some_and(is_clipped);
+
+        let alpha = match node {
+            LayerNode::Layer(layer) => composite_layer(canvas, layer, clip, keep_alpha)?,
+            LayerNode::Group { group, children } => {
+                composite_group(canvas, group, children, keep_alpha)?
+            }
+        };
+        if !clipped {
+            clip_base = alpha;
+        }
+    }
+    Ok(())
+}
+
+// flatten_layers: composite layers onto a transparent canvas ////////////////////
+// Used when merging layers. The canvas is as big as the largest layer, hidden
+// ones included, so the merged layer never loses pixels. Text layers count
+// with their text box and shape layers with their outline. A canvas larger
+// than a transform may produce is refused.
+pub fn flatten_layers(layers: Vec<Layer>) -> Result<RgbaImage, ProcessingError>
+{
+    let (mut width, mut height) = (1, 1);
+    for layer in layers.iter().filter(|layer| !layer.layer_data.is_empty()) {
+        let reader = ImageReader::new(Cursor::new(&layer.layer_data)).with_guessed_format()
+                                                                 .map_err(ImageError::IoError)?;
+        let (layer_width, layer_height) = reader.into_dimensions()?;
+        width = width.max(layer_width);
+        height = height.max(layer_height);
+    }
+    for layer in layers.iter().filter(|layer| layer.layer_type == LayerType::Text) {
+        let params: TextParams = parse_params(layer.layer_params.as_ref())?;
+        let (right, bottom) = params.extent();
+        width = width.max(right);
+        height = height.max(bottom);
+    }
+    for layer in layers.iter().filter(|layer| layer.layer_type == LayerType::Shape) {
+        let params: ShapeParams = parse_params(layer.layer_params.as_ref())?;
+        let (right, bottom) = params.extent();
+        width = width.max(right);
+        height = height.max(bottom);
+    }
+    // Layers saved before their positions were limited can still reach too far
+    transform::check_dimensions(width, height)?;
+
+    let nodes: Vec<LayerNode> = layers.into_iter().map(LayerNode::Layer).collect();
+    let mut canvas = RgbaImage::new(width, height);
+    composite_layers(&mut canvas, &nodes)?;
+    Ok(canvas)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// Render a single layer and draw it if it is visible //////////////////////////
+// Returns the layer's masked alpha when `keep_alpha` is set.
+fn composite_layer(canvas: &mut RgbaImage,
+                   layer: &Layer,
+                   clip: Option<&GrayImage>,
+                   keep_alpha: bool)
+                   -> Result<Option<GrayImage>, ProcessingError>
+{
+    let (width, height) = canvas.dimensions();
+    let pixels = match layer.layer_type {
+        _ if !layer.visibility => None,
+        LayerType::Raster if layer.layer_data.is_empty() => None,
+        LayerType::Raster => Some(decode_layer_data(&layer.layer_data)?),
+        LayerType::Fill => {
+            let fill: FillParams = parse_params(layer.layer_params.as_ref())?;
+            Some(RgbaImage::from_pixel(width, height, Rgba(fill.color)))
+        }
+        LayerType::Text => {
+            let params: TextParams = parse_params(layer.layer_params.as_ref())?;
+            Some(text::render_text(&params, width, height)?)
+        }
+        LayerType::Shape => {
+            let params: ShapeParams = parse_params(layer.layer_params.as_ref())?;
+            Some(draw::render_shape(&params, width, height)?)
+        }
+        // Adjustment layers change the canvas instead of adding pixels; as a
+        // clipping base they cover everything
+        LayerType::Adjustment => Some(RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]))),
+    };
+    let pixels = match pixels {
+        Some(pixels) => pixels,
+        None => return Ok(keep_alpha.then(|| GrayImage::new(width, height))),
+    };
+
+    let layer_mask = match &layer.mask_data {
+        Some(mask_data) if layer.mask_enabled => Some(mask::decode_mask_data(mask_data)?),
+        _ => None,
+    };
+    let mask_value = |x: u32, y: u32| match &layer_mask {
+        Some(layer_mask) => mask::mask_value(layer_mask, layer.mask_inverted, x, y),
+        None => 255,
+    };
+
+    let coverage = (layer_mask.is_some() || clip.is_some()).then(|| {
+        GrayImage::from_fn(width, height, |x, y| {
+            let clip_value = clip.map_or(255, |clip| clip.get_pixel(x, y)[0]);
+            Luma([multiply_u8(mask_value(x, y), clip_value)])
+        })
+    });
+
+    let opacity = layer_opacity(layer.opacity);
+    if opacity > 0.0 {
+        if layer.layer_type == LayerType::Adjustment {
+            let adjustment: Adjustment = parse_params(layer.layer_params.as_ref())?;
+            let mut adjusted = canvas.clone();
+            adjustment.apply(&mut adjusted)?;
+            draw_adjustment(canvas, &adjusted, opacity, layer.blend_mode, coverage.as_ref());
+        } else {
+            draw_layer(canvas, &pixels, opacity, layer.blend_mode, coverage.as_ref());
+        }
+    }
+
+    Ok(keep_alpha.then(|| {
+        GrayImage::from_fn(width, height, |x, y| {
+            let alpha = pixels.get_pixel_checked(x, y).map_or(0, |pixel| pixel[3]);
+            Luma([multiply_u8(alpha, mask_value(x, y))])
+        })
+    }))
+}
+
+// Flatten a group on its own canvas and draw the result like a layer ////////////
+fn composite_group(canvas: &mut RgbaImage,
+                   group: &LayerFolder,
+                   children: &[LayerNode],
+                   keep_alpha: bool)
+                   -> Result<Option<GrayImage>, ProcessingError>
+{
+    let (width, height) = canvas.dimensions();
+    if !group.visibility {
+        return Ok(keep_alpha.then(|| GrayImage::new(width, height)));
+    }
+
+    let mut group_canvas = RgbaImage::new(width, height);
+    composite_layers(&mut group_canvas, children)?;
+
+    let opacity = layer_opacity(group.opacity);
+    if opacity > 0.0 {
+        draw_layer(canvas, &group_canvas, opacity, group.blend_mode, None);
+    }
+
+    Ok(keep_alpha.then(|| {
+        GrayImage::from_fn(width, height, |x, y| Luma([group_canvas.get_pixel(x, y)[3]]))
+    }))
+}
+
+fn is_clipped(node: &LayerNode) -> bool
+{
+    matches!(node, LayerNode::Layer(layer) if layer.is_clipped)
+}
+
+// Convert the stored percentage to a 0..1 factor, treating garbage as hidden
+fn layer_opacity(opacity: f32) -> f32
+{
+    if opacity.is_finite() {
+        (opacity / 100.0).clamp(0.0, 1.0)
+    } else {
+        0.0
+    }
+}
+
+// Multiply two 0..255 coverage values
+fn multiply_u8(a: u8, b: u8) -> u8
+{
+    ((a as u32 * b as u32 + 127) / 255) as u8
+}
+
+// Blend a single layer onto the canvas //////////////////////////////////////////
+// `coverage` is a canvas sized mask (layer mask and clipping) that scales the
+// layer's alpha.
+fn draw_layer(canvas: &mut RgbaImage,
+              layer: &RgbaImage,
+              opacity: f32,
+              mode: BlendMode,
+              coverage: Option<&GrayImage>)
+{
+    let width = canvas.width().min(layer.width());
+    let height = canvas.height().min(layer.height());
+
+    for y in 0..height {
+        for x in 0..width {
+            let src = layer.get_pixel(x, y);
+            let cover = coverage.map_or(255, |coverage| coverage.get_pixel(x, y)[0]);
+            if src[3] == 0 || cover == 0 {
+                continue;
+            }
+            let dst = canvas.get_pixel_mut(x, y);
+            *dst = blend_pixel(*dst, *src, opacity * cover as f32 / 255.0, mode);
+        }
+    }
+}
+
+// Mix an adjusted copy of the canvas back into it ///////////////////////////////
+// Unlike a normal layer this never changes the canvas alpha; coverage and
+// opacity decide how much of the adjustment comes through.
+fn draw_adjustment(canvas: &mut RgbaImage,
+                   adjusted: &RgbaImage,
+                   opacity: f32,
+                   mode: BlendMode,
+                   coverage: Option<&GrayImage>)
+{
+    for (x, y, pixel) in canvas.enumerate_pixels_mut() {
+        let cover = coverage.map_or(255, |coverage| coverage.get_pixel(x, y)[0]);
+        if pixel[3] == 0 || cover == 0 {
+            continue;
+        }
+
+        let amount = opacity * cover as f32 / 255.0;
+        let source = adjusted.get_pixel(x, y);
+        let backdrop = [pixel[0] as f32 / 255.0, pixel[1] as f32 / 255.0, pixel[2] as f32 / 255.0];
+        let mixed = mode.blend(backdrop,
+                               [source[0] as f32 / 255.0,
+                                source[1] as f32 / 255.0,
+                                source[2] as f32 / 255.0]);
+        for c in 0..3 {
+            let value = backdrop[c] + (mixed[c].clamp(0.0, 1.0) - backdrop[c]) * amount;
+            pixel[c] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+// Blend then source-over with straight alpha. Where the backdrop is transparent
+// the source colour shows through unblended.
+fn blend_pixel(dst: Rgba<u8>, src: Rgba<u8>, opacity: f32, mode: BlendMode) -> Rgba<u8>
+{
+    let src_a = src[3] as f32 / 255.0 * opacity;
+    let dst_a = dst[3] as f32 / 255.0;
+    let out_a = src_a + dst_a * (1.0 - src_a);
+    if out_a <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+
+    let backdrop = [dst[0] as f32 / 255.0, dst[1] as f32 / 255.0, dst[2] as f32 / 255.0];
+    let source = [src[0] as f32 / 255.0, src[1] as f32 / 255.0, src[2] as f32 / 255.0];
+    let mixed = mode.blend(backdrop, source);
+
+    let mut out = [0u8; 4];
+    for c in 0..3 {
+        let blended = (1.0 - dst_a) * source[c] + dst_a * mixed[c].clamp(0.0, 1.0);
+        let value = (blended * src_a + backdrop[c] * dst_a * (1.0 - src_a)) / out_a;
+        out[c] = (value * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    out[3] = (out_a * 255.0).round().clamp(0.0, 255.0) as u8;
+    Rgba(out)
+}
+
+// Separable blend functions, backdrop first /////////////////////////////////////
+fn multiply(b: f32, s: f32) -> f32
+{
+    b * s
+}
+
+fn screen(b: f32, s: f32) -> f32
+{
+    b + s - b * s
+}
+
+fn hard_light(b: f32, s: f32) -> f32
+{
+    if s <= 0.5 {
+        multiply(b, 2.0 * s)
+    } else {
+        screen(b, 2.0 * s - 1.0)
+    }
+}
+
+fn soft_light(b: f32, s: f32) -> f32
+{
+    if s <= 0.5 {
+        b - (1.0 - 2.0 * s) * b * (1.0 - b)
+    } else {
+        let d = if b <= 0.25 { ((16.0 * b - 12.0) * b + 4.0) * b } else { b.sqrt() };
+        b + (2.0 * s - 1.0) * (d - b)
+    }
+}
+
+fn color_dodge(b: f32, s: f32) -> f32
+{
+    if b <= 0.0 {
+        0.0
+    } else if s >= 1.0 {
+        1.0
+    } else {
+        (b / (1.0 - s)).min(1.0)
+    }
+}
+
+fn color_burn(b: f32, s: f32) -> f32
+{
+    if b >= 1.0 {
+        1.0
+    } else if s <= 0.0 {
+        0.0
+    } else {
+        1.0 - ((1.0 - b) / s).min(1.0)
+    }
+}
+
+// Non-separable helpers for hue, saturation, color and luminosity ///////////////
+fn lum(c: [f32; 3]) -> f32
+{
+    0.3 * c[0] + 0.59 * c[1] + 0.11 * c[2]
+}
+
+fn set_lum(c: [f32; 3], l: f32) -> [f32; 3]
+{
+    let d = l - lum(c);
+    clip_color([c[0] + d, c[1] + d, c[2] + d])
+}
+
+// Pull out-of-range colours back into 0..1 without changing their luminosity
+fn clip_color(c: [f32; 3]) -> [f32; 3]
+{
+    let l = lum(c);
+    let min = c[0].min(c[1]).min(c[2]);
+    let max = c[0].max(c[1]).max(c[2]);
+
+    let mut c = c;
+    if min < 0.0 {
+        c = c.map(|v| l + (v - l) * l / (l - min));
+    }
+    if max > 1.0 {
+        c = c.map(|v| l + (v - l) * (1.0 - l) / (max - l));
+    }
+    c
+}
+
+fn sat(c: [f32; 3]) -> f32
+{
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_sat(c: [f32; 3], s: f32) -> [f32; 3]
+{
+    let mut order = [0, 1, 2];
+    order.sort_by(|&a, &b| c[a].total_cmp(&c[b]));
+    let [min, mid, max] = order;
+
+    let mut out = [0.0; 3];
+    if c[max] > c[min] {
+        out[mid] = (c[mid] - c[min]) * s / (c[max] - c[min]);
+        out[max] = s;
+    }
+    out
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3])
+    {
+        for c in 0..3 {
+            assert!((actual[c] - expected[c]).abs() < EPSILON,
+                    "{:?} is not {:?}",
+                    actual,
+                    expected);
+        }
+    }
+
+    #[test]
+    fn blend_mode_names_round_trip()
+    {
+        for mode in BlendMode::ALL {
+            assert_eq!(mode.as_str().parse::<BlendMode>(), Ok(mode));
+        }
+        assert!("dissolve".parse::<BlendMode>().is_err());
+    }
+
+    #[test]
+    fn separable_modes_match_the_spec()
+    {
+        let backdrop = [0.2, 0.5, 0.8];
+        let source = [0.6, 0.5, 0.1];
+        assert_close(BlendMode::Normal.blend(backdrop, source), source);
+        assert_close(BlendMode::Multiply.blend(backdrop, source), [0.12, 0.25, 0.08]);
+        assert_close(BlendMode::Screen.blend(backdrop, source), [0.68, 0.75, 0.82]);
+        assert_close(BlendMode::Darken.blend(backdrop, source), [0.2, 0.5, 0.1]);
+        assert_close(BlendMode::Lighten.blend(backdrop, source), [0.6, 0.5, 0.8]);
+        assert_close(BlendMode::Difference.blend(backdrop, source), [0.4, 0.0, 0.7]);
+        assert_close(BlendMode::Exclusion.blend(backdrop, source), [0.56, 0.5, 0.74]);
+        // Overlay is hard light with the layers swapped
+        assert_close(BlendMode::Overlay.blend(backdrop, source), [0.24, 0.5, 0.64]);
+        assert_close(BlendMode::HardLight.blend(backdrop, source), [0.36, 0.5, 0.16]);
+    }
+
+    #[test]
+    fn dodge_and_burn_handle_their_edge_cases()
+    {
+        assert_eq!(color_dodge(0.0, 1.0), 0.0);
+        assert_eq!(color_dodge(0.3, 1.0), 1.0);
+        assert!((color_dodge(0.25, 0.5) - 0.5).abs() < EPSILON);
+        assert_eq!(color_burn(1.0, 0.0), 1.0);
+        assert_eq!(color_burn(0.7, 0.0), 0.0);
+        assert!((color_burn(0.75, 0.5) - 0.5).abs() < EPSILON);
+    }
+
+    #[test]
+    fn soft_light_leaves_the_backdrop_at_mid_grey()
+    {
+        for b in [0.0, 0.1, 0.3, 0.5, 0.9, 1.0] {
+            assert!((soft_light(b, 0.5) - b).abs() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn non_separable_modes_keep_the_luminosity_they_should()
+    {
+        let backdrop = [0.9, 0.2, 0.1];
+        let source = [0.1, 0.4, 0.8];
+        for mode in [BlendMode::Hue, BlendMode::Saturation, BlendMode::Color] {
+            assert!((lum(mode.blend(backdrop, source)) - lum(backdrop)).abs() < EPSILON);
+        }
+        assert!((lum(BlendMode::Luminosity.blend(backdrop, source)) - lum(source)).abs()
+                < EPSILON);
+    }
+
+    #[test]
+    fn clip_color_stays_in_range()
+    {
+        let clipped = clip_color([1.4, 0.5, -0.2]);
+        assert!(clipped.iter().all(|&c| (-EPSILON..=1.0 + EPSILON).contains(&c)));
+        assert!((lum(clipped) - lum([1.4, 0.5, -0.2])).abs() < EPSILON);
+    }
+
+    #[test]
+    fn blend_pixel_is_source_over_for_normal()
+    {
+        let black = Rgba([0, 0, 0, 255]);
+        let white = Rgba([255, 255, 255, 255]);
+        assert_eq!(blend_pixel(black, white, 1.0, BlendMode::Normal), white);
+        assert_eq!(blend_pixel(black, white, 0.5, BlendMode::Normal), Rgba([128, 128, 128, 255]));
+        assert_eq!(blend_pixel(black, white, 0.0, BlendMode::Normal), black);
+        // Half transparent over nothing keeps its colour
+        let orange = Rgba([200, 100, 50, 128]);
+        assert_eq!(blend_pixel(Rgba([0, 0, 0, 0]), orange, 1.0, BlendMode::Normal), orange);
+    }
+
+    #[test]
+    fn blend_pixel_shows_the_source_unblended_over_transparency()
+    {
+        let red = Rgba([255, 0, 0, 255]);
+        for mode in BlendMode::ALL {
+            assert_eq!(blend_pixel(Rgba([0, 0, 0, 0]), red, 1.0, mode), red, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn draw_layer_respects_coverage_and_layer_size()
+    {
+        let mut canvas = RgbaImage::from_pixel(3, 1, Rgba([0, 0, 0, 255]));
+        let layer = RgbaImage::from_pixel(2, 1, Rgba([255, 255, 255, 255]));
+        let coverage = GrayImage::from_raw(3, 1, vec![255, 0, 255]).unwrap();
+        draw_layer(&mut canvas, &layer, 1.0, BlendMode::Normal, Some(&coverage));
+        assert_eq!(canvas.get_pixel(0, 0), &Rgba([255, 255, 255, 255]));
+        assert_eq!(canvas.get_pixel(1, 0), &Rgba([0, 0, 0, 255]));
+        // Outside the layer
+        assert_eq!(canvas.get_pixel(2, 0), &Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn opacity_and_coverage_helpers()
+    {
+        assert_eq!(layer_opacity(50.0), 0.5);
+        assert_eq!(layer_opacity(150.0), 1.0);
+        assert_eq!(layer_opacity(f32::NAN), 0.0);
+        assert_eq!(multiply_u8(255, 255), 255);
+        assert_eq!(multiply_u8(255, 0), 0);
+        assert_eq!(multiply_u8(128, 255), 128);
+    }
+
+    fn vector_layer(layer_type: LayerType, params: Value) -> Layer
+    {
+        let date = chrono::NaiveDateTime::default();
+        Layer { id: 1,
+                image_id: 1,
+                layer_name: "vector".to_string(),
+                creation_date: date,
+                last_modified: date,
+                user_id: None,
+                layer_type,
+                visibility: true,
+                opacity: 100.0,
+                layer_data: Vec::new(),
+                layer_hash: None,
+                layer_order: 0,
+                blend_mode: BlendMode::Normal,
+                group_id: None,
+                mask_data: None,
+                mask_hash: None,
+                mask_enabled: false,
+                mask_inverted: false,
+                is_clipped: false,
+                layer_params: Some(params) }
+    }
+
+    #[test]
+    fn flattening_sizes_the_canvas_for_shapes()
+    {
+        let shape = vector_layer(LayerType::Shape, serde_json::json!({
+            "shape": "rectangle", "x": 2.0, "y": 3.0, "width": 10.0, "height": 5.0,
+            "fill": { "type": "solid", "color": [255, 0, 0, 255] },
+        }));
+        let canvas = flatten_layers(vec![shape]).unwrap();
+        assert_eq!(canvas.dimensions(), (12, 8));
+        assert_eq!(canvas.get_pixel(5, 5), &Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn flattening_refuses_layers_that_reach_too_far()
+    {
+        // Saved before positions were limited, the canvas would be 2e9 pixels wide
+        let text = vector_layer(LayerType::Text, serde_json::json!({
+            "text": "far", "font_family": "DejaVu Sans", "font_size": 12.0,
+            "x": 2_000_000_000, "y": 0, "width": 100, "height": 20,
+        }));
+        assert!(flatten_layers(vec![text]).is_err());
+
+        let shape = vector_layer(LayerType::Shape, serde_json::json!({
+            "shape": "ellipse", "x": 1e9, "y": 0.0, "width": 10.0, "height": 10.0,
+        }));
+        assert!(flatten_layers(vec![shape]).is_err());
+    }
+}
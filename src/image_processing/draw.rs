@@ -1 +1,952 @@
-// draw tools like brush, pencil, etc.
\ No newline at end of file
+// draw tools like brush, pencil, vector shapes, etc.
+use super::selection::SelectionMask;
+use super::transform::MAX_DIMENSION;
+use super::ProcessingError;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::TAU;
+
+// Limits on brush input so a single request can't stall a worker
+const MAX_BRUSH_SIZE: f32 = 5000.0;
+const MAX_STROKE_POINTS: usize = 100_000;
+// Pixels a stroke's dabs may visit in total, a few seconds of work
+const MAX_STROKE_WORK: u64 = 2_000_000_000;
+// Limits on shape input, for the same reason
+const MAX_SHAPE_POINTS: usize = 100_000;
+const MAX_DASHES: usize = 64;
+const MAX_GRADIENT_STOPS: usize = 64;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Brush Settings ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Settings for a raster brush stroke.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Brush
+{
+    /// Diameter in pixels at full pressure.
+    pub size: f32,
+    /// 1.0 is a hard edge, 0.0 fades all the way from the centre.
+    #[serde(default = "one")]
+    pub hardness: f32,
+    /// Distance between dabs as a fraction of the diameter.
+    #[serde(default = "default_spacing")]
+    pub spacing: f32,
+    /// Maximum coverage of the whole stroke.
+    #[serde(default = "one")]
+    pub opacity: f32,
+    /// Coverage laid down by each dab; overlapping dabs build up to `opacity`.
+    #[serde(default = "one")]
+    pub flow: f32,
+    /// RGBA paint colour, ignored when erasing.
+    #[serde(default = "black")]
+    pub color: [u8; 4],
+    /// Remove alpha instead of painting.
+    #[serde(default)]
+    pub eraser: bool,
+    /// Scale the dab size with pen pressure.
+    #[serde(default = "yes")]
+    pub pressure_size: bool,
+    /// Scale the dab flow with pen pressure.
+    #[serde(default)]
+    pub pressure_flow: bool,
+}
+
+/// A point on a stroke. `pressure` goes from 0.0 to 1.0 and defaults to 1.0
+/// for mouse input.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StrokePoint
+{
+    pub x: f32,
+    pub y: f32,
+    #[serde(default = "one")]
+    pub pressure: f32,
+}
+
+fn one() -> f32
+{
+    1.0
+}
+
+fn default_spacing() -> f32
+{
+    0.25
+}
+
+fn black() -> [u8; 4]
+{
+    [0, 0, 0, 255]
+}
+
+fn yes() -> bool
+{
+    true
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Stroke Rendering ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// draw_stroke: rasterize a brush stroke onto an RGBA buffer /////////////////////
+// Dabs are stamped along the polyline into a coverage buffer first, so a
+// stroke that crosses itself never goes above the brush opacity. The finished
+// coverage is then painted (or erased) onto the image in one go. Strokes that
+// would take too long are rejected before the image is touched.
+pub fn draw_stroke(image: &mut RgbaImage,
+                   brush: &Brush,
+                   points: &[StrokePoint])
+                   -> Result<(), ProcessingError>
+{
+    let (width, height) = image.dimensions();
+    validate(brush, points, width, height)?;
+
+    let mut coverage = vec![0.0f32; (width * height) as usize];
+
+    // Every dab counts as at least one pixel, so dabs off the image add up too
+    let mut work = 0u64;
+    let mut stamp = |x: f32, y: f32, pressure: f32| {
+        let flow = if brush.pressure_flow { brush.flow * pressure } else { brush.flow };
+        let radius = brush_diameter(brush, pressure) / 2.0;
+        work += stamp_dab(&mut coverage, width, height, x, y, radius, brush.hardness, flow).max(1);
+        if work > MAX_STROKE_WORK {
+            return Err(ProcessingError::InvalidParameter(
+                "the stroke is too long for this brush size and spacing".to_string(),
+            ));
+        }
+        Ok(())
+    };
+    let step = |pressure: f32| (brush.spacing * brush_diameter(brush, pressure)).max(0.5) as f64;
+
+    let first = points[0];
+    let first_pressure = first.pressure.clamp(0.0, 1.0);
+    stamp(first.x, first.y, first_pressure)?;
+
+    // Walk the polyline, placing a dab every `step` pixels of travel. The
+    // distances are kept in f64, in f32 adding a step to a long stroke's
+    // travel can round back to the same value and never reach the next dab.
+    let mut travelled = 0.0f64;
+    let mut next_dab = step(first_pressure);
+    for segment in points.windows(2) {
+        let (a, b) = (segment[0], segment[1]);
+        let (dx, dy) = ((b.x - a.x) as f64, (b.y - a.y) as f64);
+        let length = (dx * dx + dy * dy).sqrt();
+
+        while next_dab <= travelled + length {
+            let t = (next_dab - travelled) / length;
+            let pressure = (a.pressure + (b.pressure - a.pressure) * t as f32).clamp(0.0, 1.0);
+            stamp((a.x as f64 + dx * t) as f32, (a.y as f64 + dy * t) as f32, pressure)?;
+            next_dab += step(pressure);
+        }
+        travelled += length;
+    }
+
+    let opacity = brush.opacity;
+    for (pixel, &cover) in image.pixels_mut().zip(coverage.iter()) {
+        let cover = cover * opacity;
+        if cover <= 0.0 {
+            continue;
+        }
+        if brush.eraser {
+            pixel[3] = (pixel[3] as f32 * (1.0 - cover)).round() as u8;
+            continue;
+        }
+
+        // Source-over with straight alpha
+        let src_a = cover * brush.color[3] as f32 / 255.0;
+        let dst_a = pixel[3] as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        if out_a <= 0.0 {
+            continue;
+        }
+        for c in 0..3 {
+            let src = brush.color[c] as f32;
+            let dst = pixel[c] as f32;
+            let out = (src * src_a + dst * dst_a * (1.0 - src_a)) / out_a;
+            pixel[c] = out.round().clamp(0.0, 255.0) as u8;
+        }
+        pixel[3] = (out_a * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    Ok(())
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+fn validate(brush: &Brush,
+            points: &[StrokePoint],
+            width: u32,
+            height: u32)
+            -> Result<(), ProcessingError>
+{
+    let invalid = |msg: &str| Err(ProcessingError::InvalidParameter(msg.to_string()));
+
+    if !(0.5..=MAX_BRUSH_SIZE).contains(&brush.size) {
+        return invalid("size must be between 0.5 and 5000 pixels");
+    }
+    if !(0.0..=1.0).contains(&brush.hardness)
+       || !(0.0..=1.0).contains(&brush.opacity)
+       || !(0.0..=1.0).contains(&brush.flow)
+    {
+        return invalid("hardness, opacity and flow must be between 0 and 1");
+    }
+    if !(0.01..=10.0).contains(&brush.spacing) {
+        return invalid("spacing must be between 0.01 and 10");
+    }
+    if points.is_empty() || points.len() > MAX_STROKE_POINTS {
+        return invalid("a stroke needs between 1 and 100000 points");
+    }
+    if points.iter().any(|p| !p.x.is_finite() || !p.y.is_finite() || !p.pressure.is_finite()) {
+        return invalid("stroke points must be finite numbers");
+    }
+    // Points further out than the largest dab can't mark the image
+    let margin = brush.size / 2.0 + 1.0;
+    let (width, height) = (width as f32, height as f32);
+    if points.iter().any(|p| {
+        p.x < -margin || p.y < -margin || p.x > width + margin || p.y > height + margin
+    }) {
+        return invalid("stroke points must be on the image or within the brush size of it");
+    }
+    Ok(())
+}
+
+fn brush_diameter(brush: &Brush, pressure: f32) -> f32
+{
+    if brush.pressure_size {
+        brush.size * pressure
+    } else {
+        brush.size
+    }
+}
+
+// Stamp a single round dab into the coverage buffer ////////////////////////////
+// Returns the number of pixels it visited.
+#[allow(clippy::too_many_arguments)]
+fn stamp_dab(coverage: &mut [f32],
+             width: u32,
+             height: u32,
+             cx: f32,
+             cy: f32,
+             radius: f32,
+             hardness: f32,
+             flow: f32)
+             -> u64
+{
+    if radius <= 0.0 || flow <= 0.0 {
+        return 0;
+    }
+
+    // Keep tiny dabs at least a pixel wide so light pressure still marks
+    let radius = radius.max(0.5);
+    let hard_radius = radius * hardness;
+    let x0 = (cx - radius - 1.0).floor().max(0.0) as u32;
+    let y0 = (cy - radius - 1.0).floor().max(0.0) as u32;
+    let x1 = ((cx + radius + 1.0).ceil().max(0.0) as u32).min(width);
+    let y1 = ((cy + radius + 1.0).ceil().max(0.0) as u32).min(height);
+
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let dx = x as f32 + 0.5 - cx;
+            let dy = y as f32 + 0.5 - cy;
+            let distance = (dx * dx + dy * dy).sqrt();
+
+            // Soft falloff between the hard core and the rim, plus a one pixel
+            // ramp at the rim for anti-aliasing
+            let falloff = if distance <= hard_radius {
+                1.0
+            } else {
+                let soft_width = (radius - hard_radius).max(f32::EPSILON);
+                let t = ((distance - hard_radius) / soft_width).min(1.0);
+                1.0 - t * t * (3.0 - 2.0 * t)
+            };
+            let edge = (radius + 0.5 - distance).clamp(0.0, 1.0);
+            let dab = falloff.max(0.0) * edge * flow;
+            if dab <= 0.0 {
+                continue;
+            }
+
+            let cell = &mut coverage[(y * width + x) as usize];
+            *cell += dab * (1.0 - *cell);
+        }
+    }
+    x1.saturating_sub(x0) as u64 * y1.saturating_sub(y0) as u64
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Shape Settings ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Settings of a shape layer, stored in layers.layer_params. The geometry is
+/// kept as vectors and rasterized whenever the image is composited, so the
+/// shape stays sharp however it is scaled or rotated.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShapeParams
+{
+    #[serde(flatten)]
+    pub geometry: ShapeGeometry,
+    /// Paint for the inside of the shape, open paths are closed for filling.
+    pub fill: Option<Paint>,
+    pub stroke: Option<ShapeStroke>,
+    /// Affine transform [a, b, c, d, e, f] applied to the geometry, mapping
+    /// (x, y) to (a*x + c*y + e, b*x + d*y + f) like SVG's matrix().
+    #[serde(default = "identity")]
+    pub transform: [f32; 6],
+}
+
+/// The outline of a shape, in canvas pixels before the transform.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum ShapeGeometry
+{
+    /// Rectangle with optionally rounded corners.
+    Rectangle
+    {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        #[serde(default)]
+        corner_radius: f32,
+    },
+    /// Ellipse inscribed in the rectangle.
+    Ellipse { x: f32, y: f32, width: f32, height: f32 },
+    /// Closed polygon, as a list of [x, y] points.
+    Polygon { points: Vec<[f32; 2]> },
+    /// Path of lines and Bézier curves starting at `start`.
+    Path
+    {
+        start: [f32; 2],
+        segments: Vec<PathSegment>,
+        #[serde(default)]
+        closed: bool,
+    },
+}
+
+/// One piece of a path, continuing from where the previous one ended.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PathSegment
+{
+    Line { to: [f32; 2] },
+    Quadratic { control: [f32; 2], to: [f32; 2] },
+    Cubic { control1: [f32; 2], control2: [f32; 2], to: [f32; 2] },
+}
+
+/// How the inside of a shape is painted. Gradient coordinates are in the
+/// shape's own space, so they follow the transform.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Paint
+{
+    Solid { color: [u8; 4] },
+    LinearGradient { start: [f32; 2], end: [f32; 2], stops: Vec<GradientStop> },
+    RadialGradient { center: [f32; 2], radius: f32, stops: Vec<GradientStop> },
+}
+
+/// A colour at `offset` (0.0 to 1.0) along a gradient.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct GradientStop
+{
+    pub offset: f32,
+    pub color: [u8; 4],
+}
+
+/// The outline drawn along a shape's edge, centred on it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShapeStroke
+{
+    pub width: f32,
+    #[serde(default = "black")]
+    pub color: [u8; 4],
+    /// Alternating dash and gap lengths; empty draws a solid line.
+    #[serde(default)]
+    pub dash: Vec<f32>,
+    /// How far into the dash pattern the stroke starts.
+    #[serde(default)]
+    pub dash_offset: f32,
+}
+
+fn identity() -> [f32; 6]
+{
+    [1.0, 0.0, 0.0, 1.0, 0.0, 0.0]
+}
+
+impl ShapeParams
+{
+    // Check the geometry and paints, rejecting anything that can't be drawn
+    pub fn validate(&self) -> Result<(), ProcessingError>
+    {
+        let invalid = |msg: &str| Err(ProcessingError::InvalidParameter(msg.to_string()));
+
+        let finite = |p: &[f32]| p.iter().all(|v| v.is_finite());
+        let geometry_ok = match &self.geometry {
+            ShapeGeometry::Rectangle { x, y, width, height, corner_radius } => {
+                finite(&[*x, *y, *width, *height, *corner_radius])
+                && *width >= 0.0
+                && *height >= 0.0
+                && *corner_radius >= 0.0
+            }
+            ShapeGeometry::Ellipse { x, y, width, height } => {
+                finite(&[*x, *y, *width, *height]) && *width >= 0.0 && *height >= 0.0
+            }
+            ShapeGeometry::Polygon { points } => points.iter().all(|p| finite(p)),
+            ShapeGeometry::Path { start, segments, .. } => {
+                finite(start)
+                && segments.iter().all(|segment| match segment {
+                                      PathSegment::Line { to } => finite(to),
+                                      PathSegment::Quadratic { control, to } => {
+                                          finite(control) && finite(to)
+                                      }
+                                      PathSegment::Cubic { control1, control2, to } => {
+                                          finite(control1) && finite(control2) && finite(to)
+                                      }
+                                  })
+            }
+        };
+        if !geometry_ok {
+            return invalid("shape coordinates must be finite and sizes can't be negative");
+        }
+        let point_count = match &self.geometry {
+            ShapeGeometry::Polygon { points } => points.len(),
+            ShapeGeometry::Path { segments, .. } => segments.len() + 1,
+            _ => 0,
+        };
+        if point_count > MAX_SHAPE_POINTS {
+            return invalid("a shape can have at most 100000 points");
+        }
+        if !finite(&self.transform) || invert(&self.transform).is_none() {
+            return invalid("transform must be an invertible matrix of finite numbers");
+        }
+
+        if let Some(paint) = &self.fill {
+            let stops = match paint {
+                Paint::Solid { .. } => &[][..],
+                Paint::LinearGradient { start, end, stops } => {
+                    if !finite(start) || !finite(end) {
+                        return invalid("gradient points must be finite numbers");
+                    }
+                    &stops[..]
+                }
+                Paint::RadialGradient { center, radius, stops } => {
+                    if !(finite(center) && radius.is_finite() && *radius > 0.0) {
+                        return invalid("a radial gradient needs a centre and a positive radius");
+                    }
+                    &stops[..]
+                }
+            };
+            if !matches!(paint, Paint::Solid { .. })
+               && (stops.is_empty() || stops.len() > MAX_GRADIENT_STOPS)
+            {
+                return invalid("a gradient needs between 1 and 64 stops");
+            }
+            if stops.iter().any(|stop| !(0.0..=1.0).contains(&stop.offset)) {
+                return invalid("gradient stop offsets must be between 0 and 1");
+            }
+        }
+
+        if let Some(stroke) = &self.stroke {
+            if !(stroke.width > 0.0 && stroke.width <= MAX_BRUSH_SIZE) {
+                return invalid("stroke width must be between 0 and 5000 pixels");
+            }
+            if stroke.dash.len() > MAX_DASHES || !stroke.dash_offset.is_finite() {
+                return invalid("a dash pattern can have at most 64 finite lengths");
+            }
+            if stroke.dash.iter().any(|length| !(length.is_finite() && *length >= 0.0))
+               || (!stroke.dash.is_empty() && stroke.dash.iter().sum::<f32>() < 1.0)
+            {
+                return invalid("dash lengths can't be negative and must add up to at least 1");
+            }
+        }
+
+        // Keeps the shape near enough to the canvas that flattening can size for it
+        let limit = MAX_DIMENSION as f32 - self.reach();
+        if self.hull().into_iter().any(|p| {
+                                      let [x, y] = apply(&self.transform, p);
+                                      !(x.abs() <= limit && y.abs() <= limit)
+                                  })
+        {
+            return Err(ProcessingError::InvalidParameter(format!(
+                "the transformed shape and its stroke must lie within {} pixels of the origin",
+                MAX_DIMENSION
+            )));
+        }
+        Ok(())
+    }
+
+    // Right and bottom edge of the drawn shape, used to size a flattened canvas
+    pub fn extent(&self) -> (u32, u32)
+    {
+        let (outline, _) = self.outline();
+        let reach = self.reach();
+        let right = outline.iter().map(|p| p[0]).fold(0.0f32, f32::max) + reach;
+        let bottom = outline.iter().map(|p| p[1]).fold(0.0f32, f32::max) + reach;
+        (right.ceil().max(0.0) as u32, bottom.ceil().max(0.0) as u32)
+    }
+
+    // How far the stroke reaches past the outline, in canvas pixels
+    fn reach(&self) -> f32
+    {
+        self.stroke.as_ref().map_or(0.0, |stroke| {
+                                stroke.width * matrix_scale(&self.transform) / 2.0
+                            })
+    }
+
+    // Points whose convex hull holds the whole outline, before the transform.
+    // Bézier curves stay inside the hull of their control points.
+    fn hull(&self) -> Vec<[f32; 2]>
+    {
+        match &self.geometry {
+            ShapeGeometry::Rectangle { x, y, width, height, .. }
+            | ShapeGeometry::Ellipse { x, y, width, height } => {
+                vec![[*x, *y], [x + width, *y], [*x, y + height], [x + width, y + height]]
+            }
+            ShapeGeometry::Polygon { points } => points.clone(),
+            ShapeGeometry::Path { start, segments, .. } => {
+                let mut points = vec![*start];
+                for segment in segments {
+                    match segment {
+                        PathSegment::Line { to } => points.push(*to),
+                        PathSegment::Quadratic { control, to } => points.extend([*control, *to]),
+                        PathSegment::Cubic { control1, control2, to } => {
+                            points.extend([*control1, *control2, *to])
+                        }
+                    }
+                }
+                points
+            }
+        }
+    }
+
+    // Flatten the geometry into transformed canvas points, and whether the
+    // outline joins back up to its start
+    fn outline(&self) -> (Vec<[f32; 2]>, bool)
+    {
+        let m = &self.transform;
+        let scale = matrix_scale(m);
+        match &self.geometry {
+            ShapeGeometry::Rectangle { x, y, width, height, corner_radius } => {
+                let radius = corner_radius.min(width / 2.0).min(height / 2.0);
+                let mut points = Vec::new();
+                // Corners clockwise from the top right, each as a quarter arc
+                let corners = [(x + width - radius, y + radius, -0.25),
+                               (x + width - radius, y + height - radius, 0.0),
+                               (x + radius, y + height - radius, 0.25),
+                               (x + radius, y + radius, 0.5)];
+                let steps = arc_steps(radius * scale, 0.25);
+                for (cx, cy, start) in corners {
+                    for step in 0..=steps {
+                        let theta = (start + 0.25 * step as f32 / steps as f32) * TAU;
+                        let point = [cx + radius * theta.cos(), cy + radius * theta.sin()];
+                        points.push(apply(m, point));
+                    }
+                }
+                (points, true)
+            }
+            ShapeGeometry::Ellipse { x, y, width, height } => {
+                let (rx, ry) = (width / 2.0, height / 2.0);
+                let (cx, cy) = (x + rx, y + ry);
+                let steps = arc_steps((rx + ry) / 2.0 * scale, 1.0);
+                let points = (0..steps).map(|i| {
+                                           let theta = i as f32 / steps as f32 * TAU;
+                                           apply(m, [cx + rx * theta.cos(), cy + ry * theta.sin()])
+                                       })
+                                       .collect();
+                (points, true)
+            }
+            ShapeGeometry::Polygon { points } => {
+                (points.iter().map(|p| apply(m, *p)).collect(), true)
+            }
+            ShapeGeometry::Path { start, segments, closed } => {
+                let mut current = apply(m, *start);
+                let mut points = vec![current];
+                for segment in segments {
+                    // Affine transforms keep Bézier curves Bézier, so transform
+                    // the control points and flatten in canvas space
+                    let controls: Vec<[f32; 2]> = match segment {
+                        PathSegment::Line { to } => vec![*to],
+                        PathSegment::Quadratic { control, to } => vec![*control, *to],
+                        PathSegment::Cubic { control1, control2, to } => {
+                            vec![*control1, *control2, *to]
+                        }
+                    };
+                    let mut curve = vec![current];
+                    curve.extend(controls.iter().map(|p| apply(m, *p)));
+                    flatten_bezier(&curve, &mut points);
+                    current = *points.last().unwrap();
+                }
+                (points, *closed)
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Shape Rendering ********** ///////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// render_shape: rasterize a shape layer onto a transparent canvas ///////////////
+// The fill is scan converted with the same anti-aliased polygon filler as the
+// lasso, then the stroke is drawn on top. The stroke's coverage comes from
+// each pixel's distance to the outline, which gives round joins and caps.
+pub fn render_shape(params: &ShapeParams,
+                    width: u32,
+                    height: u32)
+                    -> Result<RgbaImage, ProcessingError>
+{
+    params.validate()?;
+    let (outline, closed) = params.outline();
+    let mut canvas = RgbaImage::new(width, height);
+
+    if let Some(fill) = &params.fill {
+        let coverage = SelectionMask::polygon(width, height, &outline).into_gray();
+        // Gradients are evaluated in the shape's own space
+        let inverse = invert(&params.transform).unwrap_or_else(identity);
+        paint_coverage(&mut canvas, |x, y| coverage.get_pixel(x, y)[0] as f32 / 255.0, |x, y| {
+            paint_color(fill, apply(&inverse, [x as f32 + 0.5, y as f32 + 0.5]))
+        });
+    }
+
+    if let Some(stroke) = &params.stroke {
+        let scale = matrix_scale(&params.transform);
+        let mut path = outline;
+        if closed && path.len() > 1 {
+            path.push(path[0]);
+        }
+        let dash: Vec<f32> = stroke.dash.iter().map(|length| length * scale).collect();
+
+        let mut coverage = vec![0.0f32; (width * height) as usize];
+        for piece in dash_path(&path, &dash, stroke.dash_offset * scale) {
+            for segment in piece.windows(2) {
+                stroke_segment(&mut coverage,
+                               width,
+                               height,
+                               segment[0],
+                               segment[1],
+                               stroke.width * scale / 2.0);
+            }
+        }
+        paint_coverage(&mut canvas,
+                       |x, y| coverage[(y * width + x) as usize],
+                       |_, _| stroke.color);
+    }
+    Ok(canvas)
+}
+
+// Source-over a paint onto the canvas wherever the coverage is above zero
+fn paint_coverage<C, P>(canvas: &mut RgbaImage, coverage: C, paint: P)
+    where C: Fn(u32, u32) -> f32,
+          P: Fn(u32, u32) -> [u8; 4]
+{
+    for (x, y, pixel) in canvas.enumerate_pixels_mut() {
+        let cover = coverage(x, y);
+        if cover <= 0.0 {
+            continue;
+        }
+        let color = paint(x, y);
+        let src_a = cover.min(1.0) * color[3] as f32 / 255.0;
+        let dst_a = pixel[3] as f32 / 255.0;
+        let out_a = src_a + dst_a * (1.0 - src_a);
+        if out_a <= 0.0 {
+            continue;
+        }
+        for c in 0..3 {
+            let out = (color[c] as f32 * src_a + pixel[c] as f32 * dst_a * (1.0 - src_a)) / out_a;
+            pixel[c] = out.round().clamp(0.0, 255.0) as u8;
+        }
+        pixel[3] = (out_a * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+}
+
+// Colour of a paint at a point in shape space
+fn paint_color(paint: &Paint, point: [f32; 2]) -> [u8; 4]
+{
+    match paint {
+        Paint::Solid { color } => *color,
+        Paint::LinearGradient { start, end, stops } => {
+            let (dx, dy) = (end[0] - start[0], end[1] - start[1]);
+            let length = dx * dx + dy * dy;
+            let t = if length > 0.0 {
+                ((point[0] - start[0]) * dx + (point[1] - start[1]) * dy) / length
+            } else {
+                0.0
+            };
+            gradient_color(stops, t)
+        }
+        Paint::RadialGradient { center, radius, stops } => {
+            gradient_color(stops, distance(point, *center) / radius)
+        }
+    }
+}
+
+// Interpolate between the stops around `t`, holding the end colours past them
+fn gradient_color(stops: &[GradientStop], t: f32) -> [u8; 4]
+{
+    let t = t.clamp(0.0, 1.0);
+    let mut stops = stops.to_vec();
+    stops.sort_by(|a, b| a.offset.total_cmp(&b.offset));
+
+    let (first, last) = (stops[0], stops[stops.len() - 1]);
+    if t <= first.offset {
+        return first.color;
+    }
+    if t >= last.offset {
+        return last.color;
+    }
+    let pair = stops.windows(2)
+                    .find(|pair| t <= pair[1].offset)
+                    .unwrap_or(&stops[stops.len() - 2..]);
+    let (a, b) = (pair[0], pair[1]);
+    let span = b.offset - a.offset;
+    let f = if span > 0.0 { (t - a.offset) / span } else { 1.0 };
+    let mix = |c: usize| {
+        (a.color[c] as f32 + (b.color[c] as f32 - a.color[c] as f32) * f).round() as u8
+    };
+    [mix(0), mix(1), mix(2), mix(3)]
+}
+
+// Cut a polyline into the "on" pieces of a dash pattern /////////////////////////
+// Like SVG, an odd-length pattern is repeated to make it even.
+fn dash_path(path: &[[f32; 2]], dash: &[f32], offset: f32) -> Vec<Vec<[f32; 2]>>
+{
+    if dash.is_empty() {
+        return vec![path.to_vec()];
+    }
+    let mut pattern = dash.to_vec();
+    if pattern.len() % 2 == 1 {
+        pattern.extend_from_slice(dash);
+    }
+    let total: f32 = pattern.iter().sum();
+
+    // Find where in the pattern the stroke starts
+    let mut index = 0;
+    let mut remaining = offset.rem_euclid(total);
+    while remaining >= pattern[index] {
+        remaining -= pattern[index];
+        index = (index + 1) % pattern.len();
+    }
+    let mut left_in_dash = pattern[index] - remaining;
+
+    let mut pieces = Vec::new();
+    let mut current: Vec<[f32; 2]> = Vec::new();
+    if index % 2 == 0 && !path.is_empty() {
+        current.push(path[0]);
+    }
+    for segment in path.windows(2) {
+        let (a, b) = (segment[0], segment[1]);
+        let length = distance(a, b);
+        let mut travelled = 0.0;
+        while length - travelled > left_in_dash {
+            travelled += left_in_dash;
+            let t = travelled / length;
+            let point = [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t];
+            if index % 2 == 0 {
+                current.push(point);
+                pieces.push(std::mem::take(&mut current));
+            } else {
+                current.push(point);
+            }
+            index = (index + 1) % pattern.len();
+            left_in_dash = pattern[index];
+        }
+        left_in_dash -= length - travelled;
+        if index % 2 == 0 {
+            current.push(b);
+        }
+    }
+    if current.len() > 1 {
+        pieces.push(current);
+    }
+    pieces
+}
+
+// Add the coverage of a round-capped line segment to a buffer ///////////////////
+// Overlapping segments take the maximum, so joins aren't drawn twice.
+fn stroke_segment(coverage: &mut [f32],
+                  width: u32,
+                  height: u32,
+                  a: [f32; 2],
+                  b: [f32; 2],
+                  half_width: f32)
+{
+    let reach = half_width + 1.0;
+    let x0 = (a[0].min(b[0]) - reach).floor().max(0.0) as u32;
+    let y0 = (a[1].min(b[1]) - reach).floor().max(0.0) as u32;
+    let x1 = ((a[0].max(b[0]) + reach).ceil().max(0.0) as u32).min(width);
+    let y1 = ((a[1].max(b[1]) + reach).ceil().max(0.0) as u32).min(height);
+
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let length = dx * dx + dy * dy;
+    for y in y0..y1 {
+        for x in x0..x1 {
+            let (px, py) = (x as f32 + 0.5 - a[0], y as f32 + 0.5 - a[1]);
+            let t = if length > 0.0 { ((px * dx + py * dy) / length).clamp(0.0, 1.0) } else { 0.0 };
+            let distance = ((px - dx * t).powi(2) + (py - dy * t).powi(2)).sqrt();
+            // Lines thinner than a pixel fade out instead of disappearing
+            let cover = (half_width + 0.5 - distance).clamp(0.0, 1.0) * (half_width * 2.0).min(1.0);
+            let cell = &mut coverage[(y * width + x) as usize];
+            *cell = cell.max(cover);
+        }
+    }
+}
+
+// Append a flattened Bézier curve (line, quadratic or cubic) to `points` ////////
+// The first control point is the current point and isn't appended again.
+fn flatten_bezier(curve: &[[f32; 2]], points: &mut Vec<[f32; 2]>)
+{
+    // Enough steps that each piece is a couple of pixels of the control polygon
+    let length: f32 = curve.windows(2).map(|pair| distance(pair[0], pair[1])).sum();
+    let steps = match curve.len() {
+        2 => 1,
+        _ => (length / 2.0).ceil().clamp(4.0, 1024.0) as usize,
+    };
+
+    for step in 1..=steps {
+        let t = step as f32 / steps as f32;
+        // De Casteljau
+        let mut level = curve.to_vec();
+        while level.len() > 1 {
+            level = level.windows(2)
+                         .map(|pair| {
+                             [pair[0][0] + (pair[1][0] - pair[0][0]) * t,
+                              pair[0][1] + (pair[1][1] - pair[0][1]) * t]
+                         })
+                         .collect();
+        }
+        points.push(level[0]);
+    }
+}
+
+// Number of points for `turns` of a circle with this radius, under a pixel apart
+fn arc_steps(radius: f32, turns: f32) -> usize
+{
+    (radius.abs() * std::f32::consts::TAU * turns).ceil().clamp(4.0, 4096.0) as usize
+}
+
+fn distance(a: [f32; 2], b: [f32; 2]) -> f32
+{
+    ((b[0] - a[0]).powi(2) + (b[1] - a[1]).powi(2)).sqrt()
+}
+
+fn apply(m: &[f32; 6], p: [f32; 2]) -> [f32; 2]
+{
+    [m[0] * p[0] + m[2] * p[1] + m[4], m[1] * p[0] + m[3] * p[1] + m[5]]
+}
+
+fn invert(m: &[f32; 6]) -> Option<[f32; 6]>
+{
+    let det = m[0] * m[3] - m[1] * m[2];
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    let (a, b, c, d) = (m[3] / det, -m[1] / det, -m[2] / det, m[0] / det);
+    Some([a, b, c, d, -(a * m[4] + c * m[5]), -(b * m[4] + d * m[5])])
+}
+
+// How much the transform scales lengths on average, for stroke widths
+fn matrix_scale(m: &[f32; 6]) -> f32
+{
+    (m[0] * m[3] - m[1] * m[2]).abs().sqrt()
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use image::Rgba;
+
+    fn brush(size: f32) -> Brush
+    {
+        serde_json::from_value(serde_json::json!({ "size": size, "color": [255, 0, 0, 255] }))
+            .unwrap()
+    }
+
+    fn point(x: f32, y: f32) -> StrokePoint
+    {
+        StrokePoint { x, y, pressure: 1.0 }
+    }
+
+    #[test]
+    fn stroke_paints_along_the_line()
+    {
+        let mut image = RgbaImage::new(40, 11);
+        draw_stroke(&mut image, &brush(4.0), &[point(5.0, 5.5), point(35.0, 5.5)]).unwrap();
+        for x in 5..35 {
+            assert_eq!(image.get_pixel(x, 5), &Rgba([255, 0, 0, 255]), "x = {}", x);
+        }
+        assert_eq!(image.get_pixel(20, 0)[3], 0);
+        assert_eq!(image.get_pixel(20, 10)[3], 0);
+    }
+
+    #[test]
+    fn overlapping_dabs_stay_within_the_opacity()
+    {
+        let mut image = RgbaImage::new(20, 20);
+        let mut soft = brush(10.0);
+        soft.opacity = 0.5;
+        soft.spacing = 0.01;
+        let points = [point(2.0, 10.0), point(18.0, 10.0), point(2.0, 10.0)];
+        draw_stroke(&mut image, &soft, &points).unwrap();
+        assert!(image.pixels().all(|pixel| pixel[3] <= 128));
+        assert_eq!(image.get_pixel(10, 10)[3], 128);
+    }
+
+    #[test]
+    fn points_far_off_the_image_are_rejected()
+    {
+        let mut image = RgbaImage::new(10, 10);
+        // A stroke out here used to never finish, the f32 travel stopped growing
+        let far = [point(0.0, 0.0), point(16_777_216.0, 0.0)];
+        assert!(draw_stroke(&mut image, &brush(2.0), &far).is_err());
+        assert!(image.pixels().all(|pixel| pixel[3] == 0));
+
+        // Just past the edge still marks it
+        draw_stroke(&mut image, &brush(4.0), &[point(11.0, 5.0)]).unwrap();
+        assert!(image.get_pixel(9, 5)[3] > 0);
+        assert!(draw_stroke(&mut image, &brush(4.0), &[point(14.0, 5.0)]).is_err());
+    }
+
+    fn shape(params: serde_json::Value) -> ShapeParams
+    {
+        serde_json::from_value(params).unwrap()
+    }
+
+    #[test]
+    fn shape_extent_includes_the_stroke()
+    {
+        let square = shape(serde_json::json!({
+            "shape": "rectangle", "x": 10.0, "y": 20.0, "width": 30.0, "height": 40.0,
+            "stroke": { "width": 4.0 },
+        }));
+        square.validate().unwrap();
+        assert_eq!(square.extent(), (42, 62));
+    }
+
+    #[test]
+    fn shapes_far_from_the_canvas_are_rejected()
+    {
+        let far = shape(serde_json::json!({
+            "shape": "ellipse", "x": 2e9, "y": 0.0, "width": 10.0, "height": 10.0,
+        }));
+        assert!(far.validate().is_err());
+
+        // Near the origin, but scaled out of reach
+        let scaled = shape(serde_json::json!({
+            "shape": "path", "start": [0.0, 0.0],
+            "segments": [{ "type": "cubic", "control1": [0.0, 50.0], "control2": [50.0, 50.0],
+                           "to": [50.0, 0.0] }],
+            "transform": [1000.0, 0.0, 0.0, 1000.0, 0.0, 0.0],
+        }));
+        assert!(scaled.validate().is_err());
+
+        // The stroke counts too
+        let stroked = shape(serde_json::json!({
+            "shape": "polygon", "points": [[0.0, 0.0], [29_000.0, 0.0], [0.0, 100.0]],
+            "stroke": { "width": 4000.0 },
+        }));
+        assert!(stroked.validate().is_err());
+    }
+}
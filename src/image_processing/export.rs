@@ -0,0 +1,131 @@
+// Encoding images for download in the format the client asks for
+use super::ProcessingError;
+use image::codecs::jpeg::JpegEncoder;
+use image::error::{EncodingError, ImageFormatHint};
+use image::{DynamicImage, ImageError, ImageFormat, ImageOutputFormat};
+use serde::Deserialize;
+use std::io::Cursor;
+
+// Quality used for lossy formats when the request doesn't give one
+pub const DEFAULT_QUALITY: u8 = 90;
+
+// What transparent pixels become in formats without an alpha channel
+const EXPORT_BACKGROUND: [u8; 3] = [255, 255, 255];
+
+// Largest width or height libwebp can encode
+const MAX_WEBP_DIMENSION: u32 = 16_383;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Export Formats ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// The formats an image can be exported as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat
+{
+    #[default]
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    #[serde(alias = "tif")]
+    Tiff,
+    Bmp,
+}
+
+impl ExportFormat
+{
+    pub fn content_type(&self) -> &'static str
+    {
+        self.image_format().to_mime_type()
+    }
+
+    // File extension for the Content-Disposition file name
+    pub fn extension(&self) -> &'static str
+    {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::Webp => "webp",
+            ExportFormat::Tiff => "tiff",
+            ExportFormat::Bmp => "bmp",
+        }
+    }
+
+    fn image_format(&self) -> ImageFormat
+    {
+        match self {
+            ExportFormat::Png => ImageFormat::Png,
+            ExportFormat::Jpeg => ImageFormat::Jpeg,
+            ExportFormat::Webp => ImageFormat::WebP,
+            ExportFormat::Tiff => ImageFormat::Tiff,
+            ExportFormat::Bmp => ImageFormat::Bmp,
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Export Encoding ********** ///////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// export_image: encode an image as a downloadable file ///////////////////////////
+// quality (1 to 100) only applies to JPEG and WebP, the other formats are
+// lossless. JPEG has no alpha channel, transparent pixels are blended onto
+// white. PNG, WebP, TIFF and BMP keep the alpha channel.
+pub fn export_image(image: &DynamicImage,
+                    format: ExportFormat,
+                    quality: Option<u8>)
+                    -> Result<Vec<u8>, ProcessingError>
+{
+    let quality = quality.unwrap_or(DEFAULT_QUALITY);
+    if !(1..=100).contains(&quality) {
+        return Err(ProcessingError::InvalidParameter("quality must be between 1 and 100".into()));
+    }
+
+    let mut bytes = Vec::new();
+    match format {
+        ExportFormat::Jpeg => {
+            let pixels = super::remove_alpha(&image.to_rgba8(), EXPORT_BACKGROUND);
+            JpegEncoder::new_with_quality(&mut bytes, quality).encode_image(&pixels)?;
+        }
+        ExportFormat::Webp => bytes = encode_webp(image, quality)?,
+        // BMP is 8 bits per channel only, PNG and TIFF keep 16 bit images as they are
+        ExportFormat::Bmp => {
+            let pixels = DynamicImage::ImageRgba8(image.to_rgba8());
+            pixels.write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Bmp)?;
+        }
+        ExportFormat::Png | ExportFormat::Tiff => {
+            let output_format = ImageOutputFormat::from(format.image_format());
+            image.write_to(&mut Cursor::new(&mut bytes), output_format)?;
+        }
+    }
+    Ok(bytes)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// The image crate can't write lossy WebP, libwebp does. Quality 100 is encoded
+// lossless.
+fn encode_webp(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, ProcessingError>
+{
+    let (width, height) = (image.width(), image.height());
+    if width > MAX_WEBP_DIMENSION || height > MAX_WEBP_DIMENSION {
+        return Err(ProcessingError::InvalidParameter(format!("WebP images can be at most {} \
+                                                              pixels wide and high",
+                                                             MAX_WEBP_DIMENSION)));
+    }
+
+    let pixels = image.to_rgba8();
+    let encoder = webp::Encoder::from_rgba(&pixels, width, height);
+    match encoder.encode_simple(quality == 100, quality as f32) {
+        Ok(memory) => Ok(memory.to_vec()),
+        Err(e) => {
+            let hint = ImageFormatHint::Exact(ImageFormat::WebP);
+            let err = EncodingError::new(hint, format!("libwebp failed: {:?}", e));
+            Err(ImageError::Encoding(err).into())
+        }
+    }
+}
@@ -1 +1,396 @@
-// filters and effects for images
\ No newline at end of file
+// filters and effects for images
+use super::ProcessingError;
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+// Upper bounds for blur parameters, large enough for any real edit
+const MAX_SIGMA: f32 = 250.0;
+const MAX_BOX_RADIUS: u32 = 750;
+const MAX_MOTION_LENGTH: f32 = 2000.0;
+// Samples a blur may read over the whole image, a few seconds of work. Large
+// blurs are only refused on large images.
+const MAX_BLUR_WORK: u64 = 2_000_000_000;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Blur Filters ********** //////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// The blur filters that can be applied to an image or a layer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Blur
+{
+    /// Separable Gaussian blur, `sigma` is the standard deviation in pixels.
+    Gaussian { sigma: f32 },
+    /// Average of the (2 * radius + 1) square around each pixel.
+    Box { radius: u32 },
+    /// Smear along a line `length` pixels long at `angle` degrees clockwise
+    /// from horizontal.
+    Motion { angle: f32, length: f32 },
+}
+
+// blur: apply one of the blur filters ///////////////////////////////////////////
+pub fn blur(image: &RgbaImage, blur: Blur) -> Result<RgbaImage, ProcessingError>
+{
+    match blur {
+        Blur::Gaussian { sigma } => gaussian_blur(image, sigma),
+        Blur::Box { radius } => box_blur(image, radius),
+        Blur::Motion { angle, length } => motion_blur(image, angle, length),
+    }
+}
+
+// gaussian_blur: separable Gaussian with a kernel out to 3 sigma ///////////////
+pub fn gaussian_blur(image: &RgbaImage, sigma: f32) -> Result<RgbaImage, ProcessingError>
+{
+    if !sigma.is_finite() || sigma <= 0.0 || sigma > MAX_SIGMA {
+        return Err(invalid(format!("sigma must be greater than 0 and at most {}", MAX_SIGMA)));
+    }
+
+    let radius = (sigma * 3.0).ceil() as i64;
+    // Two passes, each reading the whole kernel
+    check_work(image, 2 * (2 * radius as u64 + 1), "sigma")?;
+    let mut kernel: Vec<f32> = (-radius..=radius).map(|i| gaussian(i as f32, sigma)).collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|w| *w /= total);
+
+    let (width, height) = image.dimensions();
+    let pixels = to_premultiplied(image);
+    let horizontal = convolve_1d(&pixels, width, height, &kernel, true);
+    let vertical = convolve_1d(&horizontal, width, height, &kernel, false);
+    Ok(from_premultiplied(&vertical, width, height))
+}
+
+// box_blur: separable running-sum box filter ////////////////////////////////////
+pub fn box_blur(image: &RgbaImage, radius: u32) -> Result<RgbaImage, ProcessingError>
+{
+    if radius == 0 || radius > MAX_BOX_RADIUS {
+        return Err(invalid(format!("radius must be between 1 and {}", MAX_BOX_RADIUS)));
+    }
+    // The running sums read two pixels per pass, whatever the radius
+    check_work(image, 4, "radius")?;
+
+    let (width, height) = image.dimensions();
+    let pixels = to_premultiplied(image);
+    let horizontal = box_pass(&pixels, width, height, radius as i64, true);
+    let vertical = box_pass(&horizontal, width, height, radius as i64, false);
+    Ok(from_premultiplied(&vertical, width, height))
+}
+
+// motion_blur: average evenly spaced samples along the motion direction ////////
+pub fn motion_blur(image: &RgbaImage, angle: f32, length: f32) -> Result<RgbaImage, ProcessingError>
+{
+    if !angle.is_finite() {
+        return Err(invalid("angle must be a finite number of degrees".to_string()));
+    }
+    if !(1.0..=MAX_MOTION_LENGTH).contains(&length) {
+        return Err(invalid(format!("length must be between 1 and {}", MAX_MOTION_LENGTH)));
+    }
+
+    // One sample per pixel of travel, centred on the pixel being blurred
+    let samples = length.round().max(1.0) as usize;
+    check_work(image, samples as u64, "length")?;
+
+    let (width, height) = image.dimensions();
+    let pixels = to_premultiplied(image);
+    let (sin, cos) = angle.to_radians().sin_cos();
+
+    let offsets: Vec<(f32, f32)> = (0..samples).map(|i| {
+        let t = if samples == 1 { 0.0 } else { i as f32 / (samples - 1) as f32 - 0.5 };
+        (t * length * cos, t * length * sin)
+    }).collect();
+
+    let mut out = vec![[0.0f32; 4]; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut acc = [0.0f32; 4];
+            for (dx, dy) in &offsets {
+                let sample = bilinear_clamped(&pixels, width, height, x as f32 + dx, y as f32 + dy);
+                for c in 0..4 {
+                    acc[c] += sample[c];
+                }
+            }
+            let idx = (y * width + x) as usize;
+            for c in 0..4 {
+                out[idx][c] = acc[c] / samples as f32;
+            }
+        }
+    }
+    Ok(from_premultiplied(&out, width, height))
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+fn invalid(msg: String) -> ProcessingError
+{
+    ProcessingError::InvalidParameter(msg)
+}
+
+// Refuse a blur that would read more than MAX_BLUR_WORK samples
+fn check_work(image: &RgbaImage, samples_per_pixel: u64, parameter: &str)
+              -> Result<(), ProcessingError>
+{
+    let pixels = image.width() as u64 * image.height() as u64;
+    if pixels.saturating_mul(samples_per_pixel) > MAX_BLUR_WORK {
+        return Err(invalid(format!("{} is too large for an image this size", parameter)));
+    }
+    Ok(())
+}
+
+fn gaussian(x: f32, sigma: f32) -> f32
+{
+    (-(x * x) / (2.0 * sigma * sigma)).exp()
+}
+
+// Convert to premultiplied floats so blurring doesn't pull in the colour of
+// fully transparent pixels
+pub(crate) fn to_premultiplied(image: &RgbaImage) -> Vec<[f32; 4]>
+{
+    image.pixels()
+         .map(|p| {
+             let a = p[3] as f32 / 255.0;
+             [p[0] as f32 * a, p[1] as f32 * a, p[2] as f32 * a, p[3] as f32]
+         })
+         .collect()
+}
+
+pub(crate) fn from_premultiplied(pixels: &[[f32; 4]], width: u32, height: u32) -> RgbaImage
+{
+    RgbaImage::from_fn(width, height, |x, y| {
+        let p = pixels[(y * width + x) as usize];
+        let alpha = p[3].clamp(0.0, 255.0);
+        if alpha <= 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let a = alpha / 255.0;
+        Rgba([(p[0] / a).round().clamp(0.0, 255.0) as u8,
+              (p[1] / a).round().clamp(0.0, 255.0) as u8,
+              (p[2] / a).round().clamp(0.0, 255.0) as u8,
+              alpha.round() as u8])
+    })
+}
+
+// One pass of a separable convolution, clamping at the edges ////////////////////
+fn convolve_1d(pixels: &[[f32; 4]],
+               width: u32,
+               height: u32,
+               kernel: &[f32],
+               horizontal: bool)
+               -> Vec<[f32; 4]>
+{
+    let radius = (kernel.len() / 2) as i64;
+    let (w, h) = (width as i64, height as i64);
+    let mut out = vec![[0.0f32; 4]; pixels.len()];
+
+    for y in 0..h {
+        for x in 0..w {
+            let mut acc = [0.0f32; 4];
+            for (k, weight) in kernel.iter().enumerate() {
+                let offset = k as i64 - radius;
+                let (sx, sy) = if horizontal {
+                    ((x + offset).clamp(0, w - 1), y)
+                } else {
+                    (x, (y + offset).clamp(0, h - 1))
+                };
+                let p = pixels[(sy * w + sx) as usize];
+                for c in 0..4 {
+                    acc[c] += p[c] * weight;
+                }
+            }
+            out[(y * w + x) as usize] = acc;
+        }
+    }
+    out
+}
+
+// One pass of a box blur using a running sum, clamping at the edges //////////////
+fn box_pass(pixels: &[[f32; 4]],
+            width: u32,
+            height: u32,
+            radius: i64,
+            horizontal: bool)
+            -> Vec<[f32; 4]>
+{
+    let (w, h) = (width as i64, height as i64);
+    let (lines, len) = if horizontal { (h, w) } else { (w, h) };
+    let index = |line: i64, pos: i64| -> usize {
+        let pos = pos.clamp(0, len - 1);
+        if horizontal {
+            (line * w + pos) as usize
+        } else {
+            (pos * w + line) as usize
+        }
+    };
+    let span = (2 * radius + 1) as f32;
+    let mut out = vec![[0.0f32; 4]; pixels.len()];
+
+    for line in 0..lines {
+        let mut sum = [0.0f32; 4];
+        for pos in -radius..=radius {
+            let p = pixels[index(line, pos)];
+            for c in 0..4 {
+                sum[c] += p[c];
+            }
+        }
+        for pos in 0..len {
+            let target = &mut out[index(line, pos)];
+            for c in 0..4 {
+                target[c] = sum[c] / span;
+            }
+            let leaving = pixels[index(line, pos - radius)];
+            let entering = pixels[index(line, pos + radius + 1)];
+            for c in 0..4 {
+                sum[c] += entering[c] - leaving[c];
+            }
+        }
+    }
+    out
+}
+
+// Bilinear sample of a premultiplied buffer, clamping at the edges //////////////
+fn bilinear_clamped(pixels: &[[f32; 4]], width: u32, height: u32, x: f32, y: f32) -> [f32; 4]
+{
+    let x = x.clamp(0.0, (width - 1) as f32);
+    let y = y.clamp(0.0, (height - 1) as f32);
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let at = |px: u32, py: u32| pixels[(py * width + px) as usize];
+    let (p00, p10, p01, p11) = (at(x0, y0), at(x1, y0), at(x0, y1), at(x1, y1));
+    let mut out = [0.0f32; 4];
+    for c in 0..4 {
+        let top = p00[c] + (p10[c] - p00[c]) * fx;
+        let bottom = p01[c] + (p11[c] - p01[c]) * fx;
+        out[c] = top + (bottom - top) * fy;
+    }
+    out
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const BLURS: [Blur; 3] = [Blur::Gaussian { sigma: 2.0 },
+                              Blur::Box { radius: 2 },
+                              Blur::Motion { angle: 30.0, length: 7.0 }];
+
+    // A single white pixel in the middle of a black square
+    fn impulse(size: u32) -> RgbaImage
+    {
+        let mut image = RgbaImage::from_pixel(size, size, Rgba([0, 0, 0, 255]));
+        image.put_pixel(size / 2, size / 2, Rgba([255, 255, 255, 255]));
+        image
+    }
+
+    #[test]
+    fn flat_images_stay_flat()
+    {
+        let image = RgbaImage::from_pixel(9, 7, Rgba([40, 120, 200, 180]));
+        for kind in BLURS {
+            assert_eq!(blur(&image, kind).unwrap(), image, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn premultiplied_round_trip()
+    {
+        let image = RgbaImage::from_fn(4, 4, |x, y| {
+            Rgba([x as u8 * 60, y as u8 * 60, 200, if x == 0 { 0 } else { 255 }])
+        });
+        let restored = from_premultiplied(&to_premultiplied(&image), 4, 4);
+        for (x, y, pixel) in restored.enumerate_pixels() {
+            let expected = if x == 0 { Rgba([0, 0, 0, 0]) } else { *image.get_pixel(x, y) };
+            assert_eq!(*pixel, expected);
+        }
+    }
+
+    #[test]
+    fn transparent_pixels_do_not_bleed_their_colour()
+    {
+        // Opaque red next to fully transparent green
+        let image = RgbaImage::from_fn(12, 4, |x, _| {
+            if x < 6 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 255, 0, 0]) }
+        });
+        for kind in BLURS {
+            let blurred = blur(&image, kind).unwrap();
+            for pixel in blurred.pixels().filter(|pixel| pixel[3] > 0) {
+                assert_eq!((pixel[0], pixel[1]), (255, 0), "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn box_blur_averages_the_square()
+    {
+        let blurred = box_blur(&impulse(5), 1).unwrap();
+        for (x, y, pixel) in blurred.enumerate_pixels() {
+            let inside = (1..=3).contains(&x) && (1..=3).contains(&y);
+            assert_eq!(pixel[0], if inside { 28 } else { 0 }, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn gaussian_blur_keeps_the_total_brightness()
+    {
+        let blurred = gaussian_blur(&impulse(31), 2.0).unwrap();
+        // The far tails round down to zero, so a little is lost
+        let total: u32 = blurred.pixels().map(|pixel| pixel[0] as u32).sum();
+        assert!((225..=260).contains(&total), "total was {}", total);
+        let centre = blurred.get_pixel(15, 15)[0];
+        assert!(blurred.pixels().all(|pixel| pixel[0] <= centre));
+        // Symmetric around the impulse
+        assert_eq!(blurred.get_pixel(13, 15), blurred.get_pixel(17, 15));
+        assert_eq!(blurred.get_pixel(15, 13), blurred.get_pixel(15, 17));
+    }
+
+    #[test]
+    fn horizontal_motion_blur_stays_on_its_row()
+    {
+        // Samples run from -2.5 to 2.5 pixels, bilinear sampling reaches one further
+        let blurred = motion_blur(&impulse(11), 0.0, 5.0).unwrap();
+        for (x, y, pixel) in blurred.enumerate_pixels() {
+            let lit = y == 5 && (2..=8).contains(&x);
+            assert_eq!(pixel[0] > 0, lit, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn out_of_range_parameters_are_rejected()
+    {
+        let image = impulse(3);
+        assert!(gaussian_blur(&image, 0.0).is_err());
+        assert!(gaussian_blur(&image, f32::NAN).is_err());
+        assert!(gaussian_blur(&image, MAX_SIGMA + 1.0).is_err());
+        assert!(box_blur(&image, 0).is_err());
+        assert!(motion_blur(&image, f32::INFINITY, 5.0).is_err());
+        assert!(motion_blur(&image, 0.0, 0.5).is_err());
+    }
+
+    #[test]
+    fn blurs_too_large_for_the_image_are_rejected()
+    {
+        // 4 million pixels, the limits are only hit on big images
+        let image = RgbaImage::new(2000, 2000);
+        let blurs = [Blur::Gaussian { sigma: MAX_SIGMA },
+                     Blur::Motion { angle: 0.0, length: 1000.0 }];
+        for kind in blurs {
+            match blur(&image, kind) {
+                Err(ProcessingError::InvalidParameter(msg)) => {
+                    assert!(msg.contains("too large"), "{:?}: {}", kind, msg)
+                }
+                other => panic!("{:?} was not rejected: {:?}", kind, other.map(|_| ())),
+            }
+        }
+        // The same settings are fine on a small image
+        let small = impulse(20);
+        assert!(gaussian_blur(&small, MAX_SIGMA).is_ok());
+        assert!(motion_blur(&small, 0.0, 1000.0).is_ok());
+    }
+}
@@ -0,0 +1,77 @@
+// layer masks, grayscale images that hide parts of a layer without erasing it
+use super::draw::{self, Brush, StrokePoint};
+use super::ProcessingError;
+use image::{DynamicImage, GrayImage, ImageFormat, ImageResult, Luma, Rgba, RgbaImage};
+use std::io::Cursor;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Mask Storage ********** //////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// decode_mask_data: masks are stored as an 8-bit grayscale PNG //////////////////
+pub fn decode_mask_data(mask_data: &[u8]) -> ImageResult<GrayImage>
+{
+    Ok(image::load_from_memory(mask_data)?.to_luma8())
+}
+
+// encode_mask_data: encode a mask for the layers.mask_data column ///////////////
+pub fn encode_mask_data(mask: &GrayImage) -> ImageResult<Vec<u8>>
+{
+    let mut bytes = Vec::new();
+    mask.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Mask Editing ********** //////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// mask_value: how much of a layer pixel shows through its mask //////////////////
+// White reveals and black hides. The mask is anchored at the top left corner
+// like the layer, and pixels past its edge are revealed.
+pub fn mask_value(mask: &GrayImage, inverted: bool, x: u32, y: u32) -> u8
+{
+    let value = if x < mask.width() && y < mask.height() { mask.get_pixel(x, y)[0] } else { 255 };
+    if inverted {
+        255 - value
+    } else {
+        value
+    }
+}
+
+// paint_mask: paint a brush stroke onto a mask //////////////////////////////////
+// The brush colour is converted to gray, so painting white reveals the layer
+// and painting black hides it.
+pub fn paint_mask(mask: &mut GrayImage,
+                  brush: &Brush,
+                  points: &[StrokePoint])
+                  -> Result<(), ProcessingError>
+{
+    let mut pixels = RgbaImage::from_fn(mask.width(), mask.height(), |x, y| {
+        let value = mask.get_pixel(x, y)[0];
+        Rgba([value, value, value, 255])
+    });
+
+    // Erasing would only touch alpha, which a mask doesn't have
+    let mut brush = brush.clone();
+    brush.eraser = false;
+    draw::draw_stroke(&mut pixels, &brush, points)?;
+
+    *mask = DynamicImage::ImageRgba8(pixels).to_luma8();
+    Ok(())
+}
+
+// apply_mask: bake a mask into the alpha channel of a layer /////////////////////
+pub fn apply_mask(pixels: &mut RgbaImage, mask: &GrayImage, inverted: bool)
+{
+    for (x, y, pixel) in pixels.enumerate_pixels_mut() {
+        let value = mask_value(mask, inverted, x, y) as u32;
+        pixel[3] = ((pixel[3] as u32 * value + 127) / 255) as u8;
+    }
+}
+
+// reveal_all: a white mask that hides nothing ///////////////////////////////////
+pub fn reveal_all(width: u32, height: u32) -> GrayImage
+{
+    GrayImage::from_pixel(width, height, Luma([255]))
+}
@@ -1 +1,96 @@
-// module delcaration and common functionalities
\ No newline at end of file
+// module delcaration and common functionalities
+pub mod adjust;
+pub mod composite;
+pub mod draw;
+pub mod export;
+pub mod filter;
+pub mod mask;
+pub mod selection;
+pub mod text;
+pub mod thumbnail;
+pub mod transform;
+pub mod upload;
+
+use image::{io::Reader as ImageReader, DynamicImage, ImageError, ImageFormat, ImageResult, Rgb,
+            RgbImage, RgbaImage};
+use std::fmt;
+use std::io::Cursor;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Image File Helpers ********** ////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// decode_image: decode an image file, sniffing the format from its contents /////
+// Files are stored by their hash, so the format can't be taken from a file name.
+pub fn decode_image(data: &[u8]) -> ImageResult<DynamicImage>
+{
+    ImageReader::new(Cursor::new(data)).with_guessed_format()?.decode()
+}
+
+// encode_png: encode an edited image, edits are always stored as PNG ////////////
+pub fn encode_png(image: &DynamicImage) -> ImageResult<Vec<u8>>
+{
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+// decode_layer_data: layers store their pixels as an encoded PNG ////////////////
+pub fn decode_layer_data(layer_data: &[u8]) -> ImageResult<RgbaImage>
+{
+    Ok(image::load_from_memory(layer_data)?.to_rgba8())
+}
+
+// encode_layer_data: encode pixels for the layers.layer_data column /////////////
+pub fn encode_layer_data(pixels: &RgbaImage) -> ImageResult<Vec<u8>>
+{
+    let mut bytes = Vec::new();
+    pixels.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    Ok(bytes)
+}
+
+// remove_alpha: blend every pixel over a solid background colour ///////////////
+// For formats without an alpha channel, e.g. JPEG.
+pub fn remove_alpha(image: &RgbaImage, background: [u8; 3]) -> RgbImage
+{
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let alpha = a as u32;
+        let blend = |channel: u8, background: u8| {
+            ((channel as u32 * alpha + background as u32 * (255 - alpha) + 127) / 255) as u8
+        };
+        Rgb([blend(r, background[0]), blend(g, background[1]), blend(b, background[2])])
+    })
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Error Handling ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug)]
+pub enum ProcessingError
+{
+    // The image file couldn't be read, decoded or written
+    Image(ImageError),
+    // The requested operation doesn't make sense for this image
+    InvalidParameter(String),
+}
+
+impl From<ImageError> for ProcessingError
+{
+    fn from(err: ImageError) -> ProcessingError
+    {
+        ProcessingError::Image(err)
+    }
+}
+
+impl std::error::Error for ProcessingError {}
+impl fmt::Display for ProcessingError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            ProcessingError::Image(e) => write!(f, "Image error: {}", e),
+            ProcessingError::InvalidParameter(msg) => write!(f, "Invalid parameter: {}", msg),
+        }
+    }
+}
@@ -1 +1,784 @@
-// selection tools like marquee, lasso, etc. 
\ No newline at end of file
+// selection tools like marquee, lasso, etc.
+use super::ProcessingError;
+use image::{imageops, GrayImage, Luma, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+// Sub-scanlines per pixel row when rasterizing shapes, for anti-aliasing
+const SUBSAMPLES: usize = 4;
+const MAX_POLYGON_POINTS: usize = 100_000;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Selection Mask ********** /////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// An 8-bit selection mask the size of the canvas. 255 is fully selected,
+/// 0 is not selected, anything in between is partially selected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionMask
+{
+    mask: GrayImage,
+}
+
+impl SelectionMask
+{
+    // empty: nothing selected ///////////////////////////////////////////////////
+    pub fn empty(width: u32, height: u32) -> SelectionMask
+    {
+        SelectionMask { mask: GrayImage::new(width, height) }
+    }
+
+    // rectangle: rectangular marquee ////////////////////////////////////////////
+    pub fn rectangle(width: u32, height: u32, x: f32, y: f32, w: f32, h: f32) -> SelectionMask
+    {
+        let corners = [[x, y], [x + w, y], [x + w, y + h], [x, y + h]];
+        SelectionMask::polygon(width, height, &corners)
+    }
+
+    // ellipse: elliptical marquee inscribed in the given rectangle //////////////
+    pub fn ellipse(width: u32, height: u32, x: f32, y: f32, w: f32, h: f32) -> SelectionMask
+    {
+        let (rx, ry) = (w / 2.0, h / 2.0);
+        let (cx, cy) = (x + rx, y + ry);
+        // Enough segments that the polygon edges stay under a pixel
+        let segments = ((rx.abs() + ry.abs()) * std::f32::consts::PI).ceil().clamp(16.0, 4096.0) as usize;
+        let points: Vec<[f32; 2]> = (0..segments).map(|i| {
+            let theta = i as f32 / segments as f32 * std::f32::consts::TAU;
+            [cx + rx * theta.cos(), cy + ry * theta.sin()]
+        }).collect();
+        SelectionMask::polygon(width, height, &points)
+    }
+
+    // polygon: polygon lasso, filled with the non-zero winding rule /////////////
+    pub fn polygon(width: u32, height: u32, points: &[[f32; 2]]) -> SelectionMask
+    {
+        let mut selection = SelectionMask::empty(width, height);
+        if points.len() < 3 {
+            return selection;
+        }
+
+        let edges: Vec<([f32; 2], [f32; 2])> =
+            points.iter().zip(points.iter().cycle().skip(1)).map(|(a, b)| (*a, *b)).collect();
+        let mut coverage = vec![0.0f32; width as usize];
+        let mut crossings: Vec<(f32, i32)> = Vec::new();
+
+        for y in 0..height {
+            coverage.iter_mut().for_each(|c| *c = 0.0);
+            for sub in 0..SUBSAMPLES {
+                let sy = y as f32 + (sub as f32 + 0.5) / SUBSAMPLES as f32;
+
+                // Where each edge crosses this sub-scanline, with its direction
+                crossings.clear();
+                for (a, b) in &edges {
+                    let (top, bottom, winding) = if a[1] < b[1] { (a, b, 1) } else { (b, a, -1) };
+                    if sy < top[1] || sy >= bottom[1] {
+                        continue;
+                    }
+                    let t = (sy - top[1]) / (bottom[1] - top[1]);
+                    crossings.push((top[0] + (bottom[0] - top[0]) * t, winding));
+                }
+                crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                let mut winding = 0;
+                for pair in crossings.windows(2) {
+                    winding += pair[0].1;
+                    if winding != 0 {
+                        fill_span(&mut coverage, pair[0].0, pair[1].0, 1.0 / SUBSAMPLES as f32);
+                    }
+                }
+            }
+            for (x, cover) in coverage.iter().enumerate() {
+                let value = (cover.min(1.0) * 255.0).round() as u8;
+                selection.mask.put_pixel(x as u32, y, Luma([value]));
+            }
+        }
+        selection
+    }
+
+    // magic_wand: select pixels within `tolerance` of the colour at (x, y) //////
+    // With `contiguous` only pixels connected to the seed (4-way) are selected,
+    // otherwise every matching pixel in the image is.
+    pub fn magic_wand(source: &RgbaImage,
+                      x: u32,
+                      y: u32,
+                      tolerance: u8,
+                      contiguous: bool)
+                      -> Result<SelectionMask, ProcessingError>
+    {
+        let (width, height) = source.dimensions();
+        if x >= width || y >= height {
+            return Err(ProcessingError::InvalidParameter(format!(
+                "magic wand point ({}, {}) is outside the {}x{} image",
+                x, y, width, height
+            )));
+        }
+
+        let seed = *source.get_pixel(x, y);
+        let matches = |px: u32, py: u32| {
+            let p = source.get_pixel(px, py);
+            (0..4).all(|c| p[c].abs_diff(seed[c]) <= tolerance)
+        };
+
+        let mut selection = SelectionMask::empty(width, height);
+        if !contiguous {
+            for (px, py, value) in selection.mask.enumerate_pixels_mut() {
+                if matches(px, py) {
+                    value[0] = 255;
+                }
+            }
+            return Ok(selection);
+        }
+
+        // Flood fill with an explicit stack
+        let mut stack = vec![(x, y)];
+        selection.mask.put_pixel(x, y, Luma([255]));
+        while let Some((px, py)) = stack.pop() {
+            // Wrapping at 0 lands far outside the image and gets skipped
+            let neighbours = [(px.wrapping_sub(1), py),
+                              (px + 1, py),
+                              (px, py.wrapping_sub(1)),
+                              (px, py + 1)];
+            for (nx, ny) in neighbours {
+                if nx >= width || ny >= height || selection.value(nx, ny) == 255 {
+                    continue;
+                }
+                if matches(nx, ny) {
+                    selection.mask.put_pixel(nx, ny, Luma([255]));
+                    stack.push((nx, ny));
+                }
+            }
+        }
+        Ok(selection)
+    }
+
+    // color_range: soft selection by distance to a target colour ////////////////
+    // Pixels matching `color` are fully selected, fading out to unselected at an
+    // RGB distance of `fuzziness`. Transparent pixels are never selected.
+    pub fn color_range(source: &RgbaImage, color: [u8; 3], fuzziness: f32) -> SelectionMask
+    {
+        let fuzziness = fuzziness.max(f32::EPSILON);
+        let mut selection = SelectionMask::empty(source.width(), source.height());
+        for (value, p) in selection.mask.pixels_mut().zip(source.pixels()) {
+            let distance =
+                (0..3).map(|c| (p[c] as f32 - color[c] as f32).powi(2)).sum::<f32>().sqrt();
+            let weight = (1.0 - distance / fuzziness).clamp(0.0, 1.0) * p[3] as f32 / 255.0;
+            value[0] = (weight * 255.0).round() as u8;
+        }
+        selection
+    }
+
+    // value: how selected a pixel is, from 0 to 255 /////////////////////////////
+    pub fn value(&self, x: u32, y: u32) -> u8
+    {
+        self.mask.get_pixel(x, y)[0]
+    }
+
+    // into_gray: hand the mask over, e.g. to become a layer mask ////////////////
+    pub fn into_gray(self) -> GrayImage
+    {
+        self.mask
+    }
+
+    //////////////////////////////////////////////////////////////////////////////
+    // Boolean combination ///////////////////////////////////////////////////////
+    //////////////////////////////////////////////////////////////////////////////
+
+    pub fn union(&mut self, other: &SelectionMask) -> Result<(), ProcessingError>
+    {
+        self.combine(other, |a, b| a.max(b))
+    }
+
+    pub fn subtract(&mut self, other: &SelectionMask) -> Result<(), ProcessingError>
+    {
+        self.combine(other, |a, b| a.min(255 - b))
+    }
+
+    pub fn intersect(&mut self, other: &SelectionMask) -> Result<(), ProcessingError>
+    {
+        self.combine(other, |a, b| a.min(b))
+    }
+
+    pub fn invert(&mut self)
+    {
+        self.mask.pixels_mut().for_each(|p| p[0] = 255 - p[0]);
+    }
+
+    fn combine<F: Fn(u8, u8) -> u8>(&mut self,
+                                    other: &SelectionMask,
+                                    op: F)
+                                    -> Result<(), ProcessingError>
+    {
+        if self.mask.dimensions() != other.mask.dimensions() {
+            return Err(ProcessingError::InvalidParameter(
+                "selections must be the same size to be combined".to_string(),
+            ));
+        }
+        for (a, b) in self.mask.pixels_mut().zip(other.mask.pixels()) {
+            a[0] = op(a[0], b[0]);
+        }
+        Ok(())
+    }
+
+    //////////////////////////////////////////////////////////////////////////////
+    // Edge refinement ///////////////////////////////////////////////////////////
+    //////////////////////////////////////////////////////////////////////////////
+
+    // feather: soften the edge with a Gaussian of the given radius //////////////
+    pub fn feather(&mut self, radius: f32)
+    {
+        if radius > 0.0 {
+            self.mask = imageops::blur(&self.mask, radius);
+        }
+    }
+
+    // grow: expand (positive) or contract (negative) the selection by `amount`
+    // pixels, measured with a Euclidean distance transform
+    pub fn grow(&mut self, amount: f32)
+    {
+        if amount == 0.0 {
+            return;
+        }
+        let (width, height) = self.mask.dimensions();
+
+        if amount > 0.0 {
+            let distance = distance_transform(width, height, |x, y| self.value(x, y) >= 128);
+            for (pixel, d) in self.mask.pixels_mut().zip(distance) {
+                let reach = (amount + 1.0 - d.sqrt()).clamp(0.0, 1.0);
+                pixel[0] = pixel[0].max((reach * 255.0).round() as u8);
+            }
+        } else {
+            let distance = distance_transform(width, height, |x, y| self.value(x, y) < 128);
+            for (pixel, d) in self.mask.pixels_mut().zip(distance) {
+                let keep = (d.sqrt() + amount).clamp(0.0, 1.0);
+                pixel[0] = pixel[0].min((keep * 255.0).round() as u8);
+            }
+        }
+    }
+
+    // restrict: keep `edited` only where selected, `original` elsewhere /////////
+    pub fn restrict(&self, original: &RgbaImage, edited: &mut RgbaImage) -> Result<(), ProcessingError>
+    {
+        if original.dimensions() != self.mask.dimensions()
+           || edited.dimensions() != self.mask.dimensions()
+        {
+            return Err(ProcessingError::InvalidParameter(
+                "the selection doesn't match the size of the image".to_string(),
+            ));
+        }
+        for ((out, before), m) in edited.pixels_mut().zip(original.pixels()).zip(self.mask.pixels()) {
+            let t = m[0] as f32 / 255.0;
+            for c in 0..4 {
+                let mixed = before[c] as f32 + (out[c] as f32 - before[c] as f32) * t;
+                out[c] = mixed.round() as u8;
+            }
+        }
+        Ok(())
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Selection Description ********** /////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// A selection shape as sent by the client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum SelectionShape
+{
+    Rectangle { x: f32, y: f32, width: f32, height: f32 },
+    /// Ellipse inscribed in the rectangle.
+    Ellipse { x: f32, y: f32, width: f32, height: f32 },
+    /// Lasso / polygon lasso, as a list of [x, y] points.
+    Polygon { points: Vec<[f32; 2]> },
+    /// Flood fill from a seed point, see SelectionMask::magic_wand.
+    MagicWand
+    {
+        x: u32,
+        y: u32,
+        #[serde(default = "default_tolerance")]
+        tolerance: u8,
+        #[serde(default = "yes")]
+        contiguous: bool,
+        #[serde(default)]
+        sample: SampleSource,
+    },
+    /// Soft selection around a colour, see SelectionMask::color_range.
+    ColorRange
+    {
+        color: [u8; 3],
+        #[serde(default = "default_fuzziness")]
+        fuzziness: f32,
+        #[serde(default)]
+        sample: SampleSource,
+    },
+}
+
+/// Which pixels the magic wand and colour range sample from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleSource
+{
+    /// The layer (or image) being edited.
+    #[default]
+    Layer,
+    /// The flattened image with all layers.
+    Composite,
+}
+
+fn default_tolerance() -> u8
+{
+    32
+}
+
+fn default_fuzziness() -> f32
+{
+    40.0
+}
+
+fn yes() -> bool
+{
+    true
+}
+
+/// How a shape is combined with the selection built so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionOp
+{
+    #[default]
+    Add,
+    Subtract,
+    Intersect,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelectionPart
+{
+    #[serde(default)]
+    pub op: SelectionOp,
+    #[serde(flatten)]
+    pub shape: SelectionShape,
+}
+
+/// A full selection: shapes combined in order, then grown or shrunk,
+/// feathered and optionally inverted.
+///
+/// # Example
+///
+/// { "parts": [ { "shape": "rectangle", "x": 0, "y": 0, "width": 200, "height": 100 },
+///              { "op": "subtract", "shape": "ellipse", "x": 50, "y": 20, "width": 40, "height": 40 } ],
+///   "grow": 2, "feather": 4, "invert": false }
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Selection
+{
+    pub parts: Vec<SelectionPart>,
+    /// Pixels to expand by, negative to contract.
+    #[serde(default)]
+    pub grow: f32,
+    /// Feather radius in pixels.
+    #[serde(default)]
+    pub feather: f32,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+impl Selection
+{
+    // to_mask: rasterize the selection for the pixels being edited ///////////////
+    // `composite` is the flattened image for shapes that sample from it; pass
+    // None when `layer` already is the whole image.
+    pub fn to_mask(&self,
+                   layer: &RgbaImage,
+                   composite: Option<&RgbaImage>)
+                   -> Result<SelectionMask, ProcessingError>
+    {
+        self.validate()?;
+
+        let (width, height) = layer.dimensions();
+        let composite = composite.unwrap_or(layer);
+        if composite.dimensions() != (width, height) {
+            return Err(ProcessingError::InvalidParameter(
+                "the layer and the composite image are different sizes".to_string(),
+            ));
+        }
+        let source = |sample: &SampleSource| match sample {
+            SampleSource::Layer => layer,
+            SampleSource::Composite => composite,
+        };
+
+        let mut mask = SelectionMask::empty(width, height);
+        for part in &self.parts {
+            let shape = match &part.shape {
+                SelectionShape::Rectangle { x, y, width: w, height: h } => {
+                    SelectionMask::rectangle(width, height, *x, *y, *w, *h)
+                }
+                SelectionShape::Ellipse { x, y, width: w, height: h } => {
+                    SelectionMask::ellipse(width, height, *x, *y, *w, *h)
+                }
+                SelectionShape::Polygon { points } => SelectionMask::polygon(width, height, points),
+                SelectionShape::MagicWand { x, y, tolerance, contiguous, sample } => {
+                    SelectionMask::magic_wand(source(sample), *x, *y, *tolerance, *contiguous)?
+                }
+                SelectionShape::ColorRange { color, fuzziness, sample } => {
+                    SelectionMask::color_range(source(sample), *color, *fuzziness)
+                }
+            };
+            match part.op {
+                SelectionOp::Add => mask.union(&shape)?,
+                SelectionOp::Subtract => mask.subtract(&shape)?,
+                SelectionOp::Intersect => mask.intersect(&shape)?,
+            }
+        }
+
+        mask.grow(self.grow);
+        mask.feather(self.feather);
+        if self.invert {
+            mask.invert();
+        }
+        Ok(mask)
+    }
+
+    // needs_composite: whether any shape samples from the flattened image ///////
+    pub fn needs_composite(&self) -> bool
+    {
+        self.parts.iter().any(|part| {
+            matches!(part.shape,
+                     SelectionShape::MagicWand { sample: SampleSource::Composite, .. }
+                     | SelectionShape::ColorRange { sample: SampleSource::Composite, .. })
+        })
+    }
+
+    fn validate(&self) -> Result<(), ProcessingError>
+    {
+        let invalid = |msg: &str| Err(ProcessingError::InvalidParameter(msg.to_string()));
+
+        if !(-500.0..=500.0).contains(&self.grow) {
+            return invalid("grow must be between -500 and 500 pixels");
+        }
+        if !(0.0..=250.0).contains(&self.feather) {
+            return invalid("feather must be between 0 and 250 pixels");
+        }
+        for part in &self.parts {
+            let finite = match &part.shape {
+                SelectionShape::Rectangle { x, y, width, height }
+                | SelectionShape::Ellipse { x, y, width, height } => {
+                    [x, y, width, height].iter().all(|v| v.is_finite())
+                }
+                SelectionShape::Polygon { points } => {
+                    if points.len() > MAX_POLYGON_POINTS {
+                        return invalid("a polygon can have at most 100000 points");
+                    }
+                    points.iter().flatten().all(|v| v.is_finite())
+                }
+                SelectionShape::MagicWand { .. } => true,
+                SelectionShape::ColorRange { fuzziness, .. } => {
+                    if !(0.0..=442.0).contains(fuzziness) {
+                        return invalid("fuzziness must be between 0 and 442");
+                    }
+                    true
+                }
+            };
+            if !finite {
+                return invalid("selection coordinates must be finite numbers");
+            }
+        }
+        Ok(())
+    }
+}
+
+// restrict_to_selection: limit an edit to the selected area, if there is one ////
+pub fn restrict_to_selection(selection: Option<&Selection>,
+                             original: &RgbaImage,
+                             composite: Option<&RgbaImage>,
+                             mut edited: RgbaImage)
+                             -> Result<RgbaImage, ProcessingError>
+{
+    if let Some(selection) = selection {
+        let mask = selection.to_mask(original, composite)?;
+        mask.restrict(original, &mut edited)?;
+    }
+    Ok(edited)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// Add horizontal coverage for the span [x0, x1) to a row, with partial pixels
+// at both ends
+fn fill_span(row: &mut [f32], x0: f32, x1: f32, weight: f32)
+{
+    let width = row.len() as f32;
+    let (x0, x1) = (x0.clamp(0.0, width), x1.clamp(0.0, width));
+    if x1 <= x0 {
+        return;
+    }
+
+    let first = x0.floor() as usize;
+    let last = (x1.ceil() as usize).min(row.len());
+    for (x, cell) in row.iter_mut().enumerate().take(last).skip(first) {
+        let left = x0.max(x as f32);
+        let right = x1.min(x as f32 + 1.0);
+        if right > left {
+            *cell += (right - left) * weight;
+        }
+    }
+}
+
+// Squared Euclidean distance from every pixel to the nearest pixel where `seed`
+// is true (Felzenszwalb & Huttenlocher), run along columns then rows
+fn distance_transform<F: Fn(u32, u32) -> bool>(width: u32, height: u32, seed: F) -> Vec<f32>
+{
+    let (w, h) = (width as usize, height as usize);
+    let mut grid: Vec<f32> = (0..h).flat_map(|y| (0..w).map(move |x| (x, y)))
+                                   .map(|(x, y)| if seed(x as u32, y as u32) { 0.0 } else { f32::INFINITY })
+                                   .collect();
+
+    let longest = w.max(h);
+    let mut line = vec![0.0f32; longest];
+    let mut out = vec![0.0f32; longest];
+
+    for x in 0..w {
+        for y in 0..h {
+            line[y] = grid[y * w + x];
+        }
+        distance_1d(&line[..h], &mut out[..h]);
+        for y in 0..h {
+            grid[y * w + x] = out[y];
+        }
+    }
+    for y in 0..h {
+        line[..w].copy_from_slice(&grid[y * w..(y + 1) * w]);
+        distance_1d(&line[..w], &mut out[..w]);
+        grid[y * w..(y + 1) * w].copy_from_slice(&out[..w]);
+    }
+    grid
+}
+
+// 1D squared distance transform by the lower envelope of parabolas ////////////
+fn distance_1d(f: &[f32], d: &mut [f32])
+{
+    let n = f.len();
+    let mut vertices = vec![0usize; n];
+    let mut bounds = vec![0.0f32; n + 1];
+    let mut k = 0usize;
+    let mut found = false;
+
+    for q in 0..n {
+        if f[q].is_infinite() {
+            continue;
+        }
+        if !found {
+            vertices[0] = q;
+            bounds[0] = f32::NEG_INFINITY;
+            bounds[1] = f32::INFINITY;
+            found = true;
+            continue;
+        }
+        // Drop parabolas that the new one hides, bounds[0] is -inf so this stops
+        let mut s;
+        loop {
+            let v = vertices[k];
+            s = ((f[q] + (q * q) as f32) - (f[v] + (v * v) as f32)) / (2.0 * (q as f32 - v as f32));
+            if s > bounds[k] {
+                break;
+            }
+            k -= 1;
+        }
+        k += 1;
+        vertices[k] = q;
+        bounds[k] = s;
+        bounds[k + 1] = f32::INFINITY;
+    }
+
+    if !found {
+        d.iter_mut().for_each(|v| *v = f32::INFINITY);
+        return;
+    }
+
+    k = 0;
+    for (q, out) in d.iter_mut().enumerate() {
+        while bounds[k + 1] < q as f32 {
+            k += 1;
+        }
+        let v = vertices[k];
+        let diff = q as f32 - v as f32;
+        *out = diff * diff + f[v];
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use image::Rgba;
+
+    fn selected_count(selection: &SelectionMask) -> usize
+    {
+        selection.mask.pixels().filter(|p| p[0] >= 128).count()
+    }
+
+    #[test]
+    fn pixel_aligned_rectangle_is_hard_edged()
+    {
+        let selection = SelectionMask::rectangle(10, 10, 2.0, 3.0, 4.0, 5.0);
+        for (x, y, value) in selection.mask.enumerate_pixels() {
+            let inside = (2..6).contains(&x) && (3..8).contains(&y);
+            assert_eq!(value[0], if inside { 255 } else { 0 }, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn half_covered_pixels_are_half_selected()
+    {
+        let selection = SelectionMask::rectangle(4, 1, 0.0, 0.0, 1.5, 1.0);
+        assert_eq!(selection.value(0, 0), 255);
+        assert_eq!(selection.value(1, 0), 128);
+        assert_eq!(selection.value(2, 0), 0);
+    }
+
+    #[test]
+    fn ellipse_covers_its_area()
+    {
+        let selection = SelectionMask::ellipse(64, 64, 2.0, 2.0, 60.0, 60.0);
+        let area: f32 = selection.mask.pixels().map(|p| p[0] as f32 / 255.0).sum();
+        let expected = std::f32::consts::PI * 30.0 * 30.0;
+        assert!((area - expected).abs() / expected < 0.01, "area was {}", area);
+    }
+
+    #[test]
+    fn polygon_uses_the_non_zero_winding_rule()
+    {
+        // A pentagram: with non-zero winding the middle is filled too
+        let points: Vec<[f32; 2]> = (0..5).map(|i| {
+            let theta = (i * 2) as f32 * std::f32::consts::TAU / 5.0 - std::f32::consts::FRAC_PI_2;
+            [50.0 + 40.0 * theta.cos(), 50.0 + 40.0 * theta.sin()]
+        }).collect();
+        let selection = SelectionMask::polygon(100, 100, &points);
+        assert_eq!(selection.value(50, 50), 255);
+        assert_eq!(selection.value(2, 2), 0);
+        // Fewer than three points select nothing
+        assert_eq!(selected_count(&SelectionMask::polygon(10, 10, &[[0.0, 0.0], [9.0, 9.0]])), 0);
+    }
+
+    #[test]
+    fn boolean_combinations()
+    {
+        let left = SelectionMask::rectangle(4, 1, 0.0, 0.0, 2.0, 1.0);
+        let middle = SelectionMask::rectangle(4, 1, 1.0, 0.0, 2.0, 1.0);
+        let values = |selection: &SelectionMask| -> Vec<u8> {
+            (0..4).map(|x| selection.value(x, 0)).collect()
+        };
+
+        let mut union = left.clone();
+        union.union(&middle).unwrap();
+        assert_eq!(values(&union), [255, 255, 255, 0]);
+
+        let mut subtract = left.clone();
+        subtract.subtract(&middle).unwrap();
+        assert_eq!(values(&subtract), [255, 0, 0, 0]);
+
+        let mut intersect = left.clone();
+        intersect.intersect(&middle).unwrap();
+        assert_eq!(values(&intersect), [0, 255, 0, 0]);
+
+        let mut inverted = left.clone();
+        inverted.invert();
+        assert_eq!(values(&inverted), [0, 0, 255, 255]);
+
+        let mut mismatched = left;
+        assert!(mismatched.union(&SelectionMask::empty(5, 1)).is_err());
+    }
+
+    #[test]
+    fn distance_transform_matches_brute_force()
+    {
+        let (width, height) = (23u32, 17u32);
+        let seed = |x: u32, y: u32| (x * 7 + y * 13).is_multiple_of(29) || (x == 20 && y > 10);
+        let distance = distance_transform(width, height, seed);
+
+        let seeds: Vec<(u32, u32)> = (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+                                                .filter(|&(x, y)| seed(x, y))
+                                                .collect();
+        for y in 0..height {
+            for x in 0..width {
+                let expected = seeds.iter()
+                                    .map(|&(sx, sy)| {
+                                        let (dx, dy) = (x as f32 - sx as f32, y as f32 - sy as f32);
+                                        dx * dx + dy * dy
+                                    })
+                                    .fold(f32::INFINITY, f32::min);
+                assert_eq!(distance[(y * width + x) as usize], expected, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn distance_transform_without_seeds_is_infinite()
+    {
+        assert!(distance_transform(4, 3, |_, _| false).iter().all(|d| d.is_infinite()));
+    }
+
+    #[test]
+    fn grow_and_shrink_by_whole_pixels()
+    {
+        let square = SelectionMask::rectangle(20, 20, 5.0, 5.0, 10.0, 10.0);
+
+        let mut grown = square.clone();
+        grown.grow(2.0);
+        assert_eq!(grown.value(3, 10), 255);
+        assert_eq!(grown.value(2, 10), 0);
+        // Corners are rounded, (3, 3) is sqrt(8) away from the square
+        assert!(grown.value(3, 3) < 128);
+
+        let mut shrunk = square;
+        shrunk.grow(-2.0);
+        assert_eq!(shrunk.value(7, 10), 255);
+        assert_eq!(shrunk.value(6, 10), 0);
+        assert_eq!(selected_count(&shrunk), 36);
+    }
+
+    #[test]
+    fn magic_wand_contiguous_and_global()
+    {
+        // Two red squares separated by a blue column
+        let source = RgbaImage::from_fn(5, 1, |x, _| {
+            if x == 2 { Rgba([0, 0, 255, 255]) } else { Rgba([250, 0, 0, 255]) }
+        });
+        let contiguous = SelectionMask::magic_wand(&source, 0, 0, 10, true).unwrap();
+        assert_eq!(selected_count(&contiguous), 2);
+        let global = SelectionMask::magic_wand(&source, 0, 0, 10, false).unwrap();
+        assert_eq!(selected_count(&global), 4);
+        assert!(SelectionMask::magic_wand(&source, 5, 0, 10, true).is_err());
+    }
+
+    #[test]
+    fn color_range_fades_with_distance()
+    {
+        let source = RgbaImage::from_fn(4, 1, |x, _| match x {
+            0 => Rgba([100, 100, 100, 255]),
+            1 => Rgba([120, 100, 100, 255]),
+            2 => Rgba([200, 100, 100, 255]),
+            _ => Rgba([100, 100, 100, 0]),
+        });
+        let selection = SelectionMask::color_range(&source, [100, 100, 100], 40.0);
+        assert_eq!(selection.value(0, 0), 255);
+        assert_eq!(selection.value(1, 0), 128);
+        assert_eq!(selection.value(2, 0), 0);
+        assert_eq!(selection.value(3, 0), 0);
+    }
+
+    #[test]
+    fn restrict_mixes_by_selection_value()
+    {
+        let selection = SelectionMask::rectangle(3, 1, 0.0, 0.0, 1.5, 1.0);
+        let original = RgbaImage::from_pixel(3, 1, Rgba([0, 0, 0, 255]));
+        let mut edited = RgbaImage::from_pixel(3, 1, Rgba([200, 200, 200, 255]));
+        selection.restrict(&original, &mut edited).unwrap();
+        let reds: Vec<u8> = edited.pixels().map(|p| p[0]).collect();
+        assert_eq!(reds, [200, 100, 0]);
+    }
+}
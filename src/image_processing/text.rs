@@ -0,0 +1,296 @@
+// text layers, type set inside a box and rasterized with ab_glyph
+use super::transform::MAX_DIMENSION;
+use super::ProcessingError;
+use crate::config;
+use ab_glyph::{point, Font, FontVec, PxScale, PxScaleFont, ScaleFont};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+
+const MAX_TEXT_LENGTH: usize = 10_000;
+const MAX_FONT_SIZE: f32 = 2000.0;
+const MAX_LINE_SPACING: f32 = 10.0;
+const MAX_BOX_SIZE: u32 = 20_000;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Text Parameters ********** ///////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// How each line sits between the left and right edge of the text box.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextAlign
+{
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+/// Settings of a text layer, stored in layers.layer_params. The text is
+/// wrapped to the box at (x, y) and anything that doesn't fit is cut off.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TextParams
+{
+    pub text: String,
+    /// Name of a font file in FONT_DIR, e.g. "DejaVu Sans" for DejaVuSans.ttf.
+    pub font_family: String,
+    /// Font size in pixels.
+    pub font_size: f32,
+    /// RGBA text colour.
+    #[serde(default = "default_color")]
+    pub color: [u8; 4],
+    #[serde(default)]
+    pub align: TextAlign,
+    /// Multiplier of the font's own line height.
+    #[serde(default = "default_line_spacing")]
+    pub line_spacing: f32,
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+fn default_color() -> [u8; 4]
+{
+    [0, 0, 0, 255]
+}
+
+fn default_line_spacing() -> f32
+{
+    1.0
+}
+
+impl TextParams
+{
+    // Check the ranges and that the font can be found
+    pub fn validate(&self) -> Result<(), ProcessingError>
+    {
+        if self.text.chars().count() > MAX_TEXT_LENGTH {
+            return Err(invalid(format!("text can't be longer than {} characters",
+                                       MAX_TEXT_LENGTH)));
+        }
+        if !(self.font_size > 0.0 && self.font_size <= MAX_FONT_SIZE) {
+            return Err(invalid(format!("font_size must be between 0 and {}", MAX_FONT_SIZE)));
+        }
+        if !(self.line_spacing > 0.0 && self.line_spacing <= MAX_LINE_SPACING) {
+            return Err(invalid(format!("line_spacing must be between 0 and {}", MAX_LINE_SPACING)));
+        }
+        // Keeps the box near enough to the canvas that flattening can size for it
+        let position = -(MAX_DIMENSION as i32)..=MAX_DIMENSION as i32;
+        if !position.contains(&self.x) || !position.contains(&self.y) {
+            return Err(invalid(format!("x and y must be between -{0} and {0}", MAX_DIMENSION)));
+        }
+        let box_size = 1..=MAX_BOX_SIZE;
+        if !box_size.contains(&self.width) || !box_size.contains(&self.height) {
+            return Err(invalid(format!("the text box must be between 1 and {} pixels on each side",
+                                       MAX_BOX_SIZE)));
+        }
+        load_font(&self.font_family).map(|_| ())
+    }
+
+    // Right and bottom edge of the text box, used to size a flattened canvas
+    pub fn extent(&self) -> (u32, u32)
+    {
+        let right = self.x.saturating_add(self.width as i32).max(0) as u32;
+        let bottom = self.y.saturating_add(self.height as i32).max(0) as u32;
+        (right, bottom)
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Text Rendering ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// render_text: rasterize a text layer onto a transparent canvas /////////////////
+// Lines break at newlines and are word wrapped to the box width; a word that is
+// wider than the box overflows and is clipped. Glyphs are anti-aliased, the
+// coverage of each pixel scales the alpha of the text colour.
+pub fn render_text(params: &TextParams,
+                   width: u32,
+                   height: u32)
+                   -> Result<RgbaImage, ProcessingError>
+{
+    params.validate()?;
+    let font = load_font(&params.font_family)?;
+    let font = font.as_scaled(PxScale::from(params.font_size));
+
+    let left = params.x as f32;
+    let box_width = params.width as f32;
+    let line_height = (font.height() + font.line_gap()) * params.line_spacing;
+    let lines = wrap_lines(&font, &params.text, box_width);
+
+    // Pixels outside both the canvas and the text box are never touched
+    let clip_left = params.x.max(0);
+    let clip_top = params.y.max(0);
+    let clip_right = (params.x as i64 + params.width as i64).min(width as i64) as i32;
+    let clip_bottom = (params.y as i64 + params.height as i64).min(height as i64) as i32;
+
+    let mut canvas = RgbaImage::new(width, height);
+    for (index, line) in lines.iter().enumerate() {
+        let top = params.y as f32 + line_height * index as f32;
+        if top >= clip_bottom as f32 {
+            break;
+        }
+        let baseline = top + font.ascent();
+        let mut caret = match params.align {
+            TextAlign::Left => left,
+            TextAlign::Center => left + (box_width - line_width(&font, line)) / 2.0,
+            TextAlign::Right => left + box_width - line_width(&font, line),
+        };
+
+        let mut previous = None;
+        for c in line.chars() {
+            let glyph_id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                caret += font.kern(previous, glyph_id);
+            }
+            previous = Some(glyph_id);
+            let glyph = glyph_id.with_scale_and_position(font.scale(), point(caret, baseline));
+            caret += font.h_advance(glyph_id);
+
+            let outline = match font.outline_glyph(glyph) {
+                Some(outline) => outline,
+                None => continue,
+            };
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                let x = bounds.min.x as i32 + gx as i32;
+                let y = bounds.min.y as i32 + gy as i32;
+                if x < clip_left || x >= clip_right || y < clip_top || y >= clip_bottom {
+                    return;
+                }
+                let alpha = coverage.clamp(0.0, 1.0) * params.color[3] as f32 / 255.0;
+                let pixel = canvas.get_pixel_mut(x as u32, y as u32);
+                // Every glyph has the same colour, so overlaps only add alpha
+                let below = pixel[3] as f32 / 255.0;
+                let out = alpha + below * (1.0 - alpha);
+                pixel.0 = [params.color[0],
+                           params.color[1],
+                           params.color[2],
+                           (out * 255.0).round().clamp(0.0, 255.0) as u8];
+            });
+        }
+    }
+    Ok(canvas)
+}
+
+// Break text into lines that fit the box width, greedily by word
+fn wrap_lines(font: &PxScaleFont<&FontVec>, text: &str, max_width: f32) -> Vec<String>
+{
+    let mut lines = Vec::new();
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if line.is_empty() {
+                line.push_str(word);
+                continue;
+            }
+            let candidate = format!("{} {}", line, word);
+            if line_width(font, &candidate) <= max_width {
+                line = candidate;
+            } else {
+                lines.push(std::mem::replace(&mut line, word.to_string()));
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Advance width of a line of text, kerning included
+fn line_width(font: &PxScaleFont<&FontVec>, line: &str) -> f32
+{
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in line.chars() {
+        let glyph_id = font.glyph_id(c);
+        if let Some(previous) = previous {
+            width += font.kern(previous, glyph_id);
+        }
+        width += font.h_advance(glyph_id);
+        previous = Some(glyph_id);
+    }
+    width
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Font Loading ********** //////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// list_fonts: the font families that text layers can use ////////////////////////
+// These are the names of the .ttf and .otf files in FONT_DIR.
+pub fn list_fonts() -> Vec<String>
+{
+    let mut fonts: Vec<String> = font_files().into_iter()
+                                             .filter_map(|path| {
+                                                 path.file_stem()
+                                                     .and_then(|stem| stem.to_str())
+                                                     .map(str::to_string)
+                                             })
+                                             .collect();
+    fonts.sort();
+    fonts
+}
+
+// Find a font by family name, reading each file only once ///////////////////////
+// Names are compared without case, spaces or punctuation, so "Open Sans" finds
+// OpenSans.ttf as well as OpenSans-Regular.ttf.
+fn load_font(family: &str) -> Result<Arc<FontVec>, ProcessingError>
+{
+    static FONTS: OnceLock<Mutex<HashMap<String, Arc<FontVec>>>> = OnceLock::new();
+
+    let key = normalize_name(family);
+    let fonts = FONTS.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(font) = fonts.lock().unwrap().get(&key) {
+        return Ok(font.clone());
+    }
+
+    let path = font_files().into_iter()
+                           .find(|path| {
+                               let stem = path.file_stem().and_then(|stem| stem.to_str());
+                               let stem = normalize_name(stem.unwrap_or(""));
+                               !key.is_empty() && (stem == key || stem == format!("{}regular", key))
+                           })
+                           .ok_or_else(|| invalid(format!("font '{}' was not found", family)))?;
+    let font = std::fs::read(&path).ok()
+                                   .and_then(|bytes| FontVec::try_from_vec(bytes).ok())
+                                   .ok_or_else(|| {
+                                       invalid(format!("font '{}' could not be loaded", family))
+                                   })?;
+
+    let font = Arc::new(font);
+    fonts.lock().unwrap().insert(key, font.clone());
+    Ok(font)
+}
+
+// Every .ttf and .otf file directly in the font directory
+fn font_files() -> Vec<PathBuf>
+{
+    let entries = match std::fs::read_dir(config::font_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+           .filter(|path| {
+               path.extension()
+                   .and_then(|extension| extension.to_str())
+                   .is_some_and(|extension| {
+                       extension.eq_ignore_ascii_case("ttf")
+                       || extension.eq_ignore_ascii_case("otf")
+                   })
+           })
+           .collect()
+}
+
+fn normalize_name(name: &str) -> String
+{
+    name.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+fn invalid(msg: String) -> ProcessingError
+{
+    ProcessingError::InvalidParameter(msg)
+}
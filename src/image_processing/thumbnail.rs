@@ -0,0 +1,81 @@
+// Downscaled renditions of an image for galleries and previews
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{ImageResult, RgbaImage};
+
+// Long edge of each rendition, in pixels
+pub const THUMBNAIL_SIZES: [u32; 3] = [128, 512, 2048];
+
+// Renditions are JPEG: every browser and image viewer shows it, and it encodes
+// faster than libwebp, which matters as they are redrawn after every edit
+pub const THUMBNAIL_CONTENT_TYPE: &str = "image/jpeg";
+const THUMBNAIL_QUALITY: u8 = 85;
+
+// What transparent pixels are shown on, JPEG has no alpha channel
+const THUMBNAIL_BACKGROUND: [u8; 3] = [255, 255, 255];
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Thumbnail Rendering ********** ///////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// One encoded rendition of an image.
+#[derive(Debug)]
+pub struct Rendition
+{
+    // The size it was rendered for, one of THUMBNAIL_SIZES
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+// render_thumbnails: one JPEG per entry of THUMBNAIL_SIZES, smallest first ///////
+// Each rendition fits its size on the long edge. Images smaller than that are
+// kept at their own size rather than scaled up. The largest rendition is scaled
+// from the image and every smaller one from the previous, which is much cheaper
+// on big images and looks the same with a triangle filter.
+pub fn render_thumbnails(image: &RgbaImage) -> ImageResult<Vec<Rendition>>
+{
+    // Blend onto the background before scaling, so transparent edges don't pick
+    // up the colour of fully transparent pixels
+    let mut source = super::remove_alpha(image, THUMBNAIL_BACKGROUND);
+    let mut renditions = Vec::with_capacity(THUMBNAIL_SIZES.len());
+    for &size in THUMBNAIL_SIZES.iter().rev() {
+        let (width, height) = fit_long_edge(image.width(), image.height(), size);
+        if (width, height) != source.dimensions() {
+            source = imageops::resize(&source, width, height, FilterType::Triangle);
+        }
+
+        let mut data = Vec::new();
+        JpegEncoder::new_with_quality(&mut data, THUMBNAIL_QUALITY).encode_image(&source)?;
+        renditions.push(Rendition { size, width, height, data });
+    }
+    renditions.reverse();
+    Ok(renditions)
+}
+
+// closest_size: the rendition to serve for a requested size ////////////////////
+// The smallest one at least as big as the request, or the largest there is.
+pub fn closest_size(requested: u32) -> u32
+{
+    THUMBNAIL_SIZES.iter()
+                   .copied()
+                   .find(|&size| size >= requested)
+                   .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1])
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// Dimensions that fit the long edge in size, keeping the aspect ratio
+fn fit_long_edge(width: u32, height: u32, size: u32) -> (u32, u32)
+{
+    let long_edge = width.max(height);
+    if long_edge <= size {
+        return (width, height);
+    }
+    let scale = size as f64 / long_edge as f64;
+    let scaled = |edge: u32| ((edge as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
@@ -1 +1,514 @@
-// Transformations like rotate, resize, crop, etc.
\ No newline at end of file
+// Transformations like rotate, resize, crop, etc.
+use super::ProcessingError;
+use image::{imageops, imageops::FilterType, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+// Largest width or height a transform is allowed to produce
+pub const MAX_DIMENSION: u32 = 30_000;
+
+// Largest number of pixels a transform is allowed to produce, the same as an
+// upload may have. MAX_DIMENSION on both sides would be 3.6 GB of RGBA.
+pub const MAX_PIXELS: u64 = super::upload::MAX_UPLOAD_PIXELS;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Transform Options ********** /////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Resampling filter used when a transform has to interpolate between pixels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResampleFilter
+{
+    Nearest,
+    #[default]
+    Bilinear,
+    Bicubic,
+    Lanczos,
+}
+
+impl ResampleFilter
+{
+    // Half-width of the kernel in source pixels
+    fn support(self) -> f32
+    {
+        match self {
+            ResampleFilter::Nearest => 0.5,
+            ResampleFilter::Bilinear => 1.0,
+            ResampleFilter::Bicubic => 2.0,
+            ResampleFilter::Lanczos => 3.0,
+        }
+    }
+
+    // Kernel weight at distance x from the sample point
+    fn weight(self, x: f32) -> f32
+    {
+        let x = x.abs();
+        match self {
+            ResampleFilter::Nearest => {
+                if x <= 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ResampleFilter::Bilinear => (1.0 - x).max(0.0),
+            ResampleFilter::Bicubic => {
+                // Keys cubic convolution, a = -0.5 (Catmull-Rom)
+                let a = -0.5;
+                if x < 1.0 {
+                    (a + 2.0) * x * x * x - (a + 3.0) * x * x + 1.0
+                } else if x < 2.0 {
+                    a * x * x * x - 5.0 * a * x * x + 8.0 * a * x - 4.0 * a
+                } else {
+                    0.0
+                }
+            }
+            ResampleFilter::Lanczos => {
+                if x < f32::EPSILON {
+                    1.0
+                } else if x < 3.0 {
+                    let px = std::f32::consts::PI * x;
+                    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+impl From<ResampleFilter> for FilterType
+{
+    fn from(filter: ResampleFilter) -> FilterType
+    {
+        match filter {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Bilinear => FilterType::Triangle,
+            ResampleFilter::Bicubic => FilterType::CatmullRom,
+            ResampleFilter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// What to do with the canvas when the rotated image no longer fits it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CanvasMode
+{
+    /// Grow the canvas so the whole rotated image is kept.
+    #[default]
+    Expand,
+    /// Keep the original canvas size and cut off the corners.
+    Crop,
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Rotation ********** ///////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Rotate an image clockwise by `angle` degrees.
+///
+/// Multiples of 90 degrees are done losslessly. Any other angle is resampled
+/// with `filter`, and the area outside the source image is filled with
+/// `background`. Fails if an expanded canvas would be too large.
+pub fn rotate(image: &RgbaImage,
+              angle: f32,
+              filter: ResampleFilter,
+              canvas: CanvasMode,
+              background: Rgba<u8>)
+              -> Result<RgbaImage, ProcessingError>
+{
+    let angle = angle.rem_euclid(360.0);
+    let (width, height) = image.dimensions();
+
+    // Lossless fast paths
+    let quarter_turns = angle / 90.0;
+    if (quarter_turns - quarter_turns.round()).abs() < 1e-6 {
+        let turned = match quarter_turns.round() as u32 % 4 {
+            0 => Some(image.clone()),
+            1 if canvas == CanvasMode::Expand || width == height => Some(imageops::rotate90(image)),
+            2 => Some(imageops::rotate180(image)),
+            3 if canvas == CanvasMode::Expand || width == height => Some(imageops::rotate270(image)),
+            _ => None,
+        };
+        if let Some(turned) = turned {
+            return Ok(turned);
+        }
+    }
+
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (out_width, out_height) = match canvas {
+        CanvasMode::Expand => {
+            let w = width as f32 * cos.abs() + height as f32 * sin.abs();
+            let h = width as f32 * sin.abs() + height as f32 * cos.abs();
+            // Shave a little off so float noise doesn't add an empty row/column
+            ((w - 1e-3).ceil().max(1.0) as u32, (h - 1e-3).ceil().max(1.0) as u32)
+        }
+        CanvasMode::Crop => (width, height),
+    };
+    check_dimensions(out_width, out_height)?;
+
+    let src_cx = width as f32 / 2.0;
+    let src_cy = height as f32 / 2.0;
+    let dst_cx = out_width as f32 / 2.0;
+    let dst_cy = out_height as f32 / 2.0;
+
+    Ok(RgbaImage::from_fn(out_width, out_height, |x, y| {
+        // Map the centre of the destination pixel back into the source image
+        let dx = x as f32 + 0.5 - dst_cx;
+        let dy = y as f32 + 0.5 - dst_cy;
+        let sx = dx * cos + dy * sin + src_cx - 0.5;
+        let sy = -dx * sin + dy * cos + src_cy - 0.5;
+        sample(image, sx, sy, filter, background)
+    }))
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Resize ********** ////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// How the requested size is interpreted when resizing.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ResizeMode
+{
+    /// Stretch to exactly `width` x `height`, ignoring the aspect ratio.
+    Exact { width: u32, height: u32 },
+    /// Scale to the largest size that fits inside `width` x `height`.
+    Fit { width: u32, height: u32 },
+    /// Scale to cover `width` x `height`, then crop the overflow from the centre.
+    Fill { width: u32, height: u32 },
+    /// Scale both sides by `percent` (100 keeps the current size).
+    Percentage { percent: f32 },
+}
+
+/// Resize an image according to `mode`.
+pub fn resize(image: &RgbaImage,
+              mode: ResizeMode,
+              filter: ResampleFilter)
+              -> Result<RgbaImage, ProcessingError>
+{
+    let (width, height) = image.dimensions();
+    let (src_w, src_h) = (width as f64, height as f64);
+
+    let (scaled_w, scaled_h) = match mode {
+        ResizeMode::Exact { width, height } => (width, height),
+        ResizeMode::Fit { width, height } | ResizeMode::Fill { width, height } => {
+            check_dimensions(width, height)?;
+            let scale_w = width as f64 / src_w;
+            let scale_h = height as f64 / src_h;
+            let scale = if matches!(mode, ResizeMode::Fit { .. }) {
+                scale_w.min(scale_h)
+            } else {
+                scale_w.max(scale_h)
+            };
+            (scaled_side(src_w, scale), scaled_side(src_h, scale))
+        }
+        ResizeMode::Percentage { percent } => {
+            if !percent.is_finite() || percent <= 0.0 {
+                return Err(ProcessingError::InvalidParameter(
+                    "percent must be greater than zero".to_string(),
+                ));
+            }
+            let scale = percent as f64 / 100.0;
+            (scaled_side(src_w, scale), scaled_side(src_h, scale))
+        }
+    };
+    check_dimensions(scaled_w, scaled_h)?;
+
+    let resized = if (scaled_w, scaled_h) == (width, height) {
+        image.clone()
+    } else {
+        imageops::resize(image, scaled_w, scaled_h, filter.into())
+    };
+
+    match mode {
+        ResizeMode::Fill { width, height } => {
+            let x = (scaled_w - width.min(scaled_w)) / 2;
+            let y = (scaled_h - height.min(scaled_h)) / 2;
+            Ok(imageops::crop_imm(&resized, x, y, width, height).to_image())
+        }
+        _ => Ok(resized),
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Crop & Canvas Size ********** /////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// Crop an image to the rectangle at (`x`, `y`) of size `width` x `height`.
+pub fn crop(image: &RgbaImage,
+            x: u32,
+            y: u32,
+            width: u32,
+            height: u32)
+            -> Result<RgbaImage, ProcessingError>
+{
+    check_dimensions(width, height)?;
+    let (image_w, image_h) = image.dimensions();
+    let fits = x.checked_add(width).is_some_and(|right| right <= image_w)
+               && y.checked_add(height).is_some_and(|bottom| bottom <= image_h);
+    if !fits {
+        return Err(ProcessingError::InvalidParameter(format!(
+            "crop rectangle {}x{} at ({}, {}) is outside the {}x{} image",
+            width, height, x, y, image_w, image_h
+        )));
+    }
+
+    Ok(imageops::crop_imm(image, x, y, width, height).to_image())
+}
+
+/// Where the existing image is pinned when the canvas size changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor
+{
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    #[default]
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor
+{
+    // Halves of the free space placed before the image on each axis
+    fn weights(self) -> (i64, i64)
+    {
+        match self {
+            Anchor::TopLeft => (0, 0),
+            Anchor::Top => (1, 0),
+            Anchor::TopRight => (2, 0),
+            Anchor::Left => (0, 1),
+            Anchor::Center => (1, 1),
+            Anchor::Right => (2, 1),
+            Anchor::BottomLeft => (0, 2),
+            Anchor::Bottom => (1, 2),
+            Anchor::BottomRight => (2, 2),
+        }
+    }
+}
+
+/// Change the canvas size without scaling the image.
+///
+/// The image is placed on a `width` x `height` canvas filled with `fill`,
+/// pinned at `anchor`. Making the canvas smaller crops the image.
+pub fn canvas_size(image: &RgbaImage,
+                   width: u32,
+                   height: u32,
+                   anchor: Anchor,
+                   fill: Rgba<u8>)
+                   -> Result<RgbaImage, ProcessingError>
+{
+    check_dimensions(width, height)?;
+    let (wx, wy) = anchor.weights();
+    // Offsets go negative when the canvas shrinks, which crops the image
+    let x = (width as i64 - image.width() as i64) * wx / 2;
+    let y = (height as i64 - image.height() as i64) * wy / 2;
+
+    let mut canvas = RgbaImage::from_pixel(width, height, fill);
+    imageops::replace(&mut canvas, image, x, y);
+    Ok(canvas)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Helper Functions ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// Sample the image at a fractional pixel position ///////////////////////////////
+// Pixels outside the image count as `background`. Interpolation is done on
+// premultiplied alpha so transparent pixels don't bleed their colour into the
+// edges.
+pub(crate) fn sample(image: &RgbaImage,
+                     x: f32,
+                     y: f32,
+                     filter: ResampleFilter,
+                     background: Rgba<u8>)
+                     -> Rgba<u8>
+{
+    let (width, height) = image.dimensions();
+    let support = filter.support();
+
+    if filter == ResampleFilter::Nearest {
+        let (px, py) = (x.round(), y.round());
+        if px < 0.0 || py < 0.0 || px >= width as f32 || py >= height as f32 {
+            return background;
+        }
+        return *image.get_pixel(px as u32, py as u32);
+    }
+
+    let x0 = (x - support).floor() as i64 + 1;
+    let x1 = (x + support).floor() as i64;
+    let y0 = (y - support).floor() as i64 + 1;
+    let y1 = (y + support).floor() as i64;
+
+    let mut acc = [0.0f32; 4];
+    let mut total = 0.0f32;
+    for sy in y0..=y1 {
+        let wy = filter.weight(y - sy as f32);
+        if wy == 0.0 {
+            continue;
+        }
+        for sx in x0..=x1 {
+            let w = wy * filter.weight(x - sx as f32);
+            if w == 0.0 {
+                continue;
+            }
+            let in_bounds = sx >= 0 && sy >= 0 && sx < width as i64 && sy < height as i64;
+            let pixel = if in_bounds {
+                image.get_pixel(sx as u32, sy as u32)
+            } else {
+                &background
+            };
+            let alpha = pixel[3] as f32;
+            acc[0] += w * pixel[0] as f32 * alpha;
+            acc[1] += w * pixel[1] as f32 * alpha;
+            acc[2] += w * pixel[2] as f32 * alpha;
+            acc[3] += w * alpha;
+            total += w;
+        }
+    }
+
+    if total == 0.0 || acc[3] <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let alpha = acc[3] / total;
+    Rgba([(acc[0] / acc[3]).round().clamp(0.0, 255.0) as u8,
+          (acc[1] / acc[3]).round().clamp(0.0, 255.0) as u8,
+          (acc[2] / acc[3]).round().clamp(0.0, 255.0) as u8,
+          alpha.round().clamp(0.0, 255.0) as u8])
+}
+
+// Make sure an output size is non-empty and not absurdly large /////////////////
+// Checked before every buffer a transform allocates, and before layers are
+// flattened onto a canvas big enough for all of them.
+pub(crate) fn check_dimensions(width: u32, height: u32) -> Result<(), ProcessingError>
+{
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(ProcessingError::InvalidParameter(format!(
+            "{}x{} is not a valid size, both sides must be between 1 and {}",
+            width, height, MAX_DIMENSION
+        )));
+    }
+    if width as u64 * height as u64 > MAX_PIXELS {
+        return Err(ProcessingError::InvalidParameter(format!(
+            "{}x{} is too large, images can have at most {} megapixels",
+            width, height, MAX_PIXELS / 1_000_000
+        )));
+    }
+    Ok(())
+}
+
+// Scale one side of the image, never going below a single pixel ////////////////
+fn scaled_side(side: f64, scale: f64) -> u32
+{
+    (side * scale).round().clamp(1.0, u32::MAX as f64) as u32
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+    // A small image where every pixel is different
+    fn gradient(width: u32, height: u32) -> RgbaImage
+    {
+        RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8 * 20, y as u8 * 20, 99, 255]))
+    }
+
+    #[test]
+    fn quarter_turns_are_lossless()
+    {
+        let image = gradient(5, 3);
+        let rotate_by = |angle| {
+            rotate(&image, angle, ResampleFilter::Bicubic, CanvasMode::Expand, TRANSPARENT).unwrap()
+        };
+        assert_eq!(rotate_by(90.0), imageops::rotate90(&image));
+        assert_eq!(rotate_by(180.0), imageops::rotate180(&image));
+        assert_eq!(rotate_by(-90.0), imageops::rotate270(&image));
+        assert_eq!(rotate_by(720.0), image);
+    }
+
+    #[test]
+    fn expanded_canvas_fits_the_rotated_image()
+    {
+        let image = RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 255]));
+        let expand = CanvasMode::Expand;
+        let rotated = rotate(&image, 45.0, ResampleFilter::Bilinear, expand, TRANSPARENT).unwrap();
+        assert_eq!(rotated.dimensions(), (15, 15));
+        assert_eq!(rotated.get_pixel(7, 7), &Rgba([255, 0, 0, 255]));
+        assert_eq!(rotated.get_pixel(0, 0), &TRANSPARENT);
+
+        let cropped =
+            rotate(&image, 45.0, ResampleFilter::Bilinear, CanvasMode::Crop, TRANSPARENT).unwrap();
+        assert_eq!(cropped.dimensions(), (10, 10));
+    }
+
+    #[test]
+    fn expanded_canvas_is_size_checked()
+    {
+        let image = RgbaImage::new(MAX_DIMENSION, 1);
+        let result = rotate(&image, 45.0, ResampleFilter::Nearest, CanvasMode::Expand, TRANSPARENT);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rotating_there_and_back_keeps_the_middle()
+    {
+        let image =
+            RgbaImage::from_fn(21, 21, |x, y| Rgba([(x * 12) as u8, (y * 12) as u8, 50, 255]));
+        let there =
+            rotate(&image, 30.0, ResampleFilter::Bicubic, CanvasMode::Crop, TRANSPARENT).unwrap();
+        let back =
+            rotate(&there, -30.0, ResampleFilter::Bicubic, CanvasMode::Crop, TRANSPARENT).unwrap();
+        for c in 0..4 {
+            assert!(back.get_pixel(10, 10)[c].abs_diff(image.get_pixel(10, 10)[c]) <= 2);
+        }
+    }
+
+    #[test]
+    fn kernels_sum_to_one()
+    {
+        for filter in [ResampleFilter::Bilinear, ResampleFilter::Bicubic, ResampleFilter::Lanczos] {
+            for offset in [0.0, 0.25, 0.5, 0.9] {
+                let total: f32 = (-4..=4).map(|k| filter.weight(offset - k as f32)).sum();
+                assert!((total - 1.0).abs() < 0.02, "{:?} at {}: {}", filter, offset, total);
+            }
+        }
+    }
+
+    #[test]
+    fn sample_interpolates_between_pixels()
+    {
+        let image = RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba([0, 0, 0, 255]) } else { Rgba([255, 255, 255, 255]) }
+        });
+        let background = Rgba([1, 2, 3, 4]);
+        assert_eq!(sample(&image, 1.0, 0.0, ResampleFilter::Bilinear, background),
+                   Rgba([255, 255, 255, 255]));
+        assert_eq!(sample(&image, 0.5, 0.0, ResampleFilter::Bilinear, background)[0], 128);
+        assert_eq!(sample(&image, 5.0, 0.0, ResampleFilter::Nearest, background), background);
+    }
+
+    #[test]
+    fn sample_does_not_bleed_transparent_colour()
+    {
+        // Opaque red next to transparent green
+        let image = RgbaImage::from_fn(2, 1, |x, _| {
+            if x == 0 { Rgba([255, 0, 0, 255]) } else { Rgba([0, 255, 0, 0]) }
+        });
+        let mixed = sample(&image, 0.5, 0.0, ResampleFilter::Bilinear, TRANSPARENT);
+        assert_eq!(mixed, Rgba([255, 0, 0, 128]));
+    }
+}
@@ -0,0 +1,252 @@
+// Checks run on uploaded image files before they are stored
+use image::{io::Limits, io::Reader as ImageReader, ImageError, ImageFormat};
+use std::fmt;
+use std::io::Cursor;
+
+// Largest upload accepted, in bytes of the encoded file
+pub const MAX_UPLOAD_BYTES: usize = 50 * 1024 * 1024;
+
+// Largest width or height of an uploaded image
+pub const MAX_UPLOAD_DIMENSION: u32 = 16_384;
+
+// Largest number of pixels (width * height) of an uploaded image
+pub const MAX_UPLOAD_PIXELS: u64 = 100_000_000;
+
+// Memory a decoder may use for one upload: every pixel at 16 bits per channel RGBA
+const MAX_DECODE_ALLOC: u64 = MAX_UPLOAD_PIXELS * 8;
+
+// Formats that can be uploaded, the ones the editor can decode and edit
+const SUPPORTED_FORMATS: [ImageFormat; 6] = [ImageFormat::Png,
+                                             ImageFormat::Jpeg,
+                                             ImageFormat::Gif,
+                                             ImageFormat::WebP,
+                                             ImageFormat::Bmp,
+                                             ImageFormat::Tiff];
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Upload Validation ********** /////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// What an uploaded file turned out to be.
+#[derive(Debug, Clone, Copy)]
+pub struct UploadInfo
+{
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl UploadInfo
+{
+    pub fn mime_type(&self) -> &'static str
+    {
+        self.format.to_mime_type()
+    }
+}
+
+// validate_upload: make sure an uploaded file is an image we can work with //////
+// The format comes from the file's magic bytes, not from what the client says it
+// sent. The dimensions are read from the header and checked before any pixels
+// are decoded, so a small file that claims to be huge (a decompression bomb) is
+// refused without allocating for it. Files that pass are then decoded in full,
+// within the same limits, to catch truncated or corrupt data.
+pub fn validate_upload(data: &[u8]) -> Result<UploadInfo, UploadError>
+{
+    if data.is_empty() {
+        return Err(UploadError::Empty);
+    }
+    if data.len() > MAX_UPLOAD_BYTES {
+        return Err(UploadError::TooLarge { size: data.len() });
+    }
+
+    let format = image::guess_format(data).map_err(|_| UploadError::UnsupportedFormat(None))?;
+    if !SUPPORTED_FORMATS.contains(&format) {
+        return Err(UploadError::UnsupportedFormat(Some(format)));
+    }
+
+    let (width, height) = ImageReader::with_format(Cursor::new(data), format)
+        .into_dimensions()
+        .map_err(|e| UploadError::Corrupt(e.to_string()))?;
+    if width == 0 || height == 0 {
+        return Err(UploadError::Corrupt("the image has no pixels".to_string()));
+    }
+    if width > MAX_UPLOAD_DIMENSION || height > MAX_UPLOAD_DIMENSION {
+        return Err(UploadError::DimensionsTooLarge { width, height });
+    }
+    if width as u64 * height as u64 > MAX_UPLOAD_PIXELS {
+        return Err(UploadError::TooManyPixels { width, height });
+    }
+
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(upload_limits());
+    match reader.decode() {
+        Ok(_) => Ok(UploadInfo { format, width, height }),
+        Err(ImageError::Limits(_)) => Err(UploadError::TooManyPixels { width, height }),
+        Err(e) => Err(UploadError::Corrupt(e.to_string())),
+    }
+}
+
+fn upload_limits() -> Limits
+{
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_UPLOAD_DIMENSION);
+    limits.max_image_height = Some(MAX_UPLOAD_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    limits
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Error Handling ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug)]
+pub enum UploadError
+{
+    // No file, or a file without any bytes
+    Empty,
+    // The form has no 'file' field
+    MissingFile,
+    // The upload broke off or the multipart body couldn't be read
+    Incomplete(String),
+    // More bytes than MAX_UPLOAD_BYTES
+    TooLarge { size: usize },
+    // Not an image, or a format that isn't accepted (None if it wasn't recognised)
+    UnsupportedFormat(Option<ImageFormat>),
+    // The header or the pixel data couldn't be decoded, e.g. a truncated file
+    Corrupt(String),
+    // Wider or taller than MAX_UPLOAD_DIMENSION
+    DimensionsTooLarge { width: u32, height: u32 },
+    // More pixels than MAX_UPLOAD_PIXELS, or more memory than a decode may use
+    TooManyPixels { width: u32, height: u32 },
+}
+
+impl UploadError
+{
+    // Stable name of the error for API clients
+    pub fn code(&self) -> &'static str
+    {
+        match self {
+            UploadError::Empty => "empty_file",
+            UploadError::MissingFile => "missing_file",
+            UploadError::Incomplete(_) => "incomplete_upload",
+            UploadError::TooLarge { .. } => "file_too_large",
+            UploadError::UnsupportedFormat(_) => "unsupported_format",
+            UploadError::Corrupt(_) => "corrupt_image",
+            UploadError::DimensionsTooLarge { .. } => "dimensions_too_large",
+            UploadError::TooManyPixels { .. } => "too_many_pixels",
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+impl fmt::Display for UploadError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            UploadError::Empty => write!(f, "The uploaded file is empty"),
+            UploadError::MissingFile => write!(f, "The form has no 'file' field"),
+            UploadError::Incomplete(msg) => write!(f, "The upload could not be read: {}", msg),
+            UploadError::TooLarge { size } => {
+                write!(f, "The file is {} bytes, the limit is {} bytes", size, MAX_UPLOAD_BYTES)
+            }
+            UploadError::UnsupportedFormat(Some(format)) => {
+                write!(f, "{:?} files are not supported", format)
+            }
+            UploadError::UnsupportedFormat(None) => write!(f, "The file is not a supported image"),
+            UploadError::Corrupt(msg) => write!(f, "The image could not be decoded: {}", msg),
+            UploadError::DimensionsTooLarge { width, height } => {
+                write!(f,
+                       "The image is {}x{}, width and height are limited to {}",
+                       width,
+                       height,
+                       MAX_UPLOAD_DIMENSION)
+            }
+            UploadError::TooManyPixels { width, height } => {
+                write!(f,
+                       "The image is {}x{}, uploads are limited to {} megapixels",
+                       width,
+                       height,
+                       MAX_UPLOAD_PIXELS / 1_000_000)
+            }
+        }
+    }
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use image::{ImageOutputFormat, Rgba, RgbaImage};
+
+    fn encode(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8>
+    {
+        // Noise, so the encoded pixel data is more than a few bytes
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x * 37 + y * 91) as u8, (x * y) as u8, (x ^ y) as u8, 255])
+        });
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    // A BMP whose header claims the given size, the pixel data is left as is
+    fn bmp_claiming(width: i32, height: i32) -> Vec<u8>
+    {
+        let mut data = encode(2, 2, ImageOutputFormat::Bmp);
+        data[18..22].copy_from_slice(&width.to_le_bytes());
+        data[22..26].copy_from_slice(&height.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn supported_images_pass()
+    {
+        let info = validate_upload(&encode(40, 30, ImageOutputFormat::Png)).unwrap();
+        assert_eq!((info.format, info.width, info.height), (ImageFormat::Png, 40, 30));
+        assert_eq!(info.mime_type(), "image/png");
+    }
+
+    #[test]
+    fn renamed_files_are_sniffed_from_their_contents()
+    {
+        let text = b"just some text saved as photo.png".to_vec();
+        assert!(matches!(validate_upload(&text), Err(UploadError::UnsupportedFormat(None))));
+        assert!(matches!(validate_upload(&[]), Err(UploadError::Empty)));
+    }
+
+    #[test]
+    fn formats_that_are_not_allowed_are_rejected()
+    {
+        let icon = encode(16, 16, ImageOutputFormat::Ico);
+        match validate_upload(&icon) {
+            Err(UploadError::UnsupportedFormat(Some(format))) => {
+                assert_eq!(format, ImageFormat::Ico)
+            }
+            other => panic!("an icon was not rejected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_dimensions_are_rejected_from_the_header()
+    {
+        let wide = bmp_claiming(MAX_UPLOAD_DIMENSION as i32 + 1, 1);
+        assert!(matches!(validate_upload(&wide),
+                         Err(UploadError::DimensionsTooLarge { width, height: 1 })
+                             if width == MAX_UPLOAD_DIMENSION + 1));
+        // Within the dimension limit on each side, but too many pixels in all
+        let square = bmp_claiming(12_000, 12_000);
+        assert!(matches!(validate_upload(&square), Err(UploadError::TooManyPixels { .. })));
+    }
+
+    #[test]
+    fn truncated_files_are_corrupt()
+    {
+        let png = encode(64, 64, ImageOutputFormat::Png);
+        let truncated = &png[..png.len() / 2];
+        assert!(matches!(validate_upload(truncated), Err(UploadError::Corrupt(_))));
+    }
+}
@@ -1,5 +1,9 @@
 mod api;
+mod auth;
+mod config;
 mod db;
+mod image_processing;
+mod storage;
 
 use db::create_pool;
 use api::start_server;
@@ -18,6 +22,60 @@ async fn main() -> Result<(), api::MyError>
     // Create the database connection pool
     let pool = create_pool();
 
+    // Set up the blob storage now, so bad settings stop the server right away
+    storage::backend();
+
+    // Give the users named in ADMIN_USERNAMES the admin role
+    let admin_usernames = config::admin_usernames();
+    if !admin_usernames.is_empty() {
+        match db::users::grant_admin(&pool, &admin_usernames).await {
+            Ok(granted) => println!("{} of {} admin users found", granted, admin_usernames.len()),
+            Err(e) => println!("Error granting admin roles: {:?}", e),
+        }
+    }
+
+    // Remove blobs nothing uses any more every ten minutes
+    let purge_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(600));
+        loop {
+            interval.tick().await;
+            match db::blobs::purge_unused_blobs(&purge_pool).await {
+                Ok(0) => {}
+                Ok(purged) => println!("Purged {} unused blobs", purged),
+                Err(e) => println!("Error purging blobs: {:?}", e),
+            }
+        }
+    });
+
+    // Remove stored blobs that rolled back transactions left without a row, hourly
+    let sweep_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            match db::blobs::sweep_orphaned_blobs(&sweep_pool).await {
+                Ok(0) => {}
+                Ok(swept) => println!("Swept {} orphaned blobs", swept),
+                Err(e) => println!("Error sweeping blobs: {:?}", e),
+            }
+        }
+    });
+
+    // Delete sessions that have ended, expired or gone idle every fifteen minutes
+    let session_pool = pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(900));
+        loop {
+            interval.tick().await;
+            match db::sessions::purge_dead_sessions(&session_pool).await {
+                Ok(0) => {}
+                Ok(purged) => println!("Purged {} dead sessions", purged),
+                Err(e) => println!("Error purging sessions: {:?}", e),
+            }
+        }
+    });
+
     // Start the API server
     start_server(pool).await
 }
@@ -0,0 +1,141 @@
+// blobs as files in a sharded directory tree
+use super::{content_hash, is_hash, shard_path, Storage, StorageError};
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Keeps each blob in a file named after its hash, two directory levels deep,
+/// e.g. ./uploads/ab/cd/abcd1234...
+pub struct LocalStorage
+{
+    root: PathBuf,
+}
+
+impl LocalStorage
+{
+    pub fn new(root: PathBuf) -> LocalStorage
+    {
+        LocalStorage { root }
+    }
+
+    fn blob_path(&self, hash: &str) -> Result<PathBuf, StorageError>
+    {
+        Ok(self.root.join(shard_path(hash)?))
+    }
+}
+
+impl Storage for LocalStorage
+{
+    fn put(&self, data: &[u8]) -> Result<String, StorageError>
+    {
+        let hash = content_hash(data);
+        let path = self.blob_path(&hash)?;
+        if path.exists() {
+            return Ok(hash);
+        }
+        let dir = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir)?;
+
+        // Write to a temporary file first and rename it into place, so a reader
+        // never sees half a blob and two writers of the same blob can't clash
+        let temp_path = dir.join(format!(".{}.{}.tmp", hash, rand::random::<u64>()));
+        let written = fs::File::create(&temp_path).and_then(|mut file| {
+                                                      file.write_all(data)?;
+                                                      file.sync_all()
+                                                  })
+                                                  .and_then(|_| fs::rename(&temp_path, &path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e.into());
+        }
+        Ok(hash)
+    }
+
+    fn get(&self, hash: &str) -> Result<Vec<u8>, StorageError>
+    {
+        match fs::read(self.blob_path(hash)?) {
+            Ok(data) => Ok(data),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(StorageError::NotFound(hash.into())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn delete(&self, hash: &str) -> Result<(), StorageError>
+    {
+        match fs::remove_file(self.blob_path(hash)?) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, before: SystemTime) -> Result<Vec<String>, StorageError>
+    {
+        let mut hashes = Vec::new();
+        // Blobs sit two directory levels down, temporary files are skipped
+        for first in read_dirs(&self.root)? {
+            for second in read_dirs(&first)? {
+                for entry in fs::read_dir(&second)? {
+                    let entry = entry?;
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if is_hash(&name) && entry.metadata()?.modified()? < before {
+                        hashes.push(name);
+                    }
+                }
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+// The directories inside a directory, none if it doesn't exist yet
+fn read_dirs(dir: &Path) -> Result<Vec<PathBuf>, StorageError>
+{
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn list_finds_stored_blobs_only()
+    {
+        let root = std::env::temp_dir().join(format!("blobs-{}", rand::random::<u64>()));
+        let storage = LocalStorage::new(root.clone());
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(storage.list(later).unwrap().is_empty());
+
+        let first = storage.put(b"first").unwrap();
+        let second = storage.put(b"second").unwrap();
+        fs::write(root.join(&first[0..2]).join("stray.tmp"), b"").unwrap();
+
+        let mut listed = storage.list(later).unwrap();
+        listed.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(listed, expected);
+        // Blobs stored after the cut off are left out
+        assert!(storage.list(SystemTime::now() - Duration::from_secs(60)).unwrap().is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+// content addressed storage for image files, layer pixels and masks
+pub mod local;
+pub mod s3;
+
+use crate::config::{self, StorageBackend};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Storage Backends ********** //////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+/// A place to keep blobs, addressed by the SHA-256 of their content. Equal
+/// content always ends up under the same hash, so it is only stored once.
+///
+/// Backends only keep the bytes. How many rows point at a blob is counted in
+/// the blobs table (see db::blobs), in the same transactions that change those
+/// rows, and a blob is deleted once nothing points at it any more.
+pub trait Storage: Send + Sync
+{
+    /// Store a blob and return its hash. Storing content that is already
+    /// there leaves it untouched.
+    fn put(&self, data: &[u8]) -> Result<String, StorageError>;
+
+    /// Read a blob back by its hash.
+    fn get(&self, hash: &str) -> Result<Vec<u8>, StorageError>;
+
+    /// Remove a blob. Removing one that isn't there is not an error.
+    fn delete(&self, hash: &str) -> Result<(), StorageError>;
+
+    /// The hashes of every blob stored before `before`. Used to find blobs
+    /// that lost their blobs row, see db::blobs::sweep_orphaned_blobs.
+    fn list(&self, before: SystemTime) -> Result<Vec<String>, StorageError>;
+}
+
+// backend: the storage configured with STORAGE_BACKEND //////////////////////////
+// Built on first use. Settings that can't work stop the server, main calls this
+// at startup so that happens before any request is served.
+pub fn backend() -> &'static dyn Storage
+{
+    static BACKEND: OnceLock<Box<dyn Storage>> = OnceLock::new();
+
+    BACKEND.get_or_init(|| match config::storage_backend() {
+               StorageBackend::Local => Box::new(local::LocalStorage::new(config::storage_dir())),
+               StorageBackend::S3 => match s3::S3Storage::from_env() {
+                   Ok(storage) => Box::new(storage),
+                   Err(e) => panic!("S3 storage is not configured: {}", e),
+               },
+           })
+           .as_ref()
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Content Addressing ********** ////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+// content_hash: SHA-256 of a blob as lowercase hex //////////////////////////////
+pub fn content_hash(data: &[u8]) -> String
+{
+    format!("{:x}", Sha256::digest(data))
+}
+
+// is_hash: whether a name is a blob hash, lowercase hex SHA-256 ////////////////
+pub fn is_hash(name: &str) -> bool
+{
+    name.len() == 64 && name.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+// shard_path: where a blob lives below a backend's root /////////////////////////
+// The first two byte pairs of the hash become directories ("ab/cd/abcd..."), so
+// no directory ends up with more than a few thousand entries. Anything that
+// isn't a hash is refused, it would otherwise be a path into the storage root.
+pub fn shard_path(hash: &str) -> Result<String, StorageError>
+{
+    if !is_hash(hash) {
+        return Err(StorageError::InvalidHash(hash.to_string()));
+    }
+    Ok(format!("{}/{}/{}", &hash[0..2], &hash[2..4], hash))
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Error Handling ********** ////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+#[derive(Debug)]
+pub enum StorageError
+{
+    // Reading or writing the local files failed
+    Io(std::io::Error),
+    // No blob is stored under this hash
+    NotFound(String),
+    // Not a SHA-256 hash
+    InvalidHash(String),
+    // The remote store refused the request or couldn't be reached
+    Remote(String),
+}
+
+impl From<std::io::Error> for StorageError
+{
+    fn from(err: std::io::Error) -> StorageError
+    {
+        StorageError::Io(err)
+    }
+}
+
+impl std::error::Error for StorageError {}
+impl fmt::Display for StorageError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self {
+            StorageError::Io(e) => write!(f, "Storage error: {}", e),
+            StorageError::NotFound(hash) => write!(f, "Blob {} is not stored", hash),
+            StorageError::InvalidHash(hash) => write!(f, "'{}' is not a blob hash", hash),
+            StorageError::Remote(msg) => write!(f, "Remote storage error: {}", msg),
+        }
+    }
+}
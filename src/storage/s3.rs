@@ -0,0 +1,284 @@
+// blobs as objects in an S3 compatible bucket
+use super::{content_hash, is_hash, shard_path, Storage, StorageError};
+use crate::config::S3Config;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::io::Read;
+use std::time::SystemTime;
+
+// SHA-256 of an empty body, sent with requests that have none
+const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Keeps each blob as an object keyed by its sharded hash. Requests use path
+/// style URLs ({endpoint}/{bucket}/{key}) signed with AWS Signature Version 4,
+/// which AWS, MinIO and most local stand-ins all accept.
+pub struct S3Storage
+{
+    config: S3Config,
+    // host[:port] of the endpoint, as sent in the Host header
+    host: String,
+    agent: ureq::Agent,
+}
+
+impl S3Storage
+{
+    pub fn new(config: S3Config) -> Result<S3Storage, String>
+    {
+        let host = config.endpoint
+                         .split_once("://")
+                         .map(|(_, rest)| rest)
+                         .unwrap_or(&config.endpoint)
+                         .split('/')
+                         .next()
+                         .unwrap_or_default()
+                         .to_string();
+        if host.is_empty() {
+            return Err(format!("'{}' is not a valid S3 endpoint", config.endpoint));
+        }
+        Ok(S3Storage { config, host, agent: ureq::Agent::new() })
+    }
+
+    pub fn from_env() -> Result<S3Storage, String>
+    {
+        S3Storage::new(crate::config::s3_config()?)
+    }
+
+    // Send a signed request for one object. A 404 comes back as Ok(None).
+    fn request(&self,
+               method: &str,
+               hash: &str,
+               body: Option<&[u8]>)
+               -> Result<Option<ureq::Response>, StorageError>
+    {
+        let path = format!("/{}/{}", self.config.bucket, shard_path(hash)?);
+        self.send(method, &path, "", body)
+    }
+
+    // Send a signed request. The query must already be in canonical form, its
+    // parameters sorted by name and encoded with uri_encode.
+    fn send(&self,
+            method: &str,
+            path: &str,
+            query: &str,
+            body: Option<&[u8]>)
+            -> Result<Option<ureq::Response>, StorageError>
+    {
+        let payload_hash = match body {
+            Some(body) => content_hash(body),
+            None => EMPTY_PAYLOAD_HASH.to_string(),
+        };
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = self.authorization(method, path, query, &payload_hash, &amz_date);
+
+        let url = match query {
+            "" => format!("{}{}", self.config.endpoint, path),
+            query => format!("{}{}?{}", self.config.endpoint, path, query),
+        };
+        let request = self.agent
+                          .request(method, &url)
+                          .set("x-amz-content-sha256", &payload_hash)
+                          .set("x-amz-date", &amz_date)
+                          .set("Authorization", &authorization);
+        let result = match body {
+            Some(body) => request.send_bytes(body),
+            None => request.call(),
+        };
+        match result {
+            Ok(response) => Ok(Some(response)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(ureq::Error::Status(status, response)) => {
+                let detail = response.into_string().unwrap_or_default();
+                Err(StorageError::Remote(format!("{} {} returned {}: {}",
+                                                 method, path, status, detail)))
+            }
+            Err(e) => Err(StorageError::Remote(e.to_string())),
+        }
+    }
+
+    // The Authorization header for a request, see the AWS SigV4 documentation.
+    // Only host and the two x-amz headers are signed.
+    fn authorization(&self,
+                     method: &str,
+                     path: &str,
+                     query: &str,
+                     payload_hash: &str,
+                     amz_date: &str)
+                     -> String
+    {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!("{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\n\
+                                         x-amz-date:{}\n\n{}\n{}",
+                                        method,
+                                        path,
+                                        query,
+                                        self.host,
+                                        payload_hash,
+                                        amz_date,
+                                        signed_headers,
+                                        payload_hash);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                                     amz_date,
+                                     scope,
+                                     content_hash(canonical_request.as_bytes()));
+
+        let secret = format!("AWS4{}", self.config.secret_key);
+        let key = hmac_sha256(secret.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key, self.config.region.as_bytes());
+        let key = hmac_sha256(&key, b"s3");
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = to_hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.config.access_key, scope, signed_headers, signature)
+    }
+}
+
+impl Storage for S3Storage
+{
+    fn put(&self, data: &[u8]) -> Result<String, StorageError>
+    {
+        let hash = content_hash(data);
+        // The key is the content, an object that is already there is this blob
+        if self.request("HEAD", &hash, None)?.is_none() {
+            self.request("PUT", &hash, Some(data))?;
+        }
+        Ok(hash)
+    }
+
+    fn get(&self, hash: &str) -> Result<Vec<u8>, StorageError>
+    {
+        let response = match self.request("GET", hash, None)? {
+            Some(response) => response,
+            None => return Err(StorageError::NotFound(hash.to_string())),
+        };
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn delete(&self, hash: &str) -> Result<(), StorageError>
+    {
+        // S3 answers 204 whether or not the object existed
+        self.request("DELETE", hash, None).map(|_| ())
+    }
+
+    fn list(&self, before: SystemTime) -> Result<Vec<String>, StorageError>
+    {
+        let before = DateTime::<Utc>::from(before);
+        let path = format!("/{}", self.config.bucket);
+        let mut hashes = Vec::new();
+        let mut token: Option<String> = None;
+        // ListObjectsV2 returns up to 1000 keys a page
+        loop {
+            let query = match &token {
+                Some(token) => format!("continuation-token={}&list-type=2", uri_encode(token)),
+                None => "list-type=2".to_string(),
+            };
+            let response = match self.send("GET", &path, &query, None)? {
+                Some(response) => response,
+                None => return Err(StorageError::Remote(format!("bucket {} not found", path))),
+            };
+            let page = response.into_string()?;
+            let (objects, next) = parse_list_page(&page);
+            for (key, last_modified) in objects {
+                let old_enough = DateTime::parse_from_rfc3339(last_modified)
+                    .is_ok_and(|time| time < before);
+                let name = key.rsplit('/').next().unwrap_or_default();
+                if old_enough && is_hash(name) {
+                    hashes.push(name.to_string());
+                }
+            }
+            match next {
+                Some(next) => token = Some(next.to_string()),
+                None => return Ok(hashes),
+            }
+        }
+    }
+}
+
+// The (key, last modified) of every object on a ListObjectsV2 page, and the
+// token for the next page when there is one
+fn parse_list_page(page: &str) -> (Vec<(&str, &str)>, Option<&str>)
+{
+    let objects = page.split("<Contents>")
+                      .skip(1)
+                      .filter_map(|object| {
+                          Some((xml_value(object, "Key")?, xml_value(object, "LastModified")?))
+                      })
+                      .collect();
+    let truncated = xml_value(page, "IsTruncated") == Some("true");
+    (objects, xml_value(page, "NextContinuationToken").filter(|_| truncated))
+}
+
+// The text of the first <tag>...</tag> in a piece of XML
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str>
+{
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", tag))?;
+    Some(&xml[start..start + end])
+}
+
+// Percent-encode a query value the way SigV4 expects, everything but the
+// unreserved characters
+fn uri_encode(value: &str) -> String
+{
+    value.bytes()
+         .map(|byte| match byte {
+             b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                 (byte as char).to_string()
+             }
+             _ => format!("%{:02X}", byte),
+         })
+         .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8>
+{
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+//////////////////////////////////////////////////////////////////////////////////
+//////////// ********** Tests ********** /////////////////////////////////////////
+//////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn list_pages_are_parsed()
+    {
+        let page = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+                    <ListBucketResult><Name>blobs</Name><IsTruncated>true</IsTruncated>\
+                    <Contents><Key>ab/cd/abcd</Key><LastModified>2024-01-02T03:04:05.000Z\
+                    </LastModified><Size>10</Size></Contents>\
+                    <Contents><Key>ef/01/ef01</Key><LastModified>2024-02-02T03:04:05.000Z\
+                    </LastModified></Contents>\
+                    <NextContinuationToken>1/a+b=</NextContinuationToken></ListBucketResult>";
+        let (objects, next) = parse_list_page(page);
+        assert_eq!(objects, vec![("ab/cd/abcd", "2024-01-02T03:04:05.000Z"),
+                                 ("ef/01/ef01", "2024-02-02T03:04:05.000Z")]);
+        assert_eq!(next, Some("1/a+b="));
+
+        let last = "<ListBucketResult><IsTruncated>false</IsTruncated></ListBucketResult>";
+        assert_eq!(parse_list_page(last), (vec![], None));
+    }
+
+    #[test]
+    fn query_values_are_encoded_for_signing()
+    {
+        assert_eq!(uri_encode("1/a+b= c~_.-"), "1%2Fa%2Bb%3D%20c~_.-");
+    }
+}